@@ -0,0 +1,746 @@
+use std::fmt;
+
+use hashbrown::HashSet;
+use serde::{Deserialize, Serialize};
+
+/// What is reported for each bucket.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Aggregation {
+    /// Number of entries.
+    Count,
+    /// Total of the entries' values.
+    Sum,
+    /// Mean of the entries' values.
+    Avg,
+    /// Smallest of the entries' values.
+    Min,
+    /// Largest of the entries' values.
+    Max,
+    /// Variance of the entries' values, of them as the whole population rather than a sample.
+    Var,
+    /// Standard deviation of the entries' values, the square root of their variance.
+    StdDev,
+    /// The given percentile, from 0 to 100, of the entries' values.
+    Percentile(f64),
+    /// Number of different keys among the entries.
+    Distinct,
+}
+
+impl Aggregation {
+    /// Parse an aggregation: `count`, `sum`, `avg`, `min`, `max`, `var`, `stddev`, `distinct` or a
+    /// percentile like `p95` or `p99.9`.
+    #[must_use]
+    pub fn parse(text: &str) -> Option<Self> {
+        match text {
+            "count" => Some(Aggregation::Count),
+            "sum" => Some(Aggregation::Sum),
+            "avg" => Some(Aggregation::Avg),
+            "min" => Some(Aggregation::Min),
+            "max" => Some(Aggregation::Max),
+            "var" => Some(Aggregation::Var),
+            "stddev" => Some(Aggregation::StdDev),
+            "distinct" => Some(Aggregation::Distinct),
+            _ => text
+                .strip_prefix('p')
+                .and_then(|percentile| percentile.parse::<f64>().ok())
+                .filter(|percentile| (0.0..=100.0).contains(percentile))
+                .map(Aggregation::Percentile),
+        }
+    }
+
+    /// Whether the aggregation is of values carried by the entries, rather than of the entries
+    /// themselves.
+    #[must_use]
+    pub fn needs_values(self) -> bool {
+        !matches!(self, Aggregation::Count | Aggregation::Distinct)
+    }
+}
+
+impl fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Aggregation::Count => "count",
+            Aggregation::Sum => "sum",
+            Aggregation::Avg => "avg",
+            Aggregation::Min => "min",
+            Aggregation::Max => "max",
+            Aggregation::Var => "var",
+            Aggregation::StdDev => "stddev",
+            Aggregation::Distinct => "distinct",
+            Aggregation::Percentile(percentile) => return write!(f, "p{percentile}"),
+        })
+    }
+}
+
+/// Summary of the entries counted into a bucket, and of the values and keys they carried.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Aggregate {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+    // Total of the squared differences of the values from their mean, for the variance.
+    #[serde(default)]
+    squared_deviations: f64,
+    // What is kept of the values for percentiles.
+    values: Values,
+    // What is kept of the keys for distinct counts.
+    keys: Keys,
+    // The dedupe keys of the entries recorded, when repeated entries are only recorded once.
+    #[serde(default)]
+    seen: Seen,
+    // Aggregates of the numbers of other fields which entries carry, such as for an Expression.
+    #[serde(default)]
+    fields: Vec<Aggregate>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Values {
+    // No percentiles are needed.
+    Discarded,
+    // Every value, for exact percentiles.
+    Kept(Vec<f64>),
+    // A summary of the values in bounded memory, for approximate percentiles.
+    Digest(TDigest),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+enum Keys {
+    // No distinct count is needed.
+    Discarded,
+    // Every different key, for an exact count.
+    Kept(HashSet<String>),
+    // A sketch of the keys in bounded memory, for an approximate count.
+    Sketch(HyperLogLog),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Default)]
+enum Seen {
+    // Entries aren't deduplicated.
+    #[default]
+    Discarded,
+    // Every different dedupe key.
+    Kept(HashSet<String>),
+    // A hash of every different dedupe key, which takes less memory, although two keys whose
+    // hashes collide are taken to be the same.
+    Hashed(HashSet<u64>),
+}
+
+impl Seen {
+    // Remember a dedupe key, returning whether it had been seen before.
+    fn repeats(&mut self, dedupe: &str) -> bool {
+        match self {
+            Seen::Discarded => false,
+            Seen::Kept(seen) => {
+                // Look the key up by reference first to avoid allocating for every entry.
+                seen.contains(dedupe) || !seen.insert(dedupe.to_string())
+            }
+            Seen::Hashed(seen) => !seen.insert(hash_key(dedupe)),
+        }
+    }
+}
+
+impl Default for Aggregate {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Aggregate {
+    /// An aggregate of no entries, which can't report percentiles or distinct counts.
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            count: 0,
+            sum: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            squared_deviations: 0.0,
+            values: Values::Discarded,
+            keys: Keys::Discarded,
+            seen: Seen::Discarded,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Make this aggregate record entries with the same dedupe key only once. Unless `approximate`
+    /// every dedupe key is kept, otherwise only a hash of each.
+    #[must_use]
+    pub fn deduplicated(self, approximate: bool) -> Self {
+        Self {
+            seen: if approximate {
+                Seen::Hashed(HashSet::new())
+            } else {
+                Seen::Kept(HashSet::new())
+            },
+            ..self
+        }
+    }
+
+    /// An aggregate of no entries, which keeps what it needs of the values to report the given
+    /// aggregations. Percentiles and distinct counts are exact unless `approximate`, in which case
+    /// they are estimated from sketches so that memory doesn't grow with the number of entries.
+    #[must_use]
+    pub fn for_aggregations(aggregations: &[Aggregation], approximate: bool) -> Self {
+        let needs_percentiles = aggregations
+            .iter()
+            .any(|aggregation| matches!(aggregation, Aggregation::Percentile(_)));
+        let needs_keys = aggregations.contains(&Aggregation::Distinct);
+        Self {
+            values: match (needs_percentiles, approximate) {
+                (false, _) => Values::Discarded,
+                (true, false) => Values::Kept(Vec::new()),
+                (true, true) => Values::Digest(TDigest::new()),
+            },
+            keys: match (needs_keys, approximate) {
+                (false, _) => Keys::Discarded,
+                (true, false) => Keys::Kept(HashSet::new()),
+                (true, true) => Keys::Sketch(HyperLogLog::new()),
+            },
+            ..Self::new()
+        }
+    }
+
+    /// Make this aggregate also keep aggregates of the numbers of other fields which entries carry,
+    /// one for each field, such as those of an [`Expression`](crate::Expression).
+    #[must_use]
+    pub fn with_fields(self, fields: Vec<Aggregate>) -> Self {
+        Self { fields, ..self }
+    }
+
+    /// Record the numbers of the other fields carried by an entry which was just recorded, in the
+    /// order of the aggregates given to `with_fields`. Fields without a number are left out of
+    /// their aggregate.
+    pub fn add_fields(&mut self, values: &[Option<f64>]) {
+        for (field, value) in self.fields.iter_mut().zip(values) {
+            if let Some(value) = value {
+                field.add(Some(*value), None);
+            }
+        }
+    }
+
+    /// The aggregate of one of the other fields, by its index among those given to `with_fields`.
+    #[must_use]
+    pub fn field(&self, index: usize) -> Option<&Aggregate> {
+        self.fields.get(index)
+    }
+
+    /// Record an entry, along with its value and key if it carried them.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn add(&mut self, value: Option<f64>, key: Option<&str>) {
+        self.count += 1;
+        if let Some(key) = key {
+            match &mut self.keys {
+                Keys::Discarded => {}
+                Keys::Kept(keys) => {
+                    // Look the key up by reference first to avoid allocating for every entry.
+                    if !keys.contains(key) {
+                        keys.insert(key.to_string());
+                    }
+                }
+                Keys::Sketch(sketch) => sketch.add(key),
+            }
+        }
+        if let Some(value) = value {
+            // Welford's update, which unlike a total of squares keeps its precision when the
+            // values are large and close together.
+            let previous_mean = if self.count > 1 {
+                self.sum / (self.count - 1) as f64
+            } else {
+                value
+            };
+            self.sum += value;
+            self.squared_deviations += (value - previous_mean) * (value - self.sum / self.count as f64);
+            self.min = self.min.min(value);
+            self.max = self.max.max(value);
+            match &mut self.values {
+                Values::Discarded => {}
+                Values::Kept(values) => values.push(value),
+                Values::Digest(digest) => digest.add(value, 1.0),
+            }
+        }
+    }
+
+    /// Record an entry like `add`, unless an entry with the same dedupe key has been recorded
+    /// already. Returns whether it was recorded.
+    pub fn add_deduplicated(&mut self, value: Option<f64>, key: Option<&str>, dedupe: Option<&str>) -> bool {
+        if dedupe.is_some_and(|dedupe| self.seen.repeats(dedupe)) {
+            return false;
+        }
+        self.add(value, key);
+        true
+    }
+
+    /// Record every entry of another aggregate. When deduplicating, entries of the other aggregate
+    /// which repeat the dedupe key of one recorded here aren't counted again, but their values
+    /// can't be told apart from the rest and are still added.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn merge(&mut self, other: &Self) {
+        if self.count > 0 && other.count > 0 {
+            // Combine the deviations from each mean into deviations from the combined mean.
+            let (count, other_count) = (self.count as f64, other.count as f64);
+            let difference = other.sum / other_count - self.sum / count;
+            self.squared_deviations += difference * difference * count * other_count / (count + other_count);
+        }
+        self.squared_deviations += other.squared_deviations;
+        self.count += other.count;
+        match (&mut self.seen, &other.seen) {
+            (Seen::Kept(seen), Seen::Kept(other)) => {
+                for dedupe in other {
+                    if !seen.insert(dedupe.clone()) {
+                        self.count -= 1;
+                    }
+                }
+            }
+            (Seen::Hashed(seen), Seen::Hashed(other)) => {
+                for dedupe in other {
+                    if !seen.insert(*dedupe) {
+                        self.count -= 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.sum += other.sum;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        match (&mut self.values, &other.values) {
+            (Values::Kept(values), Values::Kept(other)) => values.extend_from_slice(other),
+            (Values::Digest(digest), Values::Digest(other)) => digest.merge(other),
+            // Aggregates which keep their values differently were made for different aggregations.
+            _ => {}
+        }
+        match (&mut self.keys, &other.keys) {
+            (Keys::Kept(keys), Keys::Kept(other)) => keys.extend(other.iter().cloned()),
+            (Keys::Sketch(sketch), Keys::Sketch(other)) => sketch.merge(other),
+            _ => {}
+        }
+        for (field, other) in self.fields.iter_mut().zip(&other.fields) {
+            field.merge(other);
+        }
+    }
+
+    /// Number of entries recorded.
+    #[must_use]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The result of an aggregation. None for the average, minimum, maximum, variance or standard
+    /// deviation of no entries.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn value(&self, aggregation: Aggregation) -> Option<f64> {
+        match aggregation {
+            Aggregation::Count => Some(self.count as f64),
+            Aggregation::Sum => Some(self.sum),
+            Aggregation::Distinct => match &self.keys {
+                Keys::Discarded => None,
+                Keys::Kept(keys) => Some(keys.len() as f64),
+                Keys::Sketch(sketch) => Some(sketch.estimate().round()),
+            },
+            _ if self.count == 0 => None,
+            Aggregation::Avg => Some(self.sum / self.count as f64),
+            Aggregation::Min => Some(self.min),
+            Aggregation::Max => Some(self.max),
+            Aggregation::Var => Some(self.squared_deviations / self.count as f64),
+            Aggregation::StdDev => Some((self.squared_deviations / self.count as f64).sqrt()),
+            Aggregation::Percentile(percentile) => match &self.values {
+                Values::Discarded => None,
+                Values::Kept(values) => exact_percentile(values, percentile),
+                Values::Digest(digest) => Some(digest.quantile(percentile / 100.0, self.min, self.max)),
+            },
+        }
+    }
+
+    /// The result of an aggregation as printed: counts as integers, and nothing where there is
+    /// no result.
+    #[must_use]
+    pub fn format(&self, aggregation: Aggregation) -> String {
+        match (aggregation, self.value(aggregation)) {
+            (Aggregation::Count, _) => self.count.to_string(),
+            (_, Some(value)) => value.to_string(),
+            (_, None) => String::new(),
+        }
+    }
+}
+
+// The percentile of some values, interpolating between the two nearest when it falls between them.
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss
+)]
+fn exact_percentile(values: &[f64], percentile: f64) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    let rank = percentile / 100.0 * (values.len() - 1) as f64;
+    let lower_index = rank.floor() as usize;
+    // Only the two values around the rank need to be in order, which is cheaper than sorting.
+    let mut values = values.to_vec();
+    let (_, &mut lower, above) = values.select_nth_unstable_by(lower_index, f64::total_cmp);
+    if above.is_empty() {
+        return Some(lower);
+    }
+    let upper = above.iter().copied().fold(f64::INFINITY, f64::min);
+    Some(lower + (upper - lower) * (rank - rank.floor()))
+}
+
+// Registers of a HyperLogLog are selected by this many bits of each key's hash, giving a standard
+// error of about 1.6%.
+const HYPERLOGLOG_PRECISION: u32 = 12;
+
+// Hash a key the same way in every run and with every build of tbuck, since the sketches and
+// hashes are saved in --state files. This is the 64-bit FNV-1a hash, like the head hash of --state,
+// with its bits then mixed by the finalizer of MurmurHash3 so that the high bits which pick a
+// HyperLogLog register depend on every byte of the key.
+fn hash_key(key: &str) -> u64 {
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    });
+    let hash = (hash ^ (hash >> 33)).wrapping_mul(0xff51_afd7_ed55_8ccd);
+    let hash = (hash ^ (hash >> 33)).wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+// A HyperLogLog sketch, estimating the number of different keys from the longest run of leading
+// zeros seen in the hashes of the keys falling into each register.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct HyperLogLog {
+    // Empty until the first key is added, since many buckets may never get one.
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    fn new() -> Self {
+        Self { registers: Vec::new() }
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    fn add(&mut self, key: &str) {
+        if self.registers.is_empty() {
+            self.registers = vec![0; 1 << HYPERLOGLOG_PRECISION];
+        }
+        let hash = hash_key(key);
+        let index = (hash >> (64 - HYPERLOGLOG_PRECISION)) as usize;
+        let rank = ((hash << HYPERLOGLOG_PRECISION).leading_zeros() + 1).min(64 - HYPERLOGLOG_PRECISION + 1) as u8;
+        self.registers[index] = self.registers[index].max(rank);
+    }
+
+    fn merge(&mut self, other: &Self) {
+        if self.registers.is_empty() {
+            self.registers.clone_from(&other.registers);
+        } else if !other.registers.is_empty() {
+            for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+                *register = (*register).max(*other);
+            }
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn estimate(&self) -> f64 {
+        if self.registers.is_empty() {
+            return 0.0;
+        }
+        let registers = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / registers);
+        let sum: f64 = self.registers.iter().map(|&rank| (-f64::from(rank)).exp2()).sum();
+        let estimate = alpha * registers * registers / sum;
+        let empty = memchr::memchr_iter(0, &self.registers).count();
+        if estimate <= 2.5 * registers && empty > 0 {
+            // Linear counting is more accurate for small counts.
+            registers * (registers / empty as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+// Centroids that would be merged past this many are merged earlier, bounding the size of the
+// digest. Larger is more accurate.
+const COMPRESSION: f64 = 100.0;
+
+// How many values are buffered before being merged into the centroids.
+const BUFFER_SIZE: usize = 500;
+
+// A merging t-digest, estimating percentiles from clusters of values ("centroids") which are
+// kept small near the extremes so that high and low percentiles stay accurate.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct TDigest {
+    // Merged centroids as (mean, weight), in order of mean.
+    centroids: Vec<(f64, f64)>,
+    // Centroids not yet merged, in any order.
+    buffer: Vec<(f64, f64)>,
+}
+
+impl TDigest {
+    fn new() -> Self {
+        Self {
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, mean: f64, weight: f64) {
+        self.buffer.push((mean, weight));
+        if self.buffer.len() >= BUFFER_SIZE {
+            self.compress();
+        }
+    }
+
+    fn merge(&mut self, other: &Self) {
+        for &(mean, weight) in other.centroids.iter().chain(&other.buffer) {
+            self.add(mean, weight);
+        }
+    }
+
+    // Merge the buffer into the centroids, combining neighbouring centroids wherever the scale
+    // function allows.
+    fn compress(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut all = std::mem::take(&mut self.centroids);
+        all.append(&mut self.buffer);
+        all.sort_unstable_by(|left, right| left.0.total_cmp(&right.0));
+        let total: f64 = all.iter().map(|(_, weight)| weight).sum();
+        // Maps a quantile to a scale where each centroid may span at most 1.
+        let scale = |quantile: f64| COMPRESSION / (2.0 * std::f64::consts::PI) * (2.0 * quantile - 1.0).asin();
+
+        let mut merged: Vec<(f64, f64)> = Vec::with_capacity(all.len());
+        let mut before = 0.0;
+        let mut current = all[0];
+        for &(mean, weight) in &all[1..] {
+            let limit = scale(before / total) + 1.0;
+            if scale(((before + current.1 + weight) / total).min(1.0)) <= limit {
+                current.0 += (mean - current.0) * weight / (current.1 + weight);
+                current.1 += weight;
+            } else {
+                before += current.1;
+                merged.push(current);
+                current = (mean, weight);
+            }
+        }
+        merged.push(current);
+        self.centroids = merged;
+    }
+
+    // Estimate a quantile from 0 to 1, given the smallest and largest values added.
+    fn quantile(&self, quantile: f64, min: f64, max: f64) -> f64 {
+        let mut digest = self.clone();
+        digest.compress();
+        let centroids = &digest.centroids;
+        let total: f64 = centroids.iter().map(|(_, weight)| weight).sum();
+        let target = quantile * total;
+
+        // Each centroid's mean is taken to sit at the middle of its weight, with the extremes at
+        // either end, and values in between are interpolated.
+        let mut previous = (0.0, min);
+        let mut before = 0.0;
+        for &(mean, weight) in centroids {
+            let position = before + weight / 2.0;
+            if target < position {
+                let (previous_position, previous_mean) = previous;
+                let fraction = (target - previous_position) / (position - previous_position);
+                return previous_mean + (mean - previous_mean) * fraction.max(0.0);
+            }
+            previous = (position, mean);
+            before += weight;
+        }
+        let (previous_position, previous_mean) = previous;
+        if total <= previous_position {
+            return max;
+        }
+        let fraction = (target - previous_position) / (total - previous_position);
+        previous_mean + (max - previous_mean) * fraction.min(1.0)
+    }
+}
+
+#[cfg(test)]
+mod aggregate_tests {
+    use super::{exact_percentile, hash_key, Aggregate, Aggregation};
+
+    #[test]
+    fn parses() {
+        for aggregation in &[
+            Aggregation::Count,
+            Aggregation::Sum,
+            Aggregation::Avg,
+            Aggregation::Min,
+            Aggregation::Max,
+            Aggregation::Var,
+            Aggregation::StdDev,
+            Aggregation::Percentile(50.0),
+            Aggregation::Percentile(99.9),
+            Aggregation::Distinct,
+        ] {
+            assert_eq!(Aggregation::parse(&aggregation.to_string()), Some(*aggregation));
+        }
+        for text in &["mean", "p", "p101", "p-1", "pfifty"] {
+            assert_eq!(Aggregation::parse(text), None);
+        }
+    }
+
+    #[test]
+    fn distinct_counts() {
+        let aggregations = [Aggregation::Distinct];
+        for approximate in &[false, true] {
+            let mut left = Aggregate::for_aggregations(&aggregations, *approximate);
+            let mut right = Aggregate::for_aggregations(&aggregations, *approximate);
+            for key in 0..10_000 {
+                // Half the keys are seen twice, some of them by both aggregates.
+                let aggregate = if key % 3 == 0 { &mut left } else { &mut right };
+                aggregate.add(None, Some(&(key / 2).to_string()));
+            }
+            left.merge(&right);
+            let actual = left.value(Aggregation::Distinct).expect("keys are kept");
+            // Within 5% when approximate.
+            let error = if *approximate { 250.0 } else { 0.0 };
+            assert!((actual - 5_000.0).abs() <= error, "distinct was {}", actual);
+            assert_eq!(left.count(), 10_000);
+        }
+        let empty = Aggregate::for_aggregations(&aggregations, true);
+        assert_eq!(empty.format(Aggregation::Distinct), "0");
+    }
+
+    #[test]
+    fn hashes_keys_the_same_with_every_build() {
+        // Saved in --state files, so these must never change without raising their version.
+        assert_eq!(hash_key(""), 0xefd0_1f60_ba99_2926);
+        assert_eq!(hash_key("a"), 0x82a2_a958_a9be_ce5b);
+        assert_eq!(hash_key("user=zzz"), 0xf03b_1257_5197_5ccb);
+    }
+
+    #[test]
+    fn deduplicates() {
+        for approximate in &[false, true] {
+            let mut left = Aggregate::new().deduplicated(*approximate);
+            let mut right = Aggregate::new().deduplicated(*approximate);
+            // Whether each entry goes to the left aggregate, and whether it should be recorded.
+            let cases = vec![
+                (true, Some("a"), true),
+                (true, Some("b"), true),
+                (true, Some("a"), false),
+                (true, None, true),
+                (true, None, true),
+                (false, Some("a"), true),
+                (false, Some("c"), true),
+                (false, Some("c"), false),
+            ];
+            for (to_left, dedupe, expected) in cases {
+                let aggregate = if to_left { &mut left } else { &mut right };
+                assert_eq!(aggregate.add_deduplicated(None, None, dedupe), expected);
+            }
+            assert_eq!(left.count(), 4);
+            left.merge(&right);
+            // "a" was seen by both.
+            assert_eq!(left.count(), 5);
+            assert!(!left.add_deduplicated(None, None, Some("c")));
+        }
+    }
+
+    #[test]
+    fn exact_percentiles() {
+        let values = vec![5.0, 1.0, 4.0, 2.0, 3.0];
+        let cases = vec![(0.0, 1.0), (50.0, 3.0), (100.0, 5.0), (90.0, 4.6), (25.0, 2.0)];
+        for (percentile, expected) in cases {
+            let actual = exact_percentile(&values, percentile).expect("values aren't empty");
+            assert!((actual - expected).abs() < 1e-9, "p{} was {}", percentile, actual);
+        }
+        assert_eq!(exact_percentile(&[], 50.0), None);
+        assert_eq!(exact_percentile(&[7.0], 99.0), Some(7.0));
+    }
+
+    #[test]
+    fn approximate_percentiles() {
+        let aggregations = [Aggregation::Percentile(50.0)];
+        let mut left = Aggregate::for_aggregations(&aggregations, true);
+        let mut right = Aggregate::for_aggregations(&aggregations, true);
+        // Spread over two digests to exercise merging.
+        for value in 0..100_000 {
+            let aggregate = if value % 3 == 0 { &mut left } else { &mut right };
+            aggregate.add(Some(f64::from(value)), None);
+        }
+        left.merge(&right);
+        let cases = vec![
+            (1.0, 999.0),
+            (50.0, 49_999.5),
+            (95.0, 94_999.0),
+            (99.0, 98_999.0),
+            (99.9, 99_899.0),
+        ];
+        for (percentile, expected) in cases {
+            let actual = left
+                .value(Aggregation::Percentile(percentile))
+                .expect("there are values");
+            // Within 0.1% of the range.
+            assert!((actual - expected).abs() < 100.0, "p{} was {}", percentile, actual);
+        }
+        assert_eq!(left.value(Aggregation::Percentile(0.0)), Some(0.0));
+        assert_eq!(left.value(Aggregation::Percentile(100.0)), Some(99_999.0));
+    }
+
+    #[test]
+    fn aggregates() {
+        let mut left = Aggregate::new();
+        left.add(Some(2.0), None);
+        left.add(Some(7.5), None);
+        let mut right = Aggregate::new();
+        right.add(Some(-1.0), None);
+        left.merge(&right);
+        let cases = vec![
+            (Aggregation::Count, "3"),
+            (Aggregation::Sum, "8.5"),
+            (Aggregation::Avg, "2.8333333333333335"),
+            (Aggregation::Min, "-1"),
+            (Aggregation::Max, "7.5"),
+        ];
+        for (aggregation, expected) in cases {
+            assert_eq!(left.format(aggregation), expected);
+        }
+    }
+
+    #[test]
+    fn variances() {
+        // Large values close together, which a total of squares would lose the precision of.
+        let values = [1e9 + 4.0, 1e9 + 7.0, 1e9 + 13.0, 1e9 + 16.0];
+        let mut left = Aggregate::new();
+        let mut right = Aggregate::new();
+        for (index, value) in values.iter().enumerate() {
+            let aggregate = if index == 0 { &mut left } else { &mut right };
+            aggregate.add(Some(*value), None);
+        }
+        let mut single = Aggregate::new();
+        single.add(Some(3.0), None);
+        left.merge(&right);
+        left.merge(&Aggregate::new());
+        let cases = vec![
+            (&left, Aggregation::Var, 22.5),
+            (&left, Aggregation::StdDev, 22.5_f64.sqrt()),
+            (&right, Aggregation::Var, 14.0),
+            (&single, Aggregation::Var, 0.0),
+        ];
+        for (aggregate, aggregation, expected) in cases {
+            let actual = aggregate.value(aggregation).expect("there are values");
+            assert!((actual - expected).abs() < 1e-6, "{} was {}", aggregation, actual);
+        }
+    }
+
+    #[test]
+    fn aggregates_nothing() {
+        let empty = Aggregate::new();
+        let cases = vec![
+            (Aggregation::Count, "0"),
+            (Aggregation::Sum, "0"),
+            (Aggregation::Avg, ""),
+            (Aggregation::Min, ""),
+            (Aggregation::Max, ""),
+            (Aggregation::Var, ""),
+            (Aggregation::StdDev, ""),
+        ];
+        for (aggregation, expected) in cases {
+            assert_eq!(empty.format(aggregation), expected);
+        }
+    }
+}
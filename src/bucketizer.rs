@@ -0,0 +1,334 @@
+use std::cmp::Ordering;
+use std::error::Error;
+use std::fmt;
+use std::io::{BufRead, Result as IoResult};
+use std::iter::Peekable;
+
+use chrono::{DateTime, Utc};
+use hashbrown::HashMap;
+
+use crate::{Bucketing, DateTimeFormat, MatchIndex, Matcher};
+
+/// Counts the lines of an input into buckets by the date/time found in each line, the same way
+/// the `tbuck` command does by default.
+#[derive(Debug)]
+pub struct Bucketizer<'f> {
+    matcher: Matcher<'f>,
+    bucketing: Bucketing,
+    match_index: MatchIndex,
+    fill_empty_buckets: bool,
+}
+
+impl<'f> Bucketizer<'f> {
+    /// Create a bucketizer which uses the first match of `format` in each line, and fills in
+    /// empty buckets with a count of 0.
+    /// `bucketing` is either a [`Granularity`](crate::Granularity) or a [`Bucketing`].
+    #[must_use]
+    pub fn new(format: &'f DateTimeFormat, bucketing: impl Into<Bucketing>) -> Self {
+        Self {
+            matcher: Matcher::new(format),
+            bucketing: bucketing.into(),
+            match_index: MatchIndex::FromStart(0),
+            fill_empty_buckets: true,
+        }
+    }
+
+    /// Use the match at this index in each line, when a line has several: a [`MatchIndex`], such as
+    /// `MatchIndex::FromEnd(0)` for the last match, or a 0-based index from the start of the line.
+    #[must_use]
+    pub fn match_index(mut self, match_index: impl Into<MatchIndex>) -> Self {
+        self.match_index = match_index.into();
+        self
+    }
+
+    /// Whether to yield buckets without any entries with a count of 0.
+    #[must_use]
+    pub fn fill_empty_buckets(mut self, fill_empty_buckets: bool) -> Self {
+        self.fill_empty_buckets = fill_empty_buckets;
+        self
+    }
+
+    /// Count every line of an input. Lines without a date/time, or whose date/time can't be
+    /// parsed, are skipped. The buckets are yielded in ascending order.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the input can't be read or isn't valid UTF-8.
+    pub fn bucketize(&self, mut read: impl BufRead) -> IoResult<Buckets> {
+        let mut buckets: HashMap<DateTime<Utc>, u64> = HashMap::new();
+        // Single line buffer to avoid allocating for each line.
+        let mut line = String::with_capacity(4096);
+        loop {
+            line.clear();
+            if read.read_line(&mut line)? == 0 {
+                break;
+            }
+            if let Some(Ok(datetime)) = self.matcher.find_datetime(&line, self.match_index) {
+                *buckets.entry(self.bucketing.bucketize(&datetime)).or_insert(0) += 1;
+            }
+        }
+
+        let mut ordered_buckets: Vec<(DateTime<Utc>, u64)> = buckets.into_iter().collect();
+        ordered_buckets.sort_unstable_by_key(|(bucket, _)| *bucket);
+        Ok(Buckets {
+            counted: ordered_buckets.into_iter().peekable(),
+            bucketing: self.bucketing,
+            fill: self.fill_empty_buckets,
+            next_fill: None,
+        })
+    }
+}
+
+/// The buckets counted by [`Bucketizer::bucketize`], as `(start of bucket, count)` pairs.
+#[derive(Debug)]
+pub struct Buckets {
+    counted: Peekable<std::vec::IntoIter<(DateTime<Utc>, u64)>>,
+    bucketing: Bucketing,
+    fill: bool,
+    // Next empty bucket to yield before the next counted bucket, if filling.
+    next_fill: Option<DateTime<Utc>>,
+}
+
+impl Iterator for Buckets {
+    type Item = (DateTime<Utc>, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let &(bucket, count) = self.counted.peek()?;
+        if let Some(fill) = self.next_fill.filter(|fill| *fill < bucket) {
+            self.next_fill = Some(self.bucketing.successor(&fill));
+            return Some((fill, 0));
+        }
+        self.counted.next();
+        if self.fill {
+            self.next_fill = Some(self.bucketing.successor(&bucket));
+        }
+        Some((bucket, count))
+    }
+}
+
+/// Counts lines into buckets as they arrive, for input whose entries are in order, the same way
+/// the `tbuck --stream` command does. Each bucket is finished, and returned from
+/// [`push_line`](StreamBucketizer::push_line), as soon as an entry for a later bucket arrives.
+#[derive(Debug)]
+pub struct StreamBucketizer<'f> {
+    matcher: Matcher<'f>,
+    bucketing: Bucketing,
+    match_index: MatchIndex,
+    fill_empty_buckets: bool,
+    descending: bool,
+    tolerant: bool,
+    // Bucket being counted and its count so far.
+    current: Option<(DateTime<Utc>, u64)>,
+    // Number of out of order entries skipped because of tolerant.
+    discarded: u64,
+}
+
+/// Error from [`StreamBucketizer::push_line`] for an entry which is out of order.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct OutOfOrder {
+    /// Bucket of the out of order entry.
+    pub bucket: DateTime<Utc>,
+    /// Bucket being counted when the entry arrived.
+    pub current_bucket: DateTime<Utc>,
+}
+
+impl fmt::Display for OutOfOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "entry in bucket {} is out of order after bucket {}",
+            self.bucket, self.current_bucket
+        )
+    }
+}
+
+impl Error for OutOfOrder {}
+
+impl<'f> StreamBucketizer<'f> {
+    /// Create a stream bucketizer which expects ascending entries, uses the first match of
+    /// `format` in each line, and fills in empty buckets with a count of 0.
+    /// `bucketing` is either a [`Granularity`](crate::Granularity) or a [`Bucketing`].
+    #[must_use]
+    pub fn new(format: &'f DateTimeFormat, bucketing: impl Into<Bucketing>) -> Self {
+        Self {
+            matcher: Matcher::new(format),
+            bucketing: bucketing.into(),
+            match_index: MatchIndex::FromStart(0),
+            fill_empty_buckets: true,
+            descending: false,
+            tolerant: false,
+            current: None,
+            discarded: 0,
+        }
+    }
+
+    /// Use the match at this index in each line, when a line has several: a [`MatchIndex`], such as
+    /// `MatchIndex::FromEnd(0)` for the last match, or a 0-based index from the start of the line.
+    #[must_use]
+    pub fn match_index(mut self, match_index: impl Into<MatchIndex>) -> Self {
+        self.match_index = match_index.into();
+        self
+    }
+
+    /// Whether to return buckets without any entries with a count of 0. As in tbuck, empty
+    /// buckets are only filled in when the entries are ascending.
+    #[must_use]
+    pub fn fill_empty_buckets(mut self, fill_empty_buckets: bool) -> Self {
+        self.fill_empty_buckets = fill_empty_buckets;
+        self
+    }
+
+    /// Expect entries in descending rather than ascending order.
+    #[must_use]
+    pub fn descending(mut self, descending: bool) -> Self {
+        self.descending = descending;
+        self
+    }
+
+    /// Skip out of order entries instead of returning an error for them.
+    #[must_use]
+    pub fn tolerant(mut self, tolerant: bool) -> Self {
+        self.tolerant = tolerant;
+        self
+    }
+
+    /// Count a line, returning the buckets it finished, if any. Lines without a date/time, or
+    /// whose date/time can't be parsed, are skipped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the line's entry is out of order, unless tolerant.
+    pub fn push_line(&mut self, line: &str) -> Result<Vec<(DateTime<Utc>, u64)>, OutOfOrder> {
+        let Some(Ok(datetime)) = self.matcher.find_datetime(line, self.match_index) else {
+            return Ok(Vec::new());
+        };
+        let bucket = self.bucketing.bucketize(&datetime);
+        let Some((current_bucket, count)) = &mut self.current else {
+            // If this is the first bucket, just record the entry.
+            self.current = Some((bucket, 1));
+            return Ok(Vec::new());
+        };
+        let ordering = if self.descending {
+            (*current_bucket).cmp(&bucket)
+        } else {
+            bucket.cmp(&*current_bucket)
+        };
+        match ordering {
+            Ordering::Equal => {
+                *count += 1;
+                Ok(Vec::new())
+            }
+            Ordering::Less if self.tolerant => {
+                self.discarded += 1;
+                Ok(Vec::new())
+            }
+            Ordering::Less => Err(OutOfOrder {
+                bucket,
+                current_bucket: *current_bucket,
+            }),
+            Ordering::Greater => {
+                let mut finished = vec![(*current_bucket, *count)];
+                if self.fill_empty_buckets {
+                    let mut next_bucket = self.bucketing.successor(current_bucket);
+                    while next_bucket < bucket {
+                        finished.push((next_bucket, 0));
+                        next_bucket = self.bucketing.successor(&next_bucket);
+                    }
+                }
+                self.current = Some((bucket, 1));
+                Ok(finished)
+            }
+        }
+    }
+
+    /// The bucket being counted and its count so far, which may still grow.
+    #[must_use]
+    pub fn current(&self) -> Option<(DateTime<Utc>, u64)> {
+        self.current
+    }
+
+    /// Number of out of order entries skipped so far, when tolerant.
+    #[must_use]
+    pub fn discarded(&self) -> u64 {
+        self.discarded
+    }
+
+    /// End the input, returning the last bucket, if there were any entries.
+    #[must_use]
+    pub fn finish(self) -> Option<(DateTime<Utc>, u64)> {
+        self.current
+    }
+}
+
+#[cfg(test)]
+mod bucketizer_tests {
+    use super::{Bucketizer, StreamBucketizer};
+    use crate::{DateTimeFormat, Granularity};
+    use chrono::{DateTime, Utc};
+
+    fn minutes(buckets: Vec<(DateTime<Utc>, u64)>) -> Vec<(String, u64)> {
+        buckets
+            .into_iter()
+            .map(|(bucket, count)| (bucket.format("%H:%M").to_string(), count))
+            .collect()
+    }
+
+    #[test]
+    fn counts_lines() {
+        let format = DateTimeFormat::new("%Y-%m-%d %H:%M:%S").unwrap();
+        let input = "2019-02-13 10:02:00 c\nno date here\n2019-02-13 10:00:05 a 2019-02-13 10:05:00\n2019-02-13 10:00:59 b\n2019-02-13 10:00:61 bad\n";
+        let cases = vec![
+            (0, true, vec![("10:00", 2), ("10:01", 0), ("10:02", 1)]),
+            (0, false, vec![("10:00", 2), ("10:02", 1)]),
+            (1, true, vec![("10:05", 1)]),
+        ];
+        for (match_index, fill, expected) in cases {
+            let buckets: Vec<(String, u64)> = Bucketizer::new(&format, Granularity::parse("1m").unwrap())
+                .match_index(match_index)
+                .fill_empty_buckets(fill)
+                .bucketize(input.as_bytes())
+                .unwrap()
+                .map(|(bucket, count)| (bucket.format("%H:%M").to_string(), count))
+                .collect();
+            let expected: Vec<(String, u64)> = expected
+                .into_iter()
+                .map(|(bucket, count)| (bucket.to_string(), count))
+                .collect();
+            assert_eq!(buckets, expected);
+        }
+    }
+
+    #[test]
+    fn streams_lines() {
+        let format = DateTimeFormat::new("%Y-%m-%d %H:%M:%S").unwrap();
+        let mut bucketizer = StreamBucketizer::new(&format, Granularity::parse("1m").unwrap());
+        let cases = vec![
+            ("2019-02-13 10:00:05 a", vec![]),
+            ("no date here", vec![]),
+            ("2019-02-13 10:00:59 b", vec![]),
+            ("2019-02-13 10:03:00 c", vec![("10:00", 2), ("10:01", 0), ("10:02", 0)]),
+            ("2019-02-13 10:04:00 d", vec![("10:03", 1)]),
+        ];
+        for (line, expected) in cases {
+            let expected: Vec<(String, u64)> = expected
+                .into_iter()
+                .map(|(bucket, count)| (bucket.to_string(), count))
+                .collect();
+            assert_eq!(minutes(bucketizer.push_line(line).unwrap()), expected);
+        }
+        assert!(bucketizer.push_line("2019-02-13 10:01:00 late").is_err());
+        assert_eq!(
+            minutes(bucketizer.finish().into_iter().collect()),
+            vec![("10:04".to_string(), 1)]
+        );
+
+        let mut tolerant = StreamBucketizer::new(&format, Granularity::parse("1m").unwrap()).tolerant(true);
+        assert!(tolerant.push_line("2019-02-13 10:04:00 a").unwrap().is_empty());
+        assert!(tolerant.push_line("2019-02-13 10:01:00 late").unwrap().is_empty());
+        assert_eq!(tolerant.discarded(), 1);
+        assert_eq!(
+            minutes(tolerant.current().into_iter().collect()),
+            vec![("10:04".to_string(), 1)]
+        );
+    }
+}
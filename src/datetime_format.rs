@@ -0,0 +1,1219 @@
+use aho_corasick::AhoCorasick;
+use chrono::format::strftime::StrftimeItems;
+use chrono::format::{Fixed, Item, Numeric, Pad, Parsed};
+use chrono::{DateTime, NaiveDate, Utc};
+use memchr::memmem;
+use regex::Regex;
+use std::ops::Range;
+
+// Two-digit years are mapped to 1970--2069 unless configured otherwise, the same as chrono does.
+const DEFAULT_CENTURY_PIVOT: u32 = 70;
+
+/// A chrono strftime-style date/time format, such as `%Y-%m-%d %H:%M:%S`. Will be used both for
+/// finding timestamps within a line and parsing the timestamp into a datetime.
+#[derive(Debug)]
+pub struct DateTimeFormat {
+    chrono_items: Vec<FormatItem>,
+    // Two-digit years (%y) at or above this are in the 1900s, and below it in the 2000s.
+    century_pivot: u32,
+    // The year of timestamps whose format has no year in it.
+    assumed_year: Option<i32>,
+}
+
+impl DateTimeFormat {
+    /// Parse the chrono format specifiers in a string into a `DateTimeFormat`. Returns `Some` if all
+    /// the specifiers in the string are actually supported, or `None` if the user tried to use an
+    /// unsupported chrono specifier.
+    #[must_use]
+    pub fn new(format_string: &str) -> Option<Self> {
+        let items = parse_format_string(format_string);
+        let items_supported = items.iter().all(|item| match item {
+            Item::Numeric(numeric, pad) => numeric_format_to_regex_fragment(numeric, *pad).is_some(),
+            Item::Fixed(fixed) => fixed_format_to_regex_fragment(fixed).is_some(),
+            Item::Error => false,
+            _ => true,
+        });
+        if items_supported {
+            Some(Self {
+                chrono_items: items.into_iter().map(FormatItem::from_chrono).collect(),
+                century_pivot: DEFAULT_CENTURY_PIVOT,
+                assumed_year: None,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Set which century two-digit years (`%y`) are in: years at or above the pivot are in the
+    /// 1900s, and years below it in the 2000s. The default is 70, so `69` is 2069 and `70` is 1970.
+    #[must_use]
+    pub fn with_century_pivot(mut self, century_pivot: u32) -> Self {
+        self.century_pivot = century_pivot;
+        self
+    }
+
+    /// Set the year of timestamps whose format has no year in it, such as the `%b %e %H:%M:%S` of
+    /// syslog. Without one, such formats don't have enough info to be parsed.
+    #[must_use]
+    pub fn with_assumed_year(mut self, year: i32) -> Self {
+        self.assumed_year = Some(year);
+        self
+    }
+
+    // Build the regex which can find occurrences of this format in a line.
+    fn regex(&self) -> Regex {
+        let mut expression = String::with_capacity(128);
+        for item in &self.chrono_items {
+            match item {
+                FormatItem::Literal(string) | FormatItem::Space(string) => {
+                    // Remember to escape special characters.
+                    expression.push_str(&regex::escape(string));
+                }
+                // Fragments are grouped so that alternations in them don't extend to the rest of
+                // the expression.
+                FormatItem::Numeric(numeric, pad) => {
+                    expression.push_str("(?:");
+                    expression.push_str(
+                        numeric_format_to_regex_fragment(numeric, *pad)
+                            .expect("validator should have rejected unsupported items"),
+                    );
+                    expression.push(')');
+                }
+                FormatItem::Fixed(fixed) => {
+                    expression.push_str("(?:");
+                    expression.push_str(
+                        fixed_format_to_regex_fragment(fixed)
+                            .expect("validator should have rejected unsupported items"),
+                    );
+                    expression.push(')');
+                }
+            }
+        }
+        // Given that the only parts to the regex are A) user input that has been escaped and B) strings
+        // that our code is responsible for, we expect the regex to be valid.
+        Regex::new(&expression).expect("Regex unexpectedly invalid")
+    }
+
+    // Build the prefilter for lines which might contain this format. Every literal in the format
+    // must appear in a matching line, as must one of the alternatives of each name specifier.
+    fn prefilter(&self) -> Prefilter {
+        let mut literals: Vec<&str> = Vec::new();
+        let mut alternatives = Vec::new();
+        for item in &self.chrono_items {
+            match item {
+                FormatItem::Literal(string) | FormatItem::Space(string) => {
+                    if !string.is_empty() && !literals.contains(&string.as_str()) {
+                        literals.push(string);
+                    }
+                }
+                FormatItem::Fixed(fixed) => {
+                    if let Some(names) = fixed_format_to_literal_alternatives(fixed) {
+                        alternatives.push(AhoCorasick::new(names).expect("name alternatives are valid patterns"));
+                    }
+                }
+                FormatItem::Numeric(_, _) => {}
+            }
+        }
+        Prefilter {
+            literals: literals
+                .into_iter()
+                .map(|literal| memmem::Finder::new(literal).into_owned())
+                .collect(),
+            alternatives,
+        }
+    }
+
+    /// Parse text matched by the format, such as the span found by [`Matcher::find_span`], into a
+    /// `DateTime<Utc>`. Missing hours and minutes are taken to be 0, and a missing year to be the
+    /// assumed year if there is one, but otherwise full date/time information must be specified in
+    /// the string.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the text doesn't match the format, or what it says isn't a valid date/time.
+    pub fn parse(&self, text: &str) -> chrono::format::ParseResult<DateTime<Utc>> {
+        let mut parsed = Parsed::new();
+        chrono::format::parse(&mut parsed, text, self.chrono_items.iter().map(FormatItem::to_chrono))?;
+        if parsed.offset.is_some() {
+            // The text says which offset it's in, which would be rejected as inconsistent with UTC.
+            return Ok(parsed.to_datetime()?.with_timezone(&Utc));
+        }
+        if let (Some(year_mod_100), None, None) = (parsed.year_mod_100, parsed.year_div_100, parsed.year) {
+            let pivoted = i64::from(year_mod_100) >= i64::from(self.century_pivot);
+            parsed.set_year_div_100(if pivoted { 19 } else { 20 })?;
+        }
+        // A timestamp (%s) has every component already, and would be inconsistent with these.
+        if parsed.timestamp.is_none() {
+            if let (Some(year), None, None, None) =
+                (self.assumed_year, parsed.year, parsed.year_div_100, parsed.year_mod_100)
+            {
+                parsed.set_year(i64::from(year))?;
+            }
+            if parsed.hour_div_12.is_none() && parsed.hour_mod_12.is_none() {
+                parsed.set_hour(0)?;
+            }
+            if parsed.minute.is_none() {
+                parsed.set_minute(0)?;
+            }
+        }
+        parsed.to_datetime_with_timezone(&Utc {})
+    }
+
+    /// Determines whether there is enough information in the user's format string to satisfy chrono's
+    /// parser. This works by building up a dummy string that matches the user's specification
+    /// (substituting dummy values like 0001 for the year, etc), then trying to parse it.
+    #[must_use]
+    // Can't actually panic, since new() only accepts supported items.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn has_enough_info(&self) -> bool {
+        let mut default_values = String::with_capacity(128);
+        for item in &self.chrono_items {
+            match item {
+                FormatItem::Literal(string) | FormatItem::Space(string) => {
+                    default_values.push_str(string);
+                }
+                FormatItem::Numeric(numeric, pad) => {
+                    default_values.push_str(
+                        numeric_format_to_default_value(numeric, *pad)
+                            .expect("validator should have rejected unsupported items"),
+                    );
+                }
+                FormatItem::Fixed(fixed) => {
+                    default_values.push_str(
+                        fixed_format_to_default_value(fixed).expect("validator should have rejected unsupported items"),
+                    );
+                }
+            }
+        }
+        self.parse(&default_values).is_ok()
+    }
+}
+
+// Specifiers tbuck supports in addition to chrono's, for chrono items which have no strftime
+// specifier of their own.
+const NAMED_SPECIFIERS: &[(&str, Fixed)] = &[("%{rfc2822}", Fixed::RFC2822)];
+
+// Split a format string into chrono items, including those of the named specifiers.
+fn parse_format_string(format_string: &str) -> Vec<Item<'_>> {
+    let mut items = Vec::new();
+    let mut segment_start = 0;
+    let mut index = 0;
+    while let Some(offset) = format_string[index..].find('%') {
+        index += offset;
+        let rest = &format_string[index..];
+        if let Some((name, fixed)) = NAMED_SPECIFIERS.iter().find(|(name, _)| rest.starts_with(name)) {
+            items.extend(StrftimeItems::new(&format_string[segment_start..index]));
+            items.push(Item::Fixed(fixed.clone()));
+            index += name.len();
+            segment_start = index;
+        } else {
+            // Skip the character after the % too, so that the second % of %% doesn't start a specifier.
+            index += 1 + rest[1..].chars().next().map_or(0, char::len_utf8);
+        }
+    }
+    items.extend(StrftimeItems::new(&format_string[segment_start..]));
+    items
+}
+
+/// Which of the occurrences of a format within a line to use, when a line has several.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MatchIndex {
+    /// The match at this 0-based index, counting from the start of the line.
+    FromStart(usize),
+    /// The match at this 0-based index, counting back from the end of the line, so that 0 is the
+    /// last match.
+    FromEnd(usize),
+}
+
+impl MatchIndex {
+    /// Parse a match index as given on the command line: a 0-based index like `1`, or a negative
+    /// index like `-1` for the last match.
+    #[must_use]
+    pub fn parse(text: &str) -> Option<Self> {
+        match text.strip_prefix('-') {
+            Some(back) => back
+                .parse::<usize>()
+                .ok()
+                .and_then(|back| back.checked_sub(1))
+                .map(MatchIndex::FromEnd),
+            None => text.parse::<usize>().ok().map(MatchIndex::FromStart),
+        }
+    }
+
+    // Which match to use among `count` matches, counting from the start.
+    fn resolve(self, count: impl FnOnce() -> usize) -> Option<usize> {
+        match self {
+            MatchIndex::FromStart(index) => Some(index),
+            MatchIndex::FromEnd(index) => count().checked_sub(index + 1),
+        }
+    }
+}
+
+impl From<usize> for MatchIndex {
+    fn from(index: usize) -> Self {
+        MatchIndex::FromStart(index)
+    }
+}
+
+/// Finds occurrences of a [`DateTimeFormat`] within lines and parses them.
+#[derive(Debug)]
+pub struct Matcher<'f> {
+    format: &'f DateTimeFormat,
+    regex: Regex,
+    prefilter: Prefilter,
+    // Present if the format is simple enough for the fast path.
+    layout: Option<FixedLayout>,
+    // Present if matches must start at a given character column, along with the regex anchored to
+    // the start of the text.
+    anchor: Option<(usize, Regex)>,
+}
+
+impl<'f> Matcher<'f> {
+    /// Compile everything needed to search lines for a format.
+    #[must_use]
+    pub fn new(format: &'f DateTimeFormat) -> Self {
+        Self {
+            format,
+            regex: format.regex(),
+            prefilter: format.prefilter(),
+            layout: FixedLayout::new(&format.chrono_items),
+            anchor: None,
+        }
+    }
+
+    /// Only accept a match starting at this 0-based character column of each line, such as 0 for
+    /// timestamps at the start of lines. Only that one position is tried, which is faster than
+    /// searching the whole line and avoids matching timestamps in the rest of it. The match index
+    /// is then ignored.
+    #[must_use]
+    // Can't actually panic, since the anchored regex is as valid as the format's own.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn at_column(self, column: usize) -> Self {
+        let anchored = Regex::new(&format!("^(?:{})", self.regex.as_str())).expect("Regex unexpectedly invalid");
+        Self {
+            anchor: Some((column, anchored)),
+            ..self
+        }
+    }
+
+    /// Find the match at the given index within a line, if there is one, and parse it. The index
+    /// is either a [`MatchIndex`] or a 0-based index from the start of the line.
+    #[must_use]
+    pub fn find_datetime(
+        &self,
+        line: &str,
+        index: impl Into<MatchIndex>,
+    ) -> Option<chrono::format::ParseResult<DateTime<Utc>>> {
+        if let Some((column, anchored)) = &self.anchor {
+            return self.find_at_column(line, *column, anchored);
+        }
+        if !self.prefilter.may_match(line) {
+            return None;
+        }
+        let index = index.into();
+        if let Some(layout) = &self.layout {
+            match layout.find(line, index) {
+                LayoutMatch::None => return None,
+                LayoutMatch::Parsed(datetime) => return Some(Ok(datetime)),
+                LayoutMatch::Fallback => {}
+            }
+        }
+        // Counting the matches first avoids keeping them all around when counting from the end.
+        let index = index.resolve(|| self.regex.find_iter(line).count())?;
+        let match_ = self.regex.find_iter(line).nth(index)?;
+        Some(self.format.parse(match_.as_str()))
+    }
+
+    /// Like [`Matcher::find_datetime`], but also returns the byte range of the match within the
+    /// line, such as to look for something after it.
+    #[must_use]
+    pub fn find_datetime_span(
+        &self,
+        line: &str,
+        index: impl Into<MatchIndex>,
+    ) -> Option<(Range<usize>, chrono::format::ParseResult<DateTime<Utc>>)> {
+        let span = self.find_span(line, index)?;
+        let datetime = self.format.parse(&line[span.clone()]);
+        Some((span, datetime))
+    }
+
+    /// Whether [`Matcher::find_datetime`] parses date/times as it finds them, on the fast path for
+    /// formats made up of simple fields, so that finding and parsing can't be timed apart.
+    #[must_use]
+    pub fn parses_while_finding(&self) -> bool {
+        self.layout.is_some()
+    }
+
+    /// The regex that occurrences of the format are found with.
+    #[must_use]
+    pub fn regex(&self) -> &str {
+        self.regex.as_str()
+    }
+
+    /// Find the byte range of the match at the given index within a line, without parsing it, such
+    /// as to time finding and parsing separately. The match is found with the regex, even when the
+    /// format is simple enough for [`Matcher::find_datetime`] to do both at once.
+    #[must_use]
+    pub fn find_span(&self, line: &str, index: impl Into<MatchIndex>) -> Option<Range<usize>> {
+        if let Some((column, anchored)) = &self.anchor {
+            let (start, _) = line.char_indices().nth(*column)?;
+            let match_ = anchored.find(&line[start..])?;
+            return Some(start + match_.start()..start + match_.end());
+        }
+        if !self.prefilter.may_match(line) {
+            return None;
+        }
+        let index = index.into().resolve(|| self.regex.find_iter(line).count())?;
+        Some(self.regex.find_iter(line).nth(index)?.range())
+    }
+
+    /// Find every match within a line, from the start, and parse each of them. If the matcher is
+    /// anchored to a column there is at most one.
+    #[must_use]
+    pub fn find_all_datetimes(&self, line: &str) -> Vec<chrono::format::ParseResult<DateTime<Utc>>> {
+        if let Some((column, anchored)) = &self.anchor {
+            return self.find_at_column(line, *column, anchored).into_iter().collect();
+        }
+        if !self.prefilter.may_match(line) {
+            return Vec::new();
+        }
+        self.regex
+            .find_iter(line)
+            .map(|match_| self.format.parse(match_.as_str()))
+            .collect()
+    }
+
+    // Match only at the start of a character column. The prefilter is skipped, since trying the
+    // single position is cheaper than scanning the line for literals.
+    fn find_at_column(
+        &self,
+        line: &str,
+        column: usize,
+        anchored: &Regex,
+    ) -> Option<chrono::format::ParseResult<DateTime<Utc>>> {
+        let (start, _) = line.char_indices().nth(column)?;
+        let rest = &line[start..];
+        if let Some(layout) = &self.layout {
+            let (_, values) = layout.match_at(rest.as_bytes(), 0)?;
+            if let Some(datetime) = values.to_datetime() {
+                return Some(Ok(datetime));
+            }
+        }
+        let match_ = anchored.find(rest)?;
+        Some(self.format.parse(match_.as_str()))
+    }
+}
+
+// Fast path for formats made up of only literals, a year, and 2-digit numeric fields, like
+// '%Y-%m-%d %H:%M:%S'. Matches are found by comparing bytes directly and converted with integer
+// math, bypassing both the regex engine and chrono's Parsed. The scanner finds exactly the
+// matches the regex would; values it can't convert itself (out of range fields, leap seconds)
+// are handed back to the regex and chrono so that their behavior and error messages are kept.
+#[derive(Debug)]
+struct FixedLayout {
+    segments: Vec<LayoutSegment>,
+}
+
+#[derive(Debug)]
+enum LayoutSegment {
+    Literal(Vec<u8>),
+    // Optional '-' followed by one or more digits, like the regex fragment for %Y.
+    Year,
+    // Exactly 2 digits.
+    Field(LayoutField),
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum LayoutField {
+    Month,
+    Day,
+    Hour,
+    Minute,
+    Second,
+}
+
+// Result of looking for a match with a FixedLayout.
+#[derive(Debug, Eq, PartialEq)]
+enum LayoutMatch {
+    None,
+    Parsed(DateTime<Utc>),
+    Fallback,
+}
+
+impl FixedLayout {
+    // Returns None if the format has items that the layout can't handle.
+    fn new(items: &[FormatItem]) -> Option<Self> {
+        let mut segments: Vec<LayoutSegment> = Vec::with_capacity(items.len());
+        for item in items {
+            let segment = match item {
+                FormatItem::Literal(string) | FormatItem::Space(string) => {
+                    // Merge adjacent literals so that each segment check is a single comparison.
+                    if let Some(LayoutSegment::Literal(bytes)) = segments.last_mut() {
+                        bytes.extend_from_slice(string.as_bytes());
+                        continue;
+                    }
+                    LayoutSegment::Literal(string.as_bytes().to_vec())
+                }
+                FormatItem::Numeric(Numeric::Year, Pad::Zero) => LayoutSegment::Year,
+                FormatItem::Numeric(Numeric::Month, Pad::Zero) => LayoutSegment::Field(LayoutField::Month),
+                FormatItem::Numeric(Numeric::Day, Pad::Zero) => LayoutSegment::Field(LayoutField::Day),
+                FormatItem::Numeric(Numeric::Hour, Pad::Zero) => LayoutSegment::Field(LayoutField::Hour),
+                FormatItem::Numeric(Numeric::Minute, Pad::Zero) => LayoutSegment::Field(LayoutField::Minute),
+                FormatItem::Numeric(Numeric::Second, Pad::Zero) => LayoutSegment::Field(LayoutField::Second),
+                _ => return None,
+            };
+            segments.push(segment);
+        }
+        segments.retain(|segment| !matches!(segment, LayoutSegment::Literal(bytes) if bytes.is_empty()));
+
+        // Each field must be present exactly once, except seconds which may be left out.
+        let count =
+            |wanted: &dyn Fn(&LayoutSegment) -> bool| segments.iter().filter(|&segment| wanted(segment)).count();
+        if count(&|segment| matches!(segment, LayoutSegment::Year)) != 1 {
+            return None;
+        }
+        for field in [
+            LayoutField::Month,
+            LayoutField::Day,
+            LayoutField::Hour,
+            LayoutField::Minute,
+        ] {
+            if count(&|segment| matches!(segment, LayoutSegment::Field(f) if *f == field)) != 1 {
+                return None;
+            }
+        }
+        if count(&|segment| matches!(segment, LayoutSegment::Field(LayoutField::Second))) > 1 {
+            return None;
+        }
+
+        // The year's variable width is only unambiguous if what follows it can't be a digit, in
+        // which case the regex's greedy match never needs to backtrack.
+        let year = segments
+            .iter()
+            .position(|segment| matches!(segment, LayoutSegment::Year))?;
+        match segments.get(year + 1) {
+            None => {}
+            Some(LayoutSegment::Literal(bytes)) if !bytes[0].is_ascii_digit() => {}
+            Some(_) => return None,
+        }
+
+        Some(Self { segments })
+    }
+
+    // Find the match at the given 0-based index within a line, with the same leftmost,
+    // non-overlapping semantics as Regex::find_iter.
+    fn find(&self, line: &str, index: MatchIndex) -> LayoutMatch {
+        let Some(index) = index.resolve(|| self.matches(line).count()) else {
+            return LayoutMatch::None;
+        };
+        match self.matches(line).nth(index) {
+            Some(values) => values.to_datetime().map_or(LayoutMatch::Fallback, LayoutMatch::Parsed),
+            None => LayoutMatch::None,
+        }
+    }
+
+    // Every non-overlapping match of the layout within a line, from the start.
+    fn matches<'l>(&'l self, line: &'l str) -> impl Iterator<Item = LayoutValues> + 'l {
+        let bytes = line.as_bytes();
+        let mut position = 0;
+        std::iter::from_fn(move || {
+            while position < bytes.len() {
+                match self.match_at(bytes, position) {
+                    Some((end, values)) => {
+                        position = end;
+                        return Some(values);
+                    }
+                    None => position += 1,
+                }
+            }
+            None
+        })
+    }
+
+    // Try to match the layout starting exactly at `start`. Returns the end of the match and the
+    // raw field values if successful.
+    fn match_at(&self, bytes: &[u8], start: usize) -> Option<(usize, LayoutValues)> {
+        let mut values = LayoutValues::default();
+        let mut position = start;
+        for segment in &self.segments {
+            let rest = &bytes[position..];
+            match segment {
+                LayoutSegment::Literal(literal) => {
+                    if !rest.starts_with(literal) {
+                        return None;
+                    }
+                    position += literal.len();
+                }
+                LayoutSegment::Year => {
+                    let negative = rest.first() == Some(&b'-');
+                    let sign_len = usize::from(negative);
+                    let digits = rest[sign_len..].iter().take_while(|byte| byte.is_ascii_digit()).count();
+                    if digits == 0 {
+                        return None;
+                    }
+                    // Chrono reads at most 4 digits of an unsigned year, and has its own rules for
+                    // signed years, so anything else is handed to it.
+                    values.year = if negative || digits > 4 {
+                        None
+                    } else {
+                        Some(
+                            rest[..digits]
+                                .iter()
+                                .fold(0, |year, &digit| year * 10 + i32::from(digit - b'0')),
+                        )
+                    };
+                    position += sign_len + digits;
+                }
+                LayoutSegment::Field(field) => match rest {
+                    [tens, ones, ..] if tens.is_ascii_digit() && ones.is_ascii_digit() => {
+                        let value = u32::from(tens - b'0') * 10 + u32::from(ones - b'0');
+                        match field {
+                            LayoutField::Month => values.month = value,
+                            LayoutField::Day => values.day = value,
+                            LayoutField::Hour => values.hour = value,
+                            LayoutField::Minute => values.minute = value,
+                            LayoutField::Second => values.second = value,
+                        }
+                        position += 2;
+                    }
+                    _ => return None,
+                },
+            }
+        }
+        Some((position, values))
+    }
+}
+
+// Field values read by a FixedLayout match.
+#[derive(Debug, Default)]
+struct LayoutValues {
+    year: Option<i32>,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl LayoutValues {
+    // Returns None for anything chrono needs to weigh in on, including the leap second 60.
+    fn to_datetime(&self) -> Option<DateTime<Utc>> {
+        let naive = NaiveDate::from_ymd_opt(self.year?, self.month, self.day)?.and_hms_opt(
+            self.hour,
+            self.minute,
+            self.second,
+        )?;
+        Some(DateTime::from_utc(naive, Utc))
+    }
+}
+
+// Cheap check run before the regex to skip lines that can't possibly contain a match, either
+// because they're missing a literal part of the format (like the '-' and ':' separators) or
+// because they don't contain any of the alternatives for a name specifier (like month names).
+#[derive(Debug)]
+struct Prefilter {
+    literals: Vec<memmem::Finder<'static>>,
+    alternatives: Vec<AhoCorasick>,
+}
+
+impl Prefilter {
+    fn may_match(&self, line: &str) -> bool {
+        self.literals
+            .iter()
+            .all(|finder| finder.find(line.as_bytes()).is_some())
+            && self.alternatives.iter().all(|alternatives| alternatives.is_match(line))
+    }
+}
+
+// Convert a Numeric chrono specifier (like "%Y") into a regex fragment that will match values of
+// that kind. Fixed-width fields may be padded with zeros (the default), spaces (like syslog's
+// "May  3", or %e), or not at all (%-d).
+fn numeric_format_to_regex_fragment(numeric: &Numeric, pad: Pad) -> Option<&'static str> {
+    Some(match numeric {
+        Numeric::Year => "-?\\d+",
+        Numeric::YearMod100
+        | Numeric::Month
+        | Numeric::Day
+        | Numeric::Hour
+        | Numeric::Hour12
+        | Numeric::Minute
+        | Numeric::Second => match pad {
+            Pad::Zero => "\\d{2}",
+            Pad::Space => "[ \\d]\\d",
+            Pad::None => "\\d{1,2}",
+        },
+        Numeric::Ordinal => match pad {
+            Pad::Zero => "\\d{3}",
+            Pad::Space => " {2}\\d| \\d{2}|\\d{3}",
+            Pad::None => "\\d{1,3}",
+        },
+        Numeric::Timestamp => "\\d+",
+        _ => return None,
+    })
+}
+
+// Get a dummy value for a chrono Numeric specifier.
+fn numeric_format_to_default_value(numeric: &Numeric, _pad: Pad) -> Option<&'static str> {
+    Some(match numeric {
+        Numeric::Year => "0001",
+        Numeric::YearMod100 | Numeric::Month | Numeric::Day | Numeric::Hour12 => "01",
+        Numeric::Hour | Numeric::Minute | Numeric::Second => "00",
+        Numeric::Ordinal => "001",
+        Numeric::Timestamp => "000000000",
+        _ => return None,
+    })
+}
+
+// Convert a Fixed chrono specifier (like "%b") into a regex fragment that will match values of
+// that kind.
+fn fixed_format_to_regex_fragment(fixed: &Fixed) -> Option<&'static str> {
+    Some(match fixed {
+        Fixed::ShortMonthName => "Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec",
+        Fixed::LongMonthName => "Jan(uary)?|Feb(ruary)?|Mar(ch)?|Apr(il)?|May|June?|July?|Aug(ust)?|Sep(tember)?|Oct(ober)?|Nov(ember)?|Dec(ember)?",
+        Fixed::ShortWeekdayName => "Mon|Tue|Wed|Thu|Fri|Sat|Sun",
+        Fixed::LongWeekdayName => "Mon(day)?|Tue(sday)?|Wed(nesday)?|Thu(rsday)?|Fri(day)?|Sat(urday)?|Sun(day)?",
+        Fixed::LowerAmPm | Fixed::UpperAmPm => "am|AM|pm|PM",
+        Fixed::RFC2822 => "((Mon|Tue|Wed|Thu|Fri|Sat|Sun), *)?\\d{1,2} +(Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec) +\\d{2,4} +\\d{2}:\\d{2}(:\\d{2})? +([+-]\\d{4}|UT|GMT|[ECMP][SD]T|[A-Za-z])",
+        Fixed::RFC3339 => "\\d{4}-\\d{2}-\\d{2}[Tt]\\d{2}:\\d{2}:\\d{2}(\\.\\d+)?([Zz]|[+-]\\d{2}:\\d{2})",
+        _ => return None,
+    })
+}
+
+// Get the strings of which at least one must be present in text matching a chrono Fixed
+// specifier, or None if there's no such set of strings.
+fn fixed_format_to_literal_alternatives(fixed: &Fixed) -> Option<&'static [&'static str]> {
+    Some(match fixed {
+        Fixed::ShortMonthName | Fixed::LongMonthName | Fixed::RFC2822 => &[
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ],
+        Fixed::ShortWeekdayName | Fixed::LongWeekdayName => &["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"],
+        Fixed::LowerAmPm | Fixed::UpperAmPm => &["am", "AM", "pm", "PM"],
+        _ => return None,
+    })
+}
+
+// Get a dummy value for a chrono Fixed specifier.
+fn fixed_format_to_default_value(fixed: &Fixed) -> Option<&'static str> {
+    Some(match fixed {
+        Fixed::ShortMonthName => "Jan",
+        Fixed::LongMonthName => "January",
+        // The default date, 0001-01-01, was a Monday.
+        Fixed::ShortWeekdayName => "Mon",
+        Fixed::LongWeekdayName => "Monday",
+        Fixed::LowerAmPm => "am",
+        Fixed::UpperAmPm => "AM",
+        Fixed::RFC2822 => "1 Jan 0001 00:00:00 +0000",
+        Fixed::RFC3339 => "0001-01-01T00:00:00Z",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod datetime_format_tests {
+    use super::{DateTimeFormat, FixedLayout, LayoutMatch, MatchIndex, Matcher};
+    use chrono::{Datelike, Timelike};
+
+    #[test]
+    fn formats_are_matched() {
+        let cases = vec![
+            ("%Y", vec!["2019", "1", "0100", "100", "-1"]),
+            (
+                "%m",
+                vec!["01", "02", "03", "04", "05", "06", "07", "08", "09", "10", "11", "12"],
+            ),
+            (
+                "%b",
+                vec![
+                    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+                ],
+            ),
+            (
+                "%B",
+                vec![
+                    "January",
+                    "February",
+                    "March",
+                    "April",
+                    "May",
+                    "June",
+                    "July",
+                    "August",
+                    "September",
+                    "October",
+                    "November",
+                    "December",
+                ],
+            ),
+            ("%a", vec!["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"]),
+            (
+                "%A",
+                vec![
+                    "Monday",
+                    "Tuesday",
+                    "Wednesday",
+                    "Thursday",
+                    "Friday",
+                    "Saturday",
+                    "Sunday",
+                ],
+            ),
+            ("%d", vec!["01", "02", "10", "22", "31"]),
+            ("%e", vec![" 1", " 9", "10", "31"]),
+            ("%-d", vec!["1", "31"]),
+            ("%j", vec!["001", "059", "137", "366"]),
+            ("%y", vec!["00", "24", "99"]),
+            ("%F", vec!["1991-08-10", "2019-03-14"]),
+            ("%D", vec!["07/08/01", "12/31/99"]),
+            ("%H", vec!["00", "02", "10", "19", "23"]),
+            ("%k", vec![" 0", " 9", "10", "23"]),
+            ("%I", vec!["01", "02", "05", "10", "12"]),
+            ("%M", vec!["00", "02", "10", "19", "30", "44", "59"]),
+            ("%S", vec!["00", "02", "10", "19", "30", "44", "59", "60"]),
+            ("%T", vec!["00:00:00", "10:20:30", "23:59:60"]),
+            ("%R", vec!["00:00", "23:59"]),
+            ("%r", vec!["12:34:60 AM", "01:00:00 PM"]),
+            ("%p", vec!["AM", "PM"]),
+            ("%P", vec!["am", "pm"]),
+            ("%s", vec!["994518299"]),
+            (
+                "%+",
+                vec![
+                    "2001-07-08T00:34:60Z",
+                    "2001-07-08t00:34:60.026490+09:30",
+                    "2024-05-14T10:00:00-07:00",
+                ],
+            ),
+            (
+                "%{rfc2822}",
+                vec![
+                    "Tue, 1 Jul 2003 10:52:37 +0200",
+                    "Sun, 06 Nov 1994 08:49:37 GMT",
+                    "1 Jul 03 10:52 EDT",
+                ],
+            ),
+        ];
+        for (strftime, expected_matches) in &cases {
+            let format = DateTimeFormat::new(strftime).unwrap();
+            let regex = format.regex();
+            for expected_match in expected_matches {
+                assert!(regex.is_match(expected_match));
+            }
+        }
+    }
+
+    #[test]
+    fn has_enough_info() {
+        let cases = vec![
+            "%Y-%m-%d %H:%M:%S",
+            "%F %T",
+            "%b %d, %Y %I:%M %p",
+            "%Y-%j %H:%M:%S",
+            "%D %R",
+            "%D %r",
+            "%a %b %e %H:%M:%S %Y",
+            "%A, %d %B %Y %H:%M",
+            "%c",
+            "%+",
+            "%{rfc2822}",
+        ];
+        for strftime in &cases {
+            let format = DateTimeFormat::new(strftime).unwrap();
+            assert!(format.has_enough_info());
+        }
+    }
+
+    #[test]
+    fn century_pivot() {
+        let cases = vec![
+            (70, "69", 2069),
+            (70, "70", 1970),
+            (50, "49", 2049),
+            (50, "50", 1950),
+            (0, "00", 1900),
+            (100, "99", 2099),
+        ];
+        for (pivot, year, expected) in cases {
+            let format = DateTimeFormat::new("%y-%m-%d %H:%M:%S")
+                .unwrap()
+                .with_century_pivot(pivot);
+            let datetime = format.parse(&format!("{year}-05-14 01:02:03")).unwrap();
+            assert_eq!(expected, datetime.year());
+        }
+    }
+
+    #[test]
+    fn missing_components() {
+        let cases = vec![
+            (
+                "%m-%d %H:%M:%S",
+                Some(2024),
+                "05-14 01:02:03",
+                Some((2024, 5, 14, 1, 2, 3)),
+            ),
+            (
+                "%b %e %H:%M:%S",
+                Some(1999),
+                "Dec 31 23:59:59",
+                Some((1999, 12, 31, 23, 59, 59)),
+            ),
+            ("%m-%d %H:%M:%S", None, "05-14 01:02:03", None),
+            ("%Y-%m-%d", None, "2024-05-14", Some((2024, 5, 14, 0, 0, 0))),
+            ("%Y-%m-%d %H", None, "2024-05-14 07", Some((2024, 5, 14, 7, 0, 0))),
+            ("%Y-%m-%d %I:%M", None, "2024-05-14 07:30", None),
+            (
+                "%Y-%m-%d %H:%M:%S",
+                Some(1999),
+                "2024-05-14 01:02:03",
+                Some((2024, 5, 14, 1, 2, 3)),
+            ),
+            ("%s", Some(1999), "994518299", Some((2001, 7, 7, 15, 4, 59))),
+        ];
+        for (strftime, assumed_year, text, expected) in cases {
+            let mut format = DateTimeFormat::new(strftime).unwrap();
+            if let Some(year) = assumed_year {
+                format = format.with_assumed_year(year);
+            }
+            assert_eq!(expected.is_some(), format.has_enough_info());
+            let datetime = format.parse(text).ok();
+            assert_eq!(
+                expected,
+                datetime.map(|datetime| (
+                    datetime.year(),
+                    datetime.month(),
+                    datetime.day(),
+                    datetime.hour(),
+                    datetime.minute(),
+                    datetime.second()
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn finds_whole_match() {
+        let cases = vec![
+            ("%b %e %H:%M:%S", "host May  3 04:05:06 sshd", "May  3 04:05:06"),
+            ("%b %e %H:%M:%S", "host May 13 04:05:06 sshd", "May 13 04:05:06"),
+            ("%d %B %Y", "on 01 June 2019.", "01 June 2019"),
+            (
+                "%a %b %e %H:%M:%S %Y",
+                "[Mon Jan  2 15:04:05 2006] up",
+                "Mon Jan  2 15:04:05 2006",
+            ),
+            (
+                "%+",
+                "{\"ts\":\"2024-05-14T10:00:00.5+02:00\"}",
+                "2024-05-14T10:00:00.5+02:00",
+            ),
+            (
+                "%{rfc2822}",
+                "Received: by mx; Tue, 1 Jul 2003 10:52:37 +0200 (CEST)",
+                "Tue, 1 Jul 2003 10:52:37 +0200",
+            ),
+        ];
+        for (strftime, line, expected) in cases {
+            let format = DateTimeFormat::new(strftime).unwrap();
+            assert_eq!(format.regex().find(line).unwrap().as_str(), expected);
+        }
+    }
+
+    #[test]
+    fn unsupported_formats() {
+        let cases = vec!["%Q", "%{rfc822}", "%Y-%m-%d %H:%M:%S%", "%Y %z"];
+        for strftime in &cases {
+            assert!(DateTimeFormat::new(strftime).is_none());
+        }
+        // An escaped % isn't the start of a named specifier.
+        assert!(DateTimeFormat::new("%%{rfc2822}")
+            .unwrap()
+            .regex()
+            .is_match("%{rfc2822}"));
+    }
+
+    #[test]
+    fn prefilter() {
+        let format = DateTimeFormat::new("%b %d, %Y %H:%M:%S").unwrap();
+        let prefilter = format.prefilter();
+        assert!(prefilter.may_match("Mar 14, 2019 04:59:34 event"));
+        assert!(!prefilter.may_match("14, 2019 04:59:34 missing month name"));
+        assert!(!prefilter.may_match("Mar 14 2019 04:59:34 missing comma"));
+        assert!(!prefilter.may_match("Mar 14, 2019 04-59-34 missing colons"));
+    }
+
+    #[test]
+    fn fixed_layout_agrees_with_regex() {
+        let formats = vec!["%Y-%m-%d %H:%M:%S", "%F %T", "%d/%m/%Y %H:%M", "[%H:%M:%S %Y-%m-%d]"];
+        let lines = vec![
+            "2019-03-14 12:01:00 event",
+            "x12019-03-14 12:01:00 longer year",
+            "at -2019-03-14 12:01:00 negative year",
+            "19-03-14 12:01:00 short year",
+            "2019-13-01 12:01:00 bad month",
+            "2016-12-31 23:59:60 leap second",
+            "14/03/2019 12:01 and 15/03/2019 13:02",
+            "[12:01:00 2019-03-14] [13:02:00 2019-03-15]",
+            "no timestamp here",
+        ];
+        for strftime in &formats {
+            let format = DateTimeFormat::new(strftime).unwrap();
+            let layout = FixedLayout::new(&format.chrono_items).unwrap();
+            let regex = format.regex();
+            for line in &lines {
+                for index in 0..3 {
+                    let expected = regex.find_iter(line).nth(index).map(|m| format.parse(m.as_str()).ok());
+                    match layout.find(line, MatchIndex::FromStart(index)) {
+                        LayoutMatch::None => assert_eq!(None, expected),
+                        LayoutMatch::Parsed(datetime) => assert_eq!(Some(Some(datetime)), expected),
+                        LayoutMatch::Fallback => assert!(expected.is_some()),
+                    }
+                    let expected = regex
+                        .find_iter(line)
+                        .collect::<Vec<_>>()
+                        .into_iter()
+                        .rev()
+                        .nth(index)
+                        .map(|m| format.parse(m.as_str()).ok());
+                    match layout.find(line, MatchIndex::FromEnd(index)) {
+                        LayoutMatch::None => assert_eq!(None, expected),
+                        LayoutMatch::Parsed(datetime) => assert_eq!(Some(Some(datetime)), expected),
+                        LayoutMatch::Fallback => assert!(expected.is_some()),
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn match_indices() {
+        let cases = vec![
+            ("0", Some(MatchIndex::FromStart(0))),
+            ("2", Some(MatchIndex::FromStart(2))),
+            ("-1", Some(MatchIndex::FromEnd(0))),
+            ("-3", Some(MatchIndex::FromEnd(2))),
+            ("-0", None),
+            ("-", None),
+            ("last", None),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(MatchIndex::parse(text), expected, "{text}");
+        }
+
+        // Both the fixed layout and the regex, which handles the abbreviated month name.
+        for strftime in &["%F %T", "%F %b %T"] {
+            let format = DateTimeFormat::new(strftime).unwrap();
+            let matcher = Matcher::new(&format);
+            let line = "2019-03-14 Mar 12:01:00 retried 2019-03-15 Mar 13:02:00 at 2019-03-16 Mar 14:03:00"
+                .replace(" Mar", if strftime.contains("%b") { " Mar" } else { "" });
+            let cases = vec![
+                (MatchIndex::FromStart(0), Some("2019-03-14T12:01:00Z")),
+                (MatchIndex::FromStart(2), Some("2019-03-16T14:03:00Z")),
+                (MatchIndex::FromStart(3), None),
+                (MatchIndex::FromEnd(0), Some("2019-03-16T14:03:00Z")),
+                (MatchIndex::FromEnd(2), Some("2019-03-14T12:01:00Z")),
+                (MatchIndex::FromEnd(3), None),
+            ];
+            for (index, expected) in cases {
+                let actual = matcher
+                    .find_datetime(&line, index)
+                    .map(|datetime| datetime.unwrap().to_rfc3339());
+                assert_eq!(
+                    actual,
+                    expected.map(|expected| expected.replace('Z', "+00:00")),
+                    "{index:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn at_column() {
+        // Both the fixed layout and the regex, which handles the abbreviated month name.
+        for strftime in &["%F %T", "%F %b %T"] {
+            let format = DateTimeFormat::new(strftime).unwrap();
+            let month = if strftime.contains("%b") { " Mar" } else { "" };
+            let cases = vec![
+                (
+                    0,
+                    format!("2019-03-14{month} 12:01:00 event"),
+                    Some("2019-03-14T12:01:00+00:00"),
+                ),
+                (0, format!(" 2019-03-14{month} 12:01:00 indented"), None),
+                (0, format!("payload 2019-03-14{month} 12:01:00"), None),
+                (0, String::new(), None),
+                (
+                    2,
+                    format!("é 2019-03-14{month} 12:01:00 by characters"),
+                    Some("2019-03-14T12:01:00+00:00"),
+                ),
+                (2, format!("ab[2019-03-14{month} 12:01:00]"), None),
+                (2, format!("a 2019-13-14{month} 12:01:00 bad month"), Some("error")),
+            ];
+            for (column, line, expected) in cases {
+                let matcher = Matcher::new(&format).at_column(column);
+                let actual = matcher
+                    .find_datetime(&line, 0)
+                    .map(|datetime| datetime.map_or_else(|_| "error".to_string(), |datetime| datetime.to_rfc3339()));
+                assert_eq!(actual.as_deref(), expected, "{line}");
+            }
+        }
+    }
+
+    #[test]
+    fn spans() {
+        let format = DateTimeFormat::new("%F %T").unwrap();
+        let cases = vec![
+            (None, MatchIndex::FromStart(0), "none", None),
+            (None, MatchIndex::FromStart(0), "at 2019-03-14 12:01:00", Some(3..22)),
+            (
+                None,
+                MatchIndex::FromEnd(0),
+                "2019-03-14 12:01:00 to 2019-03-14 12:02:00",
+                Some(23..42),
+            ),
+            (Some(2), MatchIndex::FromStart(0), "é 2019-03-14 12:01:00", Some(3..22)),
+            (Some(0), MatchIndex::FromStart(0), "é 2019-03-14 12:01:00", None),
+        ];
+        for (column, index, line, expected) in cases {
+            let matcher = match column {
+                Some(column) => Matcher::new(&format).at_column(column),
+                None => Matcher::new(&format),
+            };
+            let actual = matcher.find_datetime_span(line, index).map(|(span, _)| span);
+            assert_eq!(actual, expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn all_matches() {
+        let format = DateTimeFormat::new("%F %T").unwrap();
+        let matcher = Matcher::new(&format);
+        let cases = vec![
+            ("no timestamps", vec![]),
+            ("2019-03-14 12:01:00 one", vec!["2019-03-14T12:01:00+00:00"]),
+            (
+                "batch [2019-03-14 12:01:00, 2019-13-14 12:02:00, 2019-03-14 12:03:00]",
+                vec!["2019-03-14T12:01:00+00:00", "error", "2019-03-14T12:03:00+00:00"],
+            ),
+        ];
+        for (line, expected) in cases {
+            let actual: Vec<String> = matcher
+                .find_all_datetimes(line)
+                .into_iter()
+                .map(|datetime| datetime.map_or_else(|_| "error".to_string(), |datetime| datetime.to_rfc3339()))
+                .collect();
+            assert_eq!(actual, expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn fixed_layout_unsupported() {
+        let cases = vec![
+            "%b %d %Y %H:%M:%S",
+            "%Y%m%d %H:%M:%S",
+            "%s",
+            "%Y-%m-%d %I:%M %p",
+            "%Y-%m-%e %H:%M:%S",
+        ];
+        for strftime in &cases {
+            let format = DateTimeFormat::new(strftime).unwrap();
+            assert!(FixedLayout::new(&format.chrono_items).is_none());
+        }
+    }
+
+    #[test]
+    fn parses() {
+        let cases = vec![
+            ("%Y-%m-%d %H:%M:%S", "1991-08-10 01:02:03", 1991, 8, 10, 1, 2, 3),
+            (
+                "%b %d, %Y %I:%M:%S%P",
+                "Mar 14, 2019 04:59:34pm",
+                2019,
+                3,
+                14,
+                16,
+                59,
+                34,
+            ),
+            ("%s", "1552609482", 2019, 3, 15, 00, 24, 42),
+            ("%Y-%j %H:%M:%S", "2024-137 13:00:00", 2024, 5, 16, 13, 0, 0),
+            ("%y-%m-%d %H:%M:%S", "24-05-14 01:02:03", 2024, 5, 14, 1, 2, 3),
+            ("%Y %b %e %k:%M:%S", "2019 May  3  4:05:06", 2019, 5, 3, 4, 5, 6),
+            ("%D %R", "07/08/01 00:34", 2001, 7, 8, 0, 34, 0),
+            ("%D %r", "07/08/01 12:34:56 PM", 2001, 7, 8, 12, 34, 56),
+            ("%a %b %e %H:%M:%S %Y", "Mon Jan  2 15:04:05 2006", 2006, 1, 2, 15, 4, 5),
+            (
+                "%A, %d %B %Y %H:%M",
+                "Sunday, 14 July 2019 08:30",
+                2019,
+                7,
+                14,
+                8,
+                30,
+                0,
+            ),
+            ("%c", "Mon Jan  2 15:04:05 2006", 2006, 1, 2, 15, 4, 5),
+            ("%+", "2024-05-14T10:00:00Z", 2024, 5, 14, 10, 0, 0),
+            ("%+", "2024-05-14T10:00:00.123456789+02:00", 2024, 5, 14, 8, 0, 0),
+            ("%+", "2024-05-14T23:30:00-01:00", 2024, 5, 15, 0, 30, 0),
+            ("%{rfc2822}", "Tue, 1 Jul 2003 10:52:37 +0200", 2003, 7, 1, 8, 52, 37),
+            ("%{rfc2822}", "1 Jul 2003 10:52 GMT", 2003, 7, 1, 10, 52, 0),
+            (
+                "Date: %{rfc2822}",
+                "Date: Sun, 06 Nov 1994 08:49:37 PST",
+                1994,
+                11,
+                6,
+                16,
+                49,
+                37,
+            ),
+            ("%y-%m-%d %H:%M:%S", "70-05-14 01:02:03", 1970, 5, 14, 1, 2, 3),
+        ];
+        for (strftime, text, y, mo, d, h, mi, s) in cases {
+            let format = DateTimeFormat::new(strftime).unwrap();
+            let datetime = format.parse(text).unwrap();
+            let date = datetime.date();
+            let time = datetime.time();
+            assert_eq!(y, date.year());
+            assert_eq!(mo, date.month());
+            assert_eq!(d, date.day());
+            assert_eq!(h, time.hour());
+            assert_eq!(mi, time.minute());
+            assert_eq!(s, time.second());
+        }
+    }
+}
+
+// Owned equivalent of chrono::format::Item.
+#[derive(Debug)]
+enum FormatItem {
+    Literal(String),
+    Space(String),
+    Numeric(Numeric, Pad),
+    Fixed(Fixed),
+}
+
+impl FormatItem {
+    // Convert from chrono's Item to ours. Allocates string slices into owned strings.
+    fn from_chrono(item: Item<'_>) -> Self {
+        match item {
+            Item::Literal(str_slice) => FormatItem::Literal(str_slice.to_string()),
+            Item::OwnedLiteral(box_str) => FormatItem::Literal(box_str.to_string()),
+            Item::Space(str_slice) => FormatItem::Space(str_slice.to_string()),
+            Item::OwnedSpace(box_str) => FormatItem::Space(box_str.to_string()),
+            Item::Numeric(numeric, pad) => FormatItem::Numeric(numeric, pad),
+            Item::Fixed(fixed) => FormatItem::Fixed(fixed),
+            Item::Error => unimplemented!(),
+        }
+    }
+
+    // Convert back to chrono's representation. Needed for parsing.
+    fn to_chrono(&self) -> Item<'_> {
+        match self {
+            FormatItem::Literal(string) => Item::Literal(string.as_str()),
+            FormatItem::Space(string) => Item::Space(string.as_str()),
+            FormatItem::Numeric(numeric, pad) => Item::Numeric(numeric.clone(), *pad),
+            FormatItem::Fixed(fixed) => Item::Fixed(fixed.clone()),
+        }
+    }
+}
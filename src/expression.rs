@@ -0,0 +1,343 @@
+use std::fmt;
+
+use crate::aggregate::{Aggregate, Aggregation};
+
+/// Arithmetic over the aggregations of a bucket, like `sum(bytes)/count`, for metrics derived
+/// from several of them. An aggregation on its own, like `count` or `avg`, is of the bucket's
+/// entries and their values, while one applied to a name, like `sum(bytes)` or `p95(latency)`, is
+/// of the numbers of that field which entries carry. Numbers, `+`, `-`, `*`, `/` and parentheses
+/// combine them.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expression {
+    text: String,
+    root: Node,
+    // Names of the fields, in the order of their first use.
+    fields: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    Number(f64),
+    // An aggregation of the entries, or of the field at an index if there is one.
+    Aggregation(Aggregation, Option<usize>),
+    Negate(Box<Node>),
+    Binary(Box<Node>, Operator, Box<Node>),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+impl Expression {
+    /// Parse an expression like `sum(bytes)/count`.
+    ///
+    /// # Errors
+    ///
+    /// Fails with a description of the problem if the text isn't a valid expression.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            position: 0,
+            fields: Vec::new(),
+        };
+        let root = parser.sum()?;
+        if let Some(token) = parser.tokens.get(parser.position) {
+            return Err(format!("unexpected '{token}'"));
+        }
+        Ok(Self {
+            text: text.to_string(),
+            root,
+            fields: parser.fields,
+        })
+    }
+
+    /// Names of the fields the expression aggregates, such as `bytes` for `sum(bytes)`, in the
+    /// order [`Aggregate::with_fields`] is expected to keep them in.
+    #[must_use]
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// The aggregations of the entries themselves which the expression uses, such as `count` for
+    /// `sum(bytes)/count`.
+    #[must_use]
+    pub fn aggregations(&self) -> Vec<Aggregation> {
+        let mut aggregations = Vec::new();
+        self.root.visit(&mut |node| {
+            if let Node::Aggregation(aggregation, None) = node {
+                aggregations.push(*aggregation);
+            }
+        });
+        aggregations
+    }
+
+    /// Aggregates of no entries for each of the fields, which keep what the expression needs of
+    /// them. Percentiles are exact unless `approximate`, like [`Aggregate::for_aggregations`].
+    #[must_use]
+    pub fn field_aggregates(&self, approximate: bool) -> Vec<Aggregate> {
+        (0..self.fields.len())
+            .map(|index| {
+                let mut aggregations = Vec::new();
+                self.root.visit(&mut |node| {
+                    if let Node::Aggregation(aggregation, Some(field)) = node {
+                        if *field == index {
+                            aggregations.push(*aggregation);
+                        }
+                    }
+                });
+                Aggregate::for_aggregations(&aggregations, approximate)
+            })
+            .collect()
+    }
+
+    /// The value of the expression for a bucket. None if an aggregation it uses has no result,
+    /// such as the average of no entries, or the result isn't a finite number, such as after
+    /// dividing by 0.
+    #[must_use]
+    pub fn evaluate(&self, aggregate: &Aggregate) -> Option<f64> {
+        self.root.evaluate(aggregate).filter(|value| value.is_finite())
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Node {
+    fn evaluate(&self, aggregate: &Aggregate) -> Option<f64> {
+        match self {
+            Node::Number(number) => Some(*number),
+            Node::Aggregation(aggregation, None) => aggregate.value(*aggregation),
+            Node::Aggregation(aggregation, Some(field)) => aggregate.field(*field)?.value(*aggregation),
+            Node::Negate(node) => node.evaluate(aggregate).map(|value| -value),
+            Node::Binary(left, operator, right) => {
+                let (left, right) = (left.evaluate(aggregate)?, right.evaluate(aggregate)?);
+                Some(match operator {
+                    Operator::Add => left + right,
+                    Operator::Subtract => left - right,
+                    Operator::Multiply => left * right,
+                    Operator::Divide => left / right,
+                })
+            }
+        }
+    }
+
+    fn visit(&self, f: &mut impl FnMut(&Node)) {
+        f(self);
+        match self {
+            Node::Negate(node) => node.visit(f),
+            Node::Binary(left, _, right) => {
+                left.visit(f);
+                right.visit(f);
+            }
+            Node::Number(_) | Node::Aggregation(_, _) => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Name(String),
+    Symbol(char),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(number) => write!(f, "{number}"),
+            Token::Name(name) => f.write_str(name),
+            Token::Symbol(symbol) => write!(f, "{symbol}"),
+        }
+    }
+}
+
+// Split an expression into numbers, names and symbols, skipping whitespace. Names may contain
+// dots, so that percentiles like p99.9 are a single name.
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if "+-*/()".contains(c) {
+            tokens.push(Token::Symbol(c));
+            chars.next();
+        } else if c.is_ascii_digit() || c == '.' || c.is_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(index, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '.' || c == '_') {
+                    break;
+                }
+                end = index + c.len_utf8();
+                chars.next();
+            }
+            let word = &text[start..end];
+            if c.is_ascii_digit() || c == '.' {
+                let number = word.parse::<f64>().map_err(|_| format!("'{word}' is not a number"))?;
+                tokens.push(Token::Number(number));
+            } else {
+                tokens.push(Token::Name(word.to_string()));
+            }
+        } else {
+            return Err(format!("unexpected '{c}'"));
+        }
+    }
+    Ok(tokens)
+}
+
+// Recursive descent over the tokens, with the usual precedence of * and / over + and -.
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    fields: Vec<String>,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    // Consume the next token if it is one of the symbols.
+    fn symbol(&mut self, symbols: &str) -> Option<char> {
+        match self.tokens.get(self.position) {
+            Some(Token::Symbol(symbol)) if symbols.contains(*symbol) => {
+                self.position += 1;
+                Some(*symbol)
+            }
+            _ => None,
+        }
+    }
+
+    fn sum(&mut self) -> Result<Node, String> {
+        let mut node = self.product()?;
+        while let Some(symbol) = self.symbol("+-") {
+            let operator = if symbol == '+' {
+                Operator::Add
+            } else {
+                Operator::Subtract
+            };
+            node = Node::Binary(Box::new(node), operator, Box::new(self.product()?));
+        }
+        Ok(node)
+    }
+
+    fn product(&mut self) -> Result<Node, String> {
+        let mut node = self.unary()?;
+        while let Some(symbol) = self.symbol("*/") {
+            let operator = if symbol == '*' {
+                Operator::Multiply
+            } else {
+                Operator::Divide
+            };
+            node = Node::Binary(Box::new(node), operator, Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.symbol("-").is_some() {
+            return Ok(Node::Negate(Box::new(self.unary()?)));
+        }
+        match self.next() {
+            None => Err("unexpected end of expression".to_string()),
+            Some(Token::Number(number)) => Ok(Node::Number(number)),
+            Some(Token::Symbol('(')) => {
+                let node = self.sum()?;
+                match self.symbol(")") {
+                    Some(_) => Ok(node),
+                    None => Err("missing ')'".to_string()),
+                }
+            }
+            Some(Token::Symbol(symbol)) => Err(format!("unexpected '{symbol}'")),
+            Some(Token::Name(name)) => {
+                let aggregation = Aggregation::parse(&name)
+                    .filter(|aggregation| *aggregation != Aggregation::Distinct)
+                    .ok_or_else(|| format!("unknown aggregation '{name}'"))?;
+                if self.symbol("(").is_none() {
+                    return Ok(Node::Aggregation(aggregation, None));
+                }
+                let (Some(Token::Name(field)), Some(_)) = (self.next(), self.symbol(")")) else {
+                    return Err(format!("expected a field name in {name}(...)"));
+                };
+                let index = self.fields.iter().position(|known| *known == field).unwrap_or_else(|| {
+                    self.fields.push(field);
+                    self.fields.len() - 1
+                });
+                Ok(Node::Aggregation(aggregation, Some(index)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod expression_tests {
+    use super::Expression;
+    use crate::aggregate::{Aggregate, Aggregation};
+
+    #[test]
+    fn evaluates() {
+        let expression = Expression::parse("sum(bytes) / count").unwrap();
+        assert_eq!(expression.fields(), ["bytes"]);
+        assert_eq!(expression.aggregations(), vec![Aggregation::Count]);
+
+        let cases = vec![
+            ("count", Some(3.0)),
+            ("sum(bytes) / count", Some(100.0)),
+            ("sum(bytes)/sum", Some(20.0)),
+            ("max(ms) - min(ms)", Some(40.0)),
+            ("-avg(ms) * (1 + 1)", Some(-60.0)),
+            ("2 + 3 * 4", Some(14.0)),
+            ("(2 + 3) * 4", Some(20.0)),
+            ("count(ms)", Some(2.0)),
+            ("p50(bytes)", Some(100.0)),
+            ("sum / 0", None),
+            ("avg(missing)", None),
+        ];
+        for (text, expected) in cases {
+            let expression = Expression::parse(text).unwrap();
+            let mut aggregate = Aggregate::new().with_fields(expression.field_aggregates(false));
+            let fields: Vec<Vec<Option<f64>>> = expression
+                .fields()
+                .iter()
+                .map(|field| match field.as_str() {
+                    "bytes" => vec![Some(50.0), Some(100.0), Some(150.0)],
+                    "ms" => vec![Some(10.0), None, Some(50.0)],
+                    _ => vec![None, None, None],
+                })
+                .collect();
+            for entry in 0..3 {
+                aggregate.add(Some(5.0), None);
+                let values: Vec<Option<f64>> = fields.iter().map(|field| field[entry]).collect();
+                aggregate.add_fields(&values);
+            }
+            assert_eq!(expression.evaluate(&aggregate), expected, "{text}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for text in &[
+            "",
+            "sum(",
+            "sum(bytes",
+            "count +",
+            "1 2",
+            "foo",
+            "sum(1)",
+            "distinct",
+            "a % b",
+            ")",
+        ] {
+            assert!(Expression::parse(text).is_err(), "{}", text);
+        }
+    }
+}
@@ -0,0 +1,578 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::num::NonZeroU32;
+
+use chrono::offset::LocalResult;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, Offset, TimeZone, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+
+/// Width of the buckets that entries are counted into.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Granularity {
+    Second(NonZeroU32),
+    Minute(NonZeroU32),
+    Hour(NonZeroU32),
+    Day(NonZeroU32),
+    Week(NonZeroU32),
+    Month(NonZeroU32),
+    Quarter(NonZeroU32),
+}
+
+impl Granularity {
+    /// Parse a granularity like `5s`, `1m`, `2h`, `1d`, `1w`, `1mo` or `1q`.
+    #[must_use]
+    pub fn parse(text: &str) -> Option<Self> {
+        // Checked before minutes, which would otherwise match it.
+        if let Some(index) = text.find("mo") {
+            text.split_at(index)
+                .0
+                .parse::<u32>()
+                .ok()
+                .and_then(NonZeroU32::new)
+                .map(Granularity::Month)
+        } else if let Some(index) = text.find('s') {
+            text.split_at(index)
+                .0
+                .parse::<u32>()
+                .ok()
+                .and_then(NonZeroU32::new)
+                .map(Granularity::Second)
+        } else if let Some(index) = text.find('m') {
+            text.split_at(index)
+                .0
+                .parse::<u32>()
+                .ok()
+                .and_then(NonZeroU32::new)
+                .map(Granularity::Minute)
+        } else if let Some(index) = text.find('h') {
+            text.split_at(index)
+                .0
+                .parse::<u32>()
+                .ok()
+                .and_then(NonZeroU32::new)
+                .map(Granularity::Hour)
+        } else if let Some(index) = text.find('d') {
+            text.split_at(index)
+                .0
+                .parse::<u32>()
+                .ok()
+                .and_then(NonZeroU32::new)
+                .map(Granularity::Day)
+        } else if let Some(index) = text.find('w') {
+            text.split_at(index)
+                .0
+                .parse::<u32>()
+                .ok()
+                .and_then(NonZeroU32::new)
+                .map(Granularity::Week)
+        } else if let Some(index) = text.find('q') {
+            text.split_at(index)
+                .0
+                .parse::<u32>()
+                .ok()
+                .and_then(NonZeroU32::new)
+                .map(Granularity::Quarter)
+        } else {
+            None
+        }
+    }
+
+    /// The start of the bucket containing a date/time.
+    #[must_use]
+    pub fn bucketize(&self, datetime: &DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Granularity::Second(s) => {
+                let s = s.get();
+                let time = datetime.time();
+                datetime
+                    .date()
+                    .and_hms(time.hour(), time.minute(), time.second() / s * s)
+            }
+            Granularity::Minute(m) => {
+                let m = m.get();
+                let time = datetime.time();
+                datetime.date().and_hms(time.hour(), time.minute() / m * m, 0)
+            }
+            Granularity::Hour(h) => {
+                let h = h.get();
+                let time = datetime.time();
+                datetime.date().and_hms(time.hour() / h * h, 0, 0)
+            }
+            // Days are counted from 1970-01-01, since they don't divide anything evenly.
+            Granularity::Day(d) => {
+                let d = i64::from(d.get());
+                let days = datetime.timestamp().div_euclid(86400);
+                Utc.timestamp(days.div_euclid(d) * d * 86400, 0)
+            }
+            // Weeks start on Monday, as ISO weeks do, and are counted from 1969-12-29, the Monday
+            // before 1970-01-01.
+            Granularity::Week(w) => {
+                let w = i64::from(w.get()) * 7;
+                let days = datetime.timestamp().div_euclid(86400) + 3;
+                Utc.timestamp((days.div_euclid(w) * w - 3) * 86400, 0)
+            }
+            // Months and quarters are counted from 1970-01.
+            Granularity::Month(m) => bucketize_months(datetime, i64::from(m.get())),
+            Granularity::Quarter(q) => bucketize_months(datetime, i64::from(q.get()) * 3),
+        }
+    }
+
+    /// The width of each bucket, or `None` for months and quarters, whose width varies.
+    #[must_use]
+    pub fn width(&self) -> Option<Duration> {
+        match self {
+            Granularity::Second(s) => Some(Duration::seconds(i64::from(s.get()))),
+            Granularity::Minute(m) => Some(Duration::minutes(i64::from(m.get()))),
+            Granularity::Hour(h) => Some(Duration::hours(i64::from(h.get()))),
+            Granularity::Day(d) => Some(Duration::days(i64::from(d.get()))),
+            Granularity::Week(w) => Some(Duration::weeks(i64::from(w.get()))),
+            Granularity::Month(_) | Granularity::Quarter(_) => None,
+        }
+    }
+
+    /// The start of the bucket after the one starting at a date/time.
+    #[must_use]
+    pub fn successor(&self, datetime: &DateTime<Utc>) -> DateTime<Utc> {
+        match self {
+            Granularity::Second(s) => *datetime + Duration::seconds(i64::from(s.get())),
+            Granularity::Minute(m) => *datetime + Duration::minutes(i64::from(m.get())),
+            Granularity::Hour(h) => *datetime + Duration::hours(i64::from(h.get())),
+            Granularity::Day(d) => *datetime + Duration::days(i64::from(d.get())),
+            Granularity::Week(w) => *datetime + Duration::weeks(i64::from(w.get())),
+            Granularity::Month(m) => add_months(datetime, i64::from(m.get())),
+            Granularity::Quarter(q) => add_months(datetime, i64::from(q.get()) * 3),
+        }
+    }
+}
+
+impl fmt::Display for Granularity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Granularity::Second(s) => write!(f, "{s}s"),
+            Granularity::Minute(m) => write!(f, "{m}m"),
+            Granularity::Hour(h) => write!(f, "{h}h"),
+            Granularity::Day(d) => write!(f, "{d}d"),
+            Granularity::Week(w) => write!(f, "{w}w"),
+            Granularity::Month(m) => write!(f, "{m}mo"),
+            Granularity::Quarter(q) => write!(f, "{q}q"),
+        }
+    }
+}
+
+// Number of months from 1970-01 to a date/time's month.
+fn months_since_epoch(datetime: &DateTime<Utc>) -> i64 {
+    (i64::from(datetime.year()) - 1970) * 12 + i64::from(datetime.month0())
+}
+
+// Year and month which is a number of months from 1970-01.
+fn year_and_month(months_since_epoch: i64) -> (i32, u32) {
+    (
+        i32::try_from(1970 + months_since_epoch.div_euclid(12)).expect("year is within range of a DateTime"),
+        u32::try_from(months_since_epoch.rem_euclid(12) + 1).expect("month is between 1 and 12"),
+    )
+}
+
+// The start of a bucket of calendar months, which are counted from 1970-01.
+fn bucketize_months(datetime: &DateTime<Utc>, months: i64) -> DateTime<Utc> {
+    let (year, month) = year_and_month(months_since_epoch(datetime).div_euclid(months) * months);
+    Utc.ymd(year, month, 1).and_hms(0, 0, 0)
+}
+
+// Add calendar months to a date/time, keeping the day of the month, or using the last day of
+// shorter months.
+fn add_months(datetime: &DateTime<Utc>, months: i64) -> DateTime<Utc> {
+    let (year, month) = year_and_month(months_since_epoch(datetime) + months);
+    let date = (1..=datetime.day())
+        .rev()
+        .find_map(|day| NaiveDate::from_ymd_opt(year, month, day))
+        .expect("every month has a first day");
+    DateTime::from_utc(date.and_time(datetime.time()), Utc)
+}
+
+/// Where the boundaries between buckets fall: a [`Granularity`] apart, optionally shifted from
+/// their usual alignment by an offset, counted from an anchor, or following the wall clock of a
+/// timezone.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Bucketing {
+    granularity: Granularity,
+    offset: Duration,
+    // Buckets are fixed-width intervals from this, rather than dividing up each day or hour.
+    anchor: Option<DateTime<Utc>>,
+    // Buckets divide up the wall clock time of this timezone rather than UTC.
+    timezone: Option<Tz>,
+    // Day that week buckets start on.
+    week_start: Weekday,
+}
+
+impl Bucketing {
+    /// Create a bucketing with the usual alignment of the granularity, such as 5 minute buckets
+    /// starting on the hour.
+    #[must_use]
+    pub fn new(granularity: Granularity) -> Self {
+        Self {
+            granularity,
+            offset: Duration::zero(),
+            anchor: None,
+            timezone: None,
+            week_start: Weekday::Mon,
+        }
+    }
+
+    /// Shift the bucket boundaries later by an offset, such as 5 minute buckets starting 2
+    /// minutes past the hour.
+    #[must_use]
+    pub fn with_offset(mut self, offset: Duration) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Make the buckets fixed-width intervals counted from an anchor, in both directions. By
+    /// default buckets divide up each day or hour, so widths which don't divide it evenly, like
+    /// `7h` or `90m`, give a shorter last bucket in each day or hour. Any offset shifts the anchor.
+    /// Has no effect on months and quarters, which aren't fixed-width.
+    #[must_use]
+    pub fn with_anchor(mut self, anchor: DateTime<Utc>) -> Self {
+        self.anchor = Some(anchor);
+        self
+    }
+
+    /// Align buckets to the wall clock of a timezone rather than UTC, so that, for example, day
+    /// buckets start at local midnight and are 23 or 25 hours long when the clocks change. Has no
+    /// effect with an anchor.
+    #[must_use]
+    pub fn with_timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = Some(timezone);
+        self
+    }
+
+    /// Start week buckets on this day rather than Monday, such as Sunday for US-style weeks.
+    #[must_use]
+    pub fn with_week_start(mut self, week_start: Weekday) -> Self {
+        self.week_start = week_start;
+        self
+    }
+
+    /// The width of the buckets.
+    #[must_use]
+    pub fn granularity(&self) -> Granularity {
+        self.granularity
+    }
+
+    /// The timezone buckets are aligned to, if not UTC.
+    #[must_use]
+    pub fn timezone(&self) -> Option<Tz> {
+        self.timezone
+    }
+
+    /// The start of the bucket containing a date/time.
+    #[must_use]
+    pub fn bucketize(&self, datetime: &DateTime<Utc>) -> DateTime<Utc> {
+        if let (Some(anchor), Some(width)) = (self.anchor, self.granularity.width()) {
+            let anchor = anchor + self.offset;
+            let width = width.num_seconds();
+            // Whole seconds, rounded down, so that entries just before the anchor are in the bucket
+            // before it.
+            let since_anchor = datetime.timestamp() - anchor.timestamp();
+            return anchor + Duration::seconds(since_anchor.div_euclid(width) * width);
+        }
+        let Some(timezone) = self.timezone else {
+            return self.bucketize_aligned(datetime);
+        };
+        // Bucketize the wall clock time as though it were UTC, then find when that wall clock time
+        // actually was.
+        let local = datetime.with_timezone(&timezone);
+        let wall_clock = self
+            .bucketize_aligned(&DateTime::from_utc(local.naive_local(), Utc))
+            .naive_utc();
+        match timezone.from_local_datetime(&wall_clock) {
+            LocalResult::Single(bucket) => bucket.with_timezone(&Utc),
+            // The clocks went back, so the wall clock time happened twice. Use the later time, unless
+            // that is after the entry.
+            LocalResult::Ambiguous(earliest, latest) => {
+                if latest.with_timezone(&Utc) <= *datetime {
+                    latest.with_timezone(&Utc)
+                } else {
+                    earliest.with_timezone(&Utc)
+                }
+            }
+            // The clocks went forward past the wall clock time, so the bucket starts when they did,
+            // which is the wall clock time in the offset from before they changed.
+            LocalResult::None => {
+                let offset_before = timezone
+                    .offset_from_utc_datetime(&(wall_clock - Duration::days(1)))
+                    .fix();
+                DateTime::from_utc(
+                    wall_clock - Duration::seconds(i64::from(offset_before.local_minus_utc())),
+                    Utc,
+                )
+            }
+        }
+    }
+
+    /// The start of the bucket after the one starting at a date/time.
+    #[must_use]
+    pub fn successor(&self, datetime: &DateTime<Utc>) -> DateTime<Utc> {
+        if self.timezone.is_none() || (self.anchor.is_some() && self.granularity.width().is_some()) {
+            return self.granularity.successor(&(*datetime - self.offset)) + self.offset;
+        }
+        // Buckets are longer or shorter than usual when the clocks change, so look from the usual
+        // width onwards for the first time which is in a later bucket.
+        let mut next = self.granularity.successor(datetime);
+        loop {
+            let bucket = self.bucketize(&next);
+            if bucket > *datetime {
+                return bucket;
+            }
+            next = next + Duration::minutes(15);
+        }
+    }
+
+    // The start of the bucket containing a date/time, ignoring any anchor or timezone.
+    fn bucketize_aligned(&self, datetime: &DateTime<Utc>) -> DateTime<Utc> {
+        let mut offset = self.offset;
+        if let Granularity::Week(_) = self.granularity {
+            // Weeks starting on another day are just those starting on Monday, shifted.
+            offset = offset + Duration::days(i64::from(self.week_start.num_days_from_monday()));
+        }
+        self.granularity.bucketize(&(*datetime - offset)) + offset
+    }
+}
+
+impl From<Granularity> for Bucketing {
+    fn from(granularity: Granularity) -> Self {
+        Self::new(granularity)
+    }
+}
+
+#[cfg(test)]
+mod granularity_tests {
+    use super::{Bucketing, Granularity};
+    use chrono::naive::NaiveDate;
+    use chrono::{DateTime, Duration, Timelike, Utc, Weekday};
+    use chrono_tz::America::New_York;
+    use chrono_tz::Asia::Kolkata;
+    use std::num::NonZeroU32;
+
+    #[test]
+    fn parses() {
+        let cases = vec![
+            ("1s", Granularity::Second(NonZeroU32::new(1).unwrap())),
+            ("5s", Granularity::Second(NonZeroU32::new(5).unwrap())),
+            ("1m", Granularity::Minute(NonZeroU32::new(1).unwrap())),
+            ("3m", Granularity::Minute(NonZeroU32::new(3).unwrap())),
+            ("1h", Granularity::Hour(NonZeroU32::new(1).unwrap())),
+            ("10h", Granularity::Hour(NonZeroU32::new(10).unwrap())),
+            ("1d", Granularity::Day(NonZeroU32::new(1).unwrap())),
+            ("7d", Granularity::Day(NonZeroU32::new(7).unwrap())),
+            ("1w", Granularity::Week(NonZeroU32::new(1).unwrap())),
+            ("1mo", Granularity::Month(NonZeroU32::new(1).unwrap())),
+            ("6mo", Granularity::Month(NonZeroU32::new(6).unwrap())),
+            ("1q", Granularity::Quarter(NonZeroU32::new(1).unwrap())),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(Granularity::parse(input).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn displays() {
+        for input in &["5s", "1m", "2h", "1d", "1w", "1mo", "1q"] {
+            assert_eq!(&Granularity::parse(input).unwrap().to_string(), input);
+        }
+    }
+
+    #[test]
+    fn bad_parses() {
+        let cases = vec!["1", "-1s", "m"];
+        for input in cases {
+            assert!(Granularity::parse(input).is_none());
+        }
+    }
+
+    #[test]
+    fn bucketize() {
+        for granularity_seconds in 1..100 {
+            let granularity = Granularity::Second(NonZeroU32::new(granularity_seconds).unwrap());
+            for input_second in 0..60 {
+                let expected_bucket_second = input_second / granularity_seconds * granularity_seconds;
+                let input = DateTime::from_utc(NaiveDate::from_ymd(1991, 8, 10).and_hms(10, 30, input_second), Utc {});
+                let bucket = granularity.bucketize(&input);
+                assert!(bucket.time().second().is_multiple_of(granularity_seconds));
+                assert_eq!(expected_bucket_second, bucket.time().second());
+            }
+        }
+
+        for granularity_minutes in 1..100 {
+            let granularity = Granularity::Minute(NonZeroU32::new(granularity_minutes).unwrap());
+            for input_minute in 0..60 {
+                let expected_bucket_minute = input_minute / granularity_minutes * granularity_minutes;
+                let input = DateTime::from_utc(NaiveDate::from_ymd(1991, 8, 10).and_hms(10, input_minute, 15), Utc {});
+                let bucket = granularity.bucketize(&input);
+                assert!(bucket.time().minute().is_multiple_of(granularity_minutes));
+                assert_eq!(expected_bucket_minute, bucket.time().minute());
+                assert_eq!(0, bucket.time().second());
+            }
+        }
+
+        for granularity_hours in 1..100 {
+            let granularity = Granularity::Hour(NonZeroU32::new(granularity_hours).unwrap());
+            for input_hour in 0..24 {
+                let expected_bucket_hour = input_hour / granularity_hours * granularity_hours;
+                let input = DateTime::from_utc(NaiveDate::from_ymd(1991, 8, 10).and_hms(input_hour, 43, 15), Utc {});
+                let bucket = granularity.bucketize(&input);
+                assert!(bucket.time().hour().is_multiple_of(granularity_hours));
+                assert_eq!(expected_bucket_hour, bucket.time().hour());
+                assert_eq!(0, bucket.time().second());
+                assert_eq!(0, bucket.time().minute());
+            }
+        }
+    }
+
+    #[test]
+    fn bucketize_days() {
+        let at = |(year, month, day, hour)| {
+            DateTime::<Utc>::from_utc(NaiveDate::from_ymd(year, month, day).and_hms(hour, 0, 0), Utc {})
+        };
+        let cases = vec![
+            ("1d", (2024, 3, 10, 13), (2024, 3, 10, 0)),
+            ("1d", (2024, 3, 10, 0), (2024, 3, 10, 0)),
+            ("2d", (1970, 1, 2, 23), (1970, 1, 1, 0)),
+            ("2d", (1970, 1, 3, 0), (1970, 1, 3, 0)),
+            ("2d", (1969, 12, 31, 12), (1969, 12, 30, 0)),
+        ];
+        for (granularity, input, expected) in cases {
+            let bucket = Granularity::parse(granularity).unwrap().bucketize(&at(input));
+            assert_eq!(at(expected), bucket);
+        }
+    }
+
+    #[test]
+    fn bucketize_weeks() {
+        let at = |(month, day, hour)| {
+            DateTime::<Utc>::from_utc(NaiveDate::from_ymd(2024, month, day).and_hms(hour, 0, 0), Utc {})
+        };
+        // 2024-05-12 is a Sunday and 2024-05-13 a Monday.
+        let cases = vec![
+            ("1w", Weekday::Mon, (5, 12, 23), (5, 6, 0), (5, 13, 0)),
+            ("1w", Weekday::Mon, (5, 13, 0), (5, 13, 0), (5, 20, 0)),
+            ("1w", Weekday::Mon, (5, 19, 12), (5, 13, 0), (5, 20, 0)),
+            ("1w", Weekday::Sun, (5, 11, 23), (5, 5, 0), (5, 12, 0)),
+            ("1w", Weekday::Sun, (5, 12, 0), (5, 12, 0), (5, 19, 0)),
+            ("1w", Weekday::Sun, (5, 18, 12), (5, 12, 0), (5, 19, 0)),
+            ("2w", Weekday::Mon, (5, 15, 0), (5, 6, 0), (5, 20, 0)),
+            ("2w", Weekday::Mon, (5, 20, 0), (5, 20, 0), (6, 3, 0)),
+        ];
+        for (granularity, week_start, input, expected_bucket, expected_successor) in cases {
+            let bucketing = Bucketing::new(Granularity::parse(granularity).unwrap()).with_week_start(week_start);
+            let bucket = bucketing.bucketize(&at(input));
+            assert_eq!(at(expected_bucket), bucket);
+            assert_eq!(at(expected_successor), bucketing.successor(&bucket));
+        }
+    }
+
+    #[test]
+    fn bucketize_months() {
+        let at = |(year, month, day)| {
+            DateTime::<Utc>::from_utc(NaiveDate::from_ymd(year, month, day).and_hms(0, 0, 0), Utc {})
+        };
+        let cases = vec![
+            ("1mo", (2024, 2, 29), (2024, 2, 1), (2024, 3, 1)),
+            ("1mo", (2023, 12, 31), (2023, 12, 1), (2024, 1, 1)),
+            ("2mo", (2024, 4, 15), (2024, 3, 1), (2024, 5, 1)),
+            ("1q", (2024, 6, 30), (2024, 4, 1), (2024, 7, 1)),
+            ("1q", (2024, 11, 2), (2024, 10, 1), (2025, 1, 1)),
+            ("1q", (1969, 12, 31), (1969, 10, 1), (1970, 1, 1)),
+            ("2q", (2024, 7, 1), (2024, 7, 1), (2025, 1, 1)),
+        ];
+        for (granularity, input, expected_bucket, expected_successor) in cases {
+            let bucketing = Bucketing::new(Granularity::parse(granularity).unwrap());
+            let bucket = bucketing.bucketize(&at(input));
+            assert_eq!(at(expected_bucket), bucket);
+            assert_eq!(at(expected_successor), bucketing.successor(&bucket));
+        }
+        // Days past the end of a shorter month are clamped to its last day.
+        let granularity = Granularity::parse("1mo").unwrap();
+        assert_eq!(at((2024, 2, 29)), granularity.successor(&at((2024, 1, 31))));
+        assert_eq!(at((2023, 2, 28)), granularity.successor(&at((2023, 1, 31))));
+    }
+
+    #[test]
+    fn bucketize_in_timezone() {
+        let at = |(month, day, hour, minute)| {
+            DateTime::<Utc>::from_utc(NaiveDate::from_ymd(2024, month, day).and_hms(hour, minute, 0), Utc {})
+        };
+        let cases = vec![
+            // Days which are 23 and 25 hours long in New York, and an ordinary one.
+            ("1d", New_York, (3, 10, 12, 0), (3, 10, 5, 0), (3, 11, 4, 0)),
+            ("1d", New_York, (11, 3, 12, 0), (11, 3, 4, 0), (11, 4, 5, 0)),
+            ("1d", New_York, (11, 5, 3, 0), (11, 4, 5, 0), (11, 5, 5, 0)),
+            // Both 1am hours when New York's clocks go back.
+            ("1h", New_York, (11, 3, 5, 30), (11, 3, 5, 0), (11, 3, 6, 0)),
+            ("1h", New_York, (11, 3, 6, 30), (11, 3, 6, 0), (11, 3, 7, 0)),
+            // A bucket which is 2 hours instead of 3 when New York's clocks go forward.
+            ("3h", New_York, (3, 10, 5, 30), (3, 10, 5, 0), (3, 10, 7, 0)),
+            ("1h", Kolkata, (5, 14, 10, 0), (5, 14, 9, 30), (5, 14, 10, 30)),
+            // Months which start in standard time and end in daylight saving time.
+            ("1mo", New_York, (3, 1, 2, 0), (2, 1, 5, 0), (3, 1, 5, 0)),
+            ("1mo", New_York, (3, 1, 5, 0), (3, 1, 5, 0), (4, 1, 4, 0)),
+        ];
+        for (granularity, timezone, input, expected_bucket, expected_successor) in cases {
+            let bucketing = Bucketing::new(Granularity::parse(granularity).unwrap()).with_timezone(timezone);
+            let bucket = bucketing.bucketize(&at(input));
+            assert_eq!(at(expected_bucket), bucket);
+            assert_eq!(at(expected_successor), bucketing.successor(&bucket));
+        }
+    }
+
+    #[test]
+    fn bucketize_with_anchor() {
+        let at = |(day, hour, minute)| {
+            DateTime::<Utc>::from_utc(NaiveDate::from_ymd(2024, 1, day).and_hms(hour, minute, 0), Utc {})
+        };
+        let cases = vec![
+            ("7h", Duration::zero(), (1, 0, 0), (1, 23, 30), (1, 21, 0), (2, 4, 0)),
+            ("7h", Duration::zero(), (1, 0, 0), (2, 3, 59), (1, 21, 0), (2, 4, 0)),
+            ("7h", Duration::zero(), (1, 0, 0), (2, 4, 0), (2, 4, 0), (2, 11, 0)),
+            ("90m", Duration::zero(), (1, 0, 0), (1, 1, 45), (1, 1, 30), (1, 3, 0)),
+            ("90m", Duration::zero(), (2, 0, 0), (1, 23, 59), (1, 22, 30), (2, 0, 0)),
+            (
+                "1h",
+                Duration::minutes(15),
+                (1, 0, 0),
+                (1, 10, 0),
+                (1, 9, 15),
+                (1, 10, 15),
+            ),
+        ];
+        for (granularity, offset, anchor, input, expected_bucket, expected_successor) in cases {
+            let bucketing = Bucketing::new(Granularity::parse(granularity).unwrap())
+                .with_offset(offset)
+                .with_anchor(at(anchor));
+            let bucket = bucketing.bucketize(&at(input));
+            assert_eq!(at(expected_bucket), bucket);
+            assert_eq!(at(expected_successor), bucketing.successor(&bucket));
+        }
+    }
+
+    #[test]
+    fn bucketize_with_offset() {
+        let cases = vec![
+            ("5m", Duration::minutes(2), (10, 1, 59), (9, 57, 0), (10, 2, 0)),
+            ("5m", Duration::minutes(2), (10, 2, 0), (10, 2, 0), (10, 7, 0)),
+            ("5m", Duration::minutes(2), (10, 6, 59), (10, 2, 0), (10, 7, 0)),
+            ("1h", Duration::minutes(15), (10, 14, 0), (9, 15, 0), (10, 15, 0)),
+            ("1h", Duration::minutes(15), (10, 15, 0), (10, 15, 0), (11, 15, 0)),
+            ("10s", Duration::seconds(3), (10, 0, 12), (10, 0, 3), (10, 0, 13)),
+            ("1m", Duration::zero(), (10, 0, 12), (10, 0, 0), (10, 1, 0)),
+        ];
+        for (granularity, offset, input, expected_bucket, expected_successor) in cases {
+            let bucketing = Bucketing::new(Granularity::parse(granularity).unwrap()).with_offset(offset);
+            let at = |(hour, minute, second)| {
+                DateTime::<Utc>::from_utc(NaiveDate::from_ymd(1991, 8, 10).and_hms(hour, minute, second), Utc {})
+            };
+            let bucket = bucketing.bucketize(&at(input));
+            assert_eq!(at(expected_bucket), bucket);
+            assert_eq!(at(expected_successor), bucketing.successor(&bucket));
+        }
+    }
+}
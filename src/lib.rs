@@ -0,0 +1,48 @@
+//! **tbuck** ("timeseries bucketing") by Drake Tetreault
+//!
+//! To the extent possible under law, the person who associated CC0 with
+//! tbuck has waived all copyright and related or neighboring rights
+//! to tbuck.
+//!
+//! You should have received a copy of the CC0 legalcode along with this
+//! work.  If not, see <http://creativecommons.org/publicdomain/zero/1.0/>.
+//!
+//! The date/time matching and bucketing logic behind the `tbuck` command, for use from other
+//! programs. A [`Bucketizer`] counts the lines of an input into buckets, and a
+//! [`StreamBucketizer`] does the same for lines pushed to it one at a time:
+//!
+//! ```
+//! use tbuck::{Bucketizer, DateTimeFormat, Granularity};
+//!
+//! let format = DateTimeFormat::new("%Y-%m-%d %H:%M:%S").expect("format is supported");
+//! let granularity = Granularity::parse("1m").expect("granularity is valid");
+//! let input = "2019-02-13 10:00:05 started\n2019-02-13 10:00:59 ready\n2019-02-13 10:02:00 done\n";
+//! let buckets: Vec<_> = Bucketizer::new(&format, granularity)
+//!     .bucketize(input.as_bytes())
+//!     .expect("input is readable")
+//!     .map(|(bucket, count)| (bucket.to_string(), count))
+//!     .collect();
+//! assert_eq!(
+//!     buckets,
+//!     vec![
+//!         ("2019-02-13 10:00:00 UTC".to_string(), 2),
+//!         ("2019-02-13 10:01:00 UTC".to_string(), 0),
+//!         ("2019-02-13 10:02:00 UTC".to_string(), 1),
+//!     ]
+//! );
+//! ```
+
+#![deny(clippy::all)]
+#![deny(clippy::pedantic)]
+
+mod aggregate;
+mod bucketizer;
+mod datetime_format;
+mod expression;
+mod granularity;
+
+pub use aggregate::{Aggregate, Aggregation};
+pub use bucketizer::{Bucketizer, Buckets, OutOfOrder, StreamBucketizer};
+pub use datetime_format::{DateTimeFormat, MatchIndex, Matcher};
+pub use expression::Expression;
+pub use granularity::{Bucketing, Granularity};
@@ -10,783 +10,10872 @@
 #![deny(clippy::all)]
 #![deny(clippy::pedantic)]
 
-use std::cmp::Ordering;
-use std::io::{BufRead, BufReader, Read, Result as IoResult, Write};
+use std::borrow::Cow;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{
+    BufRead, BufReader, BufWriter, Error as IoError, ErrorKind, IsTerminal, LineWriter, Read, Result as IoResult, Seek,
+    SeekFrom, Stdout, Write,
+};
+use std::net::{TcpListener, ToSocketAddrs, UdpSocket};
 use std::num::NonZeroU32;
+use std::ops::Range;
 use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration as StdDuration, Instant};
 
-use chrono::format::strftime::StrftimeItems;
-use chrono::format::{Fixed, Item, Numeric, Pad, Parsed};
-use chrono::{DateTime, Duration, Timelike, Utc};
-use clap::{App, Arg};
-use hashbrown::HashMap;
-use regex::Regex;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Datelike, Duration, NaiveDateTime, SecondsFormat, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use hashbrown::{HashMap, HashSet};
+use memmap2::Mmap;
+use rayon::prelude::*;
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Serialize};
+use tbuck::{Aggregate, Aggregation, Bucketing, DateTimeFormat, Expression, Granularity, MatchIndex, Matcher};
+use tempfile::{NamedTempFile, TempPath};
 
-fn main() -> IoResult<()> {
-    let args = parse_args();
+fn main() {
+    let result = match parse_args() {
+        Command::Count(mut args) => {
+            args.order = resolve_order(&args).unwrap_or_else(|err| err.exit());
+            stop_on_sigint().and_then(|()| run(&args))
+        }
+        Command::Merge(args) => run_merge(&args, &mut std::io::stdout().lock()),
+        Command::Downsample(args) => run_downsample(&args, &mut std::io::stdout().lock()),
+        Command::Join(args) => run_join(&args, &mut std::io::stdout().lock()),
+        Command::Gen(args) => run_gen(&args),
+        Command::Plot(args) => run_plot(&args),
+        Command::Explain(args) => run_explain(&args),
+        Command::Wizard(args) => run_wizard(&args),
+    };
+    if let Err(err) = result {
+        eprintln!("tbuck: {err}");
+        std::process::exit(err.exit_code());
+    }
+}
 
-    // Single line buffer to avoid allocating for each line.
-    let mut line = String::with_capacity(4096);
+// The first SIGINT stops counting, so that what was counted so far is still printed. A second
+// quits right away, such as when waiting for input which never comes.
+fn stop_on_sigint() -> Result<(), Error> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, atomic::Ordering::Relaxed) {
+            std::process::exit(EXIT_INTERRUPTED);
+        }
+    })
+    .map_err(|err| IoError::other(err).into())
+}
 
-    // Compile the regex only once.
-    let regex = args.datetime_format.regex();
+fn run(args: &Args) -> Result<(), Error> {
+    let started = Instant::now();
+    let context = Context::new(args)?;
 
     // Initialize mode-based logic.
-    let mut runner = Runner::from_mode(args.mode);
+    let mut runner = Runner::from_mode(args.mode, args);
+    let mut resumed = match &args.state {
+        Some(path) => State::load(path, args)?,
+        None => None,
+    };
+    // The state for the next run, saved once the output has been written.
+    let mut saved_state = None;
 
-    // TODO: parallelize reading across inputs? Probably not super helpful.
-    for input in &args.inputs {
-        // open_bare_read does dynamic dispatch based on the type of input via a `&mut dyn Read` pointer.
-        input.open_bare_read(|read| {
-            let mut reader = BufReader::new(read);
-            loop {
-                // Always clear old data.
-                line.clear();
-
-                if reader.read_line(&mut line)? == 0 {
-                    break;
-                }
+    // Line counts for each input, in the same order as the inputs.
+    let mut stats = Vec::with_capacity(args.inputs.len());
 
-                // Find the match at the indicated match_index. Ignore lines without a match.
-                let match_ = match regex.find_iter(&line).skip(args.match_index).nth(0) {
-                    None => continue,
-                    Some(m) => m,
-                };
+    if let Mode::Normal = args.mode {
+        // Normal mode doesn't care about the order entries arrive in, so inputs (and chunks of
+        // each input) can be counted into separate sets of buckets on a thread pool and the
+        // results merged afterwards.
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(args.jobs)
+            .build()
+            .map_err(IoError::other)?;
+        // With --state, only what was appended to each input since the last run is counted.
+        let ranges = resume_ranges(resumed.as_ref(), args)?;
+        let skews = args.input_skews();
+        let counted = pool.install(|| {
+            args.inputs
+                .par_iter()
+                .zip(skews.par_iter())
+                .zip(ranges.par_iter())
+                .map(|((input, skew), range)| {
+                    count_input_in_chunks(input, *skew, range.as_ref().map(|(range, _)| range.clone()), &context)
+                })
+                .collect::<Result<Vec<(Runner, Stats)>, Error>>()
+        })?;
+        for ((input_runner, input_stats), group) in counted.into_iter().zip(group_names(&args.inputs)) {
+            let input_runner = if args.per_file {
+                input_runner.into_group(&group)
+            } else {
+                input_runner
+            };
+            runner = runner.merge(input_runner);
+            stats.push(input_stats);
+        }
+        if let Some(sessions) = &args.sessions {
+            runner.count_sessions(sessions, args);
+        }
+        // Every bucket is held until the end, so the peak is the final count.
+        if let Some(first) = stats.first_mut() {
+            first.timing.record_buckets(&runner, args);
+        }
+        // The offsets of an interrupted run would be past lines which weren't counted.
+        if let (Some(path), false) = (&args.state, interrupted()) {
+            if let Some(resumed) = resumed.take() {
+                runner = runner.merge(resumed.into_runner());
+            }
+            let (offsets, heads) = args
+                .inputs
+                .iter()
+                .zip(ranges)
+                .filter_map(|(input, range)| {
+                    let (range, head) = range?;
+                    Some(((input.name(), range.end), (input.name(), head)))
+                })
+                .unzip();
+            saved_state = Some(State::save(&runner, offsets, heads, path, args)?);
+        }
+    } else {
+        for (index, input) in args.inputs.iter().enumerate() {
+            if interrupted() {
+                break;
+            }
+            // Only the last input is followed, so that rotated logs can be listed before the live one.
+            let follow = args.follow && index + 1 == args.inputs.len();
+            let mut input_stats = Stats::default();
+            process_input(input, follow, &context, &mut runner, &mut input_stats)?;
+            stats.push(input_stats);
+        }
+    }
 
-                // Convert the match into a DateTime<Utc>. Because the regex is more permissive than
-                // the chrono library (for example, a value of '61' seconds will pass the regex but
-                // not chrono's range checking), its possible the parsing may fail. This is more
-                // indicative of a problem than a line not having a match, so alert the user with
-                // a stderr message.
-                let datetime = match args.datetime_format.try_parse(match_.as_str()) {
-                    Ok(p) => p,
-                    Err(err) => {
-                        eprintln!("Failed to parse date/time match: {}", err);
-                        continue;
-                    }
-                };
+    let mut output_time = StdDuration::ZERO;
+    let breaches = timed(args.timing, &mut output_time, || runner.finish(&context.output, args))?;
+    context.output.commit()?;
+    if let (Some(saved_state), Some(path)) = (saved_state, &args.state) {
+        saved_state.persist(path).map_err(|err| err.error)?;
+    }
+    context.errors.finish();
 
-                // Increment bucket count.
-                let bucket = args.granularity.bucketize(&datetime);
-                runner.handle_bucket_entry(bucket, &args)?;
-            }
+    if let Some(stats_format) = args.stats {
+        write_stats(&mut std::io::stderr().lock(), stats_format, &args.inputs, &stats)?;
+    }
+    if args.timing {
+        let timing = stats
+            .iter()
+            .fold(Timing::default(), |total, stats| total.merge(stats.timing));
+        write_timing(&mut std::io::stderr().lock(), &timing, output_time, started.elapsed())?;
+    }
+    if interrupted() {
+        return Err(Error::Interrupted);
+    }
+    if breaches > 0 {
+        return Err(Error::Alert { breaches });
+    }
+    Ok(())
+}
+
+// Sum the buckets of earlier outputs, and print them again.
+fn run_merge(args: &MergeArgs, out: &mut impl Write) -> Result<(), Error> {
+    // The sum of each column of each group's values in each bucket. Rows without a group are in
+    // the group None.
+    let columns = args.columns.len();
+    let mut groups: BTreeMap<Option<String>, BTreeMap<DateTime<Utc>, Vec<f64>>> = BTreeMap::new();
+    read_output_rows(&args.inputs, columns, |bucket, group, column, value| {
+        groups
+            .entry(group.map(str::to_string))
+            .or_default()
+            .entry(bucket)
+            .or_insert_with(|| vec![0.0; columns])[column] += value;
+        Ok(())
+    })?;
+    let empty = vec![0.0; columns];
+    write_output_rows(
+        out,
+        &groups,
+        &args.bucketing,
+        args.fill_empty_buckets,
+        args.max_fill,
+        |sums| {
+            let sums: Vec<String> = sums.unwrap_or(&empty).iter().map(f64::to_string).collect();
+            sums.join(",")
+        },
+    )
+}
+
+// Combine the buckets of an earlier output into coarser buckets, and print them again.
+fn run_downsample(args: &DownsampleArgs, out: &mut impl Write) -> Result<(), Error> {
+    // The values of each column of each group's fine buckets which fall into each coarse bucket.
+    let columns = args.columns.len();
+    let empty = vec![Aggregate::for_aggregations(&[args.aggregation], false); columns];
+    let mut groups: BTreeMap<Option<String>, BTreeMap<DateTime<Utc>, Vec<Aggregate>>> = BTreeMap::new();
+    read_output_rows(&args.inputs, columns, |bucket, group, column, value| {
+        if args.from.bucketize(&bucket) != bucket {
+            return Err(format!(
+                "{bucket} isn't the start of a bucket of the --from granularity"
+            ));
+        }
+        let coarse = args.to.bucketize(&bucket);
+        // A fine bucket would otherwise be split between two coarse ones.
+        if args.from.bucketize(&coarse) != coarse {
+            return Err(format!(
+                "the bucket {coarse} of the --to granularity doesn't start on a bucket of the --from granularity"
+            ));
+        }
+        groups
+            .entry(group.map(str::to_string))
+            .or_default()
+            .entry(coarse)
+            .or_insert_with(|| empty.clone())[column]
+            .add(Some(value), None);
+        Ok(())
+    })?;
+    write_output_rows(
+        out,
+        &groups,
+        &args.to,
+        args.fill_empty_buckets,
+        args.max_fill,
+        |aggregates| {
+            let values: Vec<String> = aggregates
+                .unwrap_or(&empty)
+                .iter()
+                .map(|aggregate| aggregate.format(args.aggregation))
+                .collect();
+            values.join(",")
+        },
+    )
+}
+
+// The sums of the columns of a series of earlier outputs in each bucket.
+type ColumnSums = BTreeMap<DateTime<Utc>, Vec<Option<f64>>>;
+
+// Print the series of earlier outputs side by side, with a column for each.
+fn run_join(args: &JoinArgs, out: &mut impl Write) -> Result<(), Error> {
+    // The sum of each column of each series' values in each bucket, by the index of its input and
+    // its group. Columns without a value in a bucket are None.
+    let columns = args.columns.len();
+    let mut series: BTreeMap<(usize, Option<String>), ColumnSums> = BTreeMap::new();
+    for (index, input) in args.inputs.iter().enumerate() {
+        read_output_rows(std::slice::from_ref(input), columns, |bucket, group, column, value| {
+            let sum = &mut series
+                .entry((index, group.map(str::to_string)))
+                .or_default()
+                .entry(bucket)
+                .or_insert_with(|| vec![None; columns])[column];
+            *sum = Some(sum.unwrap_or(0.0) + value);
             Ok(())
         })?;
     }
 
-    runner.finish(&args)
+    let buckets = output_buckets(series.values(), &args.bucketing, args.fill_empty_buckets, args.max_fill)?;
+    // Series are named by their input, their group, or both when there are several inputs with
+    // groups, followed by the column when there are several.
+    write!(out, "bucket")?;
+    for (index, group) in series.keys() {
+        let name = match group {
+            None => args.inputs[*index].name(),
+            Some(group) if args.inputs.len() == 1 => csv_unquote(group).into_owned(),
+            Some(group) => format!("{}:{}", args.inputs[*index].name(), csv_unquote(group)),
+        };
+        for column in &args.columns {
+            if columns > 1 {
+                write!(out, ",{}", csv_field(&format!("{name}:{column}")))?;
+            } else {
+                write!(out, ",{}", csv_field(&name))?;
+            }
+        }
+    }
+    writeln!(out)?;
+    for bucket in buckets {
+        let label = Label {
+            bucket,
+            timezone: None,
+            format: LabelFormat::DateTime,
+        };
+        write!(out, "{label}")?;
+        for sums in series.values() {
+            for column in 0..columns {
+                match sums.get(&bucket).and_then(|sums| sums[column]) {
+                    Some(sum) => write!(out, ",{sum}")?,
+                    None => write!(out, ",")?,
+                }
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(())
 }
 
-// Defines CLI args. Will terminate program with an error message if args are invalid.
-fn parse_args() -> Args {
-    let app_matches = App::new("tbuck")
-        .author(clap::crate_authors!())
-        .version(clap::crate_version!())
-        .about(clap::crate_description!())
-        .arg(Arg::with_name("match-index")
-            .short("m")
-            .long("match-index")
-            .takes_value(true)
-            .value_name("MATCH_INDEX")
-            .default_value("0")
-            .help("0-based index of match to use if multiple matches are found")
-            .validator(|value| {
-                value.parse::<usize>()
-                    .map(|_| ())
-                    .map_err(|_| "Not a valid positive integer index".to_string())
-            }))
-        .arg(Arg::with_name("granularity")
-            .short("g")
-            .long("granularity")
-            .takes_value(true)
-            .value_name("GRANULARITY")
-            .default_value("1m")
-            .help("Bucket time granularity in seconds ('5s'), minutes ('1m'), or hours ('2h')")
-            .validator(|value| {
-                Granularity::parse(&value)
-                    .map(|_| ())
-                    .ok_or_else(|| "Not a valid granularity specifier".to_string())
-            }))
-        .arg(Arg::with_name("no-fill")
-            .short("n")
-            .long("no-fill")
-            .help("Disable counts of 0 being emitted for buckets with no entries")
-            .long_help("By default buckets which had no entries present will be displayed with a count of 0. If this flag is present then instead the bucket will not be printed at all."))
-        .arg(Arg::with_name("stream")
-            .short("s")
-            .long("stream")
-            .help("Enable stream mode")
-            .long_help("Enable stream mode. Entries will be expected to arrive in monotonically increasing (or --decreasing) order, and bucket information will be printed live as soon as the bucket is known to be finished. By default the presence of any entry violating the monotonic order will cause an error, but this can be made --tolerant."))
-        .arg(Arg::with_name("descending")
-            .short("d")
-            .long("descending")
-            .help("Set expected stream order to descending, or prints buckets in descending order in normal mode")
-            .long_help("By default stream mode expects entries to be in monotonically ascending order by date (earlier dates followed by later dates), which is the usual order of log files. If this flag is present then stream mode will instead expect entries in monotonically decreasing order by date (later dates followed by earlier dates). In normal mode, this flag will cause the buckets to be printed in descending order instead of the default ascending order."))
-        .arg(Arg::with_name("tolerant")
-            .short("t")
-            .long("tolerant")
-            .requires("stream")
-            .help("Make stream mode silently discard non-monotonic entries instead of erroring")
-            .long_help("By default when a non-monotonic entry is encountered in stream mode the program will terminate with an error. If this flag is present then non-monotonic entries will instead be silently discarded."))
-        .arg(Arg::with_name("format")
-            .required(true)
-            .takes_value(true)
-            .value_name("DATE_TIME_FORMAT")
-            .help("Date/time parsing format; use --help for list of specifiers")
-            .long_help(
-"Date/time parsing format. Full date and time information must be present. The following specifiers are supported, taken from Rust's chrono crate:
-Specifier   Example     Description
-%Y          2001        The full proleptic Gregorian year, zero-padded to 4 digits.
-%m          07          Month number (01--12), zero-padded to 2 digits.
-%b          Jul         Abbreviated month name. Always 3 letters.
-%B          July        Full month name. Also accepts corresponding abbreviation in parsing.
-%d          08          Day number (01--31), zero-padded to 2 digits.
-%F          2001-07-08  Year-month-day format (ISO 8601). Same to %Y-%m-%d.
-%H          00          Hour number (00--23), zero-padded to 2 digits.
-%I          12          Hour number in 12-hour clocks (01--12), zero-padded to 2 digits.
-%M          34          Minute number (00--59), zero-padded to 2 digits.
-%S          60          Second number (00--60), zero-padded to 2 digits.
-%T          00:34:60    Hour-minute-second format. Same to %H:%M:%S.
-%P          am          am or pm in 12-hour clocks.
-%p          AM          AM or PM in 12-hour clocks.
-%s          994518299   UNIX timestamp, the number of seconds since 1970-01-01 00:00 UTC.")
-            .validator(|value| {
-                DateTimeFormat::new(&value)
-                    .ok_or_else(|| "Not a valid date/time format, use --help to list supported specifiers".to_string())
-                    .and_then(|format| {
-                        if format.has_enough_info() {
-                            Ok(())
-                        } else {
-                            Err("Not enough information in the date/time format to construct a full date/time".to_string())
-                        }
+// Read the rows of earlier outputs, which end with `columns` values, passing the bucket, group,
+// column and value of each value to `record`, which may reject the row with the reason why. Empty
+// values, like those of the empty buckets of aggregations like avg, are left out.
+fn read_output_rows(
+    inputs: &[Input],
+    columns: usize,
+    mut record: impl FnMut(DateTime<Utc>, Option<&str>, usize, f64) -> Result<(), String>,
+) -> Result<(), Error> {
+    for input in inputs {
+        input.open_bare_read(false, None, |read| {
+            for (index, line) in BufReader::new(read).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                parse_merge_row(&line, columns)
+                    .ok_or_else(|| format!("not a row of tbuck's output with {columns} value columns: {line}"))
+                    .and_then(|(bucket, group, values)| {
+                        values
+                            .into_iter()
+                            .enumerate()
+                            .filter_map(|(column, value)| Some((column, value?)))
+                            .try_for_each(|(column, value)| record(bucket, group, column, value))
                     })
-            }))
-        .arg(Arg::with_name("inputs")
-            .takes_value(true)
-            .value_name("INPUT_FILE")
-            .multiple(true)
-            .help("Input files; or standard input if none provided"))
-        .get_matches();
+                    .map_err(|reason| {
+                        IoError::new(
+                            ErrorKind::InvalidData,
+                            format!("{}:{}: {reason}", input.name(), index + 1),
+                        )
+                    })?;
+            }
+            Ok::<_, Error>(())
+        })?;
+    }
+    Ok(())
+}
 
-    let datetime_format = DateTimeFormat::new(app_matches.value_of("format").expect("format is a required argument"))
-        .expect("validator should have rejected unsupported items");
-    let match_index = app_matches
-        .value_of("match-index")
-        .expect("match-index has default value")
-        .parse::<usize>()
-        .expect("validator should have rejected invalid values");
-    let granularity = Granularity::parse(
-        app_matches
-            .value_of("granularity")
-            .expect("granularity has default value"),
-    )
-    .expect("validator should have rejected invalid values");
-    let inputs = app_matches.values_of_os("inputs").map_or_else(
-        || vec![Input::Stdin {}],
-        |vals| vals.map(|val| Input::File(Path::new(val).to_path_buf())).collect(),
-    );
-    let fill_empty_buckets = !app_matches.is_present("no-fill");
-    let tolerant = app_matches.is_present("tolerant");
-    let order = if app_matches.is_present("descending") {
-        DateTimeOrder::Descending
-    } else {
-        DateTimeOrder::Ascending
+// The buckets of any of the series of earlier outputs, in order, with the empty buckets between
+// them if `fill_empty_buckets`, as long as no gap has more than `max_fill` of them. Buckets which
+// aren't of the granularity being filled in at, such as from an output counted with --offset, are
+// still kept, and the empty buckets after each are those of the granularity.
+fn output_buckets<'s, T: 's>(
+    series: impl Iterator<Item = &'s BTreeMap<DateTime<Utc>, T>>,
+    bucketing: &Bucketing,
+    fill_empty_buckets: bool,
+    max_fill: usize,
+) -> Result<Vec<DateTime<Utc>>, Error> {
+    let mut buckets: Vec<DateTime<Utc>> = series.flat_map(|values| values.keys().copied()).collect();
+    buckets.sort_unstable();
+    buckets.dedup();
+    if fill_empty_buckets {
+        for pair in buckets.windows(2) {
+            check_fill(
+                bucketing.successor(&bucketing.bucketize(&pair[0])),
+                pair[1],
+                bucketing,
+                max_fill,
+            )?;
+        }
+        if let (Some(&first), Some(&last)) = (buckets.first(), buckets.last()) {
+            // Stepped from the bucket of the granularity that the first falls in, which is the first
+            // itself unless it is off the granularity, and comes before it then.
+            let mut bucket = bucketing.successor(&bucketing.bucketize(&first));
+            while bucket <= last {
+                buckets.push(bucket);
+                bucket = bucketing.successor(&bucket);
+            }
+            buckets.sort_unstable();
+            buckets.dedup();
+        }
+    }
+    Ok(buckets)
+}
+
+// Print the buckets of each group in order, like tbuck's own output, with their values formatted
+// by `format`. Unless `fill_empty_buckets`, buckets a group has no value for are left out of it,
+// otherwise they are given to `format` as None.
+fn write_output_rows<T>(
+    out: &mut impl Write,
+    groups: &BTreeMap<Option<String>, BTreeMap<DateTime<Utc>, T>>,
+    bucketing: &Bucketing,
+    fill_empty_buckets: bool,
+    max_fill: usize,
+    format: impl Fn(Option<&T>) -> String,
+) -> Result<(), Error> {
+    let buckets = output_buckets(groups.values(), bucketing, fill_empty_buckets, max_fill)?;
+    for bucket in buckets {
+        let label = Label {
+            bucket,
+            timezone: None,
+            format: LabelFormat::DateTime,
+        };
+        for (group, values) in groups {
+            let value = match values.get(&bucket) {
+                Some(value) => format(Some(value)),
+                None if fill_empty_buckets => format(None),
+                None => continue,
+            };
+            match group {
+                Some(group) => writeln!(out, "{label},{group},{value}")?,
+                None => writeln!(out, "{label},{value}")?,
+            }
+        }
+    }
+    Ok(())
+}
+
+// Print the regex of a format and how it matches the first lines of the input: the match of each
+// line underlined, followed by what it parsed as or why it didn't parse.
+fn run_explain(args: &ExplainArgs) -> Result<(), Error> {
+    let format = DateTimeFormat::new(&args.format).expect("validator should have rejected invalid values");
+    let format = match args.assume_year {
+        Some(year) => format.with_assumed_year(year),
+        None => format,
     };
-    let mode = if app_matches.is_present("stream") {
-        Mode::Stream
-    } else {
-        Mode::Normal
+    let matcher = Matcher::new(&format);
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    writeln!(out, "format: {}", args.format)?;
+    writeln!(out, "regex:  {}", matcher.regex())?;
+    if !format.has_enough_info() {
+        writeln!(
+            out,
+            "The format doesn't have enough information for a full date/time, formats without a year need --assume-year"
+        )?;
+    }
+    let (mut tried, mut found_count, mut parsed) = (0, 0, 0);
+    args.input.open_bare_read(false, None, |read| {
+        for (index, line) in BufReader::new(read).lines().take(args.lines).enumerate() {
+            let line = line?;
+            tried += 1;
+            writeln!(out)?;
+            let Some(span) = matcher.find_span(&line, 0) else {
+                writeln!(out, "{:>5}: {line}", index + 1)?;
+                writeln!(out, "       no match")?;
+                continue;
+            };
+            found_count += 1;
+            let result = format.parse(&line[span.clone()]);
+            let (before, found, after) = (&line[..span.start], &line[span.clone()], &line[span.end..]);
+            if args.color {
+                // Green if it parsed, red if it didn't.
+                let color = if result.is_ok() { 32 } else { 31 };
+                writeln!(out, "{:>5}: {before}\x1b[1;{color}m{found}\x1b[0m{after}", index + 1)?;
+            } else {
+                writeln!(out, "{:>5}: {line}", index + 1)?;
+            }
+            let underline = " ".repeat(before.chars().count()) + &"^".repeat(found.chars().count());
+            match result {
+                Ok(datetime) => {
+                    parsed += 1;
+                    writeln!(out, "       {underline} parsed as {datetime}")?;
+                }
+                Err(err) => writeln!(out, "       {underline} failed to parse: {err}")?,
+            }
+        }
+        Ok::<_, Error>(())
+    })?;
+    writeln!(out)?;
+    writeln!(out, "{found_count} of {tried} lines matched, {parsed} parsed")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod explain_tests {
+    use super::{parse_command, Command};
+    use std::ffi::OsString;
+
+    #[test]
+    fn takes_assume_year() {
+        let cases = vec![
+            (vec!["tbuck", "explain", "-f", "%b %e %H:%M:%S"], None),
+            (
+                vec!["tbuck", "explain", "-f", "%b %e %H:%M:%S", "--assume-year", "2020"],
+                Some(2020),
+            ),
+        ];
+        for (args, expected) in cases {
+            match parse_command(args.iter().map(OsString::from).collect(), "1m") {
+                Command::Explain(explain) => assert_eq!(explain.assume_year, expected, "{args:?}"),
+                _ => panic!("{:?} should parse as explain", args),
+            }
+        }
+    }
+}
+
+// Formats the wizard tries, more specific ones first so that they are kept over others which
+// match the same text.
+const WIZARD_FORMATS: [&str; 13] = [
+    "%+",
+    "%{rfc2822}",
+    "%Y-%m-%dT%H:%M:%S",
+    "%Y-%m-%d %H:%M:%S",
+    "%Y/%m/%d %H:%M:%S",
+    "%d/%b/%Y:%H:%M:%S",
+    "%a %b %e %H:%M:%S %Y",
+    "%b %e %H:%M:%S",
+    "%m/%d/%Y %H:%M:%S",
+    "%d/%m/%Y %H:%M:%S",
+    "%d.%m.%Y %H:%M:%S",
+    "%Y-%m-%d",
+    "%s",
+];
+
+// A run of digits, of letters, or of anything else in a line, for deriving a format from the
+// timestamp-like text in it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Piece<'a> {
+    Digits(&'a str),
+    Word(&'a str),
+    Separator(&'a str),
+}
+
+// Split a line into its pieces.
+fn pieces(line: &str) -> Vec<Piece<'_>> {
+    let class = |c: char| {
+        if c.is_ascii_digit() {
+            0
+        } else if c.is_alphabetic() {
+            1
+        } else {
+            2
+        }
     };
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut chars = line.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        let end = chars.peek().map_or(line.len(), |(index, _)| *index);
+        if chars.peek().is_none_or(|(_, next)| class(*next) != class(c)) {
+            let text = &line[start..end];
+            pieces.push(match class(c) {
+                0 => Piece::Digits(text),
+                1 => Piece::Word(text),
+                _ => Piece::Separator(text),
+            });
+            start = end;
+        }
+    }
+    pieces
+}
+
+const MONTH_NAMES: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+const WEEKDAY_NAMES: [&str; 7] = [
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+];
+
+// Separators which may join the parts of a timestamp.
+const TIMESTAMP_SEPARATORS: [&str; 8] = [" ", "  ", "-", "/", ".", ":", ",", ", "];
+
+// The specifier of a month or weekday name, given the specifiers of its abbreviated and full
+// forms.
+fn name_specifier(word: &str, names: &[&str], short: &'static str, long: &'static str) -> Option<&'static str> {
+    names.iter().find_map(|name| {
+        if word == *name {
+            Some(if word.len() == 3 { short } else { long })
+        } else if word.len() == 3 && name.starts_with(word) {
+            Some(short)
+        } else {
+            None
+        }
+    })
+}
+
+// A numeric specifier, without padding if the digits have only one.
+fn numeric_specifier(digits: &str, specifier: char) -> String {
+    if digits.len() == 1 {
+        format!("%-{specifier}")
+    } else {
+        format!("%{specifier}")
+    }
+}
+
+// Derive a format from the timestamp-like text at the start of the pieces of a line, such as
+// "%d/%b/%Y:%H:%M:%S" from the pieces of "01/Jan/2024:10:01:00 +0000] GET /", or None if they
+// don't start with a full date, or a day of a month and a time of day. Parts tbuck can't parse,
+// like fractions of seconds and offsets, end the derived format.
+#[allow(clippy::too_many_lines)]
+fn derive_format(pieces: &[Piece<'_>]) -> Option<String> {
+    let mut format = String::new();
+    let (mut year, mut month, mut day, mut time, mut weekday, mut am_pm) = (false, false, false, false, false, false);
+    // Whether the last part was a month name, which a day may follow.
+    let mut after_month_name = false;
+    // The separator since the last part, added once another part follows it.
+    let mut separator = None;
+    let mut index = 0;
+    while let Some(piece) = pieces.get(index) {
+        let digits_at = |offset: usize| match pieces.get(index + offset) {
+            Some(Piece::Digits(digits)) => Some(*digits),
+            _ => None,
+        };
+        let separator_at = |offset: usize| match pieces.get(index + offset) {
+            Some(Piece::Separator(text)) => Some(*text),
+            _ => None,
+        };
+        let month_name_at = |offset: usize| match pieces.get(index + offset) {
+            Some(Piece::Word(word)) => name_specifier(word, &MONTH_NAMES, "%b", "%B"),
+            _ => None,
+        };
+        let (specifier, consumed) = match *piece {
+            Piece::Separator(text) => {
+                if format.is_empty() || separator.is_some() || !TIMESTAMP_SEPARATORS.contains(&text) {
+                    break;
+                }
+                separator = Some(text);
+                index += 1;
+                continue;
+            }
+            Piece::Word(word) => {
+                if let Some(specifier) = month_name_at(0).filter(|_| !month) {
+                    month = true;
+                    (specifier.to_string(), 1)
+                } else if let Some(specifier) = name_specifier(word, &WEEKDAY_NAMES, "%a", "%A").filter(|_| !weekday) {
+                    weekday = true;
+                    (specifier.to_string(), 1)
+                } else if word == "T" && year && month && day && !time && separator.is_none() && digits_at(1).is_some()
+                {
+                    ("T".to_string(), 1)
+                } else if ["AM", "PM", "am", "pm"].contains(&word) && time && !am_pm {
+                    am_pm = true;
+                    ("%p".to_string(), 1)
+                } else {
+                    break;
+                }
+            }
+            Piece::Digits(digits) => {
+                let date_separator = separator_at(1).filter(|text| ["-", "/", "."].contains(text));
+                if !time && digits.len() <= 2 && separator_at(1) == Some(":") && digits_at(2).map(str::len) == Some(2) {
+                    time = true;
+                    let mut specifier = numeric_specifier(digits, 'H') + ":%M";
+                    if separator_at(3) == Some(":") && digits_at(4).map(str::len) == Some(2) {
+                        specifier.push_str(":%S");
+                        (specifier, 5)
+                    } else {
+                        (specifier, 3)
+                    }
+                } else if let (false, false, false, Some(date_separator), Some(second), Some(third)) = (
+                    year,
+                    month,
+                    day,
+                    date_separator,
+                    digits_at(2).filter(|second| second.len() <= 2),
+                    digits_at(4).filter(|_| separator_at(3) == date_separator),
+                ) {
+                    let (first_number, second_number): (u32, u32) = (digits.parse().ok()?, second.parse().ok()?);
+                    let specifiers = if digits.len() == 4 && third.len() <= 2 {
+                        [
+                            "%Y".to_string(),
+                            numeric_specifier(second, 'm'),
+                            numeric_specifier(third, 'd'),
+                        ]
+                    } else if digits.len() <= 2 && (third.len() == 4 || third.len() == 2) {
+                        let year_specifier = if third.len() == 4 { "%Y" } else { "%y" }.to_string();
+                        // Month first unless the numbers or the separator say otherwise, as in
+                        // the US.
+                        if second_number <= 12 && (first_number > 12 || date_separator != "/") {
+                            [
+                                numeric_specifier(digits, 'd'),
+                                numeric_specifier(second, 'm'),
+                                year_specifier,
+                            ]
+                        } else {
+                            [
+                                numeric_specifier(digits, 'm'),
+                                numeric_specifier(second, 'd'),
+                                year_specifier,
+                            ]
+                        }
+                    } else {
+                        break;
+                    };
+                    year = true;
+                    month = true;
+                    day = true;
+                    (specifiers.join(date_separator), 5)
+                } else if !day
+                    && digits.len() <= 2
+                    && after_month_name
+                    && separator.is_some_and(|text| text.starts_with(' '))
+                    && (separator != Some("  ") || digits.len() == 1)
+                {
+                    // Days after month names are space-padded, like syslog's.
+                    day = true;
+                    separator = Some(" ");
+                    ("%e".to_string(), 1)
+                } else if !day && digits.len() <= 2 && (after_month_name || month_name_at(2).is_some()) {
+                    day = true;
+                    (numeric_specifier(digits, 'd'), 1)
+                } else if !year && digits.len() == 4 && (month || day) {
+                    year = true;
+                    ("%Y".to_string(), 1)
+                } else {
+                    break;
+                }
+            }
+        };
+        after_month_name = matches!(piece, Piece::Word(_)) && month_name_at(0).is_some();
+        index += consumed;
+        if let Some(text) = separator.take() {
+            format.push_str(text);
+        }
+        format.push_str(&specifier);
+    }
+    if am_pm {
+        format = format.replace("%H", "%I").replace("%-H", "%-I");
+    }
+    if month && day && (year || time) {
+        Some(format)
+    } else {
+        None
+    }
+}
+
+// The formats derived from the first timestamp-like text of each line, each once, in the order
+// they were first derived.
+fn derived_formats(lines: &[String]) -> Vec<String> {
+    let mut formats: Vec<String> = Vec::new();
+    for line in lines {
+        let pieces = pieces(line);
+        let derived = (0..pieces.len())
+            .filter(|start| !matches!(pieces[*start], Piece::Separator(_)))
+            .find_map(|start| derive_format(&pieces[start..]));
+        if let Some(format) = derived {
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
+        }
+    }
+    formats
+}
+
+// A format which matched some of the lines sampled by the wizard.
+struct Candidate {
+    format: String,
+    // Whether the format has no year, and so needs --assume-year.
+    yearless: bool,
+    // Lines whose first match parsed as a plausible date/time.
+    parsed: usize,
+    // The first parsed line's matched text and what it parsed as.
+    example: Option<(String, DateTime<Utc>)>,
+    // The span of each line's match and what it parsed as, to tell apart formats which match the
+    // same text.
+    found: Vec<Option<(Range<usize>, DateTime<Utc>)>>,
+}
+
+impl Candidate {
+    // Whether every match of the candidate is part of a longer match of another, or the same text
+    // parsed the same, so that the other already says as much.
+    fn subsumed_by(&self, other: &Candidate) -> bool {
+        self.found.iter().zip(&other.found).all(|pair| match pair {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some((span, datetime)), Some((other_span, other_datetime))) => {
+                other_span.start <= span.start
+                    && span.end <= other_span.end
+                    && (span != other_span || datetime == other_datetime)
+            }
+        })
+    }
+}
+
+// The common formats, and those derived from the timestamp-like text of the lines, which parse
+// any of the lines, best first, leaving out those which match the same text as a more specific
+// format. Formats without a year assume this year.
+fn wizard_candidates(lines: &[String], this_year: i32) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = Vec::new();
+    // Derived formats come after the common ones, so that a common format is kept over a derived
+    // one which matches the same text.
+    let derived = derived_formats(lines)
+        .into_iter()
+        .filter(|format_text| !WIZARD_FORMATS.contains(&format_text.as_str()));
+    for format_text in WIZARD_FORMATS
+        .iter()
+        .map(|format_text| (*format_text).to_string())
+        .chain(derived)
+    {
+        let Some(format) = DateTimeFormat::new(&format_text) else {
+            continue;
+        };
+        let yearless = !format.has_enough_info();
+        let format = if yearless {
+            format.with_assumed_year(this_year)
+        } else {
+            format
+        };
+        let matcher = Matcher::new(&format);
+        let mut candidate = Candidate {
+            format: format_text,
+            yearless,
+            parsed: 0,
+            example: None,
+            found: Vec::with_capacity(lines.len()),
+        };
+        for line in lines {
+            // Numbers like ports and counts parse as timestamps too, but not as recent ones.
+            let found = matcher
+                .find_span(line, 0)
+                .and_then(|span| Some((span.clone(), format.parse(&line[span]).ok()?)))
+                .filter(|(_, datetime)| (1990..=2100).contains(&datetime.year()));
+            if let Some((span, datetime)) = &found {
+                if candidate.example.is_none() {
+                    candidate.example = Some((line[span.clone()].to_string(), *datetime));
+                }
+                candidate.parsed += 1;
+            }
+            candidate.found.push(found);
+        }
+        if candidate.parsed > 0 && !candidates.iter().any(|kept| candidate.subsumed_by(kept)) {
+            candidates.push(candidate);
+        }
+    }
+    // Formats which explain the most text come first, so that a date alone doesn't win over a full
+    // date and time for matching a few more lines. The sort is stable, so formats which explain as
+    // much stay in order of specificity.
+    candidates.sort_by_key(|candidate| {
+        Reverse(
+            candidate
+                .found
+                .iter()
+                .flatten()
+                .map(|(span, _)| span.len())
+                .sum::<usize>(),
+        )
+    });
+    candidates
+}
+
+#[cfg(test)]
+mod wizard_tests {
+    use super::{derive_format, derived_formats, pieces, wizard_candidates};
+
+    #[test]
+    fn derives_formats() {
+        let cases = vec![
+            ("2024-01-15 10:01:00 a", Some("%Y-%m-%d %H:%M:%S")),
+            ("2024-01-15T10:01:00.123+02:00 a", Some("%Y-%m-%dT%H:%M:%S")),
+            ("2024/1/5 9:01 a", Some("%Y/%-m/%-d %-H:%M")),
+            ("15.01.2024 10:01:00 a", Some("%d.%m.%Y %H:%M:%S")),
+            ("01/02/24 10:01:00 a", Some("%m/%d/%y %H:%M:%S")),
+            ("13/02/2024 10:01:00 a", Some("%d/%m/%Y %H:%M:%S")),
+            ("01/02/2024 10:01:00 PM a", Some("%m/%d/%Y %I:%M:%S %p")),
+            ("01/Jan/2024:10:01:00 +0000] GET /", Some("%d/%b/%Y:%H:%M:%S")),
+            ("15-Jan-2024 10:01:00 a", Some("%d-%b-%Y %H:%M:%S")),
+            ("Jan  1 10:01:00 host sshd[1]: x", Some("%b %e %H:%M:%S")),
+            ("Jan 15 10:01:00 host sshd[1]: x", Some("%b %e %H:%M:%S")),
+            ("Mon, 15 Jan 2024 10:01:00 +0000", Some("%a, %d %b %Y %H:%M:%S")),
+            ("Monday January 15 2024 10:01", Some("%A %B %e %Y %H:%M")),
+            ("2024-01-15", Some("%Y-%m-%d")),
+            ("10:01:00 a", None),
+            ("host1 port 8080", None),
+        ];
+        for (line, expected) in cases {
+            let pieces = pieces(line);
+            assert_eq!(derive_format(&pieces).as_deref(), expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn derives_from_first_timestamp_of_lines() {
+        let lines = vec![
+            "[web1] 15-Jan-2024 10:01:00 a".to_string(),
+            "[web2] 15-Jan-2024 10:02:00 b, since 2024-01-01".to_string(),
+            "2024-01-15 10:03:00 c".to_string(),
+        ];
+        assert_eq!(derived_formats(&lines), vec!["%d-%b-%Y %H:%M:%S", "%Y-%m-%d %H:%M:%S"]);
+    }
+
+    #[test]
+    fn finds_candidates() {
+        let cases = vec![
+            (
+                vec!["2024-01-01 10:01:00 a", "2024-01-01 10:02:00 b"],
+                vec![("%Y-%m-%d %H:%M:%S", 2)],
+            ),
+            (
+                vec!["01/02/2024 10:01:00 a", "2024-01-01 10:01:00 b"],
+                vec![
+                    ("%Y-%m-%d %H:%M:%S", 1),
+                    ("%m/%d/%Y %H:%M:%S", 1),
+                    ("%d/%m/%Y %H:%M:%S", 1),
+                ],
+            ),
+            (vec!["Jan  1 10:01:00 host sshd[1]: x"], vec![("%b %e %H:%M:%S", 1)]),
+            (vec!["1704103260 epoch", "port 8080"], vec![("%s", 1)]),
+            (vec!["15-Jan-2024 10:01:00 a"], vec![("%d-%b-%Y %H:%M:%S", 1)]),
+            (vec!["nothing here"], vec![]),
+        ];
+        for (lines, expected) in cases {
+            let lines: Vec<String> = lines.into_iter().map(str::to_string).collect();
+            let candidates = wizard_candidates(&lines, 2024);
+            let actual: Vec<(&str, usize)> = candidates
+                .iter()
+                .map(|candidate| (candidate.format.as_str(), candidate.parsed))
+                .collect();
+            assert_eq!(actual, expected, "{lines:?}");
+        }
+    }
+}
+
+// Try the common formats, and those derived from the first lines of the input, on those lines,
+// list those which match, and print the one the user picks.
+fn run_wizard(args: &WizardArgs) -> Result<(), Error> {
+    let mut lines = Vec::new();
+    args.input.open_bare_read(false, None, |read| {
+        for line in BufReader::new(read).lines().take(args.lines) {
+            lines.push(line?);
+        }
+        Ok::<_, Error>(())
+    })?;
+    let this_year = Utc::now().year();
+    let candidates = wizard_candidates(&lines, this_year);
+    if candidates.is_empty() {
+        return Err(IoError::new(
+            ErrorKind::InvalidData,
+            format!(
+                "no common or derived format matched the first {} lines of {}, see tbuck explain to try one",
+                lines.len(),
+                args.input.name()
+            ),
+        )
+        .into());
+    }
+
+    let stderr = std::io::stderr();
+    let mut prompt = stderr.lock();
+    let width = candidates
+        .iter()
+        .map(|candidate| candidate.format.len())
+        .max()
+        .unwrap_or(0);
+    for (number, candidate) in candidates.iter().enumerate() {
+        let (text, datetime) = candidate.example.as_ref().expect("candidates parsed a line");
+        writeln!(
+            prompt,
+            "{:>3}) {:width$}  {} of {} lines, like '{text}' as {datetime}{}",
+            number + 1,
+            candidate.format,
+            candidate.parsed,
+            lines.len(),
+            if candidate.yearless {
+                ", needs --assume-year"
+            } else {
+                ""
+            }
+        )?;
+    }
+    let picked = loop {
+        write!(prompt, "Pick a format [1-{}, Enter for 1]: ", candidates.len())?;
+        prompt.flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            return Err(IoError::new(ErrorKind::UnexpectedEof, "no format was picked").into());
+        }
+        match answer.trim() {
+            "" => break &candidates[0],
+            answer => {
+                if let Some(candidate) = answer
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|number| candidates.get(number.wrapping_sub(1)))
+                {
+                    break candidate;
+                }
+            }
+        }
+    };
+    let assume_year = if picked.yearless {
+        format!(" --assume-year {this_year}")
+    } else {
+        String::new()
+    };
+    writeln!(
+        prompt,
+        "Count with: tbuck '{}'{assume_year} {}",
+        picked.format.replace('\'', "'\\''"),
+        args.input.name()
+    )?;
+    println!("{}", picked.format);
+    Ok(())
+}
+
+// Draw each row of earlier outputs as a bar, labeled with its bucket and group.
+fn run_plot(args: &PlotArgs) -> Result<(), Error> {
+    // Eighths of a column, for the end of each bar.
+    const PARTS: [&str; 8] = ["", "▏", "▎", "▍", "▌", "▋", "▊", "▉"];
+    let mut rows = Vec::new();
+    for input in &args.inputs {
+        input.open_bare_read(false, None, |read| {
+            for (index, line) in BufReader::new(read).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let (bucket, group, values) = parse_merge_row(&line, 1).ok_or_else(|| {
+                    IoError::new(
+                        ErrorKind::InvalidData,
+                        format!("{}:{}: not a row of tbuck's output: {line}", input.name(), index + 1),
+                    )
+                })?;
+                let label = Label {
+                    bucket,
+                    timezone: None,
+                    format: LabelFormat::DateTime,
+                };
+                let label = match group {
+                    Some(group) => format!("{label} {group}"),
+                    None => label.to_string(),
+                };
+                rows.push((label, values[0]));
+            }
+            Ok::<_, Error>(())
+        })?;
+    }
+
+    let width = rows.iter().map(|(label, _)| label.chars().count()).max().unwrap_or(0);
+    let max = rows.iter().filter_map(|(_, value)| *value).fold(0.0, f64::max);
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    for (label, value) in rows {
+        // Empty buckets have no value, and they and negative values have no bar.
+        let Some(value) = value else {
+            writeln!(out, "{label:width$}")?;
+            continue;
+        };
+        #[allow(
+            clippy::cast_precision_loss,
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss
+        )]
+        let eighths = if max > 0.0 {
+            (value.max(0.0) / max * (args.width * 8) as f64).round() as usize
+        } else {
+            0
+        };
+        let bar = "█".repeat(eighths / 8) + PARTS[eighths % 8];
+        writeln!(out, "{label:width$} {bar} {value}")?;
+    }
+    Ok(())
+}
+
+// Print the synthetic lines of the gen subcommand. The fields after the date/time are picked by a
+// fixed xorshift generator, so that the same options print the same lines.
+fn run_gen(args: &GenArgs) -> Result<(), Error> {
+    const LEVELS: [&str; 4] = ["INFO", "INFO", "WARN", "ERROR"];
+    const STATUSES: [u16; 5] = [200, 200, 200, 404, 500];
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let started = Instant::now();
+    let span = i128::from(args.duration.num_nanoseconds().unwrap_or(i64::MAX));
+    let mut random: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut next_random = || {
+        random ^= random << 13;
+        random ^= random >> 7;
+        random ^= random << 17;
+        random
+    };
+    for index in 0..args.lines {
+        // Evenly spread, without the rounding error of adding up a fixed interval.
+        // Never more than the span, which came from an i64.
+        #[allow(clippy::cast_possible_truncation)]
+        let offset = (span * i128::from(index) / i128::from(args.lines)) as i64;
+        if args.realtime {
+            let due = started + StdDuration::from_nanos(offset.unsigned_abs());
+            let now = Instant::now();
+            if due > now {
+                out.flush()?;
+                std::thread::sleep(due - now);
+            }
+        }
+        let datetime = args.start + Duration::nanoseconds(offset);
+        let (level, status, user, latency) = (next_random(), next_random(), next_random(), next_random());
+        writeln!(
+            out,
+            "{} level={} status={} user=u{} latency_ms={} synthetic request",
+            datetime.format(&args.format),
+            LEVELS[(level % 4) as usize],
+            STATUSES[(status % 5) as usize],
+            user % 1000,
+            // Mostly fast, with a long tail.
+            (latency % 100) * (1 + (latency >> 32) % 10),
+        )?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// Parse a --rate of the gen subcommand, like '10k/s', into lines per second. The unit defaults to
+// seconds.
+fn parse_rate(text: &str) -> Option<f64> {
+    let (amount, per) = match text.rsplit_once('/') {
+        Some((amount, "s")) => (amount, 1.0),
+        Some((amount, "m")) => (amount, 60.0),
+        Some((amount, "h")) => (amount, 3600.0),
+        Some(_) => return None,
+        None => (text, 1.0),
+    };
+    let (amount, multiplier) = match amount.chars().last()? {
+        'k' => (&amount[..amount.len() - 1], 1e3),
+        'M' => (&amount[..amount.len() - 1], 1e6),
+        _ => (amount, 1.0),
+    };
+    let rate = amount.parse::<f64>().ok()? * multiplier / per;
+    Some(rate).filter(|rate| rate.is_finite() && *rate > 0.0)
+}
+
+#[cfg(test)]
+mod gen_tests {
+    use super::parse_rate;
+
+    #[test]
+    fn parses_rates() {
+        let cases = vec![
+            ("10", Some(10.0)),
+            ("10/s", Some(10.0)),
+            ("10k/s", Some(10_000.0)),
+            ("1M/h", Some(1_000_000.0 / 3600.0)),
+            ("30/m", Some(0.5)),
+            ("0.5/s", Some(0.5)),
+            ("0/s", None),
+            ("-1/s", None),
+            ("10/d", None),
+            ("k/s", None),
+            ("", None),
+        ];
+        for (text, expected) in cases {
+            assert_eq!(parse_rate(text), expected, "{text}");
+        }
+    }
+}
+
+// A row of tbuck's output: its bucket, its group if it has one, and its values.
+type OutputRow<'l> = (DateTime<Utc>, Option<&'l str>, Vec<Option<f64>>);
+
+// Split a row of tbuck's output into its bucket, its group if it has one, and the values of its
+// last `columns` fields, each None if it was left empty. The group is kept as it was written,
+// quoted or not.
+fn parse_merge_row(line: &str, columns: usize) -> Option<OutputRow<'_>> {
+    let (label, rest) = line.split_once(',')?;
+    // Groups may have commas in them, so the values are split off the end.
+    let mut fields: Vec<&str> = rest.rsplitn(columns + 1, ',').collect();
+    let group = if fields.len() > columns { fields.pop() } else { None };
+    if fields.len() < columns {
+        return None;
+    }
+    let values = fields
+        .into_iter()
+        .rev()
+        .map(|value| match value.trim() {
+            "" => Some(None),
+            value => value.parse::<f64>().ok().map(Some),
+        })
+        .collect::<Option<Vec<_>>>()?;
+    // Buckets are labeled in UTC unless a --timezone was given, whose abbreviations can't be
+    // parsed back, but RFC 3339 labels are understood too.
+    let bucket = match DateTime::parse_from_rfc3339(label) {
+        Ok(bucket) => bucket.with_timezone(&Utc),
+        Err(_) => DateTime::from_utc(
+            NaiveDateTime::parse_from_str(label.strip_suffix(" UTC")?, "%Y-%m-%d %H:%M:%S%.f").ok()?,
+            Utc,
+        ),
+    };
+    Some((bucket, group, values))
+}
+
+#[cfg(test)]
+mod merge_tests {
+    use super::{parse_command, parse_merge_row, run_downsample, run_join, run_merge, Command, Error};
+    use chrono::{TimeZone, Utc};
+    use std::ffi::OsString;
+
+    // What a subcommand reading earlier outputs prints for inputs with these rows, along with how
+    // it ended.
+    fn run_tool(options: &[&str], inputs: &[&str]) -> (String, Result<(), Error>) {
+        let dir = tempfile::tempdir().unwrap();
+        let mut args: Vec<OsString> = options.iter().map(OsString::from).collect();
+        for (index, rows) in inputs.iter().enumerate() {
+            let path = dir.path().join(format!("{index}.csv"));
+            std::fs::write(&path, rows).unwrap();
+            args.push(path.into());
+        }
+        let mut out = Vec::new();
+        let result = match parse_command(args, "1m") {
+            Command::Merge(args) => run_merge(&args, &mut out),
+            Command::Downsample(args) => run_downsample(&args, &mut out),
+            Command::Join(args) => run_join(&args, &mut out),
+            _ => panic!("{:?} doesn't read earlier outputs", options),
+        };
+        (String::from_utf8(out).unwrap(), result)
+    }
+
+    #[test]
+    fn parses_rows() {
+        let bucket = Utc.ymd(2024, 1, 1).and_hms(10, 1, 0);
+        let cases = vec![
+            ("2024-01-01 10:01:00 UTC,2", 1, Some((bucket, None, vec![Some(2.0)]))),
+            (
+                "2024-01-01 10:01:00 UTC,500,1",
+                1,
+                Some((bucket, Some("500"), vec![Some(1.0)])),
+            ),
+            (
+                "2024-01-01 10:01:00 UTC,\"a,b\",0.5",
+                1,
+                Some((bucket, Some("\"a,b\""), vec![Some(0.5)])),
+            ),
+            ("2024-01-01T11:01:00+01:00,3", 1, Some((bucket, None, vec![Some(3.0)]))),
+            ("bucket,a,b", 1, None),
+            ("2024-01-01 10:01:00 IST,2", 1, None),
+            ("2024-01-01 10:01:00 UTC,", 1, Some((bucket, None, vec![None]))),
+            (
+                "2024-01-01 10:01:00 UTC,500,",
+                1,
+                Some((bucket, Some("500"), vec![None])),
+            ),
+            ("2024-01-01 10:01:00 UTC,x", 1, None),
+            ("2024-01-01 10:01:00 UTC", 1, None),
+            // The output of --agg count,sum, with and without groups.
+            (
+                "2024-01-01 10:01:00 UTC,2,30",
+                2,
+                Some((bucket, None, vec![Some(2.0), Some(30.0)])),
+            ),
+            (
+                "2024-01-01 10:01:00 UTC,500,2,30",
+                2,
+                Some((bucket, Some("500"), vec![Some(2.0), Some(30.0)])),
+            ),
+            (
+                "2024-01-01 10:01:00 UTC,0,",
+                2,
+                Some((bucket, None, vec![Some(0.0), None])),
+            ),
+            ("2024-01-01 10:01:00 UTC,2", 2, None),
+        ];
+        for (line, columns, expected) in cases {
+            assert_eq!(parse_merge_row(line, columns), expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn leaves_out_rows_without_a_value() {
+        // The --agg avg output of tbuck, with an empty bucket.
+        let rows = "2024-01-01 10:00:00 UTC,10\n2024-01-01 10:01:00 UTC,\n2024-01-01 10:02:00 UTC,40\n";
+        let cases = vec![
+            (
+                vec!["tbuck", "downsample", "--from", "1m", "--to", "2m", "--agg", "avg"],
+                "2024-01-01 10:00:00 UTC,10\n2024-01-01 10:02:00 UTC,40\n",
+            ),
+            (
+                vec!["tbuck", "downsample", "--from", "1m", "--to", "5m", "--agg", "max"],
+                "2024-01-01 10:00:00 UTC,40\n",
+            ),
+            (
+                vec!["tbuck", "merge", "-g", "1m"],
+                "2024-01-01 10:00:00 UTC,10\n2024-01-01 10:01:00 UTC,0\n2024-01-01 10:02:00 UTC,40\n",
+            ),
+        ];
+        for (options, expected) in cases {
+            let (output, result) = run_tool(&options, &[rows]);
+            result.unwrap();
+            assert_eq!(output, expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn fills_in_buckets_of_the_granularity_between_others() {
+        // A bucket off the granularity, such as from an output counted with --offset.
+        let rows = "2024-01-01 10:00:30 UTC,1\n2024-01-01 10:03:00 UTC,2\n";
+        let (output, result) = run_tool(&["tbuck", "merge", "-g", "1m"], &[rows]);
+        result.unwrap();
+        assert_eq!(
+            output,
+            "2024-01-01 10:00:30 UTC,1\n\
+             2024-01-01 10:01:00 UTC,0\n\
+             2024-01-01 10:02:00 UTC,0\n\
+             2024-01-01 10:03:00 UTC,2\n"
+        );
+        let (_, result) = run_tool(&["tbuck", "merge", "-g", "1m", "--max-fill", "1"], &[rows]);
+        assert!(matches!(result, Err(Error::MaxFill { .. })), "{:?}", result);
+    }
+
+    #[test]
+    fn combines_each_column() {
+        // The --agg count,sum outputs of two hosts, the first with an empty bucket.
+        let first = "2024-01-01 10:00:00 UTC,2,30\n2024-01-01 10:01:00 UTC,0,\n2024-01-01 10:02:00 UTC,1,5\n";
+        let second = "2024-01-01 10:00:00 UTC,1,10\n";
+        let cases = vec![
+            (
+                vec!["tbuck", "merge", "--columns", "count,sum"],
+                "2024-01-01 10:00:00 UTC,3,40\n2024-01-01 10:01:00 UTC,0,0\n2024-01-01 10:02:00 UTC,1,5\n",
+            ),
+            (
+                vec![
+                    "tbuck",
+                    "downsample",
+                    "--from",
+                    "1m",
+                    "--to",
+                    "2m",
+                    "--columns",
+                    "count,sum",
+                ],
+                "2024-01-01 10:00:00 UTC,3,40\n2024-01-01 10:02:00 UTC,1,5\n",
+            ),
+        ];
+        for (options, expected) in cases {
+            let (output, result) = run_tool(&options, &[first, second]);
+            result.unwrap();
+            assert_eq!(output, expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn joins_series_side_by_side() {
+        let first = "2024-01-01 10:00:00 UTC,2,30\n2024-01-01 10:01:00 UTC,0,\n2024-01-01 10:02:00 UTC,1,5\n";
+        let second = "2024-01-01 10:00:00 UTC,1,10\n";
+        let cases = vec![
+            // Series without a value for a bucket have an empty cell.
+            (
+                vec!["tbuck", "join", "--columns", "count,sum"],
+                vec![first, second],
+                "0.csv:count,0.csv:sum,1.csv:count,1.csv:sum\n\
+                 2024-01-01 10:00:00 UTC,2,30,1,10\n\
+                 2024-01-01 10:01:00 UTC,0,,,\n\
+                 2024-01-01 10:02:00 UTC,1,5,,\n",
+            ),
+            // The --agg avg output of one input with groups, with an empty bucket.
+            (
+                vec!["tbuck", "join"],
+                vec![
+                    "2024-01-01 10:00:00 UTC,GET,10\n2024-01-01 10:00:00 UTC,POST,\n2024-01-01 10:02:00 UTC,POST,20\n",
+                ],
+                "GET,POST\n\
+                 2024-01-01 10:00:00 UTC,10,\n\
+                 2024-01-01 10:01:00 UTC,,\n\
+                 2024-01-01 10:02:00 UTC,,20\n",
+            ),
+        ];
+        for (options, inputs, expected) in cases {
+            let (output, result) = run_tool(&options, &inputs);
+            result.unwrap();
+            // The header names the inputs by their paths, which are left out.
+            let (header, rows) = output.split_once('\n').unwrap();
+            let header: Vec<&str> = header.split(',').map(|name| name.rsplit('/').next().unwrap()).collect();
+            assert_eq!(format!("{}\n{rows}", header[1..].join(",")), expected, "{options:?}");
+        }
+    }
+}
+
+// Read every line of an input and feed the entries found into the runner.
+fn process_input(
+    input: &Input,
+    follow: bool,
+    context: &Context,
+    runner: &mut Runner,
+    stats: &mut Stats,
+) -> Result<(), Error> {
+    let mut input_state = InputState::new(context.args.skew(input));
+    if let (Input::File(path), true) = (input, context.args.mmap) {
+        let map = timed(context.args.timing, &mut stats.timing.read, || map_file(path))?;
+        let data = context.args.input_limit.map_or(&map[..], |limit| limit.truncate(&map));
+        for (index, line) in data.split_inclusive(|&byte| byte == b'\n').enumerate() {
+            if interrupted() {
+                break;
+            }
+            let line = to_utf8(line)?;
+            let outcome = handle_line(line, &mut input_state, context, runner, &mut stats.timing)
+                .map_err(|err| err.at(input, index + 1, line))?;
+            stats.record(outcome);
+            stats.timing.record_buckets(runner, context.args);
+        }
+        return Ok(());
+    }
+    if let Some(interval) = context.args.flush_interval {
+        return process_input_with_flush(input, follow, interval, context, runner, stats);
+    }
+
+    // Single line buffer to avoid allocating for each line.
+    let mut line = String::with_capacity(4096);
+
+    // open_bare_read does dynamic dispatch based on the type of input via a `&mut dyn Read` pointer.
+    input.open_bare_read(follow, context.args.input_limit, |read| {
+        let mut reader = BufReader::new(read);
+        let mut line_number = 0;
+        while !interrupted() {
+            // Always clear old data.
+            line.clear();
+
+            if timed(context.args.timing, &mut stats.timing.read, || {
+                reader.read_line(&mut line)
+            })? == 0
+            {
+                break;
+            }
+            line_number += 1;
+
+            let outcome = handle_line(&line, &mut input_state, context, runner, &mut stats.timing)
+                .map_err(|err| err.at(input, line_number, &line))?;
+            stats.record(outcome);
+            stats.timing.record_buckets(runner, context.args);
+        }
+        Ok(())
+    })
+}
+
+// Like process_input, but the lines are read on a separate thread so that the partial count of
+// the current bucket can be printed every `interval` even while the input is idle.
+fn process_input_with_flush(
+    input: &Input,
+    follow: bool,
+    interval: StdDuration,
+    context: &Context,
+    runner: &mut Runner,
+    stats: &mut Stats,
+) -> Result<(), Error> {
+    let mut input_state = InputState::new(context.args.skew(input));
+    let (sender, receiver) = mpsc::sync_channel::<IoResult<String>>(1024);
+    // The reader isn't joined: if counting fails the program exits without waiting for it, since
+    // a followed input may never end.
+    let reader_input = input.clone();
+    let limit = context.args.input_limit;
+    std::thread::spawn(move || {
+        let result = reader_input.open_bare_read(follow, limit, |read| {
+            for line in BufReader::new(read).lines() {
+                // Keep the line ending, the same as read_line does.
+                if sender.send(line.map(|line| line + "\n")).is_err() {
+                    // The receiving side hit an error and stopped.
+                    break;
+                }
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            // If this fails the receiving side has already stopped.
+            let _ = sender.send(Err(err));
+        }
+    });
+
+    let mut next_flush = Instant::now() + interval;
+    let mut line_number = 0;
+    while !interrupted() {
+        let next_line = timed(context.args.timing, &mut stats.timing.read, || {
+            receiver.recv_timeout(next_flush.saturating_duration_since(Instant::now()))
+        });
+        match next_line {
+            Ok(line) => {
+                let line = line?;
+                line_number += 1;
+                let outcome = handle_line(&line, &mut input_state, context, runner, &mut stats.timing)
+                    .map_err(|err| err.at(input, line_number, &line))?;
+                stats.record(outcome);
+                stats.timing.record_buckets(runner, context.args);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+        // Also checked after lines, since a busy input may never leave the channel empty.
+        if Instant::now() >= next_flush {
+            runner.flush_partial(&context.output, context.args)?;
+            next_flush = Instant::now() + interval;
+        }
+    }
+    Ok(())
+}
+
+// Exit code for I/O errors, such as an input that can't be read. Invalid arguments exit with 1.
+const EXIT_IO_ERROR: i32 = 2;
+// Exit code for an out of order entry in stream mode without --tolerant.
+const EXIT_NON_MONOTONIC: i32 = 3;
+// Exit code for a bucket breaching --alert-over or --alert-under.
+const EXIT_ALERT: i32 = 4;
+// Exit code for a gap between buckets too long to fill in under --max-fill.
+const EXIT_MAX_FILL: i32 = 5;
+// Exit code for being interrupted by SIGINT, as shells report it.
+const EXIT_INTERRUPTED: i32 = 130;
+
+// Set by the first SIGINT, after which counting stops and what was counted so far is printed.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Whether counting should stop because of SIGINT.
+fn interrupted() -> bool {
+    INTERRUPTED.load(atomic::Ordering::Relaxed)
+}
+
+// How often inputs which wait for more data check whether counting should stop.
+const INTERRUPT_POLL_INTERVAL: StdDuration = StdDuration::from_millis(250);
+
+// Errors which end the program.
+#[derive(Debug)]
+enum Error {
+    Io(IoError),
+    // An entry in stream mode was out of order compared to the bucket being counted.
+    NonMonotonic {
+        bucket: DateTime<Utc>,
+        current_bucket: DateTime<Utc>,
+        location: Option<Location>,
+    },
+    // Buckets breached the alert thresholds. Reported once everything has been printed.
+    Alert {
+        breaches: u64,
+    },
+    // Filling in the empty buckets from one bucket until another would print more than --max-fill.
+    MaxFill {
+        from: DateTime<Utc>,
+        until: DateTime<Utc>,
+        max_fill: usize,
+    },
+    // SIGINT stopped counting. Reported once what was counted so far has been printed.
+    Interrupted,
+}
+
+// Where in the inputs an error was found.
+#[derive(Debug)]
+struct Location {
+    input: String,
+    line_number: usize,
+    line: String,
+}
+
+impl Error {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_) => EXIT_IO_ERROR,
+            Error::NonMonotonic { .. } => EXIT_NON_MONOTONIC,
+            Error::Alert { .. } => EXIT_ALERT,
+            Error::MaxFill { .. } => EXIT_MAX_FILL,
+            Error::Interrupted => EXIT_INTERRUPTED,
+        }
+    }
+
+    // Attach the line an error was found at, unless it already has one.
+    fn at(self, input: &Input, line_number: usize, line: &str) -> Self {
+        match self {
+            Error::NonMonotonic {
+                bucket,
+                current_bucket,
+                location: None,
+            } => Error::NonMonotonic {
+                bucket,
+                current_bucket,
+                location: Some(Location {
+                    input: input.name(),
+                    line_number,
+                    line: line.trim_end().to_string(),
+                }),
+            },
+            err => err,
+        }
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{err}"),
+            Error::NonMonotonic {
+                bucket,
+                current_bucket,
+                location,
+            } => {
+                write!(
+                    f,
+                    "entry in bucket {bucket} is out of order after bucket {current_bucket} (use --tolerant to skip such entries)"
+                )?;
+                if let Some(location) = location {
+                    write!(
+                        f,
+                        "\n    at {}:{}: {}",
+                        location.input, location.line_number, location.line
+                    )?;
+                }
+                Ok(())
+            }
+            Error::Alert { breaches } => write!(f, "{breaches} buckets breached the alert thresholds"),
+            Error::MaxFill { from, until, max_fill } => write!(
+                f,
+                "filling in the empty buckets from {from} until {until} would print more than {max_fill} of them, which is likely a date/time being mis-parsed (use --no-fill, or a larger --max-fill)"
+            ),
+            Error::Interrupted => write!(f, "interrupted, the buckets only count the lines read until then"),
+        }
+    }
+}
+
+impl From<IoError> for Error {
+    fn from(err: IoError) -> Self {
+        Error::Io(err)
+    }
+}
+
+// State shared by everything processing lines, on every thread.
+struct Context<'a> {
+    args: &'a Args,
+    matcher: Matcher<'a>,
+    // Finds the end of each line's interval with --end-format.
+    end_matcher: Option<Matcher<'a>>,
+    line_filter: LineFilter,
+    // Matches the lines which start records, with --record-start.
+    record_start: Option<Regex>,
+    // Selects the group each line is counted in.
+    group_by: Option<Selector>,
+    // Selects the number each line carries.
+    value: Option<Selector>,
+    // Selects the key each line carries for distinct counts, with its first capture group.
+    distinct: Option<Regex>,
+    // Selects the key which repeated lines share, with its first capture group.
+    dedupe_by: Option<Regex>,
+    // Selects the key whose lines make up sessions, with its first capture group.
+    session_key: Option<Regex>,
+    // The part of each line to search for the date/time, with CSV columns resolved.
+    time_field: Option<TimeField>,
+    errors: ErrorReporter,
+    output: Output,
+}
+
+impl<'a> Context<'a> {
+    fn new(args: &'a Args) -> IoResult<Self> {
+        // Compile the regex only once.
+        Ok(Self {
+            args,
+            matcher: match args.column {
+                Some(column) => Matcher::new(&args.datetime_format).at_column(column),
+                None => Matcher::new(&args.datetime_format),
+            },
+            end_matcher: args.end_format.as_ref().map(Matcher::new),
+            line_filter: LineFilter::new(&args.grep, &args.grep_v),
+            record_start: args
+                .record_start
+                .as_ref()
+                .map(|record_start| Regex::new(record_start).expect("validator should have rejected invalid values")),
+            group_by: args.group_by.as_ref().map(Selection::compile),
+            value: args.value.as_ref().map(Selection::compile),
+            distinct: args
+                .distinct
+                .as_ref()
+                .map(|distinct| Regex::new(distinct).expect("validator should have rejected invalid values")),
+            dedupe_by: args
+                .dedupe_by
+                .as_ref()
+                .map(|dedupe_by| Regex::new(dedupe_by).expect("validator should have rejected invalid values")),
+            session_key: args
+                .sessions
+                .as_ref()
+                .map(|sessions| Regex::new(&sessions.key).expect("validator should have rejected invalid values")),
+            time_field: args
+                .time_field
+                .as_ref()
+                .map(|time_field| time_field.resolve(&args.inputs))
+                .transpose()?,
+            errors: ErrorReporter::new(args.max_errors),
+            output: Output::create(args)?,
+        })
+    }
+}
+
+// Selects lines by content with --grep and --grep-v, before any date/time is looked for.
+struct LineFilter {
+    // If present, a line must match at least one of these.
+    include: Option<RegexSet>,
+    // A line must not match any of these.
+    exclude: Option<RegexSet>,
+}
+
+impl LineFilter {
+    fn new(include: &[String], exclude: &[String]) -> Self {
+        let compile = |patterns: &[String]| {
+            if patterns.is_empty() {
+                None
+            } else {
+                Some(RegexSet::new(patterns).expect("validator should have rejected invalid values"))
+            }
+        };
+        Self {
+            include: compile(include),
+            exclude: compile(exclude),
+        }
+    }
+
+    fn accepts(&self, line: &str) -> bool {
+        self.include.as_ref().is_none_or(|include| include.is_match(line))
+            && !self.exclude.as_ref().is_some_and(|exclude| exclude.is_match(line))
+    }
+}
+
+// Where the date/time of each line is, when only part of the line is searched for it.
+#[derive(Debug, Clone)]
+enum TimeField {
+    // The field at a 0-based index after splitting the line at every delimiter.
+    Delimited { index: usize, delimiter: String },
+    // A field of lines which are JSON objects, found by following the path's keys (or array
+    // indexes) into nested objects.
+    Json { path: Vec<String> },
+    // A column of CSV lines, whose fields may be quoted.
+    Csv { column: CsvColumn },
+    // The value of a key of logfmt lines, like 'ts=2024-01-01T10:00:00Z level=info'.
+    Logfmt { key: String },
+}
+
+#[derive(Debug, Clone)]
+enum CsvColumn {
+    // 0-based.
+    Index(usize),
+    // Found in the header line of the first input.
+    Name(String),
+}
+
+impl TimeField {
+    // The part of a line to search for the date/time, if the line has it.
+    fn extract<'l>(&self, line: &'l str) -> Option<Cow<'l, str>> {
+        match self {
+            TimeField::Delimited { index, delimiter } => line.split(delimiter.as_str()).nth(*index).map(Cow::Borrowed),
+            TimeField::Json { path } => {
+                let mut value = serde_json::from_str::<serde_json::Value>(line).ok()?;
+                for key in path {
+                    value = match value {
+                        serde_json::Value::Object(mut object) => object.remove(key)?,
+                        serde_json::Value::Array(mut array) => {
+                            let index = key.parse::<usize>().ok().filter(|index| *index < array.len())?;
+                            array.swap_remove(index)
+                        }
+                        _ => return None,
+                    };
+                }
+                // Numbers are searched as written, such as for epoch timestamps with '%s'.
+                match value {
+                    serde_json::Value::String(text) => Some(Cow::Owned(text)),
+                    serde_json::Value::Number(number) => Some(Cow::Owned(number.to_string())),
+                    _ => None,
+                }
+            }
+            TimeField::Csv {
+                column: CsvColumn::Index(index),
+            } => {
+                let mut record = csv::StringRecord::new();
+                let mut reader = csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    .flexible(true)
+                    .from_reader(line.as_bytes());
+                if !reader.read_record(&mut record).ok()? {
+                    return None;
+                }
+                record.get(*index).map(|field| Cow::Owned(field.to_string()))
+            }
+            TimeField::Csv {
+                column: CsvColumn::Name(_),
+            } => unreachable!("CSV columns are resolved before counting"),
+            TimeField::Logfmt { key } => logfmt_value(line, key),
+        }
+    }
+
+    // Find a CSV column given by name in the header line of the first input, which is consumed so
+    // that it isn't counted when reading standard input.
+    fn resolve(&self, inputs: &[Input]) -> IoResult<Self> {
+        let TimeField::Csv {
+            column: CsvColumn::Name(name),
+        } = self
+        else {
+            return Ok(self.clone());
+        };
+        let mut header = String::new();
+        match inputs.first() {
+            Some(Input::File(path)) => BufReader::new(File::open(path)?).read_line(&mut header)?,
+            Some(Input::Stdin) | None => std::io::stdin().lock().read_line(&mut header)?,
+            Some(Input::Journal(_) | Input::Evtx(_) | Input::Listen(_) | Input::Kafka(_)) => {
+                unreachable!("--csv conflicts with --journal, --evtx, --listen and --kafka")
+            }
+        };
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(header.trim_end_matches(['\r', '\n']).as_bytes());
+        let mut record = csv::StringRecord::new();
+        reader.read_record(&mut record).map_err(IoError::other)?;
+        match record.iter().position(|field| field == name) {
+            Some(index) => Ok(TimeField::Csv {
+                column: CsvColumn::Index(index),
+            }),
+            None => Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!("CSV header has no column '{name}': {}", header.trim_end()),
+            )),
+        }
+    }
+}
+
+// How part of each line is selected, such as by --group-by or --group-key.
+#[derive(Debug, Clone)]
+enum Selection {
+    // The first capture group of a regex.
+    Regex(String),
+    // The value of a key of logfmt lines.
+    LogfmtKey(String),
+}
+
+impl Selection {
+    fn compile(&self) -> Selector {
+        match self {
+            Selection::Regex(regex) => {
+                Selector::Regex(Regex::new(regex).expect("validator should have rejected invalid values"))
+            }
+            Selection::LogfmtKey(key) => Selector::LogfmtKey(key.clone()),
+        }
+    }
+}
+
+// A Selection ready to be used.
+#[derive(Debug)]
+enum Selector {
+    Regex(Regex),
+    LogfmtKey(String),
+}
+
+impl Selector {
+    fn select<'l>(&self, line: &'l str) -> Option<Cow<'l, str>> {
+        match self {
+            Selector::Regex(regex) => regex
+                .captures(line)
+                .and_then(|captures| captures.get(1))
+                .map(|capture| Cow::Borrowed(capture.as_str())),
+            Selector::LogfmtKey(key) => logfmt_value(line, key),
+        }
+    }
+
+    // The numbers of the fields of a line used by --expr: named capture groups of the regex, or
+    // logfmt keys.
+    fn select_fields(&self, line: &str, names: &[String]) -> Vec<Option<f64>> {
+        let parse = |text: &str| text.parse::<f64>().ok().filter(|value| value.is_finite());
+        match self {
+            Selector::Regex(regex) => match regex.captures(line) {
+                Some(captures) => names
+                    .iter()
+                    .map(|name| captures.name(name).and_then(|capture| parse(capture.as_str())))
+                    .collect(),
+                None => vec![None; names.len()],
+            },
+            Selector::LogfmtKey(_) => names
+                .iter()
+                .map(|name| logfmt_value(line, name).and_then(|value| parse(&value)))
+                .collect(),
+        }
+    }
+}
+
+// The value of a key in a logfmt line, which is a series of 'key=value' pairs separated by spaces.
+// Values containing spaces are quoted, with backslash escapes. A key without a value is 'true'.
+fn logfmt_value<'l>(line: &'l str, key: &str) -> Option<Cow<'l, str>> {
+    let mut rest = line;
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+        let key_end = rest.find(|c: char| c == '=' || c.is_whitespace()).unwrap_or(rest.len());
+        let (pair_key, after_key) = rest.split_at(key_end);
+        let (value, after_value) = match after_key.strip_prefix('=') {
+            None => (Cow::Borrowed("true"), after_key),
+            Some(quoted) if quoted.starts_with('"') => {
+                // Find the closing quote, skipping escaped characters. An unclosed quote runs to
+                // the end of the line.
+                let mut escaped = false;
+                let mut end = quoted.len();
+                for (index, c) in quoted.char_indices().skip(1) {
+                    match c {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        '"' => {
+                            end = index;
+                            break;
+                        }
+                        _ => {}
+                    }
+                }
+                let value = &quoted[1..end];
+                let value = if value.contains('\\') {
+                    Cow::Owned(unescape_logfmt(value))
+                } else {
+                    Cow::Borrowed(value)
+                };
+                (value, quoted.get(end + 1..).unwrap_or(""))
+            }
+            Some(bare) => {
+                let end = bare.find(char::is_whitespace).unwrap_or(bare.len());
+                (Cow::Borrowed(&bare[..end]), &bare[end..])
+            }
+        };
+        if pair_key == key {
+            return Some(value);
+        }
+        rest = after_value;
+    }
+}
+
+// Replace the backslash escapes of a quoted logfmt value.
+fn unescape_logfmt(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod time_field_tests {
+    use super::{logfmt_value, CsvColumn, Input, TimeField};
+    use std::io::Write;
+
+    #[test]
+    fn extracts() {
+        let json = |path: &str| TimeField::Json {
+            path: path.split('.').map(str::to_string).collect(),
+        };
+        let cases = vec![
+            (
+                json("time"),
+                r#"{"time": "2024-01-01 10:01:00", "msg": "x"}"#,
+                Some("2024-01-01 10:01:00"),
+            ),
+            (
+                json("meta.ts"),
+                r#"{"meta": {"ts": "10:01 \"quoted\""}}"#,
+                Some("10:01 \"quoted\""),
+            ),
+            (
+                json("events.1.at"),
+                r#"{"events": [{"at": 1}, {"at": 1704103260}]}"#,
+                Some("1704103260"),
+            ),
+            (json("events.2.at"), r#"{"events": [{"at": 1}, {"at": 2}]}"#, None),
+            (json("meta.ts"), r#"{"meta": "2024-01-01 10:01:00"}"#, None),
+            (json("time"), r#"{"time": null}"#, None),
+            (json("time"), "2024-01-01 10:01:00 not json", None),
+            (
+                TimeField::Delimited {
+                    index: 1,
+                    delimiter: ", ".to_string(),
+                },
+                "a, 2024-01-01 10:01:00, c",
+                Some("2024-01-01 10:01:00"),
+            ),
+            (
+                TimeField::Delimited {
+                    index: 3,
+                    delimiter: ",".to_string(),
+                },
+                "a,b,c",
+                None,
+            ),
+        ];
+        for (time_field, line, expected) in cases {
+            assert_eq!(time_field.extract(line).as_deref(), expected, "{line}");
+        }
+    }
+
+    #[test]
+    fn extracts_logfmt() {
+        let line = r#"ts=2024-01-01T10:01:00Z level=info msg="user said \"hi\"" empty= at="a b" debug took=12ms"#;
+        let cases = vec![
+            ("ts", Some("2024-01-01T10:01:00Z")),
+            ("level", Some("info")),
+            ("msg", Some("user said \"hi\"")),
+            ("empty", Some("")),
+            ("at", Some("a b")),
+            ("debug", Some("true")),
+            ("took", Some("12ms")),
+            ("b", None),
+            ("missing", None),
+        ];
+        for (key, expected) in cases {
+            assert_eq!(logfmt_value(line, key).as_deref(), expected, "{key}");
+        }
+        assert_eq!(
+            logfmt_value(r#"msg="unclosed \" x"#, "msg").as_deref(),
+            Some("unclosed \" x")
+        );
+        assert_eq!(logfmt_value("", "ts"), None);
+    }
+
+    #[test]
+    fn extracts_csv() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "id,\"message, with comma\",timestamp").unwrap();
+        let inputs = vec![Input::File(file.path().to_path_buf())];
+        let by_name = TimeField::Csv {
+            column: CsvColumn::Name("timestamp".to_string()),
+        };
+        let resolved = by_name.resolve(&inputs).unwrap();
+        let lines = vec![
+            (
+                "1,\"retried, then failed\",2024-01-01 10:01:00",
+                Some("2024-01-01 10:01:00"),
+            ),
+            (
+                "2,\"said \"\"hi\"\"\",\"2024-01-01 10:02:00\"\r",
+                Some("2024-01-01 10:02:00"),
+            ),
+            ("3,short", None),
+            ("", None),
+        ];
+        for (line, expected) in lines {
+            assert_eq!(resolved.extract(line).as_deref(), expected, "{line}");
+        }
+        let missing = TimeField::Csv {
+            column: CsvColumn::Name("time".to_string()),
+        };
+        assert!(missing.resolve(&inputs).is_err());
+    }
+}
+
+// Prints date/time parse errors to stderr. Only the first occurrence of each distinct error is
+// printed, along with an example line, and only up to a limit so that a malformed input can't
+// flood stderr. Everything else is summarized at the end.
+struct ErrorReporter {
+    max_errors: usize,
+    state: Mutex<ErrorReporterState>,
+}
+
+#[derive(Default)]
+struct ErrorReporterState {
+    // Distinct error messages which have been printed.
+    printed: Vec<String>,
+    // Number of errors which weren't printed.
+    suppressed: u64,
+}
+
+impl ErrorReporter {
+    fn new(max_errors: usize) -> Self {
+        Self {
+            max_errors,
+            state: Mutex::new(ErrorReporterState::default()),
+        }
+    }
+
+    fn report(&self, err: &impl std::fmt::Display, line: &str) {
+        let message = err.to_string();
+        let mut state = self.state.lock().expect("error reporter lock poisoned");
+        if state.printed.len() < self.max_errors && !state.printed.contains(&message) {
+            eprintln!("Failed to parse date/time match: {message}");
+            eprintln!("    in line: {}", line.trim_end());
+            state.printed.push(message);
+        } else {
+            state.suppressed += 1;
+        }
+    }
+
+    // Print the summary of suppressed errors, if there were any.
+    fn finish(&self) {
+        let state = self.state.lock().expect("error reporter lock poisoned");
+        if state.suppressed > 0 {
+            eprintln!(
+                "{} further date/time parse errors suppressed (use --max-errors to show more)",
+                state.suppressed
+            );
+        }
+    }
+}
+
+// Size of the blocks that an input is split into for parallel counting in normal mode. Blocks
+// are extended to end on a line boundary.
+const CHUNK_SIZE: usize = 1 << 20;
+
+// How many chunks are read ahead while the previous batch is being counted.
+const CHUNKS_PER_BATCH: usize = 64;
+
+// Count an entire input in normal mode. The input is read in batches of line-aligned chunks, and
+// while one batch is being counted in parallel on the thread pool the next is read from the input.
+#[allow(clippy::cast_possible_truncation)]
+fn count_input_in_chunks(
+    input: &Input,
+    skew: Duration,
+    range: Option<Range<u64>>,
+    context: &Context,
+) -> Result<(Runner, Stats), Error> {
+    let mut input_state = InputState::new(skew);
+    if let (Input::File(path), true) = (input, context.args.mmap) {
+        // The whole file is already available, so it can be split up and counted all at once.
+        let mut read_time = StdDuration::ZERO;
+        let map = timed(context.args.timing, &mut read_time, || map_file(path))?;
+        let mut chunks = Vec::new();
+        let mut rest = match range {
+            Some(range) => &map[range.start as usize..range.end as usize],
+            None => context.args.input_limit.map_or(&map[..], |limit| limit.truncate(&map)),
+        };
+        while !rest.is_empty() {
+            let split = rest
+                .iter()
+                .skip(CHUNK_SIZE)
+                .position(|&byte| byte == b'\n')
+                .map_or(rest.len(), |newline| CHUNK_SIZE + newline + 1);
+            let (chunk, remainder) = rest.split_at(split);
+            chunks.push(chunk);
+            rest = remainder;
+        }
+        // Counted a batch at a time, so that SIGINT can stop it part way.
+        let mut runner = Runner::from_mode(Mode::Normal, context.args);
+        let mut stats = Stats::default();
+        for batch in chunks.chunks(CHUNKS_PER_BATCH) {
+            if interrupted() {
+                break;
+            }
+            let (batch_runner, batch_stats) = count_batch(batch, &mut input_state, context)?;
+            runner = runner.merge(batch_runner);
+            stats = stats.merge(batch_stats);
+        }
+        stats.timing.read += read_time;
+        return Ok((runner, stats));
+    }
+
+    let mut runner = Runner::from_mode(Mode::Normal, context.args);
+    let mut stats = Stats::default();
+    let mut read_time = StdDuration::ZERO;
+    let timing = context.args.timing;
+    let count = |read: &mut dyn Read| {
+        // Partial line left over at the end of the previous chunk.
+        let mut carry = Vec::new();
+        let mut batch = timed(timing, &mut read_time, || read_batch(read, &mut carry))?;
+        while !batch.is_empty() {
+            let mut counted = None;
+            let next_batch = rayon::in_place_scope(|scope| {
+                scope.spawn(|_| counted = Some(count_batch(&batch, &mut input_state, context)));
+                // What was read is still counted after SIGINT, but nothing more is read.
+                if interrupted() {
+                    return Ok(Vec::new());
+                }
+                timed(timing, &mut read_time, || read_batch(read, &mut carry))
+            });
+            let (batch_runner, batch_stats) = counted.expect("counting task has finished")?;
+            runner = std::mem::replace(&mut runner, Runner::from_mode(Mode::Normal, context.args)).merge(batch_runner);
+            stats = stats.merge(batch_stats);
+            batch = next_batch?;
+        }
+        Ok::<_, Error>(())
+    };
+    match range {
+        Some(range) => input.open_range(range, count)?,
+        None => input.open_bare_read(false, context.args.input_limit, count)?,
+    }
+    stats.timing.read += read_time;
+    Ok((runner, stats))
+}
+
+// Read up to CHUNKS_PER_BATCH chunks. Returns an empty batch at the end of the input.
+fn read_batch(read: &mut dyn Read, carry: &mut Vec<u8>) -> IoResult<Vec<Vec<u8>>> {
+    let mut batch = Vec::with_capacity(CHUNKS_PER_BATCH);
+    while batch.len() < CHUNKS_PER_BATCH {
+        match read_chunk(read, carry)? {
+            Some(chunk) => batch.push(chunk),
+            None => break,
+        }
+    }
+    Ok(batch)
+}
+
+// Read roughly CHUNK_SIZE bytes, ending at the last complete line. The bytes after that line are
+// left in `carry` to start the next chunk. Returns None at the end of the input.
+fn read_chunk(read: &mut dyn Read, carry: &mut Vec<u8>) -> IoResult<Option<Vec<u8>>> {
+    let mut chunk = std::mem::take(carry);
+    loop {
+        let read_len = (&mut *read).take(CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+        if read_len < CHUNK_SIZE {
+            // End of the input, so whatever is left is the final chunk.
+            return Ok(if chunk.is_empty() { None } else { Some(chunk) });
+        }
+        // Keep reading if a single line is longer than the whole chunk so far.
+        if let Some(newline) = chunk.iter().rposition(|&byte| byte == b'\n') {
+            *carry = chunk.split_off(newline + 1);
+            return Ok(Some(chunk));
+        }
+    }
+}
+
+// Count the lines of a batch of chunks in parallel.
+fn count_batch(
+    batch: &[impl AsRef<[u8]> + Sync],
+    input_state: &mut InputState,
+    context: &Context,
+) -> Result<(Runner, Stats), Error> {
+    if context.args.attach_continuations || context.args.carry_forward {
+        // Continuation lines may belong to a record in an earlier chunk, so the chunks are
+        // counted in order instead.
+        let mut runner = Runner::from_mode(Mode::Normal, context.args);
+        let mut stats = Stats::default();
+        for chunk in batch {
+            let (chunk_runner, chunk_stats) = count_chunk(chunk.as_ref(), input_state, context)?;
+            runner = runner.merge(chunk_runner);
+            stats = stats.merge(chunk_stats);
+        }
+        return Ok((runner, stats));
+    }
+    let input_state: &InputState = input_state;
+    batch
+        .par_iter()
+        .map(|chunk| count_chunk(chunk.as_ref(), &mut input_state.clone(), context))
+        .try_reduce(
+            || (Runner::from_mode(Mode::Normal, context.args), Stats::default()),
+            |left, right| Ok((left.0.merge(right.0), left.1.merge(right.1))),
+        )
+}
+
+// Count the lines of a single chunk.
+fn count_chunk(chunk: &[u8], input_state: &mut InputState, context: &Context) -> Result<(Runner, Stats), Error> {
+    let text = to_utf8(chunk)?;
+    let mut runner = Runner::from_mode(Mode::Normal, context.args);
+    let mut stats = Stats::default();
+    for line in text.split_inclusive('\n') {
+        let outcome = handle_line(line, input_state, context, &mut runner, &mut stats.timing)?;
+        stats.record(outcome);
+    }
+    Ok((runner, stats))
+}
+
+// Memory-map an input file so its lines can be processed without copying them into a buffer.
+fn map_file(path: &Path) -> IoResult<Mmap> {
+    let file = File::open(path)?;
+    // Safety: the mapping is only ever read, and only lives as long as the input is being
+    // processed. As with any memory map, another process truncating the file while we read it
+    // is undefined behavior; that's the tradeoff the user opts into with --mmap.
+    unsafe { Mmap::map(&file) }
+}
+
+// The part of an input to count with --state, and the head_hash of what it counts up to.
+type ResumeRange = (Range<u64>, u32);
+
+// The part of each input to count with --state, or none for inputs which are counted whole.
+fn resume_ranges(resumed: Option<&State>, args: &Args) -> IoResult<Vec<Option<ResumeRange>>> {
+    args.inputs
+        .iter()
+        .map(|input| match (input, resumed) {
+            (Input::File(path), Some(resumed)) => {
+                let name = input.name();
+                let offset = resumed.offsets.get(&name).copied();
+                resume_range(path, offset.zip(resumed.heads.get(&name).copied())).map(Some)
+            }
+            (Input::File(path), None) if args.state.is_some() => resume_range(path, None).map(Some),
+            _ => Ok(None),
+        })
+        .collect()
+}
+
+// The part of an input file to count when resuming from --state, along with the head_hash of what
+// it counts up to: from the offset the last run stopped at, unless the file has shrunk or no longer
+// starts with what was counted then, up to the end of its last complete line. A line still being
+// written is left for the next run.
+fn resume_range(path: &Path, resumed: Option<(u64, u32)>) -> IoResult<ResumeRange> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let start = match resumed {
+        Some((offset, head)) if offset <= len && head_hash(&mut file, offset)? == head => offset,
+        _ => 0,
+    };
+    let end = last_line_end(&mut file, start, len)?;
+    Ok((start..end, head_hash(&mut file, end)?))
+}
+
+// The end of the last complete line of a file between start and len, or start if there is none.
+#[allow(clippy::cast_possible_truncation)]
+fn last_line_end(file: &mut File, start: u64, len: u64) -> IoResult<u64> {
+    // Look back from the end for the last newline, a block at a time.
+    let mut block = vec![0; 64 * 1024];
+    let mut end = len;
+    while end > start {
+        let block_start = end.saturating_sub(block.len() as u64).max(start);
+        let block = &mut block[..(end - block_start) as usize];
+        file.seek(SeekFrom::Start(block_start))?;
+        file.read_exact(block)?;
+        if let Some(newline) = memchr::memrchr(b'\n', block) {
+            return Ok(block_start + newline as u64 + 1);
+        }
+        end = block_start;
+    }
+    Ok(start)
+}
+
+// Bytes at the start of an input which --state hashes to tell whether it is still the same file.
+const HEAD_LEN: u64 = 1024;
+
+// The 32-bit FNV-1a hash of the first HEAD_LEN bytes of a file, or of its first len bytes if fewer.
+// It is saved rather than the inode, which a log rotated by copying and truncating keeps.
+fn head_hash(file: &mut File, len: u64) -> IoResult<u32> {
+    let mut head = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.take(len.min(HEAD_LEN)).read_to_end(&mut head)?;
+    Ok(head.iter().fold(0x811c_9dc5, |hash, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    }))
+}
+
+// What --state keeps between runs: the buckets counted so far, and how much of each input they
+// cover.
+#[derive(Debug, Serialize, Deserialize)]
+struct State {
+    // The options the buckets were counted with, which a run must share to resume from them. State
+    // files saved before they were versioned have none.
+    #[serde(default)]
+    counted_with: StateOptions,
+    // Bytes counted of each input, by name.
+    offsets: BTreeMap<String, u64>,
+    // The head_hash of what was counted of each input, by name.
+    heads: BTreeMap<String, u32>,
+    latest: Option<DateTime<Utc>>,
+    // The buckets of each group, for each granularity.
+    series: Vec<BTreeMap<String, SavedBuckets>>,
+    // The buckets of the other group of --top, for each granularity.
+    #[serde(default)]
+    other: Vec<SavedBuckets>,
+    // The groups folded into the other group, for each granularity, in order.
+    #[serde(default)]
+    folded: Vec<Vec<String>>,
+}
+
+// The buckets of a group in a --state file, in order.
+type SavedBuckets = Vec<(DateTime<Utc>, Aggregate)>;
+
+// The options which affect what is counted into the buckets, as they were given on the command line,
+// for a --state file.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct StateOptions {
+    // Raised whenever the options saved or their meaning change, or how the buckets are saved, so
+    // that state files saved before are told apart.
+    version: u32,
+    // The values of each of STATE_OPTIONS given, by name, or none for a flag.
+    values: BTreeMap<String, Vec<String>>,
+}
+
+// The options saved in StateOptions.
+const STATE_OPTIONS: &[&str] = &[
+    "format",
+    "match-index",
+    "all-matches",
+    "anchored",
+    "at-column",
+    "start-format",
+    "end-format",
+    "field",
+    "json-field",
+    "csv",
+    "time-column",
+    "logfmt",
+    "time-key",
+    "delimiter",
+    "century-pivot",
+    "assume-year",
+    "leap-seconds",
+    "skew",
+    "granularity",
+    "align",
+    "anchor",
+    "timezone",
+    "week-start",
+    "heatmap",
+    "by",
+    "agg",
+    "expr",
+    "value",
+    "value-key",
+    "weight",
+    "distinct",
+    "dedupe-by",
+    "approx",
+    "group-by",
+    "group-key",
+    "per-file",
+    "top",
+    "grep",
+    "grep-v",
+    "since",
+    "until",
+    "record-start",
+    "attach-continuations",
+    "carry-forward",
+];
+
+impl StateOptions {
+    const VERSION: u32 = 2;
+
+    fn new(matches: &ArgMatches) -> Self {
+        let values = STATE_OPTIONS
+            .iter()
+            .filter(|name| matches.is_present(name))
+            .map(|name| {
+                let values = matches.values_of_lossy(name).unwrap_or_default();
+                ((*name).to_string(), values)
+            })
+            .collect();
+        Self {
+            version: Self::VERSION,
+            values,
+        }
+    }
+}
+
+impl State {
+    // Load the state saved by an earlier run, if there was one.
+    fn load(path: &Path, args: &Args) -> IoResult<Option<State>> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let state: State = toml::from_str(&text).map_err(|err| {
+            IoError::new(
+                ErrorKind::InvalidData,
+                format!("Invalid state file {}: {err}", path.display()),
+            )
+        })?;
+        if state.counted_with != args.state_options {
+            return Err(IoError::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "The state file {} was saved with different options; remove it to start over",
+                    path.display()
+                ),
+            ));
+        }
+        Ok(Some(state))
+    }
+
+    // The buckets counted by earlier runs.
+    fn into_runner(self) -> Runner {
+        let mut other = self.other;
+        other.resize(self.series.len(), SavedBuckets::new());
+        let mut folded = self.folded;
+        folded.resize(self.series.len(), Vec::new());
+        Runner::Normal {
+            other: other.into_iter().map(|buckets| buckets.into_iter().collect()).collect(),
+            folded: folded.into_iter().map(|groups| groups.into_iter().collect()).collect(),
+            buckets: self
+                .series
+                .into_iter()
+                .map(|groups| {
+                    groups
+                        .into_iter()
+                        .map(|(group, buckets)| (group, buckets.into_iter().collect()))
+                        .collect()
+                })
+                .collect(),
+            latest: self.latest,
+            sessions: HashMap::new(),
+        }
+    }
+
+    // Write the state for the next run to a temporary file next to the state file, which is moved
+    // into place once the output has been written.
+    fn save(
+        runner: &Runner,
+        offsets: BTreeMap<String, u64>,
+        heads: BTreeMap<String, u32>,
+        path: &Path,
+        args: &Args,
+    ) -> IoResult<TempPath> {
+        let Runner::Normal {
+            buckets,
+            other,
+            folded,
+            latest,
+            ..
+        } = runner
+        else {
+            unreachable!("--state is only used in normal mode");
+        };
+        let saved = |buckets: &HashMap<DateTime<Utc>, Aggregate>| {
+            let mut buckets: SavedBuckets = buckets
+                .iter()
+                .map(|(bucket, aggregate)| (*bucket, aggregate.clone()))
+                .collect();
+            buckets.sort_unstable_by_key(|(bucket, _)| *bucket);
+            buckets
+        };
+        let state = State {
+            counted_with: args.state_options.clone(),
+            offsets,
+            heads,
+            latest: *latest,
+            series: buckets
+                .iter()
+                .map(|groups| {
+                    groups
+                        .iter()
+                        .map(|(group, buckets)| (group.clone(), saved(buckets)))
+                        .collect()
+                })
+                .collect(),
+            other: other.iter().map(saved).collect(),
+            folded: folded
+                .iter()
+                .map(|groups| {
+                    let mut groups: Vec<String> = groups.iter().cloned().collect();
+                    groups.sort_unstable();
+                    groups
+                })
+                .collect(),
+        };
+        let text = toml::to_string(&state).map_err(IoError::other)?;
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut temp = NamedTempFile::new_in(directory)?;
+        temp.write_all(text.as_bytes())?;
+        temp.as_file().sync_all()?;
+        Ok(temp.into_temp_path())
+    }
+}
+
+#[cfg(test)]
+mod state_tests {
+    use super::{resume_range, test_args, State};
+    use std::io::Write;
+
+    #[test]
+    fn resumes_after_what_was_counted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, "one\ntwo\nthr").unwrap();
+        let (range, head) = resume_range(&path, None).unwrap();
+        assert_eq!(range, 0..8);
+
+        let mut file = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"ee\n").unwrap();
+        assert_eq!(resume_range(&path, Some((8, head))).unwrap().0, 8..14);
+
+        // Rotated and written past the offset again.
+        std::fs::write(&path, "uno\ndos\ntres\n").unwrap();
+        assert_eq!(resume_range(&path, Some((8, head))).unwrap().0, 0..13);
+        // Shrunk.
+        std::fs::write(&path, "one\n").unwrap();
+        assert_eq!(resume_range(&path, Some((8, head))).unwrap().0, 0..4);
+    }
+
+    #[test]
+    fn resumes_only_with_the_same_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.toml");
+        let saved = test_args(&["tbuck", "%F %T", "-g", "5m", "--grep", "ERROR"]);
+        let text = toml::to_string(&State {
+            counted_with: saved.state_options.clone(),
+            offsets: vec![("app.log".to_string(), 8)].into_iter().collect(),
+            heads: vec![("app.log".to_string(), 1)].into_iter().collect(),
+            latest: None,
+            series: vec![],
+            other: vec![],
+            folded: vec![],
+        })
+        .unwrap();
+        std::fs::write(&path, &text).unwrap();
+        let cases = vec![
+            (vec!["-g", "5m", "--grep", "ERROR"], true),
+            // Options which only affect the output don't matter.
+            (vec!["-g", "5m", "--grep", "ERROR", "--no-fill"], true),
+            (vec!["-g", "1m", "--grep", "ERROR"], false),
+            (vec!["-g", "5m", "--grep", "WARN"], false),
+            (vec!["-g", "5m", "--grep", "ERROR", "--per-file"], false),
+        ];
+        for (options, resumed) in cases {
+            let args = test_args(&[&["tbuck", "%F %T"], options.as_slice()].concat());
+            assert_eq!(State::load(&path, &args).is_ok(), resumed, "{options:?}");
+        }
+
+        // States saved by an earlier version, or before the options were versioned.
+        std::fs::write(&path, text.replace("version = 2", "version = 1")).unwrap();
+        assert!(State::load(&path, &saved).is_err());
+        std::fs::write(&path, text.replace("[counted_with]", "[old]")).unwrap();
+        assert!(State::load(&path, &saved).is_err());
+    }
+}
+
+// Interpret input bytes as text, failing the same way BufRead::read_line does for invalid UTF-8.
+fn to_utf8(bytes: &[u8]) -> IoResult<&str> {
+    std::str::from_utf8(bytes).map_err(|err| IoError::new(ErrorKind::InvalidData, err))
+}
+
+// What happened to a single input line.
+#[derive(Debug, Copy, Clone)]
+enum LineOutcome {
+    Counted,
+    NoMatch,
+    ParseFailure,
+    Filtered,
+    // Not the start of a record, with --record-start.
+    Continuation,
+}
+
+// What is carried from one line of an input to the next.
+#[derive(Debug, Clone)]
+struct InputState {
+    // How far the input's clock was ahead, which is subtracted from its date/times.
+    skew: Duration,
+    // The date/times of the current record, which its continuation lines are counted at. Only kept
+    // with --attach-continuations, or of the last line with any with --carry-forward.
+    previous: Vec<DateTime<Utc>>,
+}
+
+impl InputState {
+    fn new(skew: Duration) -> Self {
+        Self {
+            skew,
+            previous: Vec::new(),
+        }
+    }
+}
+
+// Find the date/time in a single line and count it into its bucket.
+fn handle_line(
+    line: &str,
+    state: &mut InputState,
+    context: &Context,
+    runner: &mut Runner,
+    timing: &mut Timing,
+) -> Result<LineOutcome, Error> {
+    let args = context.args;
+
+    // Continuation lines, like those of a stack trace, aren't searched for a date/time of their
+    // own. They are left out, or with --attach-continuations counted at their record's.
+    if context
+        .record_start
+        .as_ref()
+        .is_some_and(|record_start| !record_start.is_match(line))
+    {
+        if !args.attach_continuations || state.previous.is_empty() {
+            return Ok(LineOutcome::Continuation);
+        }
+        let previous = std::mem::take(&mut state.previous);
+        let outcome = timed(args.timing, &mut timing.aggregation, || {
+            count_line(line, &previous, context, runner)
+        });
+        state.previous = previous;
+        return outcome;
+    }
+    if !args.carry_forward {
+        state.previous.clear();
+    }
+
+    if !context.line_filter.accepts(line) {
+        return Ok(LineOutcome::Filtered);
+    }
+
+    // Only part of the line is searched with --field, --json-field or --csv.
+    let text = match &context.time_field {
+        None => Cow::Borrowed(line),
+        Some(time_field) => match time_field.extract(line) {
+            Some(text) => text,
+            None => return carry_forward(line, LineOutcome::NoMatch, state, context, runner, timing),
+        },
+    };
+
+    // Find the match at the indicated match_index and convert it into a DateTime<Utc>. Ignore
+    // lines without a match. Because the regex is more permissive than the chrono library (for
+    // example, a value of '61' seconds will pass the regex but not chrono's range checking), its
+    // possible the parsing may fail. This is more indicative of a problem than a line not having
+    // a match, so alert the user with a stderr message.
+    let datetime;
+    let all;
+    let datetimes = if args.all_matches {
+        all = match find_all_datetimes(&text, line, context, timing) {
+            Ok(all) => all,
+            Err(outcome) => return carry_forward(line, outcome, state, context, runner, timing),
+        };
+        all.as_slice()
+    } else {
+        let after;
+        (datetime, after) = match find_datetime(&text, line, context, timing) {
+            Ok(found) => found,
+            Err(outcome) => return carry_forward(line, outcome, state, context, runner, timing),
+        };
+        match &context.end_matcher {
+            Some(end_matcher) => {
+                all = match interval_buckets(datetime, after, line, end_matcher, state.skew, context) {
+                    Ok(buckets) => buckets,
+                    Err(outcome) => return Ok(outcome),
+                };
+                all.as_slice()
+            }
+            None => std::slice::from_ref(&datetime),
+        }
+    };
+    // Only allocated for the lines of inputs with a --skew. The buckets of intervals are already
+    // corrected.
+    let corrected: Vec<DateTime<Utc>>;
+    let datetimes = if state.skew.is_zero() || context.end_matcher.is_some() {
+        datetimes
+    } else {
+        corrected = datetimes.iter().map(|datetime| *datetime - state.skew).collect();
+        corrected.as_slice()
+    };
+    if args.attach_continuations || args.carry_forward {
+        state.previous.clear();
+        state.previous.extend_from_slice(datetimes);
+    }
+
+    timed(args.timing, &mut timing.aggregation, || {
+        count_line(line, datetimes, context, runner)
+    })
+}
+
+// With --carry-forward, count a line without a date/time at those of the last line with any.
+// Other outcomes, and lines before the first date/time, are returned as they are.
+fn carry_forward(
+    line: &str,
+    outcome: LineOutcome,
+    state: &mut InputState,
+    context: &Context,
+    runner: &mut Runner,
+    timing: &mut Timing,
+) -> Result<LineOutcome, Error> {
+    if !context.args.carry_forward || !matches!(outcome, LineOutcome::NoMatch) || state.previous.is_empty() {
+        return Ok(outcome);
+    }
+    timed(context.args.timing, &mut timing.aggregation, || {
+        count_line(line, &state.previous, context, runner)
+    })
+}
+
+// Count a line into the buckets of its date/times, unless it is left out by --since, --until,
+// or for lacking a group, value or key.
+fn count_line(
+    line: &str,
+    datetimes: &[DateTime<Utc>],
+    context: &Context,
+    runner: &mut Runner,
+) -> Result<LineOutcome, Error> {
+    let args = context.args;
+
+    // Drop entries outside of --since and --until. The buckets of intervals were already kept to
+    // them, by the times the intervals start and end rather than those the buckets start at.
+    let within = |datetime: &DateTime<Utc>| {
+        context.end_matcher.is_some()
+            || (args.since.is_none_or(|since| *datetime >= since) && args.until.is_none_or(|until| *datetime < until))
+    };
+    if !datetimes.iter().any(within) {
+        return Ok(LineOutcome::Filtered);
+    }
+
+    if args.last.is_some() {
+        for datetime in datetimes.iter().copied().filter(within) {
+            runner.record_latest(datetime);
+        }
+    }
+
+    // With --sessions, lines are only counted once the sessions are known. Lines without a
+    // session key aren't part of any.
+    if let Some(session_key) = &context.session_key {
+        let Some(key) = session_key.captures(line).and_then(|captures| captures.get(1)) else {
+            return Ok(LineOutcome::Filtered);
+        };
+        for datetime in datetimes.iter().copied().filter(within) {
+            runner.record_session(key.as_str(), datetime);
+        }
+        return Ok(LineOutcome::Counted);
+    }
+
+    // Lines are only counted if they have a group, when grouping.
+    let group = match &context.group_by {
+        None => Cow::Borrowed(""),
+        Some(group_by) => match group_by.select(line) {
+            Some(group) => group,
+            None => return Ok(LineOutcome::Filtered),
+        },
+    };
+
+    // Lines are only counted if they carry a number, when aggregating values.
+    let value = match &context.value {
+        None => None,
+        Some(value) => {
+            let Some(value) = value
+                .select(line)
+                .and_then(|value| value.parse::<f64>().ok())
+                .filter(|value| value.is_finite())
+            else {
+                return Ok(LineOutcome::Filtered);
+            };
+            Some(value)
+        }
+    };
+
+    // Likewise for a key, when counting distinct keys.
+    let key = match &context.distinct {
+        None => None,
+        Some(distinct) => match distinct.captures(line).and_then(|captures| captures.get(1)) {
+            Some(key) => Some(key.as_str()),
+            None => return Ok(LineOutcome::Filtered),
+        },
+    };
+
+    // Lines without a dedupe key are never repeats.
+    let dedupe = context
+        .dedupe_by
+        .as_ref()
+        .and_then(|dedupe_by| dedupe_by.captures(line))
+        .and_then(|captures| captures.get(1))
+        .map(|dedupe| dedupe.as_str());
+
+    // The fields of --expr, which lines are counted without.
+    let fields = match (&args.expression, &context.value) {
+        (Some(expression), Some(value)) if !expression.fields().is_empty() => {
+            value.select_fields(line, expression.fields())
+        }
+        _ => Vec::new(),
+    };
+
+    // Increment bucket count, once for each date/time with --all-matches.
+    let carried = Carried {
+        value,
+        key,
+        dedupe,
+        fields,
+    };
+    let mut counted = false;
+    for datetime in datetimes.iter().copied().filter(within) {
+        counted |= runner.handle_entry(datetime, &group, &carried, &context.output, args)?;
+    }
+    if !counted {
+        return Ok(LineOutcome::Filtered);
+    }
+    Ok(LineOutcome::Counted)
+}
+
+// What a line carries into the buckets it is counted in, besides itself.
+struct Carried<'l> {
+    // The number selected by --value or --value-key.
+    value: Option<f64>,
+    // The key counted by --distinct.
+    key: Option<&'l str>,
+    // The key shared by repeats with --dedupe-by.
+    dedupe: Option<&'l str>,
+    // The numbers of the fields of --expr, in its order.
+    fields: Vec<Option<f64>>,
+}
+
+impl Carried<'_> {
+    // Record the line in the aggregate of a bucket, unless it repeats the dedupe key of a line
+    // recorded already. Returns whether it was recorded.
+    fn record(&self, aggregate: &mut Aggregate) -> bool {
+        if !aggregate.add_deduplicated(self.value, self.key, self.dedupe) {
+            return false;
+        }
+        aggregate.add_fields(&self.fields);
+        true
+    }
+}
+
+#[cfg(test)]
+mod dedupe_tests {
+    use super::{test_args, Carried, Mode, Output, Runner};
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn counts_repeats_which_only_coarser_buckets_see() {
+        let args = test_args(&["tbuck", "%F %T", "-g", "1m", "-g", "1h", "--dedupe-by", "id=(\\w+)"]);
+        let out = Output::create(&args).unwrap();
+        let mut runner = Runner::from_mode(Mode::Normal, &args);
+        let carried = Carried {
+            value: None,
+            key: None,
+            dedupe: Some("r1"),
+            fields: Vec::new(),
+        };
+        let cases = vec![((0, 30), true), ((1, 30), true), ((1, 40), false)];
+        for ((minute, second), counted) in cases {
+            let datetime = Utc.ymd(2024, 5, 14).and_hms(10, minute, second);
+            assert_eq!(
+                runner.handle_entry(datetime, "", &carried, &out, &args).unwrap(),
+                counted,
+                "{datetime}"
+            );
+        }
+    }
+}
+
+// What is done with date/times on a leap second, with seconds of 60.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum LeapSeconds {
+    // Read as :59 of the same minute.
+    Clamp,
+    // Kept as chrono parses them.
+    Keep,
+    // Their lines aren't counted.
+    Skip,
+}
+
+impl LeapSeconds {
+    // Apply the policy to a parsed date/time, returning None if it is skipped. chrono represents a
+    // leap second as the second before it with a nanosecond of a second or more.
+    fn apply(self, datetime: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let nanosecond = datetime.nanosecond();
+        if nanosecond < 1_000_000_000 {
+            return Some(datetime);
+        }
+        match self {
+            LeapSeconds::Clamp => datetime.with_nanosecond(nanosecond - 1_000_000_000),
+            LeapSeconds::Keep => Some(datetime),
+            LeapSeconds::Skip => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod leap_second_tests {
+    use super::LeapSeconds;
+    use tbuck::DateTimeFormat;
+
+    #[test]
+    fn applies_policies() {
+        let format = DateTimeFormat::new("%+").unwrap();
+        let cases = vec![
+            (
+                "2016-12-31T23:59:60Z",
+                LeapSeconds::Clamp,
+                Some("2016-12-31 23:59:59 UTC"),
+            ),
+            (
+                "2016-12-31T23:59:60.5Z",
+                LeapSeconds::Clamp,
+                Some("2016-12-31 23:59:59.500 UTC"),
+            ),
+            (
+                "2016-12-31T23:59:60Z",
+                LeapSeconds::Keep,
+                Some("2016-12-31 23:59:60 UTC"),
+            ),
+            ("2016-12-31T23:59:60Z", LeapSeconds::Skip, None),
+            (
+                "2016-12-31T23:59:59.5Z",
+                LeapSeconds::Skip,
+                Some("2016-12-31 23:59:59.500 UTC"),
+            ),
+        ];
+        for (text, policy, expected) in cases {
+            let datetime = format.parse(text).unwrap();
+            let applied = policy.apply(datetime).map(|datetime| datetime.to_string());
+            assert_eq!(applied.as_deref(), expected, "{text} {policy:?}");
+        }
+    }
+}
+
+// Find and parse the date/time at the match index in the text of a line, reporting it if it fails
+// to parse. With --end-format the text after it is returned too, which the end is looked for in.
+// The outcome of the line is returned instead if it can't be counted.
+fn find_datetime<'t>(
+    text: &'t str,
+    line: &str,
+    context: &Context,
+    timing: &mut Timing,
+) -> Result<(DateTime<Utc>, &'t str), LineOutcome> {
+    let match_index = context.args.match_index;
+    let (found, after) = if context.args.timing && !context.matcher.parses_while_finding() {
+        // Found and parsed separately, so that each can be timed, which costs nothing extra unless
+        // the format is simple enough to do both at once.
+        match timed(true, &mut timing.matching, || {
+            context.matcher.find_span(text, match_index)
+        }) {
+            Some(span) => {
+                let datetime = timed(true, &mut timing.parsing, || {
+                    context.args.datetime_format.parse(&text[span.clone()])
+                });
+                (Some(datetime), &text[span.end..])
+            }
+            None => (None, ""),
+        }
+    } else if context.end_matcher.is_some() {
+        match context.matcher.find_datetime_span(text, match_index) {
+            Some((span, datetime)) => (Some(datetime), &text[span.end..]),
+            None => (None, ""),
+        }
+    } else {
+        // Parsing is timed as part of finding.
+        let found = timed(context.args.timing, &mut timing.matching, || {
+            context.matcher.find_datetime(text, match_index)
+        });
+        (found, "")
+    };
+    match found {
+        None => Err(LineOutcome::NoMatch),
+        Some(Ok(datetime)) => match context.args.leap_seconds.apply(datetime) {
+            Some(datetime) => Ok((datetime, after)),
+            None => Err(LineOutcome::Filtered),
+        },
+        Some(Err(err)) => {
+            context.errors.report(&err, line);
+            Err(LineOutcome::ParseFailure)
+        }
+    }
+}
+
+// Every bucket that the interval of a line overlaps with --end-format, corrected by the --skew of
+// its input, as interval_span finds them. The end is the last match of the end format in the text
+// after the start. The outcome of the line is returned instead if it has no end, if none of it is
+// within --since and --until, or if it overlaps more buckets than --max-fill, which is likely a
+// mis-parsed date/time.
+fn interval_buckets(
+    start: DateTime<Utc>,
+    after: &str,
+    line: &str,
+    end_matcher: &Matcher<'_>,
+    skew: Duration,
+    context: &Context,
+) -> Result<Vec<DateTime<Utc>>, LineOutcome> {
+    let end = match end_matcher.find_datetime(after, MatchIndex::FromEnd(0)) {
+        None => return Err(LineOutcome::NoMatch),
+        Some(Ok(end)) => match context.args.leap_seconds.apply(end) {
+            Some(end) => end,
+            None => return Err(LineOutcome::Filtered),
+        },
+        Some(Err(err)) => {
+            context.errors.report(&err, line);
+            return Err(LineOutcome::ParseFailure);
+        }
+    };
+    let (start, end) = (start - skew, end - skew);
+    let buckets = interval_span(start, end, &context.args.bucketings[0], context.args);
+    if buckets.is_empty() {
+        return Err(LineOutcome::Filtered);
+    }
+    if buckets.len() > context.args.max_fill {
+        let err = format!(
+            "the interval from {start} to {end} overlaps more than {} buckets (see --max-fill)",
+            context.args.max_fill
+        );
+        context.errors.report(&err, line);
+        return Err(LineOutcome::ParseFailure);
+    }
+    Ok(buckets)
+}
+
+// The buckets that an interval overlaps within --since and --until, from the bucket the part
+// within them starts in until the last one before it ends. An interval which ends before it starts
+// is only counted where it starts. At most one bucket more than --max-fill is returned.
+fn interval_span(start: DateTime<Utc>, end: DateTime<Utc>, bucketing: &Bucketing, args: &Args) -> Vec<DateTime<Utc>> {
+    let within = |datetime: DateTime<Utc>| {
+        args.since.is_none_or(|since| datetime >= since) && args.until.is_none_or(|until| datetime < until)
+    };
+    if end <= start {
+        return if within(start) {
+            vec![bucketing.bucketize(&start)]
+        } else {
+            Vec::new()
+        };
+    }
+    let from = args.since.map_or(start, |since| since.max(start));
+    let until = args.until.map_or(end, |until| until.min(end));
+    let mut buckets = Vec::new();
+    let mut bucket = bucketing.bucketize(&from);
+    while bucket < until && buckets.len() <= args.max_fill {
+        buckets.push(bucket);
+        bucket = bucketing.successor(&bucket);
+    }
+    buckets
+}
+
+#[cfg(test)]
+mod interval_tests {
+    use super::{interval_span, test_args};
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn spans_the_buckets_an_interval_overlaps() {
+        let at = |hour, minute, second| Utc.ymd(2024, 5, 14).and_hms(hour, minute, second);
+        let cases = vec![
+            (vec![], (10, 0, 30), (10, 2, 0), vec![(10, 0, 0), (10, 1, 0)]),
+            (
+                vec![],
+                (10, 0, 30),
+                (10, 2, 1),
+                vec![(10, 0, 0), (10, 1, 0), (10, 2, 0)],
+            ),
+            // Ending before it starts.
+            (vec![], (10, 3, 0), (10, 1, 0), vec![(10, 3, 0)]),
+            // Within --since and --until by when it starts and ends, not by its buckets.
+            (
+                vec!["--since", "2024-05-14T10:01:30Z"],
+                (10, 0, 30),
+                (10, 3, 0),
+                vec![(10, 1, 0), (10, 2, 0)],
+            ),
+            (
+                vec!["--until", "2024-05-14T10:01:30Z"],
+                (10, 0, 30),
+                (10, 3, 0),
+                vec![(10, 0, 0), (10, 1, 0)],
+            ),
+            (vec!["--since", "2024-05-14T10:05:00Z"], (10, 0, 30), (10, 3, 0), vec![]),
+            (vec!["--since", "2024-05-14T10:05:00Z"], (10, 3, 0), (10, 1, 0), vec![]),
+            // One more than --max-fill, to tell that it overlaps too many.
+            (
+                vec!["--max-fill", "2"],
+                (10, 0, 0),
+                (14, 0, 0),
+                vec![(10, 0, 0), (10, 1, 0), (10, 2, 0)],
+            ),
+        ];
+        for (options, start, end, expected) in cases {
+            let args = test_args(&[&["tbuck", "%F %T"], options.as_slice()].concat());
+            let buckets = interval_span(
+                at(start.0, start.1, start.2),
+                at(end.0, end.1, end.2),
+                &args.bucketings[0],
+                &args,
+            );
+            let expected: Vec<_> = expected
+                .into_iter()
+                .map(|(hour, minute, second)| at(hour, minute, second))
+                .collect();
+            assert_eq!(buckets, expected, "{options:?} {start:?} {end:?}");
+        }
+    }
+}
+
+// Find and parse every date/time in the text of a line for --all-matches, reporting those which
+// fail to parse. The outcome of the line is returned instead if none can be counted. Parsing is
+// timed as part of matching.
+fn find_all_datetimes(
+    text: &str,
+    line: &str,
+    context: &Context,
+    timing: &mut Timing,
+) -> Result<Vec<DateTime<Utc>>, LineOutcome> {
+    let found = timed(context.args.timing, &mut timing.matching, || {
+        context.matcher.find_all_datetimes(text)
+    });
+    if found.is_empty() {
+        return Err(LineOutcome::NoMatch);
+    }
+    let mut datetimes = Vec::with_capacity(found.len());
+    let mut skipped = false;
+    for datetime in found {
+        match datetime {
+            Ok(datetime) => match context.args.leap_seconds.apply(datetime) {
+                Some(datetime) => datetimes.push(datetime),
+                None => skipped = true,
+            },
+            Err(err) => context.errors.report(&err, line),
+        }
+    }
+    if datetimes.is_empty() {
+        return Err(if skipped {
+            LineOutcome::Filtered
+        } else {
+            LineOutcome::ParseFailure
+        });
+    }
+    Ok(datetimes)
+}
+
+// Line counts for an input, reported by --stats.
+#[derive(Debug, Default, Copy, Clone)]
+struct Stats {
+    lines: u64,
+    // Lines whose date/time was parsed and counted into a bucket.
+    matched: u64,
+    // Lines without any match of the date/time format.
+    unmatched: u64,
+    // Lines where the date/time format matched but couldn't be parsed.
+    parse_failures: u64,
+    // Lines which were parsed but excluded, such as by --since or as repeats by --dedupe-by.
+    filtered: u64,
+    // Lines which continued a record rather than starting one, and so weren't counted.
+    continuations: u64,
+    // Time spent on the input, only tracked for --timing.
+    timing: Timing,
+}
+
+impl Stats {
+    fn record(&mut self, outcome: LineOutcome) {
+        self.lines += 1;
+        match outcome {
+            LineOutcome::Counted => self.matched += 1,
+            LineOutcome::NoMatch => self.unmatched += 1,
+            LineOutcome::ParseFailure => self.parse_failures += 1,
+            LineOutcome::Filtered => self.filtered += 1,
+            LineOutcome::Continuation => self.continuations += 1,
+        }
+    }
+
+    fn merge(self, other: Self) -> Self {
+        Self {
+            lines: self.lines + other.lines,
+            matched: self.matched + other.matched,
+            unmatched: self.unmatched + other.unmatched,
+            parse_failures: self.parse_failures + other.parse_failures,
+            filtered: self.filtered + other.filtered,
+            continuations: self.continuations + other.continuations,
+            timing: self.timing.merge(other.timing),
+        }
+    }
+}
+
+// Time spent on each phase of counting, reported by --timing. Time spent on different threads
+// is added up.
+#[derive(Debug, Default, Copy, Clone)]
+struct Timing {
+    // Reading the input, or waiting for it.
+    read: StdDuration,
+    // Finding the date/time in lines.
+    matching: StdDuration,
+    // Parsing the date/times found.
+    parsing: StdDuration,
+    // Selecting groups and values and counting lines into buckets.
+    aggregation: StdDuration,
+    // Most buckets held at once, as sampled.
+    peak_buckets: usize,
+    // Lines until the buckets are counted again for peak_buckets.
+    until_sample: u32,
+}
+
+impl Timing {
+    fn merge(self, other: Self) -> Self {
+        Self {
+            read: self.read + other.read,
+            matching: self.matching + other.matching,
+            parsing: self.parsing + other.parsing,
+            aggregation: self.aggregation + other.aggregation,
+            peak_buckets: self.peak_buckets.max(other.peak_buckets),
+            until_sample: 0,
+        }
+    }
+
+    // Remember how many buckets the runner holds, in modes where they are printed along the way.
+    // Counting them takes a while with many groups, so it is only done every BUCKET_SAMPLE_LINES
+    // lines.
+    fn record_buckets(&mut self, runner: &Runner, args: &Args) {
+        if !args.timing {
+            return;
+        }
+        if self.until_sample == 0 {
+            self.peak_buckets = self.peak_buckets.max(runner.bucket_count());
+            self.until_sample = BUCKET_SAMPLE_LINES;
+        }
+        self.until_sample -= 1;
+    }
+}
+
+// Lines between counts of the buckets held, for the peak of --timing.
+const BUCKET_SAMPLE_LINES: u32 = 1024;
+
+// Run f, adding the time it takes to the phase if timing.
+fn timed<T>(timing: bool, phase: &mut StdDuration, f: impl FnOnce() -> T) -> T {
+    if !timing {
+        return f();
+    }
+    let start = Instant::now();
+    let result = f();
+    *phase += start.elapsed();
+    result
+}
+
+// Write the --timing report: the phases of counting, then writing the output and the whole run.
+// The phases of counting are added up across threads, so they are CPU time rather than wall clock
+// time, and say so.
+fn write_timing(out: &mut impl Write, timing: &Timing, output: StdDuration, total: StdDuration) -> IoResult<()> {
+    writeln!(out, "{:14} {:>12} clock", "phase", "seconds")?;
+    for (phase, duration, clock) in [
+        ("read", timing.read, "cpu"),
+        ("matching", timing.matching, "cpu"),
+        ("parsing", timing.parsing, "cpu"),
+        ("aggregation", timing.aggregation, "cpu"),
+        ("output", output, "wall"),
+        ("total", total, "wall"),
+    ] {
+        writeln!(out, "{phase:14} {:>12.6} {clock}", duration.as_secs_f64())?;
+    }
+    writeln!(out, "{:14} {:>12}", "peak buckets", timing.peak_buckets)
+}
+
+#[derive(Debug, Copy, Clone)]
+enum StatsFormat {
+    Text,
+    Json,
+}
+
+// Where --summary writes its rows.
+#[derive(Debug, Copy, Clone)]
+enum SummaryTarget {
+    Output,
+    Stderr,
+}
+
+// Where the rows are written: stdout, or with --output-file a temporary file next to it which
+// replaces it only once everything has been written, so that a run which fails part way leaves any
+// previous file alone, or with --output-rotate a file for each window of time.
+enum Output {
+    Stdout(Mutex<Buffered<Stdout>>),
+    File {
+        writer: Mutex<Buffered<File>>,
+        // The temporary file being written and the file it replaces, unless the output is something
+        // other than a regular file, such as a pipe or a device, which is written to directly.
+        replace: Option<(TempPath, PathBuf)>,
+    },
+    Rotate {
+        rotation: Rotation,
+        // The window whose file is open, and the file. Lines are written through as they complete,
+        // so that the files can be read while tbuck keeps running.
+        current: Mutex<Option<(DateTime<Utc>, LineWriter<File>)>>,
+    },
+}
+
+// A lock on the output, held while writing rows which belong together.
+enum OutputLock<'a> {
+    Stdout(MutexGuard<'a, Buffered<Stdout>>),
+    File(MutexGuard<'a, Buffered<File>>),
+    Rotate(MutexGuard<'a, Option<(DateTime<Utc>, LineWriter<File>)>>),
+}
+
+impl Output {
+    fn create(args: &Args) -> IoResult<Output> {
+        if let Some(rotation) = &args.output_rotation {
+            return Ok(Output::Rotate {
+                rotation: rotation.clone(),
+                current: Mutex::new(None),
+            });
+        }
+        let Some(path) = args.output_file.as_deref() else {
+            // Rows of stream and sorted modes are wanted as soon as their buckets complete.
+            let by_line = args.flush || !matches!(args.mode, Mode::Normal);
+            return Ok(Output::Stdout(Mutex::new(Buffered::new(std::io::stdout(), by_line))));
+        };
+        let existing = std::fs::metadata(path).ok();
+        if existing.as_ref().is_some_and(|metadata| !metadata.is_file()) {
+            return Ok(Output::File {
+                writer: Mutex::new(Buffered::new(OpenOptions::new().write(true).open(path)?, args.flush)),
+                replace: None,
+            });
+        }
+        // Replace the file a symlink points to, rather than the symlink.
+        let path = if existing.is_some() {
+            std::fs::canonicalize(path)?
+        } else {
+            path.to_path_buf()
+        };
+        // The temporary file must be on the same filesystem for the rename to be atomic.
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let mut builder = tempfile::Builder::new();
+        // Temporary files are private by default, but a new output file should get the usual
+        // permissions as if the shell had created it.
+        #[cfg(unix)]
+        builder.permissions(std::os::unix::fs::PermissionsExt::from_mode(0o666));
+        let temp = builder.tempfile_in(directory)?;
+        if let Some(metadata) = existing {
+            std::fs::set_permissions(temp.path(), metadata.permissions())?;
+        }
+        let (file, temp_path) = temp.into_parts();
+        Ok(Output::File {
+            writer: Mutex::new(Buffered::new(file, args.flush)),
+            replace: Some((temp_path, path)),
+        })
+    }
+
+    // Lock the output to write the rows of normal mode, which --output-rotate isn't used with.
+    fn lock(&self) -> OutputLock<'_> {
+        match self {
+            Output::Stdout(writer) => OutputLock::Stdout(writer.lock().expect("output lock should not be poisoned")),
+            Output::File { writer, .. } => OutputLock::File(writer.lock().expect("output lock should not be poisoned")),
+            Output::Rotate { .. } => unreachable!("only stream and sorted modes rotate their output"),
+        }
+    }
+
+    // Lock the output to write the row of a bucket, moving on to the file of its window if the
+    // output is rotated.
+    fn lock_for(&self, bucket: DateTime<Utc>) -> IoResult<OutputLock<'_>> {
+        let Output::Rotate { rotation, current } = self else {
+            return Ok(self.lock());
+        };
+        let mut current = current.lock().expect("output lock should not be poisoned");
+        let window = rotation.windows.bucketize(&bucket);
+        if current
+            .as_ref()
+            .is_none_or(|(current_window, _)| *current_window != window)
+        {
+            if let Some((_, mut file)) = current.take() {
+                file.flush()?;
+            }
+            let path = local_time(window, &rotation.windows)
+                .format(&rotation.template)
+                .to_string();
+            let file = OpenOptions::new().create(true).append(true).open(&path)?;
+            *current = Some((window, LineWriter::new(file)));
+        }
+        Ok(OutputLock::Rotate(current))
+    }
+
+    // Move the temporary file into place, once everything has been written to it.
+    fn commit(self) -> IoResult<()> {
+        if let Output::Stdout(writer) = self {
+            writer
+                .into_inner()
+                .expect("output lock should not be poisoned")
+                .flush()?;
+        } else if let Output::File { writer, replace } = self {
+            let file = writer
+                .into_inner()
+                .expect("output lock should not be poisoned")
+                .into_inner()?;
+            if let Some((temp, path)) = replace {
+                file.sync_all()?;
+                temp.persist(path).map_err(|err| err.error)?;
+            }
+        } else if let Output::Rotate { current, .. } = self {
+            if let Some((_, mut file)) = current.into_inner().expect("output lock should not be poisoned") {
+                file.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// A writer which collects what is written into blocks, or with --flush writes out each line.
+enum Buffered<W: Write> {
+    Blocks(BufWriter<W>),
+    Lines(LineWriter<W>),
+}
+
+impl<W: Write> Buffered<W> {
+    fn new(writer: W, by_line: bool) -> Self {
+        if by_line {
+            Buffered::Lines(LineWriter::new(writer))
+        } else {
+            Buffered::Blocks(BufWriter::new(writer))
+        }
+    }
+
+    // Write out what is left, and return the writer.
+    fn into_inner(self) -> IoResult<W> {
+        match self {
+            Buffered::Blocks(writer) => writer.into_inner().map_err(std::io::IntoInnerError::into_error),
+            Buffered::Lines(writer) => writer.into_inner().map_err(std::io::IntoInnerError::into_error),
+        }
+    }
+}
+
+impl<W: Write> Write for Buffered<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        match self {
+            Buffered::Blocks(writer) => writer.write(buf),
+            Buffered::Lines(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match self {
+            Buffered::Blocks(writer) => writer.flush(),
+            Buffered::Lines(writer) => writer.flush(),
+        }
+    }
+}
+
+impl Write for OutputLock<'_> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        match self {
+            OutputLock::Stdout(lock) => lock.write(buf),
+            OutputLock::File(lock) => lock.write(buf),
+            OutputLock::Rotate(lock) => lock.as_mut().expect("lock_for opens a file").1.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match self {
+            OutputLock::Stdout(lock) => lock.flush(),
+            OutputLock::File(lock) => lock.flush(),
+            OutputLock::Rotate(lock) => lock.as_mut().expect("lock_for opens a file").1.flush(),
+        }
+    }
+}
+
+// Write the --stats report, with one entry per input followed by the totals.
+fn write_stats(out: &mut impl Write, format: StatsFormat, inputs: &[Input], stats: &[Stats]) -> IoResult<()> {
+    let total = stats.iter().fold(Stats::default(), |total, stats| total.merge(*stats));
+    match format {
+        StatsFormat::Text => {
+            let names: Vec<String> = inputs.iter().map(Input::name).collect();
+            let width = names
+                .iter()
+                .map(String::len)
+                .chain(Some("total".len()))
+                .max()
+                .unwrap_or(0);
+            writeln!(
+                out,
+                "{:width$} {:>12} {:>12} {:>12} {:>14} {:>12} {:>13}",
+                "input", "lines", "matched", "unmatched", "parse failures", "filtered", "continuations"
+            )?;
+            for (name, stats) in names.iter().zip(stats).chain(Some((&"total".to_string(), &total))) {
+                writeln!(
+                    out,
+                    "{name:width$} {:>12} {:>12} {:>12} {:>14} {:>12} {:>13}",
+                    stats.lines,
+                    stats.matched,
+                    stats.unmatched,
+                    stats.parse_failures,
+                    stats.filtered,
+                    stats.continuations
+                )?;
+            }
+        }
+        StatsFormat::Json => {
+            let json = |stats: &Stats| {
+                serde_json::json!({
+                    "lines": stats.lines,
+                    "matched": stats.matched,
+                    "unmatched": stats.unmatched,
+                    "parse_failures": stats.parse_failures,
+                    "filtered": stats.filtered,
+                    "continuations": stats.continuations,
+                })
+            };
+            let entries: Vec<serde_json::Value> = inputs
+                .iter()
+                .zip(stats)
+                .map(|(input, stats)| {
+                    let mut entry = json(stats);
+                    entry["input"] = input.name().into();
+                    entry
+                })
+                .collect();
+            writeln!(
+                out,
+                "{}",
+                serde_json::json!({ "inputs": entries, "total": json(&total) })
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod stats_tests {
+    use super::{test_args, write_stats, write_timing, Input, Runner, Stats, StatsFormat, Timing};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn timing_tells_cpu_from_wall_clock_time() {
+        let timing = Timing {
+            read: Duration::from_millis(1500),
+            peak_buckets: 3,
+            ..Timing::default()
+        };
+        let mut out = Vec::new();
+        write_timing(&mut out, &timing, Duration::from_millis(20), Duration::from_secs(1)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "phase               seconds clock\n\
+             read               1.500000 cpu\n\
+             matching           0.000000 cpu\n\
+             parsing            0.000000 cpu\n\
+             aggregation        0.000000 cpu\n\
+             output             0.020000 wall\n\
+             total              1.000000 wall\n\
+             peak buckets              3\n"
+        );
+    }
+
+    #[test]
+    fn samples_the_buckets_held() {
+        let args = test_args(&["tbuck", "%F %T", "--timing"]);
+        let runner = Runner::from_mode(args.mode, &args);
+        let mut timing = Timing::default();
+        for _ in 0..2048 {
+            timing.record_buckets(&runner, &args);
+        }
+        assert_eq!(timing.until_sample, 0);
+        timing.record_buckets(&runner, &args);
+        assert_eq!(timing.until_sample, 1023);
+    }
+
+    #[test]
+    fn json_names_are_escaped() {
+        let names = vec!["logs/app.log", "C:\\logs\\\"app\".log", "tab\tand\u{1}"];
+        for name in names {
+            let inputs = vec![Input::File(PathBuf::from(name))];
+            let stats = vec![Stats {
+                lines: 3,
+                matched: 2,
+                ..Stats::default()
+            }];
+            let mut out = Vec::new();
+            write_stats(&mut out, StatsFormat::Json, &inputs, &stats).unwrap();
+            let json: serde_json::Value = serde_json::from_slice(&out).unwrap();
+            assert_eq!(json["inputs"][0]["input"], name);
+            assert_eq!(json["inputs"][0]["matched"], 2);
+            assert_eq!(json["total"]["lines"], 3);
+        }
+    }
+}
+
+// The Args of a command line counting entries, without a config file.
+#[cfg(test)]
+fn test_args(args: &[&str]) -> Args {
+    match parse_command(args.iter().map(OsString::from).collect(), "1m") {
+        Command::Count(args) => *args,
+        _ => panic!("{:?} doesn't count entries", args),
+    }
+}
+
+// Count the lines of an input with the options, as tbuck does, and return what it wrote along
+// with how the run ended.
+#[cfg(test)]
+fn test_run(options: &[&str], lines: &str) -> (String, Result<(), Error>) {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("app.log");
+    let output = dir.path().join("out.csv");
+    std::fs::write(&input, lines).unwrap();
+    let (input, output) = (input.to_str().unwrap(), output.to_str().unwrap());
+    let args = test_args(&[&["tbuck", "%F %T", input, "--output-file", output], options].concat());
+    let result = run(&args);
+    (std::fs::read_to_string(output).unwrap_or_default(), result)
+}
+
+// The args of counting, which is what tbuck does with the count subcommand or without any.
+// The default granularity is the one set by the config file, if any.
+#[allow(clippy::too_many_lines)]
+fn count_args<'a, 'b>(app: App<'a, 'b>, default_granularity: &'a str) -> App<'a, 'b> {
+    app
+        .arg(Arg::with_name("match-index")
+            .short("m")
+            .long("match-index")
+            .takes_value(true)
+            .value_name("MATCH_INDEX")
+            .default_value("0")
+            .allow_hyphen_values(true)
+            .help("0-based index of match to use if multiple matches are found, or negative to count from the end")
+            .long_help("0-based index of match to use if multiple matches are found. Negative indexes count back from the end of the line, so -1 uses the last match, such as when lines embed other timestamps before the time they were logged.")
+            .validator(|value| {
+                MatchIndex::parse(&value)
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid integer index".to_string())
+            }))
+        .arg(Arg::with_name("all-matches")
+            .long("all-matches")
+            .conflicts_with_all(&["anchored", "at-column"])
+            .help("Count every date/time found on a line, rather than only the one at --match-index")
+            .long_help("Count every date/time found on a line as an entry of its own, rather than only the one at --match-index, such as for batch log lines which embed the timestamps of several events. Each entry has the group, value and key of the line. Can't be combined with --match-index."))
+        .arg(Arg::with_name("end-format")
+            .long("end-format")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .conflicts_with("all-matches")
+            .help("Count each line in every bucket from its date/time until the end time in this format, like a request span")
+            .long_help("Count each line as an interval, in every bucket from the one its date/time is in until the last one before the end time found with this format, rather than only in the bucket it starts in. Long-running requests or sessions are then counted in each bucket they were active in. The end is the last match of the format after the start, which may have the same format. Lines without an end aren't counted, and those which end before they start are only counted where they start. With --since or --until, only the part of each interval within them is counted, by the times it starts and ends. An interval overlapping more buckets than --max-fill is likely a mis-parsed date/time, so it is reported like one rather than counted. Only one --granularity can be used.")
+            .validator(validate_format))
+        .arg(Arg::with_name("start-format")
+            .long("start-format")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .requires("end-format")
+            .help("Format of the start of each interval with --end-format, given instead of DATE_TIME_FORMAT")
+            .long_help("Format of the start of each interval with --end-format, given instead of DATE_TIME_FORMAT, such as when the start and end have different formats. The first positional argument is then an input file too.")
+            .validator(validate_format))
+        .arg(Arg::with_name("anchored")
+            .long("anchored")
+            .conflicts_with("at-column")
+            .help("Only use a date/time at the very start of each line, like --at-column 0"))
+        .arg(Arg::with_name("at-column")
+            .long("at-column")
+            .takes_value(true)
+            .value_name("COLUMN")
+            .help("Only use a date/time starting at this 0-based character column of each line")
+            .long_help("Only use a date/time starting at this 0-based character column of each line. Lines are only checked at that one position rather than searched, which is faster and avoids counting timestamps in the free-text part of lines. Can't be combined with --match-index.")
+            .validator(|value| {
+                value.parse::<usize>()
+                    .map(|_| ())
+                    .map_err(|_| "Not a valid column".to_string())
+            }))
+        .arg(Arg::with_name("field")
+            .long("field")
+            .takes_value(true)
+            .value_name("N")
+            .help("Only look for the date/time in the Nth field of each line, counting from 1 as cut does")
+            .long_help("Only look for the date/time in the Nth field of each line, counting from 1 as cut does, after splitting the line at every --delimiter. This is faster than searching the whole line, and avoids matching timestamps in other fields of CSV or TSV inputs. Lines with fewer fields aren't counted. Fields aren't unquoted, so quoted fields may contain the delimiter only if they come after the Nth.")
+            .validator(|value| match value.parse::<usize>() {
+                Ok(field) if field > 0 => Ok(()),
+                _ => Err("Not a valid field number, which start at 1".to_string()),
+            }))
+        .arg(Arg::with_name("json-field")
+            .long("json-field")
+            .takes_value(true)
+            .value_name("PATH")
+            .conflicts_with("field")
+            .help("Only look for the date/time in this field of each line's JSON object, with dots separating nested keys like 'meta.ts'")
+            .long_help("Only look for the date/time in this field of each line's JSON object, with dots separating nested keys like 'meta.ts', and numbers selecting from arrays like 'events.0.time'. The field must be a string, or a number such as an epoch timestamp for '%s'. Lines which aren't JSON, or don't have the field, aren't counted.")
+            .validator(|value| {
+                if value.split('.').any(str::is_empty) {
+                    return Err("The path has an empty key".to_string());
+                }
+                Ok(())
+            }))
+        .arg(Arg::with_name("csv")
+            .long("csv")
+            .requires("time-column")
+            .conflicts_with_all(&["field", "json-field"])
+            .help("Read the date/time from a column of CSV input, see --time-column"))
+        .arg(Arg::with_name("time-column")
+            .long("time-column")
+            .takes_value(true)
+            .value_name("COLUMN")
+            .requires("csv")
+            .help("Column of --csv input to look for the date/time in, by its name in the header or its number counting from 1")
+            .long_help("Column of --csv input to look for the date/time in, by its name in the header line of the first input, or its number counting from 1. Fields are parsed as CSV, so quoted fields may contain commas, but a record must fit on one line. Lines without the column aren't counted.")
+            .validator(|value| {
+                if value == "0" {
+                    return Err("Column numbers start at 1".to_string());
+                }
+                Ok(())
+            }))
+        .arg(Arg::with_name("logfmt")
+            .long("logfmt")
+            .requires("time-key")
+            .conflicts_with_all(&["field", "json-field", "csv"])
+            .help("Read the date/time from a key of logfmt input like 'ts=... level=info', see --time-key"))
+        .arg(Arg::with_name("time-key")
+            .long("time-key")
+            .takes_value(true)
+            .value_name("KEY")
+            .requires("logfmt")
+            .help("Key of --logfmt input to look for the date/time in, like 'ts'")
+            .long_help("Key of --logfmt input to look for the date/time in, like 'ts' for lines like 'ts=2024-01-01T10:00:00Z level=info msg=\"a b\"'. Quoted values are unquoted. Lines without the key aren't counted."))
+        .arg(Arg::with_name("group-key")
+            .long("group-key")
+            .takes_value(true)
+            .value_name("KEY")
+            .requires("logfmt")
+            .conflicts_with_all(&["group-by", "stream", "sorted-input", "per-file"])
+            .help("Like --group-by, but counts lines separately for each value of this key of --logfmt input"))
+        .arg(Arg::with_name("value-key")
+            .long("value-key")
+            .takes_value(true)
+            .value_name("KEY")
+            .requires("logfmt")
+            .conflicts_with_all(&["value", "dedupe-by"])
+            .help("Like --value, but aggregates the number which is the value of this key of --logfmt input"))
+        .arg(Arg::with_name("delimiter")
+            .long("delimiter")
+            .takes_value(true)
+            .value_name("DELIMITER")
+            .requires("field")
+            .help("String which separates the fields of --field; a tab by default, which may also be given as '\\t'")
+            .validator(|value| {
+                if value.is_empty() {
+                    return Err("The delimiter can't be empty".to_string());
+                }
+                Ok(())
+            }))
+        .arg(Arg::with_name("granularity")
+            .short("g")
+            .long("granularity")
+            .takes_value(true)
+            .value_name("GRANULARITY")
+            .default_value(default_granularity)
+            .multiple(true)
+            .number_of_values(1)
+            .help("Bucket time granularity in seconds ('5s'), minutes ('1m'), hours ('2h'), days ('1d'), weeks ('1w'), months ('1mo'), or quarters ('1q')")
+            .long_help("Bucket time granularity in seconds ('5s'), minutes ('1m'), hours ('2h'), days ('1d'), weeks ('1w'), months ('1mo'), or quarters ('1q'). In normal mode, may be given more than once to count at several granularities in a single pass, such as '-g 1m -g 1h'. Each granularity's buckets are then printed in turn, with the granularity as an extra first column.")
+            .validator(|value| {
+                Granularity::parse(&value)
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid granularity specifier".to_string())
+            }))
+        .arg(Arg::with_name("align")
+            .long("align")
+            .takes_value(true)
+            .value_name("OFFSET")
+            .help("Shift bucket boundaries later by this much, like '30s', or to this many minutes past the hour, like ':02'")
+            .long_help("Shift bucket boundaries later than their usual alignment by this much, like '30s' or '2m', or to this many minutes (and seconds) past the hour, like ':02' or ':02:30'. For example, '-g 5m --align :02' gives buckets starting at :02, :07, :12 and so on.")
+            .validator(|value| {
+                parse_alignment(&value)
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid offset".to_string())
+            }))
+        .arg(Arg::with_name("value")
+            .long("value")
+            .takes_value(true)
+            .value_name("REGEX")
+            .help("Aggregate the number captured by this regex's first capture group, like 'took=(\\d+)ms', with --agg")
+            .long_help("Aggregate the number captured by this regex's first capture group, like 'took=(\\d+)ms', with --agg, such as a response time or a size. Lines the regex doesn't match, or whose capture isn't a number, aren't counted, and are reported as filtered by --stats.")
+            .validator(|value| {
+                let regex = Regex::new(&value).map_err(|err| err.to_string())?;
+                if regex.captures_len() < 2 {
+                    return Err("The regex needs a capture group".to_string());
+                }
+                Ok(())
+            }))
+        .arg(Arg::with_name("agg")
+            .long("agg")
+            .takes_value(true)
+            .value_name("AGGREGATION")
+            .default_value("count")
+            .help("What is reported for each bucket: 'count' of lines, or 'sum', 'avg', 'min', 'max', 'var', 'stddev' or percentiles like 'p95' of their --value, several like 'count,avg,p95'")
+            .long_help("What is reported for each bucket: the 'count' of lines, or the 'sum', 'avg', 'min', 'max', population variance 'var' or standard deviation 'stddev' of the numbers selected by --value, which every aggregation other than 'count' needs, or percentiles like 'p99' or 'p99.9'. Several may be listed together, like 'count,sum,avg,p95', for a column each in that order, all counted in the same pass over the input. Alerts, spikes, --summary and the derived columns like --delta are of the first. Percentiles are exact, except in --stream mode where they are estimated with a t-digest so that memory doesn't grow with the number of lines. Buckets without any values report nothing other than for 'count' and 'sum'.")
+            .validator(|value| {
+                value
+                    .split(',')
+                    .try_for_each(|aggregation| {
+                        Aggregation::parse(aggregation)
+                            .map(|_| ())
+                            .ok_or_else(|| format!("Unknown aggregation '{aggregation}'"))
+                    })
+            }))
+        .arg(Arg::with_name("expr")
+            .long("expr")
+            .takes_value(true)
+            .value_name("EXPRESSION")
+            .help("Add a column computed from the aggregations of each bucket, like 'sum(bytes)/count'")
+            .long_help("Add a column after the --agg columns computed from the aggregations of each bucket, like 'sum(bytes)/count' for the average payload size. 'count', 'sum', 'avg', 'min', 'max', 'var', 'stddev' and percentiles like 'p95' on their own are of the lines and their --value, and applied to a name, like 'sum(bytes)', are of the numbers of that field: the named capture group of the --value regex, like 'bytes=(?P<bytes>\\d+)', or with --value-key the logfmt key. A line without a logfmt key still counts, but not towards its aggregations. The regex has to match lines without a field too, or they aren't counted at all, so make fields which some lines lack optional, like '(?: bytes=(?P<bytes>\\d+))?'. Numbers, +, -, *, / and parentheses combine them. The column is empty where an aggregation has no result, or for division by 0.")
+            .validator(|value| Expression::parse(&value).map(|_| ())))
+        .arg(Arg::with_name("rate")
+            .long("rate")
+            .help("Report counts and sums per second of each bucket, to compare different granularities")
+            .long_help("Report counts and sums per second, dividing them by the length of each bucket in seconds, so that results at different granularities can be compared directly. Calendar buckets are divided by their actual length, such as 28 to 31 days for months, and their columns are named like 'count/s' in headers. Other aggregations are unaffected."))
+        .arg(Arg::with_name("moving-avg")
+            .long("moving-avg")
+            .takes_value(true)
+            .value_name("BUCKETS")
+            .help("Add a column averaging each row with the rows before it, this many in all, to smooth out noise")
+            .long_help("Add a column with the trailing moving average over this many rows: each row and the rows before it. This smooths out noisy counts, making spikes easier to tell apart. It averages the first aggregation, separately for each group. Rows without a value, such as for 'avg' of an empty bucket, are left out of the average.")
+            .validator(|value| {
+                value.parse::<usize>()
+                    .ok()
+                    .filter(|window| *window > 0)
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid positive number of buckets".to_string())
+            }))
+        .arg(Arg::with_name("ewma")
+            .long("ewma")
+            .takes_value(true)
+            .value_name("ALPHA")
+            .help("Add a column with the exponentially weighted moving average, weighting each row by ALPHA")
+            .long_help("Add a column with the exponentially weighted moving average: each row's value weighted by ALPHA, from 0 exclusive to 1, plus the previous average weighted by 1 - ALPHA. Smaller weights smooth more. Unlike --moving-avg, every earlier row contributes, less the older it is, which handles bursty data without a window to tune. It averages the first aggregation, separately for each group, starting from the first row. Rows without a value, such as for 'avg' of an empty bucket, leave the average as it was.")
+            .validator(|value| {
+                value.parse::<f64>()
+                    .ok()
+                    .filter(|alpha| *alpha > 0.0 && *alpha <= 1.0)
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid weight, more than 0 and at most 1".to_string())
+            }))
+        .arg(Arg::with_name("delta")
+            .long("delta")
+            .help("Add a column with the change from the previous row")
+            .long_help("Add a column with the change from the previous row, such as for logs which dump the running total of a counter. It is of the first aggregation, separately for each group, and empty for the first row."))
+        .arg(Arg::with_name("delta-only")
+            .long("delta-only")
+            .conflicts_with_all(&["moving-avg", "ewma", "percent"])
+            .help("Like --delta, but print the change instead of the aggregations themselves")
+            .long_help("Like --delta, but print the change instead of the aggregations themselves. Only the columns of changes, --delta and --derivative, are printed, so it can't be combined with --moving-avg, --ewma or --percent."))
+        .arg(Arg::with_name("derivative")
+            .long("derivative")
+            .help("Add a column with the change from the previous row per second")
+            .long_help("Add a column with the change from the previous row divided by the seconds from its bucket to this one, the rate of change per second, such as for logs which dump the running total of a counter. It is of the first aggregation, separately for each group, and empty for the first row. With --delta-only, it is printed instead of the aggregations too."))
+        .arg(Arg::with_name("percent")
+            .long("percent")
+            .conflicts_with_all(&["stream", "sorted-input"])
+            .help("Add a column with each bucket's percentage of the total")
+            .long_help("Add a column with each bucket's share of the total of all buckets printed, as a percentage. It is of the first aggregation, and with --group-by or --per-file each group's buckets add up to 100 separately. Not available in --stream or --sorted-input mode, since the total is only known at the end."))
+        .arg(Arg::with_name("alert-over")
+            .long("alert-over")
+            .takes_value(true)
+            .value_name("THRESHOLD")
+            .help("Exit with status 4 if any bucket is over this threshold")
+            .long_help("Exit with status 4 if any bucket is over this threshold, after printing the output as usual, so that tbuck can be used for alerting from a script. It is compared to the first aggregation, after --rate. See also --alert-only.")
+            .validator(|value| {
+                value.parse::<f64>()
+                    .map(|_| ())
+                    .map_err(|_| "Not a valid number".to_string())
+            }))
+        .arg(Arg::with_name("alert-under")
+            .long("alert-under")
+            .takes_value(true)
+            .value_name("THRESHOLD")
+            .help("Exit with status 4 if any bucket is under this threshold")
+            .long_help("Exit with status 4 if any bucket is under this threshold, such as when logging has gone quiet, after printing the output as usual. It is compared to the first aggregation, after --rate. Empty buckets are only included when filling, which is the default. See also --alert-only.")
+            .validator(|value| {
+                value.parse::<f64>()
+                    .map(|_| ())
+                    .map_err(|_| "Not a valid number".to_string())
+            }))
+        .arg(Arg::with_name("alert-only")
+            .long("alert-only")
+            .help("Only print the buckets which breach --alert-over or --alert-under"))
+        .arg(Arg::with_name("distinct")
+            .long("distinct")
+            .takes_value(true)
+            .value_name("REGEX")
+            .help("Report the number of different values of this regex's first capture group in each bucket, like 'user=(\\w+)'")
+            .long_help("Report the number of different values of this regex's first capture group in each bucket, like 'user=(\\w+)', such as unique users or client addresses, rather than the number of lines. Use --agg to report it alongside other aggregations, as 'distinct'. Lines the regex doesn't match aren't counted, and are reported as filtered by --stats.")
+            .validator(|value| {
+                let regex = Regex::new(&value).map_err(|err| err.to_string())?;
+                if regex.captures_len() < 2 {
+                    return Err("The regex needs a capture group".to_string());
+                }
+                Ok(())
+            }))
+        .arg(Arg::with_name("weight")
+            .long("weight")
+            .takes_value(true)
+            .value_name("REGEX")
+            .conflicts_with_all(&["value", "value-key", "agg", "distinct", "dedupe-by"])
+            .help("Add the number captured by this regex's first capture group to the bucket instead of 1, like 'bytes=(\\d+)'")
+            .long_help("Add the number captured by this regex's first capture group to each line's bucket instead of 1, like 'bytes=(\\d+)' to total the bytes transferred rather than count requests. This is the same as --value with --agg sum. Lines the regex doesn't match, or whose capture isn't a number, add nothing.")
+            .validator(|value| {
+                let regex = Regex::new(&value).map_err(|err| err.to_string())?;
+                if regex.captures_len() < 2 {
+                    return Err("The regex needs a capture group".to_string());
+                }
+                Ok(())
+            }))
+        .arg(Arg::with_name("sessions")
+            .long("sessions")
+            .takes_value(true)
+            .min_values(0)
+            .max_values(1)
+            .require_equals(true)
+            .value_name("COUNT")
+            .possible_values(&["started", "active"])
+            .requires("session-key")
+            .conflicts_with_all(&[
+                "stream", "sorted-input", "state", "group-by", "group-key", "per-file", "value", "value-key", "weight",
+                "agg", "distinct", "dedupe-by", "all-matches", "end-format",
+            ])
+            .help("Count the sessions started in each bucket, or with --sessions=active those active in it, see --session-key")
+            .long_help("Count sessions rather than lines: the lines of each --session-key, such as a user, are split into sessions wherever they are more than --session-gap apart, and each session is counted in the bucket it started in, or with --sessions=active in every bucket from its first line to its last. The sessions are only known once every line has been read, and the date/times of every key are kept until then."))
+        .arg(Arg::with_name("session-key")
+            .long("session-key")
+            .takes_value(true)
+            .value_name("REGEX")
+            .requires("sessions")
+            .help("Regex whose first capture group is the key of each line's session with --sessions, like 'user=(\\w+)'")
+            .long_help("Regex whose first capture group is the key of each line's session with --sessions, like 'user=(\\w+)'. Lines the regex doesn't match aren't part of any session, and are reported as filtered by --stats.")
+            .validator(|value| {
+                let regex = Regex::new(&value).map_err(|err| err.to_string())?;
+                if regex.captures_len() < 2 {
+                    return Err("The regex needs a capture group".to_string());
+                }
+                Ok(())
+            }))
+        .arg(Arg::with_name("session-gap")
+            .long("session-gap")
+            .takes_value(true)
+            .value_name("DURATION")
+            .default_value("30m")
+            .help("Lines of a --session-key further apart than this, like '30m', are in different sessions")
+            .validator(|value| {
+                parse_duration(&value)
+                    .filter(|gap| *gap >= Duration::zero())
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid duration".to_string())
+            }))
+        .arg(Arg::with_name("dedupe-by")
+            .long("dedupe-by")
+            .takes_value(true)
+            .value_name("REGEX")
+            .conflicts_with("value")
+            .help("Count lines with the same value of this regex's first capture group only once per bucket, like 'request_id=(\\w+)'")
+            .long_help("Count lines with the same value of this regex's first capture group only once per bucket, like 'request_id=(\\w+)', such as to not count retries of a request again. Lines the regex doesn't match are all counted. Repeats are reported as filtered by --stats. With --approx, and always in --stream mode, only a hash of each value is kept, which takes less memory but may rarely take two different values to be the same.")
+            .validator(|value| {
+                let regex = Regex::new(&value).map_err(|err| err.to_string())?;
+                if regex.captures_len() < 2 {
+                    return Err("The regex needs a capture group".to_string());
+                }
+                Ok(())
+            }))
+        .arg(Arg::with_name("approx")
+            .long("approx")
+            .help("Estimate distinct counts and percentiles in bounded memory rather than keep every value")
+            .long_help("Estimate distinct counts with a HyperLogLog and percentiles with a t-digest, so that memory doesn't grow with the number of different values, rather than keep every value for exact results. Distinct counts are then within a few percent. This is always the case in --stream mode."))
+        .arg(Arg::with_name("week-start")
+            .long("week-start")
+            .takes_value(true)
+            .value_name("DAY")
+            .default_value("mon")
+            .possible_values(&["mon", "sun"])
+            .help("Day week buckets start on: Monday, as ISO weeks do, or Sunday")
+            .long_help("Day week buckets start on: Monday, as ISO weeks do, or Sunday, as US-style weeks do. Weeks of more than one, like '2w', are counted from the week of 1970-01-01."))
+        .arg(Arg::with_name("timezone")
+            .long("timezone")
+            .takes_value(true)
+            .value_name("TIMEZONE")
+            .help("Align buckets to the wall clock of this timezone, like 'America/New_York', and print them in it")
+            .long_help("Align buckets to the wall clock of this IANA timezone, like 'America/New_York', rather than UTC, and print them in it. Day buckets start at local midnight, so are 23 or 25 hours long when the clocks change. Date/times without an offset are still taken to be UTC.")
+            .validator(|value| value.parse::<Tz>().map(|_| ())))
+        .arg(Arg::with_name("anchor")
+            .long("anchor")
+            .takes_value(true)
+            .value_name("DATETIME")
+            .conflicts_with_all(&["align", "timezone"])
+            .help("Make buckets fixed-width intervals from this RFC 3339 date/time, like '2024-01-01T00:00:00Z'")
+            .long_help("Make buckets fixed-width intervals counted from this RFC 3339 date/time, like '2024-01-01T00:00:00Z', in both directions. Otherwise buckets divide up each day (for hours) or hour (for minutes and seconds), so granularities which don't divide it evenly, like '7h' or '90m', give a shorter bucket at the end of each. Has no effect on months and quarters.")
+            .validator(|value| parse_datetime_arg(&value).map(|_| ())))
+        .arg(Arg::with_name("century-pivot")
+            .long("century-pivot")
+            .takes_value(true)
+            .value_name("YY")
+            .default_value("70")
+            .help("Two-digit years (%y) from this one up are in the 1900s, and below it in the 2000s")
+            .validator(|value| {
+                value.parse::<u32>()
+                    .ok()
+                    .filter(|pivot| *pivot <= 100)
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid two-digit year".to_string())
+            }))
+        .arg(Arg::with_name("assume-year")
+            .long("assume-year")
+            .takes_value(true)
+            .value_name("YEAR")
+            .help("Year of timestamps whose date/time format has no year in it")
+            .long_help("Year of timestamps whose date/time format has no year in it, such as the '%b %e %H:%M:%S' of syslog. Every such timestamp is taken to be in this year, so entries spanning a new year will be out of order. Formats without a year are rejected unless this is given. Missing hours and minutes are always taken to be 0, whether or not this is given.")
+            .validator(|value| {
+                value.parse::<i32>()
+                    .map(|_| ())
+                    .map_err(|_| "Not a valid year".to_string())
+            }))
+        .arg(Arg::with_name("leap-seconds")
+            .long("leap-seconds")
+            .takes_value(true)
+            .value_name("POLICY")
+            .default_value("keep")
+            .possible_values(&["clamp", "keep", "skip"])
+            .help("What to do with timestamps on a leap second, like 23:59:60: read them as :59, keep them, or skip their lines")
+            .long_help("What to do with timestamps whose seconds are 60, like the leap second 2016-12-31 23:59:60. 'keep' parses them as chrono does, into a second after :59 which still falls in its bucket but makes durations across it a second longer. 'clamp' reads them as :59 of the same minute, and 'skip' leaves their lines out like --since does, counting them as filtered. Seconds over 60 are parse errors whatever the policy."))
+        .arg(Arg::with_name("no-fill")
+            .short("n")
+            .long("no-fill")
+            .help("Disable counts of 0 being emitted for buckets with no entries")
+            .long_help("By default buckets which had no entries present will be displayed with a count of 0. If this flag is present then instead the bucket will not be printed at all."))
+        .arg(max_fill_arg())
+        .arg(Arg::with_name("fill-value")
+            .long("fill-value")
+            .takes_value(true)
+            .value_name("VALUE")
+            .conflicts_with("no-fill")
+            .help("Print this for the aggregations of empty buckets instead of 0, like '', 'NaN' or 'null'")
+            .long_help("Print this for the aggregations of buckets with no entries instead of their usual value, like an empty field with '', 'NaN' or 'null'. A count of 0 can be misleading for a metric like an average, which some plotting tools handle better as a missing value. This also applies to the empty cells of --heatmap and the empty rows of --by. Derived columns like --delta are unaffected."))
+        .arg(Arg::with_name("fill")
+            .long("fill")
+            .takes_value(true)
+            .value_name("MODE")
+            .possible_values(&["zero", "interpolate"])
+            .conflicts_with_all(&["no-fill", "fill-value", "stream", "sorted-input"])
+            .help("Fill empty buckets with their usual values, like a count of 0, or 'interpolate' their values")
+            .long_help("How empty buckets are filled. 'zero', the default, prints their usual values, like a count or sum of 0 and no average. 'interpolate' instead draws a straight line between the values of the nearest buckets with entries on either side, for every aggregation of --value and the --expr, since zeros distort the averages of sparse series. Counts and distinct counts are still 0, and buckets before the first or after the last with a value are left empty. Not available in --stream or --sorted-input mode, since the next bucket with entries isn't known yet."))
+        .arg(Arg::with_name("stream")
+            .short("s")
+            .long("stream")
+            .help("Enable stream mode")
+            .long_help("Enable stream mode. Entries will be expected to arrive in monotonically increasing (or --decreasing) order, and bucket information will be printed live as soon as the bucket is known to be finished. By default the presence of any entry violating the monotonic order will cause an error, but this can be made --tolerant."))
+        .arg(Arg::with_name("descending")
+            .short("d")
+            .long("descending")
+            .help("Set expected stream order to descending, or prints buckets in descending order in normal mode")
+            .long_help("By default stream mode expects entries to be in monotonically ascending order by date (earlier dates followed by later dates), which is the usual order of log files. If this flag is present then stream mode will instead expect entries in monotonically decreasing order by date (later dates followed by earlier dates). In normal mode, this flag will cause the buckets to be printed in descending order instead of the default ascending order."))
+        .arg(Arg::with_name("order")
+            .long("order")
+            .takes_value(true)
+            .value_name("ORDER")
+            .possible_values(&["asc", "desc", "auto"])
+            .conflicts_with("descending")
+            .help("Order entries are expected in and buckets are printed in: 'asc', 'desc', or 'auto' to sample the inputs")
+            .long_help("Order that entries are expected in in stream mode, and that buckets are printed in in normal mode: 'asc', the default, 'desc', the same as --descending, or 'auto' to decide from the first lines of each input file. With 'auto', an input is descending if more of the date/times in those lines are earlier than the one before them than are later, after any --skew. The inputs must all be in the same order, except for those too short to tell, and if none can tell the order is ascending. Only input files can be sampled, so 'auto' can't be used with stdin or other streams."))
+        .arg(Arg::with_name("tolerant")
+            .short("t")
+            .long("tolerant")
+            .requires("stream")
+            .help("Make stream mode discard non-monotonic entries instead of erroring")
+            .long_help("By default when a non-monotonic entry is encountered in stream mode the program will terminate with an error. If this flag is present then non-monotonic entries will instead be discarded, and the number discarded and the earliest and latest of their date/times are reported on stderr at the end."))
+        .arg(Arg::with_name("follow")
+            .short("F")
+            .long("follow")
+            .requires("stream")
+            .help("Keep reading the last input file as it grows, like tail -f")
+            .long_help("Instead of stopping at the end of the last input file, wait for new lines to be appended to it and feed them into stream mode as they arrive. If the file is truncated or replaced by another file (for example by log rotation), it is reopened and read from the beginning. Earlier input files are read normally, so rotated logs can be listed before the live one."))
+        .arg(Arg::with_name("journal")
+            .long("journal")
+            .takes_value(true)
+            .min_values(0)
+            .max_values(1)
+            .require_equals(true)
+            .value_name("UNIT")
+            .conflicts_with_all(&[
+                "format", "inputs", "files-from", "field", "json-field", "csv", "logfmt", "at-column", "state",
+            ])
+            .help("Read the entries of the systemd journal, or of a unit like --journal=nginx.service, instead of input files")
+            .long_help("Read the entries of the systemd journal with journalctl, or only those of a unit like --journal=nginx.service, instead of input files. The journal's own timestamps are used, so no date/time format is given. With --stream --follow, new entries are counted as they are logged."))
+        .arg(Arg::with_name("evtx")
+            .long("evtx")
+            .conflicts_with_all(&[
+                "journal", "field", "json-field", "csv", "logfmt", "at-column", "state", "follow",
+            ])
+            .help("Read Windows Event Log (.evtx) files, counting each event by its System/TimeCreated")
+            .long_help("Read the input files as Windows Event Log (.evtx) files, counting each event by its System/TimeCreated, decoded from the binary XML of the event. This is when the event happened, which can be earlier than when its record was written to the log, such as for events forwarded from other hosts. No date/time format is given, so the first positional argument is an input file too. Each event is seen as a line like '1704103260 record=42', for --grep and the like, or as 'record=42' without a date/time if its TimeCreated can't be read."))
+        .arg(Arg::with_name("listen")
+            .long("listen")
+            .takes_value(true)
+            .value_name("URL")
+            .requires("stream")
+            .conflicts_with_all(&[
+                "format", "inputs", "files-from", "journal", "evtx", "field", "json-field", "csv", "logfmt",
+                "at-column", "state", "follow",
+            ])
+            .help("Receive syslog messages on an address like udp://0.0.0.0:5514 or tcp://0.0.0.0:5514, in stream mode")
+            .long_help("Receive syslog messages on a network address like udp://0.0.0.0:5514 or tcp://0.0.0.0:5514 instead of reading input files, and count them live in --stream mode. Each UDP datagram is one message, and messages sent over TCP are separated by newlines. Messages are counted by the time they arrive, so no date/time format is given, and each is seen as a line starting with that time in seconds, like '1704103260 <34>Jan  1 10:01:00 host app: message', for --grep and the like.")
+            .validator(|value| Listener::parse(&value).map(|_| ())))
+        .arg(Arg::with_name("kafka")
+            .long("kafka")
+            .takes_value(true)
+            .value_name("BROKERS")
+            .requires("topic")
+            .conflicts_with_all(&[
+                "format", "inputs", "files-from", "journal", "evtx", "listen", "field", "json-field", "csv",
+                "logfmt", "at-column", "state",
+            ])
+            .help("Read the messages of a Kafka --topic from brokers like broker:9092, instead of input files")
+            .long_help("Read the messages of a Kafka --topic from a comma-separated list of brokers like broker:9092, instead of input files. Messages are counted by the time they were produced, so no date/time format is given, and each is seen as a line starting with that time in seconds followed by the message, for --grep and the like. All of the messages are read, up to the end of every partition. With --stream --follow, only those produced from now on are read instead, as they are produced, or all of them with --from-beginning. Partitions are read directly rather than as part of a consumer group, so no offsets are committed. Only available if tbuck was built with the kafka feature."))
+        .arg(Arg::with_name("topic")
+            .long("topic")
+            .takes_value(true)
+            .value_name("TOPIC")
+            .requires("kafka")
+            .help("Kafka topic to read with --kafka"))
+        .arg(Arg::with_name("from-beginning")
+            .long("from-beginning")
+            .requires("kafka")
+            .help("Read a followed --kafka topic from its oldest messages, rather than only those produced from now on")
+            .long_help("With --follow, read a --kafka topic from its oldest messages, rather than only those produced from now on. Without --follow, the topic is always read from its oldest messages, since there would be nothing to count otherwise."))
+        .arg(Arg::with_name("state")
+            .long("state")
+            .takes_value(true)
+            .value_name("FILE")
+            .conflicts_with_all(&["stream", "sorted-input", "max-lines", "max-bytes"])
+            .help("Only count what was appended to the inputs since the last run with this state file")
+            .long_help("Only count what was appended to each input file since the last run with this state file, and merge it with the buckets counted then, so that scheduled runs can aggregate incrementally. The buckets and how far each input was read are saved to the file again once the output has been written. A line still being written at the end of an input is left for the next run, and an input which has shrunk or starts differently since, such as after log rotation, is read from the beginning. The state file can only be resumed with the same format and counting options, and stdin can't be resumed."))
+        .arg(Arg::with_name("max-lines")
+            .long("max-lines")
+            .takes_value(true)
+            .value_name("COUNT")
+            .help("Only read the first COUNT lines of each input, for a quick preview")
+            .long_help("Only read the first COUNT lines of each input, such as to check a date/time format against the start of a giant file without waiting for the whole of it.")
+            .validator(|value| {
+                value.parse::<u64>()
+                    .ok()
+                    .filter(|count| *count > 0)
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid positive number of lines".to_string())
+            }))
+        .arg(Arg::with_name("max-bytes")
+            .long("max-bytes")
+            .takes_value(true)
+            .value_name("SIZE")
+            .help("Only read about the first SIZE bytes of each input, like '64M', for a quick preview")
+            .long_help("Only read the first SIZE bytes of each input, like '4096', '64K', '10M' or '1G', and the rest of the line the limit falls in, such as to check a date/time format against the start of a giant file without waiting for the whole of it.")
+            .validator(|value| {
+                parse_size(&value)
+                    .filter(|size| *size > 0)
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid positive size".to_string())
+            }))
+        .arg(Arg::with_name("mmap")
+            .long("mmap")
+            .conflicts_with_all(&["follow", "flush-interval"])
+            .help("Memory-map input files instead of reading them through a buffer")
+            .long_help("Memory-map input files and iterate over lines in the mapping instead of copying each line into a buffer, which is noticeably faster for multi-gigabyte inputs. Standard input is unaffected. The input files must not be truncated while tbuck is running. Partial counts can't be flushed with --flush-interval while a mapping is counted."))
+        .arg(Arg::with_name("jobs")
+            .short("j")
+            .long("jobs")
+            .takes_value(true)
+            .value_name("JOBS")
+            .default_value("0")
+            .help("Number of threads used to count entries in normal mode, or 0 for one per CPU")
+            .long_help("Number of threads used to count entries in normal mode, or 0 for one per CPU. Input files are processed in parallel, and each file is also split into chunks of lines which are counted in parallel. Stream mode always processes entries in order on a single thread.")
+            .validator(|value| {
+                value.parse::<usize>()
+                    .map(|_| ())
+                    .map_err(|_| "Not a valid number of jobs".to_string())
+            }))
+        .arg(Arg::with_name("files-from")
+            .long("files-from")
+            .takes_value(true)
+            .value_name("LIST_FILE")
+            .help("Also process the files listed in this file, or in standard input if '-'")
+            .long_help("Also process the files whose paths are listed in this file, or in standard input if '-' is given. Paths may be separated by newlines or, if the list contains any NUL characters, by NULs, so that the output of `find ... -print0` can be used directly. Listed paths are not glob-expanded."))
+        .arg(Arg::with_name("recursive")
+            .short("r")
+            .long("recursive")
+            .help("Descend into subdirectories of directory inputs")
+            .long_help("By default a directory given as an input is expanded to the files directly inside it. If this flag is present then its subdirectories are walked as well, and every file found is processed in sorted path order."))
+        .arg(Arg::with_name("name")
+            .long("name")
+            .takes_value(true)
+            .value_name("PATTERN")
+            .help("Only process files in directory inputs whose name matches this glob pattern, like '*.log'")
+            .validator(|value| {
+                glob::Pattern::new(&value)
+                    .map(|_| ())
+                    .map_err(|err| format!("Not a valid glob pattern: {err}"))
+            }))
+        .arg(Arg::with_name("sorted-input")
+            .long("sorted-input")
+            .conflicts_with("stream")
+            .help("Emit buckets incrementally with bounded memory, for input which is already sorted")
+            .long_help("Hint that the input is sorted by date (ascending, or --descending), as log files usually are. The output is the same as in normal mode, but each bucket is printed as soon as the input has moved past it instead of holding every bucket in memory until the end, so long runs at fine granularities use bounded memory. Entries may be out of order by up to the --reorder-window; entries arriving later than that are discarded with a warning."))
+        .arg(Arg::with_name("reorder-window")
+            .long("reorder-window")
+            .takes_value(true)
+            .value_name("DURATION")
+            .default_value("0s")
+            .help("How far out of order entries may be with --sorted-input, like '30s' or '5m'")
+            .validator(|value| {
+                parse_duration(&value)
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid duration".to_string())
+            }))
+        .arg(Arg::with_name("flush")
+            .long("flush")
+            .help("Write each row out as soon as it is printed, rather than in blocks")
+            .long_help("Write each row out as soon as it is printed, rather than collecting rows into blocks for fewer writes, so that a program reading the output sees them right away. Rows of stream and sorted modes printed to stdout are always written out as each bucket completes; this also does so for --output-file, such as a named pipe, and for normal mode."))
+        .arg(Arg::with_name("flush-interval")
+            .long("flush-interval")
+            .takes_value(true)
+            .value_name("DURATION")
+            .requires("stream")
+            .conflicts_with("output-rotate")
+            .help("In stream mode, print the current bucket's partial count this often, like '5s'")
+            .long_help("In stream mode, print the count of the bucket currently being counted this often, like '5s', even if no newer entry has arrived to complete it. Partial rows have a third ',partial' column, and the bucket is printed again without it once complete. Useful when tailing a live log so dashboards aren't a bucket behind. Can't be used with --output-rotate, whose files would keep the partial rows.")
+            .validator(|value| {
+                parse_duration(&value)
+                    .filter(|duration| *duration > Duration::zero())
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid positive duration".to_string())
+            }))
+        .arg(Arg::with_name("since")
+            .long("since")
+            .takes_value(true)
+            .value_name("DATETIME")
+            .help("Discard entries before this RFC 3339 date/time, like '2024-05-01T00:00:00Z'")
+            .long_help("Discard entries before this RFC 3339 date/time, like '2024-05-01T00:00:00Z'. In normal mode, empty buckets are filled from the bucket containing this date/time instead of from the first entry.")
+            .validator(|value| parse_datetime_arg(&value).map(|_| ())))
+        .arg(Arg::with_name("until")
+            .long("until")
+            .takes_value(true)
+            .value_name("DATETIME")
+            .help("Discard entries at or after this RFC 3339 date/time, like '2024-05-02T00:00:00Z'")
+            .long_help("Discard entries at or after this RFC 3339 date/time, like '2024-05-02T00:00:00Z'. In normal mode, empty buckets are filled up to this date/time instead of only up to the last entry.")
+            .validator(|value| parse_datetime_arg(&value).map(|_| ())))
+        .arg(Arg::with_name("fill-from")
+            .long("fill-from")
+            .takes_value(true)
+            .value_name("DATETIME")
+            .conflicts_with_all(&["stream", "sorted-input", "no-fill"])
+            .help("Fill empty buckets from this RFC 3339 date/time, even if the entries start later")
+            .long_help("Fill empty buckets from the bucket containing this RFC 3339 date/time, like '2024-05-01T00:00:00Z', even if the entries start later, so that the output always covers a fixed window. Unlike --since, entries before it are still counted.")
+            .validator(|value| parse_datetime_arg(&value).map(|_| ())))
+        .arg(Arg::with_name("fill-to")
+            .long("fill-to")
+            .takes_value(true)
+            .value_name("DATETIME")
+            .conflicts_with_all(&["stream", "sorted-input", "no-fill"])
+            .help("Fill empty buckets up to this RFC 3339 date/time, even if the entries end earlier")
+            .long_help("Fill empty buckets up to (but not including) this RFC 3339 date/time, like '2024-05-02T00:00:00Z', even if the entries end earlier, so that the output always covers a fixed window. Unlike --until, entries after it are still counted.")
+            .validator(|value| parse_datetime_arg(&value).map(|_| ())))
+        .arg(Arg::with_name("last")
+            .long("last")
+            .takes_value(true)
+            .value_name("DURATION")
+            .help("Only count entries within this long of the latest entry (or of now, see --now), like '2h'")
+            .long_help("Only count entries within this long of the latest entry, like '2h'. The start of the window is rounded back to the start of its bucket. Because the latest entry is only known once all input is read, this can't be used with --stream or --sorted-input unless --now is also given.")
+            .validator(|value| {
+                parse_duration(&value)
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid duration".to_string())
+            }))
+        .arg(Arg::with_name("now")
+            .long("now")
+            .requires("last")
+            .help("Count --last back from the current time instead of from the latest entry"))
+        .arg(Arg::with_name("grep")
+            .long("grep")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("REGEX")
+            .help("Only count lines matching this regex; may be given more than once to allow any of several")
+            .validator(|value| Regex::new(&value).map(|_| ()).map_err(|err| err.to_string())))
+        .arg(Arg::with_name("grep-v")
+            .long("grep-v")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("REGEX")
+            .help("Don't count lines matching this regex; may be given more than once")
+            .validator(|value| Regex::new(&value).map(|_| ()).map_err(|err| err.to_string())))
+        .arg(Arg::with_name("record-start")
+            .long("record-start")
+            .takes_value(true)
+            .value_name("REGEX")
+            .help("Only count lines matching this regex as the start of a record, like '^\\d{4}-', leaving out continuation lines")
+            .long_help("Only lines matching this regex start a record, like '^\\d{4}-' or '^\\[', and are counted. The lines after each, up to the next, continue its record, like the lines of a stack trace, and aren't searched for a date/time of their own, so that a timestamp in a stack trace isn't counted again. They are left out and counted as continuations by --stats, or with --attach-continuations counted at their record's date/time.")
+            .validator(|value| Regex::new(&value).map(|_| ()).map_err(|err| err.to_string())))
+        .arg(Arg::with_name("attach-continuations")
+            .long("attach-continuations")
+            .requires("record-start")
+            .help("Count the continuation lines of --record-start at their record's date/time")
+            .long_help("Count each continuation line of --record-start as a line of its own at its record's date/time, such as to count the total volume of logs including stack traces. Its group, value and key are its own. Continuation lines of records which weren't counted, such as those excluded by --grep or without a date/time, are left out too."))
+        .arg(Arg::with_name("carry-forward")
+            .long("carry-forward")
+            .help("Count lines without a date/time at that of the last line with one")
+            .long_help("Count each line in which no date/time is found at the date/time of the last line with one, such as to count the total volume of logs including stack traces. Lines before the first date/time of an input, and lines whose date/time fails to parse, are still left out. Its group, value and key are its own. Unlike --attach-continuations, every line is searched for a date/time, so a line of a stack trace which has one is counted at its own."))
+        .arg(Arg::with_name("group-by")
+            .long("group-by")
+            .takes_value(true)
+            .value_name("REGEX")
+            .conflicts_with_all(&["stream", "sorted-input"])
+            .help("Count lines separately for each value of this regex's first capture group, like 'status=(\\d+)'")
+            .long_help("Count lines separately for each value of this regex's first capture group, like 'status=(\\d+)', such as per status code or per host. Each row is then 'bucket,group,count', or with --wide there is one column per group. Lines the regex doesn't match aren't counted, and are reported as filtered by --stats.")
+            .validator(|value| {
+                let regex = Regex::new(&value).map_err(|err| err.to_string())?;
+                if regex.captures_len() < 2 {
+                    return Err("The regex needs a capture group".to_string());
+                }
+                Ok(())
+            }))
+        .arg(Arg::with_name("wide")
+            .long("wide")
+            .conflicts_with_all(&["stream", "sorted-input"])
+            .help("Print one column per group (or file) after a header row, instead of a row per group and bucket"))
+        .arg(Arg::with_name("top")
+            .long("top")
+            .takes_value(true)
+            .value_name("COUNT")
+            .help("Only keep the groups (or files) with the highest total counts, folding the rest into '__other__', or with --sort count the busiest buckets")
+            .long_help("Only keep this many groups (or files), those with the highest total counts, and fold the rest into a group named '__other__'. This bounds the width of the output and, since the smallest groups are folded in while counting once there are many, the memory used. A group folded in while counting stays in '__other__', even if it grows later. With --sort count, only this many of the busiest buckets are printed instead.")
+            .validator(|value| {
+                value.parse::<usize>()
+                    .ok()
+                    .filter(|top| *top > 0)
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid positive number".to_string())
+            }))
+        .arg(Arg::with_name("sort")
+            .long("sort")
+            .takes_value(true)
+            .value_name("ORDER")
+            .possible_values(&["time", "count"])
+            .default_value("time")
+            .help("Order buckets by 'time', or by 'count' with the busiest first, see --top")
+            .long_help("Order the buckets by 'time', or by 'count' with the busiest bucket first, such as to find the minute a spike of traffic peaked. Buckets with the same count stay in time order. With --top, only that many of the busiest buckets are printed."))
+        .arg(Arg::with_name("heatmap")
+            .long("heatmap")
+            .takes_value(true)
+            .value_name("COLUMNS")
+            .possible_values(&["hour", "minute"])
+            .conflicts_with_all(&[
+                "stream", "sorted-input", "group-by", "group-key", "per-file", "wide", "anchor", "moving-avg", "ewma", "delta",
+                "delta-only", "derivative", "percent", "alert-over", "alert-under", "by", "align",
+            ])
+            .help("Print a matrix with a row for each day and a column for each hour, or for each hour and minute")
+            .long_help("Print a matrix with a row for each day and a column for each hour of it, or with 'minute' a row for each hour and a column for each minute of it, to make daily patterns stand out. The hours are those of the --timezone if one is given. This sets the granularity, so --granularity can't be used. See also --heatmap-style."))
+        .arg(Arg::with_name("heatmap-style")
+            .long("heatmap-style")
+            .takes_value(true)
+            .value_name("STYLE")
+            .possible_values(&["csv", "grid"])
+            .requires("heatmap")
+            .help("Print the --heatmap as CSV (the default), or as a grid of colored cells for the terminal"))
+        .arg(Arg::with_name("by")
+            .long("by")
+            .takes_value(true)
+            .value_name("CYCLE")
+            .possible_values(&["day-of-week", "hour-of-day"])
+            .conflicts_with_all(&[
+                "stream", "sorted-input", "anchor", "align", "rate", "moving-avg", "ewma", "delta", "delta-only",
+                "derivative", "percent", "alert-over", "alert-under",
+            ])
+            .help("Fold all entries into a row for each day of the week, or each hour of the day, whatever their date")
+            .long_help("Fold all entries into a row for each day of the week, Mon to Sun (or Sun to Sat with --week-start sun), or with 'hour-of-day' a row for each clock hour, 00 to 23, whatever their date, to show which days or hours are busiest. The days and hours are those of the --timezone if one is given. This sets the granularity, so --granularity can't be used."))
+        .arg(Arg::with_name("gaps")
+            .long("gaps")
+            .takes_value(true)
+            .value_name("DURATION")
+            .conflicts_with_all(&[
+                "stream", "sorted-input", "no-fill", "fill-value", "wide", "heatmap", "by", "rate", "moving-avg", "ewma",
+                "delta", "delta-only", "derivative", "percent", "alert-over", "alert-under",
+            ])
+            .help("Print the spans with no entries which last at least this long, like '10m', instead of the buckets")
+            .long_help("Instead of the buckets, print each span of empty buckets which lasts at least this long, like '10m', as 'start,end,seconds', to find outages or logging blackouts. Spans are found to the nearest bucket, so use a --granularity finer than the duration. The spans before the first entry and after the last are only known with --fill-from and --fill-to, or --since and --until. With --group-by or --per-file, the spans of each group are printed, as 'start,end,group,seconds'.")
+            .validator(|value| {
+                parse_duration(&value)
+                    .filter(|duration| *duration > Duration::zero())
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid duration".to_string())
+            }))
+        .arg(Arg::with_name("per-file")
+            .long("per-file")
+            .conflicts_with_all(&["stream", "sorted-input", "group-by"])
+            .help("Count each input file separately, as if grouped by file name")
+            .long_help("Count each input file separately instead of merging them, so traffic can be compared across inputs. Each row is then 'bucket,file,count', or with --wide there is one column per file. A file given more than once is counted separately each time, named like 'app.log (2)'."))
+        .arg(Arg::with_name("skew")
+            .long("skew")
+            .takes_value(true)
+            .value_name("FILE=OFFSET")
+            .multiple(true)
+            .number_of_values(1)
+            .help("Correct the date/times of an input file whose clock was off by this much, like 'hostA.log=+00:02:13'")
+            .long_help("Correct the date/times of an input file whose clock was off, like 'hostA.log=+00:02:13' for a host whose clock was 2 minutes 13 seconds ahead, by subtracting the offset from them before they are bucketed, so that the logs of several hosts line up. Offsets are '+' for ahead or '-' for behind, then hours, minutes and seconds. The file matches each input whose path ends with it, such as 'logs/hostA.log', including --evtx inputs. May be given once for each file, but no input may match more than one.")
+            .validator(|value| parse_skew(&value).map(|_| ()).ok_or_else(|| "Not a valid FILE=OFFSET, like 'hostA.log=+00:02:13'".to_string())))
+        .arg(Arg::with_name("compare")
+            .long("compare")
+            .conflicts_with_all(&[
+                "stream", "sorted-input", "group-by", "group-key", "per-file", "wide", "top", "heatmap", "by", "gaps",
+                "moving-avg", "ewma", "delta", "delta-only", "derivative", "percent", "summary", "alert-over",
+                "alert-under", "alert-only", "spike-sigma",
+            ])
+            .help("Count two input files side by side, with the difference and ratio of each bucket")
+            .long_help("Count exactly two input files separately, such as the logs from before and after a deployment, and print a row for each bucket with the first aggregation of each, the difference of the second from the first, and their ratio. A header row naming the files comes first, once even with several granularities. The ratio is empty where the first is 0. Empty buckets are filled in as usual, with --fill-value or --fill interpolate too. To compare two time windows of the same input file instead, see --compare-offset."))
+        .arg(Arg::with_name("compare-offset")
+            .long("compare-offset")
+            .takes_value(true)
+            .value_name("DURATION")
+            .requires("compare")
+            .conflicts_with("state")
+            .help("Compare a single input file with itself this much later, like '1w' for week over week")
+            .long_help("With --compare, compare a single input file with itself this much later, like '1d' for day over day or '1w' for week over week, rather than two input files. Each bucket is side by side with the bucket this long after it, and the second column is named after the input and the offset, like 'app.log+1w'. Use --since and --until to choose the first window; the second is the same window moved later by the offset.")
+            .validator(|value| {
+                parse_duration(&value)
+                    .filter(|duration| *duration > Duration::zero())
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid positive duration".to_string())
+            }))
+        .arg(Arg::with_name("max-errors")
+            .long("max-errors")
+            .takes_value(true)
+            .value_name("COUNT")
+            .default_value("10")
+            .help("Maximum number of distinct date/time parse errors to print")
+            .long_help("Maximum number of distinct date/time parse errors to print. Each distinct error is printed once along with an example line; repeated and further errors are only counted, and the count is printed at the end.")
+            .validator(|value| {
+                value.parse::<usize>()
+                    .map(|_| ())
+                    .map_err(|_| "Not a valid number of errors".to_string())
+            }))
+        .arg(Arg::with_name("output")
+            .long("output")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .possible_values(&["csv", "table"])
+            .conflicts_with_all(&["stream", "sorted-input", "heatmap", "gaps", "by"])
+            .help("Print the buckets as 'csv', the default, or as an aligned 'table' for reading in a terminal")
+            .long_help("Print the buckets as 'csv', the default, or as a 'table' with a header, aligned columns and right-aligned numbers, drawn with box-drawing characters, for when the output is read by a person rather than another program. A table can only be printed once every row is known, so it can't be used in --stream or --sorted-input mode."))
+        .arg(Arg::with_name("output-epoch")
+            .long("output-epoch")
+            .takes_value(true)
+            .value_name("UNIT")
+            .min_values(0)
+            .require_equals(true)
+            .possible_values(&["s", "ms"])
+            .conflicts_with_all(&["rfc3339", "heatmap", "by"])
+            .help("Label buckets with UNIX timestamps in seconds, or with --output-epoch=ms in milliseconds")
+            .long_help("Label each bucket with the UNIX timestamp it starts at, in seconds, or in milliseconds with --output-epoch=ms, rather than a formatted date/time, for plotting tools and spreadsheets which want epoch values. Buckets are still aligned to the --timezone if one is given."))
+        .arg(Arg::with_name("rfc3339")
+            .long("rfc3339")
+            .conflicts_with_all(&["heatmap", "by"])
+            .help("Label buckets as RFC 3339 date/times, like '2024-05-14T10:00:00Z'")
+            .long_help("Label buckets as RFC 3339 date/times, like '2024-05-14T10:00:00Z', rather than as '2024-05-14 10:00:00 UTC', for parsers which don't understand the latter. With --timezone the label has the offset of the bucket's start, like '2024-05-14T06:00:00-04:00'."))
+        .arg(Arg::with_name("color")
+            .long("color")
+            .takes_value(true)
+            .value_name("WHEN")
+            .possible_values(&["auto", "always", "never"])
+            .default_value("auto")
+            .help("Highlight rows which breach --alert-over, --alert-under or --spike-sigma: 'auto', 'always' or 'never'")
+            .long_help("Highlight the rows whose buckets breach --alert-over or --alert-under, or are spikes by --spike-sigma, in bold red so that they stand out. With 'auto', the default, rows are only highlighted when stdout is a terminal and the NO_COLOR environment variable isn't set. Tables of --output table aren't highlighted."))
+        .arg(Arg::with_name("spike-sigma")
+            .long("spike-sigma")
+            .takes_value(true)
+            .value_name("N")
+            .conflicts_with_all(&["stream", "sorted-input"])
+            .help("With --color, also highlight buckets more than N standard deviations above the mean of their series")
+            .long_help("With --color, also highlight the buckets whose first aggregation is more than N standard deviations above the mean of their series, such as 3 for unusual spikes, without having to pick a threshold for --alert-over. Unlike the alert thresholds, spikes don't change the exit status. The whole series has to be known first, so it can't be used in --stream or --sorted-input mode.")
+            .validator(|value| {
+                value.parse::<f64>()
+                    .ok()
+                    .filter(|sigma| sigma.is_finite())
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid number".to_string())
+            }))
+        .arg(Arg::with_name("output-file")
+            .long("output-file")
+            .takes_value(true)
+            .value_name("PATH")
+            .conflicts_with("follow")
+            .help("Write the output to this file, replacing it only once everything has been written")
+            .long_help("Write the output to this file instead of stdout. It is written to a temporary file in the same directory first, which replaces the file only once tbuck has finished without an error, so a failed run never leaves a partly written file in place of a previous good one."))
+        .arg(Arg::with_name("output-rotate")
+            .long("output-rotate")
+            .takes_value(true)
+            .value_name("GRANULARITY")
+            .requires("output-template")
+            .conflicts_with("output-file")
+            .help("Append each bucket to a file for its window of this length, named by --output-template")
+            .long_help("In stream mode, or with --sorted-input, append each finished bucket to a file for the window of this length it falls in, such as '1h' for a file per hour, named by --output-template. This lets a long running --follow keep its results in files partitioned by time rather than on stdout. Windows follow the --timezone if one is given.")
+            .validator(|value| {
+                Granularity::parse(&value)
+                    .map(|_| ())
+                    .ok_or_else(|| "Not a valid granularity".to_string())
+            }))
+        .arg(Arg::with_name("output-template")
+            .long("output-template")
+            .takes_value(true)
+            .value_name("TEMPLATE")
+            .requires("output-rotate")
+            .help("Name the files of --output-rotate by the start of their window, like 'out-%Y%m%d%H.csv'")
+            .validator(|value| {
+                if StrftimeItems::new(&value).any(|item| matches!(item, Item::Error)) {
+                    return Err("Not a valid strftime template".to_string());
+                }
+                Ok(())
+            }))
+        .arg(Arg::with_name("stats")
+            .long("stats")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .min_values(0)
+            .require_equals(true)
+            .possible_values(&["text", "json"])
+            .help("Print line counts for each input to stderr, as a table or --stats=json")
+            .long_help("After the buckets are printed, write a report to stderr with the number of lines read from each input, how many of them had a date/time that was counted, how many had no match of the format, how many matched but failed to parse, and how many were filtered out or left out as continuation lines of --record-start. This helps tell apart 'no traffic' from 'wrong format string'. The report is a table by default, or a single JSON object with --stats=json."))
+        .arg(Arg::with_name("timing")
+            .long("timing")
+            .help("Print the time spent reading, matching, parsing and aggregating, and the peak bucket count, to stderr")
+            .long_help("After the buckets are printed, write a report to stderr of the time spent reading the inputs, finding the date/times in lines, parsing them, counting lines into buckets and writing the output, along with the most buckets held at once, to tell whether a slow run is waiting on I/O or on the CPU. The phases of counting are timed on every thread and added up, so they are labeled as CPU time, and with several --jobs they can add up to more than the wall clock time of the run. Formats simple enough to be found and parsed at once are timed as matching, with no time for parsing. The peak bucket count is sampled every 1024 lines."))
+        .arg(Arg::with_name("summary")
+            .long("summary")
+            .takes_value(true)
+            .value_name("TARGET")
+            .min_values(0)
+            .require_equals(true)
+            .possible_values(&["output", "stderr"])
+            .conflicts_with_all(&["wide", "heatmap", "gaps", "by"])
+            .help("Append the total, min, max, mean and median of the bucket counts after the buckets, or with --summary=stderr to stderr")
+            .long_help("After the buckets of each series, append rows with the total, min, max, mean and median of the buckets printed, labeled in place of the bucket, or write them to stderr with --summary=stderr to keep the output to buckets only. They are of the first --agg, which is the count of lines by default, and empty buckets which are filled in count as 0. Groups are each summarized separately."))
+        .arg(Arg::with_name("config")
+            .long("config")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Read defaults and presets from this file instead of ~/.config/tbuck/config.toml")
+            .long_help("Read defaults and presets from this file instead of $XDG_CONFIG_HOME/tbuck/config.toml (by default ~/.config/tbuck/config.toml). The file is TOML. Its [defaults] table sets options by their long name, such as granularity = \"5m\" or no-fill = true, and each [presets.NAME] table sets a format and any other options to use with --preset NAME. A flag turned on by the config file, such as stream = true, can be turned off again with --no-NAME, such as --no-stream. Options given on the command line take precedence over presets, which take precedence over defaults, except that options which can be repeated, such as --grep, are combined."))
+        .arg(Arg::with_name("preset")
+            .long("preset")
+            .takes_value(true)
+            .value_name("NAME")
+            .help("Use a format and options defined in the config file; see --config")
+            .long_help("Use the format and options defined by [presets.NAME] in the config file; see --config. If the preset has a format, every positional argument is an input."))
+        .arg(Arg::with_name("format")
+            .required_unless_one(&["journal", "evtx", "listen", "kafka", "start-format"])
+            .takes_value(true)
+            .value_name("DATE_TIME_FORMAT")
+            .help("Date/time parsing format; use --help for list of specifiers")
+            .long_help(
+"Date/time parsing format, except with --evtx or --start-format, which take the first positional argument as an input file instead. Full date and time information must be present. The following specifiers are supported, taken from Rust's chrono crate:
+Specifier   Example     Description
+%Y          2001        The full proleptic Gregorian year, zero-padded to 4 digits.
+%y          01          The proleptic Gregorian year modulo 100, zero-padded to 2 digits. See --century-pivot.
+%m          07          Month number (01--12), zero-padded to 2 digits.
+%b          Jul         Abbreviated month name. Always 3 letters.
+%B          July        Full month name. Also accepts corresponding abbreviation in parsing.
+%d          08          Day number (01--31), zero-padded to 2 digits.
+%a          Sun         Abbreviated weekday name. Always 3 letters.
+%A          Sunday      Full weekday name. Also accepts corresponding abbreviation in parsing.
+%e           8          Same to %d but space-padded. Same to %_d.
+%j          189         Day of the year (001--366), zero-padded to 3 digits.
+%F          2001-07-08  Year-month-day format (ISO 8601). Same to %Y-%m-%d.
+%D          07/08/01    Month-day-year format. Same to %m/%d/%y.
+%H          00          Hour number (00--23), zero-padded to 2 digits.
+%k           0          Same to %H but space-padded. Same to %_H.
+%I          12          Hour number in 12-hour clocks (01--12), zero-padded to 2 digits.
+%M          34          Minute number (00--59), zero-padded to 2 digits.
+%S          60          Second number (00--60), zero-padded to 2 digits.
+%T          00:34:60    Hour-minute-second format. Same to %H:%M:%S.
+%R          00:34       Hour-minute format. Same to %H:%M.
+%r          12:34:60 AM Hour-minute-second format in 12-hour clocks. Same to %I:%M:%S %p.
+%c          Sun Jul  8 00:34:60 2001
+                    ctime date & time format. Same to %a %b %e %T %Y.
+%P          am          am or pm in 12-hour clocks.
+%p          AM          AM or PM in 12-hour clocks.
+%s          994518299   UNIX timestamp, the number of seconds since 1970-01-01 00:00 UTC.
+%+          2001-07-08T00:34:60.026490+09:30
+                    ISO 8601 / RFC 3339 date & time format, converted to UTC from its offset.
+%{rfc2822}  Tue, 1 Jul 2003 10:52:37 +0200
+                    RFC 2822 date & time format of email and HTTP Date headers, converted to UTC
+                    from its offset. Specific to tbuck, chrono has no specifier for it.")
+            .validator(validate_format))
+        .arg(Arg::with_name("inputs")
+            .takes_value(true)
+            .value_name("INPUT_FILE")
+            .multiple(true)
+            .help("Input files; or standard input if none provided")
+            .long_help("Input files; or standard input if none provided. Glob patterns such as 'logs/app-*.log' are expanded by tbuck itself, in sorted order, so they work the same regardless of the shell. Directories are expanded to the files they contain, see --recursive and --name."))
+}
+
+const EXIT_STATUS_HELP: &str = "EXIT STATUS:\n    0    Success\n    1    Invalid arguments\n    2    An input couldn't be read\n    3    An entry was out of order in --stream mode without --tolerant\n    4    A bucket breached --alert-over or --alert-under\n    5    A gap between buckets was too long to fill in under --max-fill";
+
+// The --max-fill option, of counting as well as the subcommands which fill in empty buckets.
+fn max_fill_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("max-fill")
+        .long("max-fill")
+        .takes_value(true)
+        .value_name("COUNT")
+        .default_value("1000000")
+        .help("Fail rather than fill in more than this many empty buckets in a row")
+        .long_help("Fail with exit status 5 rather than fill in more than this many empty buckets in a row, naming the buckets on either side of the gap. A single date/time mis-parsed into 1970 or 2099 would otherwise print millions of rows of zeros. The default of 1,000,000 is over 11 days of second buckets, or nearly two years of minute buckets. Use --no-fill to print only the buckets with entries instead.")
+        .validator(|value| {
+            value.parse::<usize>()
+                .map(|_| ())
+                .map_err(|_| "Not a valid number of buckets".to_string())
+        })
+}
+
+// The --columns option of the subcommands which read earlier outputs.
+fn columns_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("columns")
+        .long("columns")
+        .takes_value(true)
+        .value_name("NAMES")
+        .help("Names of the value columns each row ends with, like 'count,sum' for outputs of --agg count,sum")
+        .long_help("Names of the value columns each row ends with, like 'count,sum' for outputs of --agg count,sum. Rows otherwise end with a single value, and any field before it is their group, so that a row of two values would be read as a group and a value. Each column is combined on its own.")
+}
+
+// The names of the value columns of earlier outputs, with --columns, or of the single one there
+// is otherwise.
+fn columns(matches: &ArgMatches) -> Vec<String> {
+    matches.value_of("columns").map_or_else(
+        || vec!["value".to_string()],
+        |names| names.split(',').map(str::to_string).collect(),
+    )
+}
+
+// The value of --max-fill.
+fn max_fill(matches: &ArgMatches) -> usize {
+    matches
+        .value_of("max-fill")
+        .expect("max-fill has default value")
+        .parse()
+        .expect("validator should have rejected invalid values")
+}
+
+// The subcommands of tbuck other than count, which read or write something other than logs to count.
+#[allow(clippy::too_many_lines)]
+fn tool_subcommands<'a, 'b>() -> Vec<App<'a, 'b>> {
+    vec![
+        SubCommand::with_name("merge")
+            .about("Sum the buckets of earlier outputs of tbuck, such as from several hosts, and print them again")
+            .long_about("Read earlier outputs of tbuck, rows of 'bucket,count' or 'bucket,group,count', and sum the counts (or other values) of each bucket and group across them, so that series counted separately, such as on each host, can be combined. Rows without a value, the empty buckets of aggregations like avg, are left out. The buckets are printed in order, with empty buckets filled in. Buckets must be labeled in UTC or RFC 3339, as they are without --timezone.")
+            .arg(Arg::with_name("granularity")
+                .short("g")
+                .long("granularity")
+                .takes_value(true)
+                .value_name("GRANULARITY")
+                .default_value("1m")
+                .help("Granularity of the buckets being merged, which empty buckets are filled in at")
+                .validator(|value| {
+                    Granularity::parse(&value)
+                        .map(|_| ())
+                        .ok_or_else(|| "Not a valid granularity".to_string())
+                }))
+            .arg(Arg::with_name("no-fill")
+                .short("n")
+                .long("no-fill")
+                .help("Disable counts of 0 being emitted for buckets with no entries"))
+            .arg(max_fill_arg())
+            .arg(columns_arg())
+            .arg(Arg::with_name("inputs")
+                .takes_value(true)
+                .value_name("INPUT_FILE")
+                .multiple(true)
+                .help("Earlier outputs of tbuck; or standard input if none provided")),
+        SubCommand::with_name("downsample")
+            .about("Combine the buckets of an earlier output of tbuck into coarser buckets, without the original logs")
+            .long_about("Read an earlier output of tbuck, rows of 'bucket,count' or 'bucket,group,count', and combine the buckets of the --from granularity which fall into each bucket of the coarser --to granularity, such as to turn per-second counts into per-minute counts when the logs they were counted from are gone. Rows without a value, the empty buckets of aggregations like avg, are left out. Each --to bucket must start on a --from bucket. The buckets are printed in order, with empty buckets filled in. Buckets must be labeled in UTC or RFC 3339, as they are without --timezone.")
+            .arg(Arg::with_name("from")
+                .long("from")
+                .takes_value(true)
+                .value_name("GRANULARITY")
+                .required(true)
+                .help("Granularity of the buckets being read")
+                .validator(|value| {
+                    Granularity::parse(&value)
+                        .map(|_| ())
+                        .ok_or_else(|| "Not a valid granularity".to_string())
+                }))
+            .arg(Arg::with_name("to")
+                .long("to")
+                .takes_value(true)
+                .value_name("GRANULARITY")
+                .required(true)
+                .help("Coarser granularity to combine them into")
+                .validator(|value| {
+                    Granularity::parse(&value)
+                        .map(|_| ())
+                        .ok_or_else(|| "Not a valid granularity".to_string())
+                }))
+            .arg(Arg::with_name("agg")
+                .long("agg")
+                .takes_value(true)
+                .value_name("AGGREGATION")
+                .possible_values(&["sum", "avg", "min", "max"])
+                .default_value("sum")
+                .help("How the values of the buckets being combined are combined")
+                .long_help("How the values of the buckets being combined are combined: 'sum' for counts and sums, or the 'avg', 'min' or 'max' of them, such as for outputs of the 'max' of each bucket. Averages of averages are of the buckets, not weighted by how many lines each had."))
+            .arg(Arg::with_name("no-fill")
+                .short("n")
+                .long("no-fill")
+                .help("Disable counts of 0 being emitted for buckets with no entries"))
+            .arg(max_fill_arg())
+            .arg(columns_arg())
+            .arg(Arg::with_name("inputs")
+                .takes_value(true)
+                .value_name("INPUT_FILE")
+                .multiple(true)
+                .help("Earlier outputs of tbuck; or standard input if none provided")),
+        SubCommand::with_name("join")
+            .about("Print the series of earlier outputs of tbuck side by side, with a column for each")
+            .long_about("Read earlier outputs of tbuck, rows of 'bucket,count' or 'bucket,group,count', and print one wide CSV with a row for each bucket and a column for each series, such as for charting in a spreadsheet. Each input is a series, or each group of it if it has groups, named after the input, the group, or both when several inputs have groups. Series without a value for a bucket have an empty cell in it, so that a missing count isn't mistaken for 0. With --columns, each series has a column for each, named after the series and the column, like 'app.log:sum'. The buckets are printed in order, with empty buckets filled in. Buckets must be labeled in UTC or RFC 3339, as they are without --timezone.")
+            .arg(Arg::with_name("granularity")
+                .short("g")
+                .long("granularity")
+                .takes_value(true)
+                .value_name("GRANULARITY")
+                .default_value("1m")
+                .help("Granularity of the buckets being joined, which empty buckets are filled in at")
+                .validator(|value| {
+                    Granularity::parse(&value)
+                        .map(|_| ())
+                        .ok_or_else(|| "Not a valid granularity".to_string())
+                }))
+            .arg(Arg::with_name("no-fill")
+                .short("n")
+                .long("no-fill")
+                .help("Disable rows being emitted for buckets which no series has"))
+            .arg(max_fill_arg())
+            .arg(columns_arg())
+            .arg(Arg::with_name("inputs")
+                .takes_value(true)
+                .value_name("INPUT_FILE")
+                .multiple(true)
+                .help("Earlier outputs of tbuck; or standard input if none provided")),
+        SubCommand::with_name("gen")
+            .about("Print synthetic timestamped log lines, such as for benchmarking tbuck or testing a pipeline")
+            .long_about("Print synthetic log lines to stdout, each starting with a date/time in the --format, followed by a level, a status, a user and a latency that vary from line to line, so that options like --group-by and --value have something to work with. The lines are spread evenly over the --duration at the --rate. They are the same on every run with the same options, apart from the default --start.")
+            .arg(Arg::with_name("rate")
+                .long("rate")
+                .takes_value(true)
+                .value_name("RATE")
+                .default_value("10/s")
+                .help("Lines per second, minute or hour, like '10k/s' or '30/m', with an optional k or M suffix")
+                .validator(|value| {
+                    parse_rate(&value)
+                        .map(|_| ())
+                        .ok_or_else(|| "Not a valid rate".to_string())
+                }))
+            .arg(Arg::with_name("duration")
+                .long("duration")
+                .takes_value(true)
+                .value_name("DURATION")
+                .default_value("1h")
+                .help("How much time the lines span, like '1h'")
+                .validator(|value| {
+                    parse_duration(&value)
+                        .map(|_| ())
+                        .ok_or_else(|| "Not a valid duration".to_string())
+                }))
+            .arg(Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .default_value("%Y-%m-%d %H:%M:%S")
+                .help("strftime format of the date/time at the start of each line")
+                .validator(|value| {
+                    if StrftimeItems::new(&value).any(|item| matches!(item, Item::Error)) {
+                        return Err("Not a valid strftime format".to_string());
+                    }
+                    Ok(())
+                }))
+            .arg(Arg::with_name("start")
+                .long("start")
+                .takes_value(true)
+                .value_name("DATETIME")
+                .help("Date/time of the first line, in RFC 3339 like '2024-01-01T00:00:00Z'; the --duration before now by default, or now with --realtime")
+                .validator(|value| parse_datetime_arg(&value).map(|_| ())))
+            .arg(Arg::with_name("realtime")
+                .long("realtime")
+                .help("Print each line once the clock reaches its date/time, rather than as fast as possible")),
+        SubCommand::with_name("plot")
+            .about("Draw the buckets of an earlier output of tbuck as a bar chart in the terminal")
+            .long_about("Read an earlier output of tbuck, rows of 'bucket,count' or 'bucket,group,count', and draw each row's count (or other value) as a horizontal bar, scaled so that the largest fills the --width, for a quick look at the shape of a series without leaving the terminal.")
+            .arg(Arg::with_name("width")
+                .short("w")
+                .long("width")
+                .takes_value(true)
+                .value_name("COLUMNS")
+                .default_value("60")
+                .help("Width of the longest bar, in columns")
+                .validator(|value| {
+                    value.parse::<usize>()
+                        .ok()
+                        .filter(|width| *width > 0)
+                        .map(|_| ())
+                        .ok_or_else(|| "Not a positive integer".to_string())
+                }))
+            .arg(Arg::with_name("inputs")
+                .takes_value(true)
+                .value_name("INPUT_FILE")
+                .multiple(true)
+                .help("Earlier outputs of tbuck; or standard input if none provided")),
+        SubCommand::with_name("explain")
+            .about("Show how a date/time format matches the first lines of an input, to debug a format which counts nothing")
+            .long_about("Print the regex that a date/time format is searched for with, then try it on the first lines of an input, underlining what matched in each line and showing what it parsed as, or why it failed to parse. This is the quickest way to find out why a format yields no buckets. Only the first match of each line is shown, as tbuck counts by default.")
+            .arg(Arg::with_name("format")
+                .short("f")
+                .long("format")
+                .takes_value(true)
+                .value_name("DATE_TIME_FORMAT")
+                .required(true)
+                .help("The date/time format to explain, as given to tbuck")
+                .validator(validate_format))
+            .arg(Arg::with_name("assume-year")
+                .long("assume-year")
+                .takes_value(true)
+                .value_name("YEAR")
+                .help("Year of timestamps whose date/time format has no year in it, as given to tbuck")
+                .validator(|value| {
+                    value.parse::<i32>()
+                        .map(|_| ())
+                        .map_err(|_| "Not a valid year".to_string())
+                }))
+            .arg(Arg::with_name("lines")
+                .short("n")
+                .long("lines")
+                .takes_value(true)
+                .value_name("COUNT")
+                .default_value("10")
+                .help("Number of lines of the input to try the format on")
+                .validator(|value| {
+                    value.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| "Not a valid number".to_string())
+                }))
+            .arg(Arg::with_name("input")
+                .takes_value(true)
+                .value_name("INPUT_FILE")
+                .help("Input file; or standard input if none provided")),
+        SubCommand::with_name("wizard")
+            .about("Find the date/time format of an input's lines by picking from the formats which match them")
+            .long_about("Try common date/time formats, as well as formats derived from the timestamp-like text of each line, such as '%d/%b/%Y:%H:%M:%S' from '01/Jan/2024:10:01:00', on the first lines of an input, and list those which match, with an example of what each matched and how many lines it parsed, best first. Picking one prints its format to stdout, ready to pass to tbuck, while the list and the prompt go to stderr, so that the format can be captured like fmt=$(tbuck wizard app.log).")
+            .arg(Arg::with_name("lines")
+                .short("n")
+                .long("lines")
+                .takes_value(true)
+                .value_name("COUNT")
+                .default_value("100")
+                .help("Number of lines of the input to try the formats on")
+                .validator(|value| {
+                    value.parse::<usize>()
+                        .map(|_| ())
+                        .map_err(|_| "Not a valid number".to_string())
+                }))
+            .arg(Arg::with_name("input")
+                .takes_value(true)
+                .value_name("INPUT_FILE")
+                .required(true)
+                .help("Input file, which can't be standard input since that is where the answer is read from")),
+    ]
+}
+
+// Defines CLI args. Will terminate program with an error message if args are invalid.
+fn parse_args() -> Command {
+    let (args, default_granularity) = apply_config_file(std::env::args_os().collect())
+        .unwrap_or_else(|err| clap::Error::with_description(&err, clap::ErrorKind::InvalidValue).exit());
+    parse_command(args, default_granularity.as_deref().unwrap_or("1m"))
+}
+
+// Parses the command line once the config file has been applied to it.
+#[allow(clippy::too_many_lines)]
+fn parse_command(args: Vec<OsString>, default_granularity: &str) -> Command {
+    let app = App::new("tbuck")
+        .author(clap::crate_authors!())
+        .version(clap::crate_version!())
+        .about(clap::crate_description!())
+        .after_help(EXIT_STATUS_HELP);
+    let app_matches = count_args(app, default_granularity)
+        .subcommand(
+            count_args(SubCommand::with_name("count"), default_granularity)
+                .about("Count the lines of the inputs into buckets, which is what tbuck does without a subcommand")
+                .after_help(EXIT_STATUS_HELP),
+        )
+        .subcommands(tool_subcommands())
+        .global_setting(AppSettings::AllArgsOverrideSelf)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .get_matches_from(args);
+
+    if let Some(merge_matches) = app_matches.subcommand_matches("merge") {
+        return Command::Merge(MergeArgs {
+            inputs: collect_inputs(merge_matches)
+                .unwrap_or_else(|err| clap::Error::with_description(&err, clap::ErrorKind::InvalidValue).exit()),
+            bucketing: Bucketing::new(
+                Granularity::parse(
+                    merge_matches
+                        .value_of("granularity")
+                        .expect("granularity has default value"),
+                )
+                .expect("validator should have rejected invalid values"),
+            ),
+            fill_empty_buckets: !merge_matches.is_present("no-fill"),
+            max_fill: max_fill(merge_matches),
+            columns: columns(merge_matches),
+        });
+    }
+
+    if let Some(downsample_matches) = app_matches.subcommand_matches("downsample") {
+        let bucketing = |name| {
+            Bucketing::new(
+                Granularity::parse(downsample_matches.value_of(name).expect("granularity is required"))
+                    .expect("validator should have rejected invalid values"),
+            )
+        };
+        return Command::Downsample(DownsampleArgs {
+            inputs: collect_inputs(downsample_matches)
+                .unwrap_or_else(|err| clap::Error::with_description(&err, clap::ErrorKind::InvalidValue).exit()),
+            from: bucketing("from"),
+            to: bucketing("to"),
+            aggregation: Aggregation::parse(downsample_matches.value_of("agg").expect("agg has default value"))
+                .expect("validator should have rejected invalid values"),
+            fill_empty_buckets: !downsample_matches.is_present("no-fill"),
+            max_fill: max_fill(downsample_matches),
+            columns: columns(downsample_matches),
+        });
+    }
+
+    if let Some(join_matches) = app_matches.subcommand_matches("join") {
+        return Command::Join(JoinArgs {
+            inputs: collect_inputs(join_matches)
+                .unwrap_or_else(|err| clap::Error::with_description(&err, clap::ErrorKind::InvalidValue).exit()),
+            bucketing: Bucketing::new(
+                Granularity::parse(
+                    join_matches
+                        .value_of("granularity")
+                        .expect("granularity has default value"),
+                )
+                .expect("validator should have rejected invalid values"),
+            ),
+            fill_empty_buckets: !join_matches.is_present("no-fill"),
+            max_fill: max_fill(join_matches),
+            columns: columns(join_matches),
+        });
+    }
+
+    if let Some(gen_matches) = app_matches.subcommand_matches("gen") {
+        let duration = parse_duration(gen_matches.value_of("duration").expect("duration has default value"))
+            .expect("validator should have rejected invalid values");
+        let rate = parse_rate(gen_matches.value_of("rate").expect("rate has default value"))
+            .expect("validator should have rejected invalid values");
+        let realtime = gen_matches.is_present("realtime");
+        let start = match gen_matches.value_of("start") {
+            Some(start) => parse_datetime_arg(start).expect("validator should have rejected invalid values"),
+            None if realtime => Utc::now(),
+            None => Utc::now() - duration,
+        };
+        #[allow(
+            clippy::cast_precision_loss,
+            clippy::cast_possible_truncation,
+            clippy::cast_sign_loss
+        )]
+        let lines = (rate * duration.num_milliseconds() as f64 / 1000.0).round() as u64;
+        return Command::Gen(GenArgs {
+            start,
+            duration,
+            lines,
+            format: gen_matches
+                .value_of("format")
+                .expect("format has default value")
+                .to_string(),
+            realtime,
+        });
+    }
+
+    if let Some(plot_matches) = app_matches.subcommand_matches("plot") {
+        return Command::Plot(PlotArgs {
+            inputs: collect_inputs(plot_matches)
+                .unwrap_or_else(|err| clap::Error::with_description(&err, clap::ErrorKind::InvalidValue).exit()),
+            width: plot_matches
+                .value_of("width")
+                .expect("width has default value")
+                .parse()
+                .expect("validator should have rejected invalid values"),
+        });
+    }
+
+    if let Some(explain_matches) = app_matches.subcommand_matches("explain") {
+        return Command::Explain(ExplainArgs {
+            format: explain_matches
+                .value_of("format")
+                .expect("format is required")
+                .to_string(),
+            assume_year: explain_matches
+                .value_of("assume-year")
+                .map(|year| year.parse().expect("validator should have rejected invalid values")),
+            input: explain_matches
+                .value_of_os("input")
+                .map_or(Input::Stdin, |path| Input::File(PathBuf::from(path))),
+            lines: explain_matches
+                .value_of("lines")
+                .expect("lines has default value")
+                .parse()
+                .expect("validator should have rejected invalid values"),
+            color: std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal(),
+        });
+    }
+
+    if let Some(wizard_matches) = app_matches.subcommand_matches("wizard") {
+        return Command::Wizard(WizardArgs {
+            input: Input::File(PathBuf::from(
+                wizard_matches.value_of_os("input").expect("input is required"),
+            )),
+            lines: wizard_matches
+                .value_of("lines")
+                .expect("lines has default value")
+                .parse()
+                .expect("validator should have rejected invalid values"),
+        });
+    }
+
+    // tbuck counts without a subcommand, the same as with count.
+    let app_matches = app_matches.subcommand_matches("count").unwrap_or(&app_matches);
+
+    // Entries of the journal are read as journalctl's short-unix output, and records of event logs
+    // and received messages are rendered the same way, starting with a timestamp in seconds.
+    let format = if ["journal", "evtx", "listen", "kafka"]
+        .iter()
+        .any(|name| app_matches.is_present(name))
+    {
+        EPOCH_FORMAT
+    } else {
+        app_matches.value_of("format").expect("format is a required argument")
+    };
+    let format = app_matches.value_of("start-format").unwrap_or(format);
+    let parse_format = |format| {
+        let datetime_format = DateTimeFormat::new(format)
+            .expect("validator should have rejected unsupported items")
+            .with_century_pivot(
+                app_matches
+                    .value_of("century-pivot")
+                    .expect("century-pivot has default value")
+                    .parse::<u32>()
+                    .expect("validator should have rejected invalid values"),
+            );
+        let datetime_format = match app_matches.value_of("assume-year") {
+            Some(year) => {
+                datetime_format.with_assumed_year(year.parse().expect("validator should have rejected invalid values"))
+            }
+            None => datetime_format,
+        };
+        // Checked here rather than by the validator, as it depends on --assume-year as well.
+        if !datetime_format.has_enough_info() {
+            clap::Error::with_description(
+                "Not enough information in the date/time format to construct a full date/time, formats without a year need --assume-year",
+                clap::ErrorKind::InvalidValue,
+            )
+            .exit();
+        }
+        datetime_format
+    };
+    let datetime_format = parse_format(format);
+    let end_format = app_matches.value_of("end-format").map(parse_format);
+    let match_index = app_matches
+        .value_of("match-index")
+        .expect("match-index has default value");
+    let match_index = MatchIndex::parse(match_index).expect("validator should have rejected invalid values");
+    let column = if ["anchored", "journal", "evtx", "listen", "kafka"]
+        .iter()
+        .any(|name| app_matches.is_present(name))
+    {
+        Some(0)
+    } else {
+        app_matches.value_of("at-column").map(|value| {
+            value
+                .parse::<usize>()
+                .expect("validator should have rejected invalid values")
+        })
+    };
+    let time_field = if let Some(field) = app_matches.value_of("field") {
+        Some(TimeField::Delimited {
+            index: field
+                .parse::<usize>()
+                .expect("validator should have rejected invalid values")
+                - 1,
+            delimiter: match app_matches.value_of("delimiter") {
+                None | Some("\\t") => "\t".to_string(),
+                Some(delimiter) => delimiter.to_string(),
+            },
+        })
+    } else if let Some(key) = app_matches.value_of("time-key") {
+        Some(TimeField::Logfmt { key: key.to_string() })
+    } else if let Some(column) = app_matches.value_of("time-column") {
+        Some(TimeField::Csv {
+            column: match column.parse::<usize>() {
+                Ok(number) => CsvColumn::Index(number - 1),
+                Err(_) => CsvColumn::Name(column.to_string()),
+            },
+        })
+    } else {
+        app_matches.value_of("json-field").map(|path| TimeField::Json {
+            path: path.split('.').map(str::to_string).collect(),
+        })
+    };
+    // --match-index has a default value, so it can't conflict with the anchors through clap.
+    if column.is_some() && app_matches.occurrences_of("match-index") > 0 {
+        clap::Error::with_description(
+            "--match-index can't be combined with --anchored or --at-column, which allow a single match",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    if app_matches.is_present("all-matches") && app_matches.occurrences_of("match-index") > 0 {
+        clap::Error::with_description(
+            "--match-index can't be combined with --all-matches, which counts every match",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    let offset = app_matches.value_of("align").map_or_else(Duration::zero, |value| {
+        parse_alignment(value).expect("validator should have rejected invalid values")
+    });
+    let sessions = app_matches.value_of("session-key").map(|key| Sessions {
+        key: key.to_string(),
+        gap: parse_duration(
+            app_matches
+                .value_of("session-gap")
+                .expect("session-gap has default value"),
+        )
+        .expect("validator should have rejected invalid values"),
+        active: app_matches.value_of("sessions") == Some("active"),
+    });
+    let week_start = match app_matches
+        .value_of("week-start")
+        .expect("week-start has default value")
+    {
+        "sun" => Weekday::Sun,
+        _ => Weekday::Mon,
+    };
+    let leap_seconds = match app_matches
+        .value_of("leap-seconds")
+        .expect("leap-seconds has default value")
+    {
+        "clamp" => LeapSeconds::Clamp,
+        "skip" => LeapSeconds::Skip,
+        _ => LeapSeconds::Keep,
+    };
+    let timezone: Option<Tz> = app_matches
+        .value_of("timezone")
+        .map(|timezone| timezone.parse().expect("validator should have rejected invalid values"));
+    let anchor = app_matches
+        .value_of("anchor")
+        .map(|anchor| parse_datetime_arg(anchor).expect("validator should have rejected invalid values"));
+    let mut bucketings: Vec<Bucketing> = app_matches
+        .values_of("granularity")
+        .expect("granularity has default value")
+        .map(|granularity| {
+            let bucketing =
+                Bucketing::new(Granularity::parse(granularity).expect("validator should have rejected invalid values"))
+                    .with_offset(offset)
+                    .with_week_start(week_start);
+            let bucketing = match timezone {
+                Some(timezone) => bucketing.with_timezone(timezone),
+                None => bucketing,
+            };
+            match anchor {
+                Some(anchor) => bucketing.with_anchor(anchor),
+                None => bucketing,
+            }
+        })
+        .collect();
+    let inputs = if app_matches.is_present("journal") {
+        vec![Input::Journal(app_matches.value_of("journal").map(str::to_string))]
+    } else if let Some(url) = app_matches.value_of("listen") {
+        vec![Input::Listen(
+            Listener::parse(url).expect("validator should have rejected invalid values"),
+        )]
+    } else if let Some(brokers) = app_matches.value_of("kafka") {
+        vec![Input::Kafka(KafkaSource {
+            brokers: brokers.to_string(),
+            topic: app_matches.value_of("topic").expect("kafka requires topic").to_string(),
+            // Without --follow, nothing would be read from the end.
+            from_beginning: app_matches.is_present("from-beginning") || !app_matches.is_present("follow"),
+        })]
+    } else {
+        collect_inputs(app_matches)
+            .unwrap_or_else(|err| clap::Error::with_description(&err, clap::ErrorKind::InvalidValue).exit())
+    };
+    let inputs = if app_matches.is_present("evtx") {
+        inputs
+            .into_iter()
+            .map(|input| match input {
+                Input::File(path) => Input::Evtx(path),
+                _ => clap::Error::with_description(
+                    "--evtx needs input files, since event logs can't be read from stdin",
+                    clap::ErrorKind::MissingRequiredArgument,
+                )
+                .exit(),
+            })
+            .collect()
+    } else {
+        inputs
+    };
+    if app_matches.value_of("order") == Some("auto")
+        && !inputs
+            .iter()
+            .all(|input| matches!(input, Input::File(_) | Input::Evtx(_)))
+    {
+        clap::Error::with_description(
+            "--order auto needs input files, since stdin and other streams can't be read twice; give --order asc or desc",
+            clap::ErrorKind::InvalidValue,
+        )
+        .exit();
+    }
+    if app_matches.is_present("state") && inputs.iter().any(|input| matches!(input, Input::Stdin)) {
+        clap::Error::with_description(
+            "--state needs input files, since stdin can't be resumed",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit();
+    }
+    let jobs = app_matches
+        .value_of("jobs")
+        .expect("jobs has default value")
+        .parse::<usize>()
+        .expect("validator should have rejected invalid values");
+    let fill_empty_buckets = !app_matches.is_present("no-fill");
+    let tolerant = app_matches.is_present("tolerant");
+    let follow = app_matches.is_present("follow");
+    let mmap = app_matches.is_present("mmap");
+    let order = if app_matches.is_present("descending") || app_matches.value_of("order") == Some("desc") {
+        DateTimeOrder::Descending
+    } else {
+        DateTimeOrder::Ascending
+    };
+    let mode = if app_matches.is_present("stream") {
+        Mode::Stream
+    } else if app_matches.is_present("sorted-input") {
+        Mode::Sorted
+    } else {
+        Mode::Normal
+    };
+    let max_errors = app_matches
+        .value_of("max-errors")
+        .expect("max-errors has default value")
+        .parse::<usize>()
+        .expect("validator should have rejected invalid values");
+    let stats = if app_matches.is_present("stats") {
+        match app_matches.value_of("stats") {
+            Some("json") => Some(StatsFormat::Json),
+            _ => Some(StatsFormat::Text),
+        }
+    } else {
+        None
+    };
+    let label_format = if app_matches.is_present("output-epoch") {
+        match app_matches.value_of("output-epoch") {
+            Some("ms") => LabelFormat::EpochMilliseconds,
+            _ => LabelFormat::EpochSeconds,
+        }
+    } else if app_matches.is_present("rfc3339") {
+        LabelFormat::Rfc3339
+    } else {
+        LabelFormat::DateTime
+    };
+    let summary = if app_matches.is_present("summary") {
+        match app_matches.value_of("summary") {
+            Some("stderr") => Some(SummaryTarget::Stderr),
+            _ => Some(SummaryTarget::Output),
+        }
+    } else {
+        None
+    };
+    // Tables are aligned by the width of their cells, which escape codes would throw off.
+    let color = app_matches.value_of("output") != Some("table")
+        && match app_matches.value_of("color").expect("color has default value") {
+            "always" => true,
+            "never" => false,
+            _ => {
+                !app_matches.is_present("output-file")
+                    && !app_matches.is_present("output-rotate")
+                    && std::env::var_os("NO_COLOR").is_none()
+                    && std::io::stdout().is_terminal()
+            }
+        };
+    let reorder_window = parse_duration(
+        app_matches
+            .value_of("reorder-window")
+            .expect("reorder-window has default value"),
+    )
+    .expect("validator should have rejected invalid values");
+    let since = app_matches
+        .value_of("since")
+        .map(|value| parse_datetime_arg(value).expect("validator should have rejected invalid values"));
+    let until = app_matches
+        .value_of("until")
+        .map(|value| parse_datetime_arg(value).expect("validator should have rejected invalid values"));
+    let patterns = |name| {
+        app_matches
+            .values_of(name)
+            .map(|values| values.map(str::to_string).collect())
+            .unwrap_or_default()
+    };
+    let grep = patterns("grep");
+    let grep_v = patterns("grep-v");
+    if app_matches.is_present("alert-only")
+        && !app_matches.is_present("alert-over")
+        && !app_matches.is_present("alert-under")
+    {
+        clap::Error::with_description(
+            "--alert-only needs --alert-over or --alert-under",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit();
+    }
+    let group_by = match (app_matches.value_of("group-by"), app_matches.value_of("group-key")) {
+        (Some(regex), _) => Some(Selection::Regex(regex.to_string())),
+        (None, key) => key.map(|key| Selection::LogfmtKey(key.to_string())),
+    };
+    // A --weight is the --value which is summed.
+    let weight = app_matches.value_of("weight");
+    let value = match (
+        app_matches.value_of("value").or(weight),
+        app_matches.value_of("value-key"),
+    ) {
+        (Some(regex), _) => Some(Selection::Regex(regex.to_string())),
+        (None, key) => key.map(|key| Selection::LogfmtKey(key.to_string())),
+    };
+    let distinct = app_matches.value_of("distinct").map(str::to_string);
+    let aggregations: Vec<Aggregation> = if distinct.is_some() && app_matches.occurrences_of("agg") == 0 {
+        // Counting distinct keys is the point of --distinct, unless told otherwise.
+        vec![Aggregation::Distinct]
+    } else if weight.is_some() {
+        vec![Aggregation::Sum]
+    } else {
+        app_matches
+            .value_of("agg")
+            .expect("agg has default value")
+            .split(',')
+            .map(|aggregation| Aggregation::parse(aggregation).expect("validator should have rejected invalid values"))
+            .collect()
+    };
+    if aggregations.contains(&Aggregation::Distinct) && distinct.is_none() {
+        clap::Error::with_description(
+            "--agg distinct needs --distinct to select the keys to count",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit();
+    }
+    let expression = app_matches
+        .value_of("expr")
+        .map(|expression| Expression::parse(expression).expect("validator should have rejected invalid values"));
+    if let (Some(expression), Some(Selection::Regex(regex))) = (&expression, &value) {
+        let regex = Regex::new(regex).expect("validator should have rejected invalid values");
+        if let Some(field) = expression
+            .fields()
+            .iter()
+            .find(|field| !regex.capture_names().any(|name| name == Some(field.as_str())))
+        {
+            clap::Error::with_description(
+                &format!("--expr uses the field '{field}', which the --value regex has no capture group (?P<{field}>...) for"),
+                clap::ErrorKind::InvalidValue,
+            )
+            .exit();
+        }
+    }
+    let expression_needs_values = expression.as_ref().is_some_and(|expression| {
+        !expression.fields().is_empty()
+            || expression
+                .aggregations()
+                .iter()
+                .any(|aggregation| aggregation.needs_values())
+    });
+    if expression_needs_values && value.is_none() {
+        clap::Error::with_description(
+            "--expr needs --value or --value-key to select the numbers it aggregates, except for 'count'",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit();
+    }
+    if aggregations.iter().any(|aggregation| aggregation.needs_values()) && value.is_none() {
+        clap::Error::with_description(
+            "--agg needs --value or --value-key to select the numbers to aggregate, except for 'count'",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit();
+    }
+    let wide = app_matches.is_present("wide");
+    let skews: Vec<(PathBuf, Duration)> = app_matches
+        .values_of("skew")
+        .into_iter()
+        .flatten()
+        .map(|value| parse_skew(value).expect("validator should have rejected invalid values"))
+        .collect();
+    let input_paths = || {
+        inputs.iter().filter_map(|input| match input {
+            Input::File(path) | Input::Evtx(path) => Some(path),
+            _ => None,
+        })
+    };
+    if let Some((path, _)) = skews
+        .iter()
+        .find(|(path, _)| !input_paths().any(|input| input.ends_with(path)))
+    {
+        clap::Error::with_description(
+            &format!("--skew {} doesn't match any input file", path.display()),
+            clap::ErrorKind::InvalidValue,
+        )
+        .exit();
+    }
+    // Only one offset can apply to each input.
+    for input in input_paths() {
+        let matching: Vec<String> = skews
+            .iter()
+            .filter(|(path, _)| input.ends_with(path))
+            .map(|(path, _)| path.display().to_string())
+            .collect();
+        if matching.len() > 1 {
+            clap::Error::with_description(
+                &format!("--skew {} all match the input {}", matching.join(", "), input.display()),
+                clap::ErrorKind::ArgumentConflict,
+            )
+            .exit();
+        }
+    }
+    let compare = app_matches.is_present("compare");
+    let compare_offset = app_matches.value_of("compare-offset").map(|value| {
+        let offset = parse_duration(value).expect("validator should have rejected invalid values");
+        (offset, value.to_string())
+    });
+    let comparable = if compare_offset.is_some() {
+        inputs.len() == 1 && matches!(inputs[0], Input::File(_))
+    } else {
+        inputs.len() == 2
+            && inputs.iter().all(|input| matches!(input, Input::File(_)))
+            && inputs[0].name() != inputs[1].name()
+    };
+    if compare && !comparable {
+        clap::Error::with_description(
+            "--compare needs exactly two different input files, or one with --compare-offset",
+            clap::ErrorKind::WrongNumberOfValues,
+        )
+        .exit();
+    }
+    // The input compared with itself is counted twice, as if it were two inputs.
+    let inputs = match &compare_offset {
+        Some(_) => vec![inputs[0].clone(), inputs[0].clone()],
+        None => inputs,
+    };
+    // Compared inputs are counted as a group each, like --per-file.
+    let per_file = app_matches.is_present("per-file") || compare;
+    let top = app_matches.value_of("top").map(|value| {
+        value
+            .parse::<usize>()
+            .expect("validator should have rejected invalid values")
+    });
+    let sort_by_count = app_matches.value_of("sort") == Some("count");
+    // Checked here rather than by clap, since --sort has a default value and only 'count' conflicts.
+    let unsortable = [
+        "group-by",
+        "group-key",
+        "per-file",
+        "wide",
+        "heatmap",
+        "gaps",
+        "by",
+        "stream",
+        "sorted-input",
+        "moving-avg",
+        "ewma",
+        "delta",
+        "delta-only",
+        "derivative",
+        "compare",
+    ];
+    if let Some(name) = unsortable
+        .iter()
+        .find(|name| app_matches.is_present(name))
+        .filter(|_| sort_by_count)
+    {
+        clap::Error::with_description(
+            &format!("--sort count can't be combined with --{name}, as it sorts the buckets of a single series"),
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    if (wide || (top.is_some() && !sort_by_count)) && group_by.is_none() && !per_file {
+        clap::Error::with_description(
+            "--wide and --top can only be used with --group-by, --group-key or --per-file, or --top with --sort count",
+            clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit();
+    }
+    let last = app_matches
+        .value_of("last")
+        .map(|value| parse_duration(value).expect("validator should have rejected invalid values"));
+    // Counting back from now is just a --since which is already known.
+    let (since, last) = match last {
+        Some(last) if app_matches.is_present("now") => {
+            let window_start = Utc::now() - last;
+            (Some(since.map_or(window_start, |since| since.max(window_start))), None)
+        }
+        last => (since, last),
+    };
+    let heatmap = app_matches.value_of("heatmap").map(|heatmap| match heatmap {
+        "minute" => Heatmap::Minutes,
+        _ => Heatmap::Hours,
+    });
+    if let Some(heatmap) = heatmap {
+        if app_matches.occurrences_of("granularity") > 0 {
+            clap::Error::with_description(
+                "--heatmap sets the granularity, so --granularity can't be used with it",
+                clap::ErrorKind::ArgumentConflict,
+            )
+            .exit();
+        }
+        let granularity = match heatmap {
+            Heatmap::Hours => Granularity::Hour(NonZeroU32::new(1).expect("1 is not zero")),
+            Heatmap::Minutes => Granularity::Minute(NonZeroU32::new(1).expect("1 is not zero")),
+        };
+        let bucketing = Bucketing::new(granularity).with_week_start(week_start);
+        bucketings = vec![match timezone {
+            Some(timezone) => bucketing.with_timezone(timezone),
+            None => bucketing,
+        }];
+    }
+    let input_limit = InputLimit {
+        lines: app_matches
+            .value_of("max-lines")
+            .map(|value| value.parse().expect("validator should have rejected invalid values")),
+        bytes: app_matches
+            .value_of("max-bytes")
+            .map(|value| parse_size(value).expect("validator should have rejected invalid values")),
+    };
+    let input_limit = if input_limit.lines.is_some() || input_limit.bytes.is_some() {
+        Some(input_limit)
+    } else {
+        None
+    };
+    let heatmap_grid = app_matches.value_of("heatmap-style") == Some("grid");
+    let by = app_matches.value_of("by").map(|by| match by {
+        "hour-of-day" => Cycle::HourOfDay,
+        _ => Cycle::DayOfWeek(week_start),
+    });
+    if let Some(cycle) = by {
+        if app_matches.occurrences_of("granularity") > 0 {
+            clap::Error::with_description(
+                "--by sets the granularity, so --granularity can't be used with it",
+                clap::ErrorKind::ArgumentConflict,
+            )
+            .exit();
+        }
+        let granularity = match cycle {
+            Cycle::DayOfWeek(_) => Granularity::Day(NonZeroU32::new(1).expect("1 is not zero")),
+            Cycle::HourOfDay => Granularity::Hour(NonZeroU32::new(1).expect("1 is not zero")),
+        };
+        let bucketing = Bucketing::new(granularity).with_week_start(week_start);
+        bucketings = vec![match timezone {
+            Some(timezone) => bucketing.with_timezone(timezone),
+            None => bucketing,
+        }];
+    }
+    let output_rotation = app_matches.value_of("output-rotate").map(|windows| {
+        if let Mode::Normal = mode {
+            clap::Error::with_description(
+                "--output-rotate needs --stream or --sorted-input, since normal mode prints everything at the end",
+                clap::ErrorKind::MissingRequiredArgument,
+            )
+            .exit();
+        }
+        let windows =
+            Bucketing::new(Granularity::parse(windows).expect("validator should have rejected invalid values"));
+        Rotation {
+            windows: match timezone {
+                Some(timezone) => windows.with_timezone(timezone),
+                None => windows,
+            },
+            template: app_matches
+                .value_of("output-template")
+                .expect("output-rotate requires output-template")
+                .to_string(),
+        }
+    });
+    if bucketings.len() > 1 && !matches!(mode, Mode::Normal) {
+        clap::Error::with_description(
+            "Only one --granularity can be used with --stream or --sorted-input",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    if bucketings.len() > 1 && end_format.is_some() {
+        clap::Error::with_description(
+            "Only one --granularity can be used with --end-format",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    if let (Some(_), false) = (last, matches!(mode, Mode::Normal)) {
+        clap::Error::with_description(
+            "--last can only be used with --stream or --sorted-input together with --now",
+            clap::ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
+    if let (Some(since), Some(until)) = (since, until) {
+        if since >= until {
+            clap::Error::with_description("--since must be earlier than --until", clap::ErrorKind::InvalidValue).exit();
+        }
+    }
+    let flush_interval = app_matches.value_of("flush-interval").map(|value| {
+        parse_duration(value)
+            .and_then(|duration| duration.to_std().ok())
+            .expect("validator should have rejected invalid values")
+    });
+
+    Command::Count(Box::new(Args {
+        datetime_format,
+        match_index,
+        all_matches: app_matches.is_present("all-matches"),
+        end_format,
+        sessions,
+        column,
+        leap_seconds,
+        time_field,
+        bucketings,
+        inputs,
+        skews,
+        jobs,
+        fill_empty_buckets,
+        max_fill: max_fill(app_matches),
+        fill_value: app_matches.value_of("fill-value").map(str::to_string),
+        interpolate: app_matches.value_of("fill") == Some("interpolate"),
+        mode,
+        order,
+        detect_order: app_matches.value_of("order") == Some("auto"),
+        tolerant,
+        follow,
+        mmap,
+        input_limit,
+        state: app_matches.value_of_os("state").map(PathBuf::from),
+        state_options: StateOptions::new(app_matches),
+        reorder_window,
+        output_file: app_matches.value_of_os("output-file").map(PathBuf::from),
+        output_rotation,
+        table: app_matches.value_of("output") == Some("table"),
+        label_format,
+        color,
+        spike_sigma: app_matches
+            .value_of("spike-sigma")
+            .map(|value| value.parse().expect("validator should have rejected invalid values")),
+        stats,
+        timing: app_matches.is_present("timing"),
+        summary,
+        max_errors,
+        flush: app_matches.is_present("flush"),
+        flush_interval,
+        since,
+        until,
+        fill_from: app_matches
+            .value_of("fill-from")
+            .map(|value| parse_datetime_arg(value).expect("validator should have rejected invalid values")),
+        fill_to: app_matches
+            .value_of("fill-to")
+            .map(|value| parse_datetime_arg(value).expect("validator should have rejected invalid values")),
+        last,
+        grep,
+        grep_v,
+        record_start: app_matches.value_of("record-start").map(str::to_string),
+        attach_continuations: app_matches.is_present("attach-continuations"),
+        carry_forward: app_matches.is_present("carry-forward"),
+        group_by,
+        wide,
+        per_file,
+        compare,
+        compare_offset,
+        top,
+        sort_by_count,
+        value,
+        aggregations,
+        expression,
+        distinct,
+        dedupe_by: app_matches.value_of("dedupe-by").map(str::to_string),
+        approximate: app_matches.is_present("approx"),
+        rate: app_matches.is_present("rate"),
+        moving_avg: app_matches
+            .value_of("moving-avg")
+            .map(|value| value.parse().expect("validator should have rejected invalid values")),
+        ewma: app_matches
+            .value_of("ewma")
+            .map(|value| value.parse().expect("validator should have rejected invalid values")),
+        delta: app_matches.is_present("delta") || app_matches.is_present("delta-only"),
+        delta_only: app_matches.is_present("delta-only"),
+        derivative: app_matches.is_present("derivative"),
+        percent: app_matches.is_present("percent"),
+        alert_over: app_matches
+            .value_of("alert-over")
+            .map(|value| value.parse().expect("validator should have rejected invalid values")),
+        alert_under: app_matches
+            .value_of("alert-under")
+            .map(|value| value.parse().expect("validator should have rejected invalid values")),
+        alert_only: app_matches.is_present("alert-only"),
+        heatmap,
+        heatmap_grid,
+        by,
+        gaps: app_matches
+            .value_of("gaps")
+            .map(|value| parse_duration(value).expect("validator should have rejected invalid values")),
+    }))
+}
+
+// Validate a date/time format argument.
+#[allow(clippy::needless_pass_by_value)]
+fn validate_format(value: String) -> Result<(), String> {
+    DateTimeFormat::new(&value)
+        .map(|_| ())
+        .ok_or_else(|| "Not a valid date/time format, use --help to list supported specifiers".to_string())
+}
+
+// Parse a --since or --until value.
+fn parse_datetime_arg(value: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|datetime| datetime.with_timezone(&Utc))
+        .map_err(|err| format!("Not a valid RFC 3339 date/time: {err}"))
+}
+
+// Contents of the config file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    // Options applied to every invocation, by long name.
+    defaults: BTreeMap<String, toml::Value>,
+    presets: BTreeMap<String, Preset>,
+}
+
+// Named set of options selected with --preset.
+#[derive(Debug, Deserialize)]
+struct Preset {
+    format: Option<String>,
+    #[serde(flatten)]
+    options: BTreeMap<String, toml::Value>,
+}
+
+// Insert the options from the config file, and the preset selected with --preset, into the
+// command line arguments. They go before the arguments the user gave, which take precedence. The
+// granularity is returned instead, to be the default of --granularity, since the granularities
+// given on the command line would otherwise be counted at as well as it.
+fn apply_config_file(args: Vec<OsString>) -> Result<(Vec<OsString>, Option<String>), String> {
+    let explicit_path = prescan_option(&args, "--config").map(PathBuf::from);
+    let default_path = || {
+        let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(config_dir.join("tbuck").join("config.toml"))
+    };
+    let config = match explicit_path.clone().or_else(default_path) {
+        None => Config::default(),
+        Some(path) => match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                toml::from_str(&text).map_err(|err| format!("Invalid config file {}: {err}", path.display()))?
+            }
+            // Not having the default config file is normal.
+            Err(err) if err.kind() == ErrorKind::NotFound && explicit_path.is_none() => Config::default(),
+            Err(err) => return Err(format!("Failed to read config file {}: {err}", path.display())),
+        },
+    };
+    apply_config(&config, prescan_option(&args, "--preset").as_deref(), args)
+}
+
+// Find the value of an option before clap has parsed the arguments.
+fn prescan_option(args: &[OsString], long: &str) -> Option<String> {
+    let mut args = args.iter().skip(1).map(|arg| arg.to_str().unwrap_or_default());
+    let mut value = None;
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == long {
+            value = args.next().map(str::to_string);
+        } else if let Some(rest) = arg.strip_prefix(long).and_then(|rest| rest.strip_prefix('=')) {
+            value = Some(rest.to_string());
+        }
+    }
+    value
+}
+
+fn apply_config(
+    config: &Config,
+    preset: Option<&str>,
+    args: Vec<OsString>,
+) -> Result<(Vec<OsString>, Option<String>), String> {
+    // The config is only for counting, and goes after the count subcommand if it was given.
+    let subcommand = args.get(1).and_then(|arg| arg.to_str());
+    if subcommand.is_some_and(|subcommand| {
+        subcommand == "help" || tool_subcommands().iter().any(|app| app.get_name() == subcommand)
+    }) {
+        return Ok((args, None));
+    }
+    let preset = preset
+        .map(|name| {
+            config
+                .presets
+                .get(name)
+                .ok_or_else(|| format!("No preset named '{name}' in the config file"))
+        })
+        .transpose()?;
+    let leading = if subcommand == Some("count") { 2 } else { 1 };
+    let mut args = args.into_iter();
+    let mut expanded: Vec<OsString> = args.by_ref().take(leading).collect();
+
+    // Flags which the config file turns on can be turned off again with --no-NAME, which is
+    // consumed here rather than passed on. Their options are skipped, as is the granularity.
+    let sets_flag = |name: &str| {
+        let set = |options: &BTreeMap<String, toml::Value>| options.get(name) == Some(&toml::Value::Boolean(true));
+        set(&config.defaults) || preset.is_some_and(|preset| set(&preset.options))
+    };
+    let mut skipped = vec!["granularity".to_string()];
+    let mut rest = Vec::new();
+    let mut options_ended = false;
+    for arg in args {
+        options_ended |= arg == "--";
+        match arg.to_str().and_then(|arg| arg.strip_prefix("--no-")) {
+            Some(name) if !options_ended && sets_flag(name) => skipped.push(name.to_string()),
+            _ => rest.push(arg),
+        }
+    }
+
+    push_config_options(&mut expanded, &config.defaults, &skipped)?;
+    if let Some(preset) = preset {
+        push_config_options(&mut expanded, &preset.options, &skipped)?;
+        // Positional, so it is taken as the format and the user's positional arguments are inputs.
+        if let Some(format) = &preset.format {
+            expanded.push(format.into());
+        }
+    }
+    expanded.extend(rest);
+
+    let granularity = preset
+        .and_then(|preset| preset.options.get("granularity"))
+        .or_else(|| config.defaults.get("granularity"))
+        .map(|granularity| match granularity {
+            toml::Value::String(granularity) => Ok(granularity.clone()),
+            _ => Err("Only a single granularity, like granularity = \"5m\", can be set in the config file".to_string()),
+        })
+        .transpose()?;
+    Ok((expanded, granularity))
+}
+
+// Convert options from the config file to command line arguments, except those named in `skipped`.
+fn push_config_options(
+    args: &mut Vec<OsString>,
+    options: &BTreeMap<String, toml::Value>,
+    skipped: &[String],
+) -> Result<(), String> {
+    for (name, value) in options.iter().filter(|(name, _)| !skipped.contains(name)) {
+        let values = match value {
+            toml::Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => args.push(format!("--{name}").into()),
+                toml::Value::Boolean(false) => {}
+                toml::Value::String(value) => args.push(format!("--{name}={value}").into()),
+                toml::Value::Integer(value) => args.push(format!("--{name}={value}").into()),
+                _ => return Err(format!("Unsupported value for '{name}' in the config file")),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::{apply_config, prescan_option, Config};
+    use std::ffi::OsString;
+
+    fn os_args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn prescans_options() {
+        let cases = vec![
+            (vec!["tbuck", "--preset", "app", "a.log"], Some("app")),
+            (vec!["tbuck", "--preset=app", "a.log"], Some("app")),
+            (vec!["tbuck", "--preset=web", "--preset", "app"], Some("app")),
+            (vec!["tbuck", "--", "--preset", "app"], None),
+            (vec!["tbuck", "--presets", "app"], None),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(prescan_option(&os_args(&args), "--preset").as_deref(), expected);
+        }
+    }
+
+    #[test]
+    fn applies_defaults_and_presets() {
+        let config: Config = toml::from_str(
+            r#"
+            [defaults]
+            granularity = "5m"
+            no-fill = true
+            stream = false
+
+            [presets.app]
+            format = "%F %T"
+            grep = ["ERROR", "WARN"]
+            max-errors = 3
+            tolerant = true
+            "#,
+        )
+        .unwrap();
+        let cases = vec![
+            (
+                None,
+                vec!["tbuck", "%F", "a.log"],
+                vec!["tbuck", "--no-fill", "%F", "a.log"],
+            ),
+            (
+                Some("app"),
+                vec!["tbuck", "-g", "1h", "a.log"],
+                vec![
+                    "tbuck",
+                    "--no-fill",
+                    "--grep=ERROR",
+                    "--grep=WARN",
+                    "--max-errors=3",
+                    "--tolerant",
+                    "%F %T",
+                    "-g",
+                    "1h",
+                    "a.log",
+                ],
+            ),
+            (
+                Some("app"),
+                vec![
+                    "tbuck",
+                    "--no-tolerant",
+                    "--no-no-fill",
+                    "--no-stream",
+                    "a.log",
+                    "--",
+                    "--no-tolerant",
+                ],
+                vec![
+                    "tbuck",
+                    "--grep=ERROR",
+                    "--grep=WARN",
+                    "--max-errors=3",
+                    "%F %T",
+                    "--no-stream",
+                    "a.log",
+                    "--",
+                    "--no-tolerant",
+                ],
+            ),
+            (
+                None,
+                vec!["tbuck", "--no-fill", "%F", "a.log"],
+                vec!["tbuck", "--no-fill", "--no-fill", "%F", "a.log"],
+            ),
+            (
+                None,
+                vec!["tbuck", "count", "%F", "a.log"],
+                vec!["tbuck", "count", "--no-fill", "%F", "a.log"],
+            ),
+            (
+                Some("app"),
+                vec!["tbuck", "merge", "a.csv"],
+                vec!["tbuck", "merge", "a.csv"],
+            ),
+        ];
+        for (preset, args, expected) in cases {
+            let (applied, granularity) = apply_config(&config, preset, os_args(&args)).unwrap();
+            assert_eq!(applied, os_args(&expected));
+            let expected_granularity = if args[1] == "merge" { None } else { Some("5m") };
+            assert_eq!(granularity.as_deref(), expected_granularity);
+        }
+        assert!(apply_config(&config, Some("missing"), os_args(&["tbuck"])).is_err());
+    }
+}
+
+// Build the list of inputs from the positional arguments and --files-from. Standard input is
+// only used as the data input if no files were named at all.
+fn collect_inputs(app_matches: &ArgMatches<'_>) -> Result<Vec<Input>, String> {
+    let recursive = app_matches.is_present("recursive");
+    let name_filter = app_matches
+        .value_of("name")
+        .map(|value| glob::Pattern::new(value).expect("validator should have rejected invalid values"));
+
+    // With --evtx or --start-format no date/time format is given, so the first positional argument
+    // is an input too.
+    let format = if app_matches.is_present("evtx") || app_matches.is_present("start-format") {
+        app_matches.values_of_os("format")
+    } else {
+        None
+    };
+    let mut paths = Vec::new();
+    for val in format
+        .into_iter()
+        .flatten()
+        .chain(app_matches.values_of_os("inputs").into_iter().flatten())
+    {
+        paths.extend(expand_input_pattern(val)?);
+    }
+    if let Some(list) = app_matches.value_of_os("files-from") {
+        paths.extend(read_file_list(list).map_err(|err| format!("Failed to read file list: {err}"))?);
+    }
+    if paths.is_empty() && !app_matches.is_present("files-from") {
+        return Ok(vec![Input::Stdin]);
+    }
+
+    let mut inputs = Vec::with_capacity(paths.len());
+    for path in paths {
+        if path.is_dir() {
+            let mut files = Vec::new();
+            walk_directory(&path, recursive, name_filter.as_ref(), &mut files)
+                .map_err(|err| format!("Failed to read directory '{}': {err}", path.display()))?;
+            inputs.extend(files.into_iter().map(Input::File));
+        } else {
+            inputs.push(Input::File(path));
+        }
+    }
+    Ok(inputs)
+}
+
+// Read the paths listed in a --files-from file, or standard input if the name is '-'. Paths are
+// NUL-separated if the list contains any NUL bytes (as produced by `find -print0`), and
+// newline-separated otherwise. Empty entries are ignored.
+fn read_file_list(list: &OsStr) -> IoResult<Vec<PathBuf>> {
+    let mut contents = String::new();
+    if list == "-" {
+        std::io::stdin().read_to_string(&mut contents)?;
+    } else {
+        File::open(list)?.read_to_string(&mut contents)?;
+    }
+    let separator = if contents.contains('\0') { '\0' } else { '\n' };
+    Ok(contents
+        .split(separator)
+        .map(|entry| entry.strip_suffix('\r').unwrap_or(entry))
+        .filter(|entry| !entry.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+// Expand an input argument into the file paths it refers to. Arguments containing glob
+// metacharacters are expanded and sorted so the processing order is deterministic; anything else
+// (including an existing file whose name happens to contain metacharacters) is used verbatim.
+fn expand_input_pattern(value: &OsStr) -> Result<Vec<PathBuf>, String> {
+    let path = Path::new(value);
+    let pattern = match value.to_str() {
+        Some(pattern) if pattern.contains(&['*', '?', '['][..]) && !path.exists() => pattern,
+        _ => return Ok(vec![path.to_path_buf()]),
+    };
+    let mut paths = glob::glob(pattern)
+        .map_err(|err| format!("Invalid glob pattern '{pattern}': {err}"))?
+        .collect::<Result<Vec<PathBuf>, _>>()
+        .map_err(|err| format!("Failed to expand glob pattern '{pattern}': {err}"))?;
+    if paths.is_empty() {
+        return Err(format!("No files matched the pattern '{pattern}'"));
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+// Collect the files inside a directory input, sorted by path. Only files whose name matches
+// `name_filter` (if any) are kept, and subdirectories are only walked if `recursive` is set.
+fn walk_directory(
+    dir: &Path,
+    recursive: bool,
+    name_filter: Option<&glob::Pattern>,
+    files: &mut Vec<PathBuf>,
+) -> IoResult<()> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<IoResult<Vec<PathBuf>>>()?;
+    entries.sort();
+    for path in entries {
+        if path.is_dir() {
+            if recursive {
+                walk_directory(&path, recursive, name_filter, files)?;
+            }
+        } else {
+            let name = path.file_name().map(OsStr::to_string_lossy).unwrap_or_default();
+            if name_filter.is_none_or(|filter| filter.matches(&name)) {
+                files.push(path);
+            }
+        }
+    }
+    Ok(())
+}
+
+// What tbuck was asked to do.
+enum Command {
+    // Count entries into buckets, which is all tbuck does without a subcommand.
+    Count(Box<Args>),
+    // Sum the buckets of earlier outputs.
+    Merge(MergeArgs),
+    // Combine the buckets of an earlier output into coarser buckets.
+    Downsample(DownsampleArgs),
+    // Print the series of earlier outputs side by side.
+    Join(JoinArgs),
+    // Print synthetic log lines.
+    Gen(GenArgs),
+    // Draw the buckets of an earlier output.
+    Plot(PlotArgs),
+    // Show how a format matches the first lines of an input.
+    Explain(ExplainArgs),
+    // Find the format of an input's date/times with the user.
+    Wizard(WizardArgs),
+}
+
+// Parsed CLI args of the merge subcommand.
+#[derive(Debug)]
+struct MergeArgs {
+    inputs: Vec<Input>,
+    // Names of the value columns each row ends with.
+    columns: Vec<String>,
+    // The granularity of the buckets being merged, which empty buckets are filled at.
+    bucketing: Bucketing,
+    fill_empty_buckets: bool,
+    // Most empty buckets filled in between two others.
+    max_fill: usize,
+}
+
+// Parsed CLI args of the downsample subcommand.
+#[derive(Debug)]
+struct DownsampleArgs {
+    inputs: Vec<Input>,
+    // Names of the value columns each row ends with.
+    columns: Vec<String>,
+    // The granularity of the buckets being read.
+    from: Bucketing,
+    // The coarser granularity they are combined into, which empty buckets are filled at.
+    to: Bucketing,
+    // How the values of the buckets in each coarser bucket are combined.
+    aggregation: Aggregation,
+    fill_empty_buckets: bool,
+    // Most empty buckets filled in between two others.
+    max_fill: usize,
+}
+
+// Parsed CLI args of the join subcommand.
+#[derive(Debug)]
+struct JoinArgs {
+    inputs: Vec<Input>,
+    // Names of the value columns each row ends with.
+    columns: Vec<String>,
+    // The granularity of the buckets being joined, which empty buckets are filled at.
+    bucketing: Bucketing,
+    fill_empty_buckets: bool,
+    // Most empty buckets filled in between two others.
+    max_fill: usize,
+}
+
+// Parsed CLI args of the plot subcommand.
+#[derive(Debug)]
+struct PlotArgs {
+    inputs: Vec<Input>,
+    // Columns of the longest bar.
+    width: usize,
+}
+
+// Parsed CLI args of the explain subcommand.
+#[derive(Debug)]
+struct ExplainArgs {
+    format: String,
+    // Year of timestamps whose format has none.
+    assume_year: Option<i32>,
+    input: Input,
+    // How many lines of the input are tried.
+    lines: usize,
+    // Whether the matches are colored as well as underlined.
+    color: bool,
+}
+
+// Parsed CLI args of the wizard subcommand.
+#[derive(Debug)]
+struct WizardArgs {
+    input: Input,
+    // How many lines of the input the candidate formats are tried on.
+    lines: usize,
+}
+
+// Parsed CLI args of the gen subcommand.
+#[derive(Debug)]
+struct GenArgs {
+    // Date/time of the first line.
+    start: DateTime<Utc>,
+    // The lines are spread evenly over this much time after the start.
+    duration: Duration,
+    lines: u64,
+    format: String,
+    // Print each line once the clock reaches its date/time, rather than all at once.
+    realtime: bool,
+}
+
+// How lines are split into sessions for --sessions.
+#[derive(Debug)]
+struct Sessions {
+    // Regex whose first capture group is the key, like a user id, that a session belongs to.
+    key: String,
+    // Lines of a key further apart than this are in different sessions.
+    gap: Duration,
+    // Whether sessions are counted in every bucket they were active in, rather than the one they
+    // started in.
+    active: bool,
+}
+
+// Parsed CLI args.
+#[derive(Debug)]
+#[allow(clippy::struct_excessive_bools)]
+struct Args {
+    datetime_format: DateTimeFormat,
+    match_index: MatchIndex,
+    // Whether every date/time on a line is counted, rather than the one at the match index.
+    all_matches: bool,
+    // Counts sessions rather than lines, if set.
+    sessions: Option<Sessions>,
+    // The format of the end of each line's interval, if lines are counted in every bucket they
+    // overlap rather than only the bucket they start in.
+    end_format: Option<DateTimeFormat>,
+    // The character column timestamps must start at, if anchored.
+    column: Option<usize>,
+    leap_seconds: LeapSeconds,
+    // The part of each line to search for the date/time, if not all of it.
+    time_field: Option<TimeField>,
+    // One for each granularity. Only normal mode supports more than one.
+    bucketings: Vec<Bucketing>,
+    inputs: Vec<Input>,
+    // How far the clocks of the input files whose paths end with each path were ahead.
+    skews: Vec<(PathBuf, Duration)>,
+    // Number of threads used to process inputs in parallel, with 0 meaning one per CPU.
+    jobs: usize,
+    fill_empty_buckets: bool,
+    // Most empty buckets filled in a row.
+    max_fill: usize,
+    // Printed for the aggregations of empty buckets instead of their values.
+    fill_value: Option<String>,
+    // Fill the values of empty buckets in by interpolating between their neighbours.
+    interpolate: bool,
+    mode: Mode,
+    order: DateTimeOrder,
+    // Whether the order is decided by sampling the inputs, with --order auto.
+    detect_order: bool,
+    tolerant: bool,
+    follow: bool,
+    mmap: bool,
+    // How much of each input is read, if not all of it.
+    input_limit: Option<InputLimit>,
+    // Resume counting from the buckets and input offsets saved in this file.
+    state: Option<PathBuf>,
+    // The options saved with the state, which it must have been saved with to be resumed.
+    state_options: StateOptions,
+    reorder_window: Duration,
+    // Write the output to this file rather than stdout.
+    output_file: Option<PathBuf>,
+    // Append the output to a file for each window of time rather than writing it to stdout.
+    output_rotation: Option<Rotation>,
+    // Whether the output is laid out as a table for people rather than as CSV.
+    table: bool,
+    label_format: LabelFormat,
+    // Highlight the rows which breach the alert thresholds or are spikes.
+    color: bool,
+    // Rows whose first aggregation is this many standard deviations above the mean of their series
+    // are spikes.
+    spike_sigma: Option<f64>,
+    stats: Option<StatsFormat>,
+    // Report the time spent on each phase.
+    timing: bool,
+    summary: Option<SummaryTarget>,
+    max_errors: usize,
+    // Write out each row as soon as it is printed.
+    flush: bool,
+    flush_interval: Option<StdDuration>,
+    // Entries outside of [since, until) are discarded.
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    // Empty buckets are filled over [fill_from, fill_to) in normal mode, on top of the span of the
+    // entries. Each defaults to since or until.
+    fill_from: Option<DateTime<Utc>>,
+    fill_to: Option<DateTime<Utc>>,
+    // Only entries within this long of the latest entry are counted, in normal mode.
+    last: Option<Duration>,
+    // Regexes selecting which lines are counted.
+    grep: Vec<String>,
+    grep_v: Vec<String>,
+    // Regex matching the lines which start records, whose other lines are continuations.
+    record_start: Option<String>,
+    // Count continuation lines at the date/times of their records, rather than leave them out.
+    attach_continuations: bool,
+    // Count lines without a date/time at that of the last line with one.
+    carry_forward: bool,
+    // Selects the group each line is counted in, with --group-by or --group-key.
+    group_by: Option<Selection>,
+    // Print groups as columns rather than rows.
+    wide: bool,
+    // Count each input as its own group.
+    per_file: bool,
+    // Print the two inputs side by side, with their difference and ratio.
+    compare: bool,
+    // With --compare of a single input, how much later the window it is compared with is, as
+    // parsed and as given.
+    compare_offset: Option<(Duration, String)>,
+    // Number of groups to keep, folding the rest into OTHER_GROUP, or with --sort count the number
+    // of buckets printed.
+    top: Option<usize>,
+    // Whether buckets are printed busiest first rather than in time order.
+    sort_by_count: bool,
+    // Selects the number each line carries, with --value or --value-key.
+    value: Option<Selection>,
+    // What is reported for each bucket, each in its own column.
+    aggregations: Vec<Aggregation>,
+    // Computed from the aggregations of each bucket, in a column after theirs.
+    expression: Option<Expression>,
+    // Regex whose first capture group selects the key each line carries for distinct counts.
+    distinct: Option<String>,
+    // Regex whose first capture group selects the key which repeated lines share, so that they are
+    // only counted once per bucket.
+    dedupe_by: Option<String>,
+    // Estimate percentiles and distinct counts in bounded memory.
+    approximate: bool,
+    // Report counts and sums per second.
+    rate: bool,
+    // Number of rows, ending with each row, averaged into an extra column.
+    moving_avg: Option<usize>,
+    // Weight of each row in the exponentially weighted moving average in an extra column.
+    ewma: Option<f64>,
+    // Add a column with the change from the previous row.
+    delta: bool,
+    // Leave out the aggregations, leaving only the derived columns.
+    delta_only: bool,
+    // Add a column with the change from the previous row per second.
+    derivative: bool,
+    // Add a column with each row's share of the total of its series.
+    percent: bool,
+    // Buckets whose first aggregation is over or under these breach the alert thresholds.
+    alert_over: Option<f64>,
+    alert_under: Option<f64>,
+    // Only print the buckets which breach the alert thresholds.
+    alert_only: bool,
+    // Print a matrix of buckets rather than a row for each bucket.
+    heatmap: Option<Heatmap>,
+    // Print the heatmap as colored cells rather than CSV.
+    heatmap_grid: bool,
+    // Fold the buckets into a row for each part of a cycle, such as each day of the week.
+    by: Option<Cycle>,
+    // Print the spans of empty buckets lasting at least this long rather than the buckets.
+    gaps: Option<Duration>,
+}
+
+// What the columns of a --heatmap are, with each row spanning all of them.
+#[derive(Debug, Copy, Clone)]
+enum Heatmap {
+    // A row for each day and a column for each hour.
+    Hours,
+    // A row for each hour and a column for each minute.
+    Minutes,
+}
+
+// The files which --output-rotate appends buckets to.
+#[derive(Debug, Clone)]
+struct Rotation {
+    // Each window of this bucketing gets a file.
+    windows: Bucketing,
+    // Names each file by the local date/time its window starts at.
+    template: String,
+}
+
+// What --by folds buckets into, whatever their date.
+#[derive(Debug, Copy, Clone)]
+enum Cycle {
+    // The days of the week, starting on this one.
+    DayOfWeek(Weekday),
+    // The hours of the day.
+    HourOfDay,
+}
+
+impl Cycle {
+    // The label of each row, in order.
+    fn labels(self) -> Vec<String> {
+        match self {
+            Cycle::DayOfWeek(week_start) => std::iter::successors(Some(week_start), |day| Some(day.succ()))
+                .take(7)
+                .map(|day| format!("{day:?}"))
+                .collect(),
+            Cycle::HourOfDay => (0..24).map(|hour| format!("{hour:02}")).collect(),
+        }
+    }
+
+    // The row which a bucket starting at this local date/time is folded into.
+    fn row(self, local: NaiveDateTime) -> usize {
+        match self {
+            Cycle::DayOfWeek(week_start) => {
+                (local.weekday().num_days_from_monday() + 7 - week_start.num_days_from_monday()) as usize % 7
+            }
+            Cycle::HourOfDay => local.hour() as usize,
+        }
+    }
+}
+
+impl Args {
+    // How far an input's clock was ahead, which is subtracted from its date/times.
+    fn skew(&self, input: &Input) -> Duration {
+        let skew = match input {
+            Input::File(path) | Input::Evtx(path) => self.skews.iter().find(|(skewed, _)| path.ends_with(skewed)),
+            _ => None,
+        };
+        skew.map_or_else(Duration::zero, |(_, skew)| *skew)
+    }
+
+    // The skew of each input, in order. With --compare-offset the input is counted twice, the
+    // second time moved back by the offset so that it lines up with the first.
+    fn input_skews(&self) -> Vec<Duration> {
+        let mut skews: Vec<Duration> = self.inputs.iter().map(|input| self.skew(input)).collect();
+        if let (Some((offset, _)), Some(skew)) = (&self.compare_offset, skews.last_mut()) {
+            *skew = *skew + *offset;
+        }
+        skews
+    }
+
+    // The bucketing of stream and sorted modes, which only support one granularity.
+    fn bucketing(&self) -> &Bucketing {
+        &self.bucketings[0]
+    }
+
+    // An empty bucket, ready to keep what the aggregations need.
+    fn new_aggregate(&self) -> Aggregate {
+        // Stream mode may run forever, so it always estimates rather than keep every value or key.
+        let approximate = self.approximate || matches!(self.mode, Mode::Stream);
+        let aggregate = match &self.expression {
+            Some(expression) => {
+                let aggregations: Vec<Aggregation> = self
+                    .aggregations
+                    .iter()
+                    .copied()
+                    .chain(expression.aggregations())
+                    .collect();
+                Aggregate::for_aggregations(&aggregations, approximate)
+                    .with_fields(expression.field_aggregates(approximate))
+            }
+            None => Aggregate::for_aggregations(&self.aggregations, approximate),
+        };
+        if self.dedupe_by.is_some() {
+            aggregate.deduplicated(approximate)
+        } else {
+            aggregate
+        }
+    }
+
+    // The aggregations of a bucket, in the order they are printed, followed by the --expr. With
+    // --rate, counts and sums are divided by the length of the bucket in seconds.
+    #[allow(clippy::cast_precision_loss)]
+    fn values(&self, aggregate: &Aggregate, bucketing: &Bucketing, bucket: DateTime<Utc>) -> Vec<Option<f64>> {
+        // Calendar buckets vary in length, so it is measured for each bucket.
+        let seconds = if self.rate {
+            Some((bucketing.successor(&bucket) - bucket).num_milliseconds() as f64 / 1000.0)
+        } else {
+            None
+        };
+        self.aggregations
+            .iter()
+            .map(|aggregation| match (seconds, aggregation) {
+                (Some(seconds), Aggregation::Count | Aggregation::Sum) => {
+                    aggregate.value(*aggregation).map(|value| value / seconds)
+                }
+                _ => aggregate.value(*aggregation),
+            })
+            .chain(
+                self.expression
+                    .as_ref()
+                    .map(|expression| expression.evaluate(aggregate)),
+            )
+            .collect()
+    }
+
+    // Names of the columns of the values of a bucket, in the order they are printed. Those which
+    // --rate divides by the length of the bucket are named as rates per second.
+    fn value_columns(&self) -> Vec<String> {
+        self.aggregations
+            .iter()
+            .map(|aggregation| match (self.rate, aggregation) {
+                (true, Aggregation::Count | Aggregation::Sum) => format!("{aggregation}/s"),
+                _ => aggregation.to_string(),
+            })
+            .chain(self.expression.as_ref().map(ToString::to_string))
+            .collect()
+    }
+
+    // Names of the columns of the rows printed for each bucket, for --output table. Wide rows are
+    // named by the header printed with them instead.
+    fn table_header(&self) -> Vec<String> {
+        let mut header = Vec::new();
+        if self.bucketings.len() > 1 {
+            header.push("granularity".to_string());
+        }
+        header.push("bucket".to_string());
+        if self.group_by.is_some() || self.per_file {
+            header.push("group".to_string());
+        }
+        if !self.delta_only {
+            header.extend(self.value_columns());
+        }
+        header.extend(self.derived_columns());
+        header
+    }
+
+    // Names of the columns derived from earlier rows, in the order they are printed.
+    fn derived_columns(&self) -> Vec<String> {
+        let mut columns = Vec::new();
+        if let Some(window) = self.moving_avg {
+            columns.push(format!("moving_avg{window}"));
+        }
+        if let Some(alpha) = self.ewma {
+            columns.push(format!("ewma{alpha}"));
+        }
+        if self.delta {
+            columns.push("delta".to_string());
+        }
+        if self.derivative {
+            columns.push("derivative".to_string());
+        }
+        if self.percent {
+            columns.push("percent".to_string());
+        }
+        columns
+    }
+
+    // How a bucket is printed, in the --timezone if one was given.
+    fn label(&self, bucket: DateTime<Utc>) -> Label {
+        Label {
+            bucket,
+            timezone: self.bucketing().timezone(),
+            format: self.label_format,
+        }
+    }
+}
+
+// How buckets are labeled in the output.
+#[derive(Debug, Copy, Clone)]
+enum LabelFormat {
+    // As chrono displays date/times, like '2024-05-14 10:00:00 UTC'.
+    DateTime,
+    Rfc3339,
+    // UNIX timestamps, with --output-epoch.
+    EpochSeconds,
+    EpochMilliseconds,
+}
+
+// A bucket as printed in the output.
+struct Label {
+    bucket: DateTime<Utc>,
+    timezone: Option<Tz>,
+    format: LabelFormat,
+}
+
+impl std::fmt::Display for Label {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match (self.format, self.timezone) {
+            (LabelFormat::DateTime, Some(timezone)) => write!(f, "{}", self.bucket.with_timezone(&timezone)),
+            (LabelFormat::DateTime, None) => write!(f, "{}", self.bucket),
+            (LabelFormat::Rfc3339, Some(timezone)) => write!(
+                f,
+                "{}",
+                self.bucket
+                    .with_timezone(&timezone)
+                    .to_rfc3339_opts(SecondsFormat::AutoSi, true)
+            ),
+            (LabelFormat::Rfc3339, None) => write!(f, "{}", self.bucket.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+            (LabelFormat::EpochSeconds, _) => write!(f, "{}", self.bucket.timestamp()),
+            (LabelFormat::EpochMilliseconds, _) => write!(f, "{}", self.bucket.timestamp_millis()),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Mode {
+    Normal,
+    Stream,
+    // Normal mode output for input that is known to be (nearly) sorted.
+    Sorted,
+}
+
+// Mode-based runner. Contains business logic for normal and streaming modes.
+enum Runner {
+    // Normal mode will put everything into buckets and print them all at the end.
+    Normal {
+        // Unordered buckets of each group for each granularity - will be ordered after all lines
+        // have been counted. Without --group-by everything is in the group "".
+        buckets: Vec<HashMap<String, HashMap<DateTime<Utc>, Aggregate>>>,
+        // Buckets of the groups folded away by --top for each granularity. They are kept apart from
+        // the groups so that a group which happens to be named like the other group isn't merged
+        // with them.
+        other: Vec<HashMap<DateTime<Utc>, Aggregate>>,
+        // Groups folded away by --top while counting, for each granularity. Their later entries are
+        // counted into the other group too, rather than into a new group starting over from 0.
+        folded: Vec<HashSet<String>>,
+        // Latest date/time seen, only tracked for --last.
+        latest: Option<DateTime<Utc>>,
+        // Date/times seen of each session key, only tracked for --sessions. They are split into
+        // sessions and counted into the buckets once all lines have been seen.
+        sessions: HashMap<String, Vec<DateTime<Utc>>>,
+    },
+    Stream {
+        // The entries which have been seen for the current bucket.
+        aggregate: Aggregate,
+        // Current bucket. None only at the runner's beginning, when no bucket
+        // has been encountered yet, and then Some from then on.
+        bucket: Option<DateTime<Utc>>,
+        // Out of order entries skipped because of --tolerant.
+        discarded: Discarded,
+        // Rows printed so far.
+        trailing: Trailing,
+    },
+    // Sorted mode prints the same output as normal mode, but emits each bucket as soon as the
+    // input has moved more than the reorder window past it, so only a few buckets are ever held.
+    Sorted {
+        // Buckets which may still receive entries.
+        pending: BTreeMap<DateTime<Utc>, Aggregate>,
+        // Newest (or oldest if descending) bucket seen so far.
+        newest: Option<DateTime<Utc>>,
+        // Last bucket printed. Entries for it or anything before it arrived too late.
+        emitted: Option<DateTime<Utc>>,
+        // Entries which arrived too late to be counted.
+        late: Discarded,
+        // Rows printed so far.
+        trailing: Trailing,
+    },
+}
+
+impl Runner {
+    fn from_mode(mode: Mode, args: &Args) -> Self {
+        match mode {
+            Mode::Normal => Runner::Normal {
+                buckets: vec![HashMap::new(); args.bucketings.len()],
+                other: vec![HashMap::new(); args.bucketings.len()],
+                folded: vec![HashSet::new(); args.bucketings.len()],
+                latest: None,
+                sessions: HashMap::new(),
+            },
+            Mode::Stream => Runner::Stream {
+                aggregate: args.new_aggregate(),
+                bucket: None,
+                discarded: Discarded::default(),
+                trailing: Trailing::default(),
+            },
+            Mode::Sorted => Runner::Sorted {
+                pending: BTreeMap::new(),
+                newest: None,
+                emitted: None,
+                late: Discarded::default(),
+                trailing: Trailing::default(),
+            },
+        }
+    }
+
+    // Count an entry, along with the value and key it carried if aggregating those. Only normal
+    // mode supports groups other than "". Returns false if the entry repeats the dedupe key of one
+    // already counted in its bucket, and so wasn't counted anywhere.
+    fn handle_entry(
+        &mut self,
+        datetime: DateTime<Utc>,
+        group: &str,
+        carried: &Carried,
+        out: &Output,
+        args: &Args,
+    ) -> Result<bool, Error> {
+        if let Runner::Normal {
+            buckets, other, folded, ..
+        } = self
+        {
+            // Coarser buckets can see repeats which finer ones don't, so the entry only counts as a
+            // repeat if it was one in the buckets of every granularity.
+            let mut counted = false;
+            for (((series, other), folded), bucketing) in buckets
+                .iter_mut()
+                .zip(other.iter_mut())
+                .zip(folded.iter_mut())
+                .zip(&args.bucketings)
+            {
+                counted |= carried.record(
+                    group_buckets(series, other, folded, group, args.top)
+                        .entry(bucketing.bucketize(&datetime))
+                        .or_insert_with(|| args.new_aggregate()),
+                );
+            }
+            return Ok(counted);
+        }
+        match self {
+            Runner::Normal { .. } => unreachable!("normal mode was handled above"),
+            Runner::Sorted {
+                pending,
+                newest,
+                emitted,
+                late,
+                trailing,
+            } => Self::handle_sorted_entry(pending, newest, emitted, late, trailing, datetime, carried, out, args),
+            Runner::Stream {
+                aggregate,
+                bucket,
+                discarded,
+                trailing,
+            } => {
+                let entry = args.bucketing().bucketize(&datetime);
+                let Some(current_bucket) = *bucket else {
+                    // If this is the first bucket, just record the entry and return.
+                    *bucket = Some(entry);
+                    carried.record(aggregate);
+                    return Ok(true);
+                };
+                // What to do next depends on both what ordering the user configured and what the actual relation between the
+                // current bucket and new entry is.
+                match (args.order, entry.cmp(&current_bucket)) {
+                    (_, Ordering::Equal) => {
+                        // Same bucket. Just increment the count.
+                        return Ok(carried.record(aggregate));
+                    }
+                    (DateTimeOrder::Ascending, Ordering::Less) | (DateTimeOrder::Descending, Ordering::Greater) => {
+                        // Non-monotonic according to configured ordering.
+                        if !args.tolerant {
+                            return Err(Error::NonMonotonic {
+                                bucket: entry,
+                                current_bucket,
+                                location: None,
+                            });
+                        }
+                        discarded.record(datetime);
+                    }
+                    (DateTimeOrder::Ascending, Ordering::Greater) | (DateTimeOrder::Descending, Ordering::Less) => {
+                        // Monotonic. Print bucket(s) and advance to the next.
+                        trailing.write_row(
+                            &mut out.lock_for(current_bucket)?,
+                            "",
+                            aggregate,
+                            args.bucketing(),
+                            current_bucket,
+                            args,
+                        )?;
+                        if args.fill_empty_buckets {
+                            let empty = args.new_aggregate();
+                            let mut next_bucket = args.bucketing().successor(&current_bucket);
+                            check_fill(next_bucket, entry, args.bucketing(), args.max_fill)?;
+                            while next_bucket < entry {
+                                trailing.write_row(
+                                    &mut out.lock_for(next_bucket)?,
+                                    "",
+                                    &empty,
+                                    args.bucketing(),
+                                    next_bucket,
+                                    args,
+                                )?;
+                                next_bucket = args.bucketing().successor(&next_bucket);
+                            }
+                        }
+                        *aggregate = args.new_aggregate();
+                        carried.record(aggregate);
+                        *bucket = Some(entry);
+                    }
+                }
+                Ok(true)
+            }
+        }
+    }
+
+    // Record an entry in sorted mode, then print every pending bucket that is now further than the
+    // reorder window behind the newest bucket.
+    #[allow(clippy::too_many_arguments)]
+    fn handle_sorted_entry(
+        pending: &mut BTreeMap<DateTime<Utc>, Aggregate>,
+        newest: &mut Option<DateTime<Utc>>,
+        emitted: &mut Option<DateTime<Utc>>,
+        late: &mut Discarded,
+        trailing: &mut Trailing,
+        datetime: DateTime<Utc>,
+        carried: &Carried,
+        out: &Output,
+        args: &Args,
+    ) -> Result<bool, Error> {
+        let entry = args.bucketing().bucketize(&datetime);
+        // Compare in the direction of the configured order, so "before" means earlier in the input.
+        let before = |left: &DateTime<Utc>, right: &DateTime<Utc>| match args.order {
+            DateTimeOrder::Ascending => left < right,
+            DateTimeOrder::Descending => left > right,
+        };
+        if emitted.is_some_and(|emitted| !before(&emitted, &entry)) {
+            late.record(datetime);
+            return Ok(true);
+        }
+        if !carried.record(pending.entry(entry).or_insert_with(|| args.new_aggregate())) {
+            return Ok(false);
+        }
+        if newest.is_none_or(|newest| before(&newest, &entry)) {
+            *newest = Some(entry);
+        }
+        let newest = newest.expect("newest was just set");
+        let cutoff = match args.order {
+            DateTimeOrder::Ascending => newest - args.reorder_window,
+            DateTimeOrder::Descending => newest + args.reorder_window,
+        };
+
+        loop {
+            let next = match args.order {
+                DateTimeOrder::Ascending => pending.iter().next(),
+                DateTimeOrder::Descending => pending.iter().next_back(),
+            };
+            let Some(&bucket) = next.map(|(bucket, _)| bucket).filter(|bucket| before(bucket, &cutoff)) else {
+                break;
+            };
+            let aggregate = pending.remove(&bucket).expect("bucket is pending");
+            write_filled_bucket(out, *emitted, bucket, &aggregate, trailing, args)?;
+            *emitted = Some(bucket);
+        }
+        Ok(true)
+    }
+
+    // Print the count so far of the current bucket in stream mode, marked as partial. The bucket is
+    // printed again as usual once it is complete.
+    fn flush_partial(&self, out: &Output, args: &Args) -> IoResult<()> {
+        if let Runner::Stream {
+            aggregate,
+            bucket: Some(bucket),
+            trailing,
+            ..
+        } = self
+        {
+            let mut out_lock = out.lock_for(*bucket)?;
+            writeln!(
+                out_lock,
+                "{},{},partial",
+                args.label(*bucket),
+                trailing.peek_fields(aggregate, args.bucketing(), *bucket, args)
+            )?;
+            out_lock.flush()?;
+        }
+        Ok(())
+    }
+
+    // Number of buckets held, across every granularity and group.
+    fn bucket_count(&self) -> usize {
+        match self {
+            Runner::Normal { buckets, other, .. } => buckets
+                .iter()
+                .flat_map(HashMap::values)
+                .chain(other)
+                .map(HashMap::len)
+                .sum(),
+            Runner::Stream { bucket, .. } => usize::from(bucket.is_some()),
+            Runner::Sorted { pending, .. } => pending.len(),
+        }
+    }
+
+    // Track the latest date/time seen in normal mode, which --last counts back from.
+    fn record_latest(&mut self, datetime: DateTime<Utc>) {
+        if let Runner::Normal { latest, .. } = self {
+            *latest = (*latest).max(Some(datetime));
+        }
+    }
+
+    // Record a date/time of a session key in normal mode, for --sessions.
+    fn record_session(&mut self, key: &str, datetime: DateTime<Utc>) {
+        if let Runner::Normal { sessions, .. } = self {
+            // Look the key up by reference first to avoid allocating for every line.
+            match sessions.get_mut(key) {
+                Some(datetimes) => datetimes.push(datetime),
+                None => {
+                    sessions.insert(key.to_string(), vec![datetime]);
+                }
+            }
+        }
+    }
+
+    // Split the date/times of each session key into sessions wherever they are more than the gap
+    // apart, and count each session in the bucket it started in, or with --sessions=active in every
+    // bucket from its first date/time to its last.
+    fn count_sessions(&mut self, sessions: &Sessions, args: &Args) {
+        let Runner::Normal {
+            buckets,
+            sessions: seen,
+            ..
+        } = self
+        else {
+            unreachable!("--sessions is only used in normal mode");
+        };
+        let mut count = |start: DateTime<Utc>, end: DateTime<Utc>| {
+            for (series, bucketing) in buckets.iter_mut().zip(&args.bucketings) {
+                let group_buckets = series.entry(String::new()).or_insert_with(HashMap::new);
+                let mut bucket = bucketing.bucketize(&start);
+                let last = if sessions.active {
+                    bucketing.bucketize(&end)
+                } else {
+                    bucket
+                };
+                while bucket <= last {
+                    group_buckets
+                        .entry(bucket)
+                        .or_insert_with(|| args.new_aggregate())
+                        .add(None, None);
+                    bucket = bucketing.successor(&bucket);
+                }
+            }
+        };
+        for (_, mut datetimes) in seen.drain() {
+            datetimes.sort_unstable();
+            let mut start = datetimes[0];
+            let mut end = start;
+            for &datetime in &datetimes[1..] {
+                if datetime - end > sessions.gap {
+                    count(start, end);
+                    start = datetime;
+                }
+                end = datetime;
+            }
+            count(start, end);
+        }
+    }
+
+    // Move everything counted by a normal mode runner into a single group.
+    fn into_group(self, group: &str) -> Self {
+        match self {
+            Runner::Normal {
+                buckets,
+                other,
+                latest,
+                sessions,
+                ..
+            } => Runner::Normal {
+                other: vec![HashMap::new(); buckets.len()],
+                folded: vec![HashSet::new(); buckets.len()],
+                buckets: buckets
+                    .into_iter()
+                    .zip(other)
+                    .map(|(series, other)| {
+                        // The group is kept even if it is empty, so that it still gets a column.
+                        let mut group_buckets = HashMap::new();
+                        for buckets in series.into_iter().map(|(_, buckets)| buckets).chain(Some(other)) {
+                            for (bucket, aggregate) in buckets {
+                                merge_bucket(&mut group_buckets, bucket, aggregate);
+                            }
+                        }
+                        let mut grouped = HashMap::new();
+                        grouped.insert(group.to_string(), group_buckets);
+                        grouped
+                    })
+                    .collect(),
+                latest,
+                sessions,
+            },
+            _ => unreachable!("only normal mode runners are grouped"),
+        }
+    }
+
+    // Combine the buckets counted by two normal mode runners.
+    fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (
+                Runner::Normal {
+                    buckets: mut left,
+                    other: mut left_other,
+                    folded: mut left_folded,
+                    latest: left_latest,
+                    sessions: mut left_sessions,
+                },
+                Runner::Normal {
+                    buckets: right,
+                    other: right_other,
+                    folded: right_folded,
+                    latest: right_latest,
+                    sessions: right_sessions,
+                },
+            ) => {
+                for (left, right) in left.iter_mut().zip(right) {
+                    for (group, right) in right {
+                        let left = left.entry(group).or_insert_with(HashMap::new);
+                        for (bucket, aggregate) in right {
+                            merge_bucket(left, bucket, aggregate);
+                        }
+                    }
+                }
+                for (left, right) in left_other.iter_mut().zip(right_other) {
+                    for (bucket, aggregate) in right {
+                        merge_bucket(left, bucket, aggregate);
+                    }
+                }
+                // A group folded away by either runner is folded away in both, so that it isn't
+                // split between the other group and a group of its own.
+                for (((series, other), left), right) in left
+                    .iter_mut()
+                    .zip(left_other.iter_mut())
+                    .zip(left_folded.iter_mut())
+                    .zip(right_folded)
+                {
+                    left.extend(right);
+                    for group in left.iter() {
+                        for (bucket, aggregate) in series.remove(group).into_iter().flatten() {
+                            merge_bucket(other, bucket, aggregate);
+                        }
+                    }
+                }
+                for (key, right) in right_sessions {
+                    left_sessions.entry(key).or_insert_with(Vec::new).extend(right);
+                }
+                Runner::Normal {
+                    buckets: left,
+                    other: left_other,
+                    folded: left_folded,
+                    latest: left_latest.max(right_latest),
+                    sessions: left_sessions,
+                }
+            }
+            _ => unreachable!("only normal mode runners are merged"),
+        }
+    }
+
+    // Print whatever hasn't been printed yet. Returns the number of rows which breached the alert
+    // thresholds.
+    fn finish(self, out: &Output, args: &Args) -> Result<u64, Error> {
+        let breaches;
+        match self {
+            Runner::Normal {
+                buckets,
+                other,
+                mut folded,
+                latest,
+                ..
+            } => {
+                let mut out_lock = out.lock();
+                // With --output table, the rows are collected to be laid out once their widths are known.
+                let mut table = Vec::new();
+                let mut rows: &mut dyn Write = if args.table { &mut table } else { &mut out_lock };
+                let mut series_breaches = 0;
+                for (index, (((mut series, mut other), folded), bucketing)) in buckets
+                    .into_iter()
+                    .zip(other)
+                    .zip(&mut folded)
+                    .zip(&args.bucketings)
+                    .enumerate()
+                {
+                    // With several granularities, each row says which one it is for.
+                    let prefix = if args.bucketings.len() > 1 {
+                        format!("{},", bucketing.granularity())
+                    } else {
+                        String::new()
+                    };
+                    if let Some(heatmap) = args.heatmap {
+                        write_heatmap(&mut rows, series, bucketing, latest, heatmap, args)?;
+                        continue;
+                    }
+                    if args.compare {
+                        write_comparison(&mut rows, series, bucketing, latest, &prefix, index == 0, args)?;
+                        continue;
+                    }
+                    if let Some(top) = args.top {
+                        fold_small_groups(&mut series, &mut other, folded, top);
+                    }
+                    let groups = sorted_groups(series, other);
+                    if let Some(min_gap) = args.gaps {
+                        write_gaps(&mut rows, groups, bucketing, latest, min_gap, &prefix, args)?;
+                        continue;
+                    }
+                    if let Some(cycle) = args.by {
+                        write_cycle(&mut rows, groups, bucketing, latest, cycle, args)?;
+                        continue;
+                    }
+                    series_breaches += write_normal_series(&mut rows, &groups, bucketing, latest, &prefix, args)?;
+                }
+                if args.table {
+                    let header = if args.wide || args.compare {
+                        None
+                    } else {
+                        Some(args.table_header())
+                    };
+                    write_table(&mut out_lock, &table, header)?;
+                }
+                breaches = series_breaches;
+            }
+            Runner::Stream {
+                aggregate,
+                bucket,
+                discarded,
+                mut trailing,
+            } => {
+                if let Some(bucket) = bucket {
+                    let mut out_lock = out.lock_for(bucket)?;
+                    trailing.write_row(&mut out_lock, "", &aggregate, args.bucketing(), bucket, args)?;
+                    // With --output-rotate, the summary goes in the file of the last bucket.
+                    write_summaries(&mut out_lock, &[(None, &trailing)], "", args)?;
+                }
+                breaches = trailing.breaches;
+                discarded.report("which were out of order");
+            }
+            Runner::Sorted {
+                pending,
+                mut emitted,
+                late,
+                mut trailing,
+                ..
+            } => {
+                let remaining: Box<dyn Iterator<Item = (DateTime<Utc>, Aggregate)>> = match args.order {
+                    DateTimeOrder::Ascending => Box::new(pending.into_iter()),
+                    DateTimeOrder::Descending => Box::new(pending.into_iter().rev()),
+                };
+                for (bucket, aggregate) in remaining {
+                    write_filled_bucket(out, emitted, bucket, &aggregate, &mut trailing, args)?;
+                    emitted = Some(bucket);
+                }
+                if let Some(emitted) = emitted {
+                    write_summaries(&mut out.lock_for(emitted)?, &[(None, &trailing)], "", args)?;
+                }
+                breaches = trailing.breaches;
+                late.report("which arrived after their bucket was printed; consider a larger --reorder-window");
+            }
+        }
+        Ok(breaches)
+    }
+}
+
+// Record the entries of an aggregate in the aggregate of the same bucket, if there is one.
+fn merge_bucket(buckets: &mut HashMap<DateTime<Utc>, Aggregate>, bucket: DateTime<Utc>, aggregate: Aggregate) {
+    if let Some(existing) = buckets.get_mut(&bucket) {
+        existing.merge(&aggregate);
+    } else {
+        buckets.insert(bucket, aggregate);
+    }
+}
+
+// Group that --top folds the groups with the lowest counts into.
+const OTHER_GROUP: &str = "__other__";
+
+// How many groups are held while counting with --top before the smallest are folded into the
+// other group, to bound memory. Generous, so that the groups which end up on top are very
+// unlikely to be folded early.
+fn top_group_limit(top: usize) -> usize {
+    top.max(100) * 20
+}
+
+// The buckets which an entry of the group is counted into: those of the group, or of the other
+// group if --top folded the group away while counting.
+fn group_buckets<'a>(
+    groups: &'a mut HashMap<String, HashMap<DateTime<Utc>, Aggregate>>,
+    other: &'a mut HashMap<DateTime<Utc>, Aggregate>,
+    folded: &mut HashSet<String>,
+    group: &str,
+    top: Option<usize>,
+) -> &'a mut HashMap<DateTime<Utc>, Aggregate> {
+    // Look the group up by reference first to avoid allocating for every line.
+    if !groups.contains_key(group) {
+        if folded.contains(group) {
+            return other;
+        }
+        if let Some(top) = top {
+            if groups.len() >= top_group_limit(top) {
+                fold_small_groups(groups, other, folded, top_group_limit(top) / 2);
+            }
+        }
+        groups.insert(group.to_string(), HashMap::new());
+    }
+    groups.get_mut(group).expect("group was just inserted")
+}
+
+// Keep the groups with the highest total counts, and fold the rest into the buckets of the other
+// group, adding their names to the folded groups.
+fn fold_small_groups(
+    groups: &mut HashMap<String, HashMap<DateTime<Utc>, Aggregate>>,
+    other: &mut HashMap<DateTime<Utc>, Aggregate>,
+    folded: &mut HashSet<String>,
+    keep: usize,
+) {
+    let mut totals: Vec<(u64, String)> = groups
+        .iter()
+        .map(|(group, buckets)| (buckets.values().map(Aggregate::count).sum(), group.clone()))
+        .collect();
+    if totals.len() <= keep {
+        return;
+    }
+    // Highest first, and by name among equal counts so the result doesn't depend on hash order.
+    totals.sort_unstable_by(|left, right| right.0.cmp(&left.0).then_with(|| left.1.cmp(&right.1)));
+    for (_, group) in totals.drain(keep..) {
+        for (bucket, aggregate) in groups.remove(&group).expect("group was just listed") {
+            merge_bucket(other, bucket, aggregate);
+        }
+        folded.insert(group);
+    }
+}
+
+// The groups of a series in order, followed by the other group of --top if anything was folded
+// into it.
+fn sorted_groups(
+    groups: HashMap<String, HashMap<DateTime<Utc>, Aggregate>>,
+    other: HashMap<DateTime<Utc>, Aggregate>,
+) -> Vec<(String, HashMap<DateTime<Utc>, Aggregate>)> {
+    let mut groups: Vec<(String, HashMap<DateTime<Utc>, Aggregate>)> = groups.into_iter().collect();
+    groups.sort_unstable_by(|(left, _), (right, _)| left.cmp(right));
+    if !other.is_empty() {
+        groups.push((OTHER_GROUP.to_string(), other));
+    }
+    groups
+}
+
+#[cfg(test)]
+mod top_tests {
+    use super::{fold_small_groups, sorted_groups, test_run, OTHER_GROUP};
+    use chrono::{TimeZone, Utc};
+    use hashbrown::{HashMap, HashSet};
+    use std::fmt::Write;
+    use tbuck::Aggregate;
+
+    #[test]
+    fn folds_small_groups() {
+        let first = Utc.timestamp(0, 0);
+        let second = Utc.timestamp(60, 0);
+        let mut groups = HashMap::new();
+        for (group, first_count, second_count) in &[("a", 5, 0), ("b", 1, 1), ("c", 2, 7), ("d", 2, 0)] {
+            let buckets = groups.entry(group.to_string()).or_insert_with(HashMap::new);
+            for (bucket, count) in &[(first, *first_count), (second, *second_count)] {
+                let aggregate = buckets.entry(*bucket).or_insert_with(Aggregate::new);
+                for _ in 0..*count {
+                    aggregate.add(None, None);
+                }
+            }
+        }
+        let mut other = HashMap::new();
+        let mut folded = HashSet::new();
+        fold_small_groups(&mut groups, &mut other, &mut folded, 2);
+        let mut names: Vec<&str> = groups.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["a", "c"]);
+        assert_eq!(other[&first].count(), 3);
+        assert_eq!(other[&second].count(), 1);
+
+        // Folding again adds to the existing other group.
+        fold_small_groups(&mut groups, &mut other, &mut folded, 1);
+        assert_eq!(other[&first].count(), 8);
+        assert_eq!(groups.len(), 1);
+        let mut folded: Vec<&str> = folded.iter().map(String::as_str).collect();
+        folded.sort_unstable();
+        assert_eq!(folded, vec!["a", "b", "d"]);
+    }
+
+    #[test]
+    fn keeps_a_group_named_like_the_other_group_apart() {
+        let bucket = Utc.timestamp(0, 0);
+        let mut groups = HashMap::new();
+        for (group, count) in &[(OTHER_GROUP, 1), ("a", 5), ("b", 2)] {
+            let aggregate = groups
+                .entry(group.to_string())
+                .or_insert_with(HashMap::new)
+                .entry(bucket)
+                .or_insert_with(Aggregate::new);
+            for _ in 0..*count {
+                aggregate.add(None, None);
+            }
+        }
+        let mut other = HashMap::new();
+        fold_small_groups(&mut groups, &mut other, &mut HashSet::new(), 2);
+        let sorted = sorted_groups(groups, other);
+        let counts: Vec<(&str, u64)> = sorted
+            .iter()
+            .map(|(group, buckets)| (group.as_str(), buckets[&bucket].count()))
+            .collect();
+        assert_eq!(counts, vec![("a", 5), ("b", 2), (OTHER_GROUP, 1)]);
+    }
+
+    #[test]
+    fn keeps_counting_folded_groups_into_the_other_group() {
+        // A group which is folded away while counting, among the thousands of single line groups
+        // after its first line, and then comes back.
+        let zzz = "2024-05-14 10:00:00 user=zzz\n";
+        let mut lines = zzz.to_string();
+        for user in 0..2100 {
+            writeln!(lines, "2024-05-14 10:00:00 user=u{user}").unwrap();
+        }
+        lines.push_str(&zzz.repeat(50));
+        let (output, result) = test_run(&["--group-by", "user=(\\w+)", "--top", "2"], &lines);
+        result.unwrap();
+        assert_eq!(
+            output,
+            "2024-05-14 10:00:00 UTC,u0,1\n\
+             2024-05-14 10:00:00 UTC,u1,1\n\
+             2024-05-14 10:00:00 UTC,__other__,2149\n"
+        );
+    }
+}
+
+// Print the buckets of one granularity in normal mode, each row starting with the prefix. Returns
+// the number of rows which breached the alert thresholds.
+fn write_normal_series(
+    out: &mut impl Write,
+    groups: &[(String, HashMap<DateTime<Utc>, Aggregate>)],
+    bucketing: &Bucketing,
+    latest: Option<DateTime<Utc>>,
+    prefix: &str,
+    args: &Args,
+) -> Result<u64, Error> {
+    let filled_buckets = series_buckets(groups, bucketing, latest, args)?;
+
+    let empty = args.new_aggregate();
+    let breaches = if args.wide {
+        // One column per group, named in a header.
+        let first = if prefix.is_empty() {
+            "bucket"
+        } else {
+            "granularity,bucket"
+        };
+        write_wide_header(out, first, groups.iter().map(|(group, _)| group.as_str()), args)?;
+        let mut trailing: Vec<Trailing> = groups
+            .iter()
+            .map(|(_, buckets)| Trailing::for_series(buckets, &filled_buckets, bucketing, args))
+            .collect();
+        for bucket in filled_buckets {
+            let mut row = format!("{prefix}{}", args.label(bucket));
+            let mut shown = false;
+            let mut highlighted = false;
+            for ((_, buckets), trailing) in groups.iter().zip(&mut trailing) {
+                let aggregate = buckets.get(&bucket).unwrap_or(&empty);
+                write!(row, ",{}", trailing.fields(aggregate, bucketing, bucket, args))
+                    .expect("writing to a String can't fail");
+                shown |= trailing.admit(args);
+                highlighted |= trailing.highlighted;
+            }
+            // With --alert-only, rows are printed if any group breached, and highlighted if any group is.
+            if shown {
+                writeln!(out, "{}", paint(row, highlighted))?;
+            }
+        }
+        trailing.iter().map(|trailing| trailing.breaches).sum()
+    } else if args.group_by.is_some() || args.per_file {
+        // One row per group which has entries in the bucket, or every group when filling.
+        let mut trailing: Vec<Trailing> = groups
+            .iter()
+            .map(|(_, buckets)| Trailing::for_series(buckets, &filled_buckets, bucketing, args))
+            .collect();
+        for bucket in filled_buckets {
+            for ((group, buckets), trailing) in groups.iter().zip(&mut trailing) {
+                let aggregate = buckets.get(&bucket).unwrap_or(&empty);
+                // Skipped rows still count as earlier rows of the group.
+                let fields = trailing.fields(aggregate, bucketing, bucket, args);
+                if (aggregate.count() > 0 || args.fill_empty_buckets) && trailing.admit(args) {
+                    let row = format!("{prefix}{},{},{fields}", args.label(bucket), csv_field(group));
+                    writeln!(out, "{}", paint(row, trailing.highlighted))?;
+                }
+            }
+        }
+        let series: Vec<(Option<&str>, &Trailing)> = groups
+            .iter()
+            .zip(&trailing)
+            .map(|((group, _), trailing)| (Some(group.as_str()), trailing))
+            .collect();
+        write_summaries(out, &series, prefix, args)?;
+        trailing.iter().map(|trailing| trailing.breaches).sum()
+    } else {
+        let no_buckets = HashMap::new();
+        let buckets = groups.first().map_or(&no_buckets, |(_, buckets)| buckets);
+        let mut filled_buckets = filled_buckets;
+        if args.sort_by_count {
+            // The sort is stable, so buckets with the same count stay in time order.
+            filled_buckets.sort_by_key(|bucket| Reverse(buckets.get(bucket).map_or(0, Aggregate::count)));
+            if let Some(top) = args.top {
+                filled_buckets.truncate(top);
+            }
+        }
+        let mut trailing = Trailing::for_series(buckets, &filled_buckets, bucketing, args);
+        for bucket in filled_buckets {
+            let aggregate = buckets.get(&bucket).unwrap_or(&empty);
+            trailing.write_row(out, prefix, aggregate, bucketing, bucket, args)?;
+        }
+        write_summaries(out, &[(None, &trailing)], prefix, args)?;
+        trailing.breaches
+    };
+    Ok(breaches)
+}
+
+// Print the header of --wide output: the columns before the groups, then the columns of each group,
+// named after it.
+fn write_wide_header<'a>(
+    out: &mut impl Write,
+    first: &str,
+    groups: impl Iterator<Item = &'a str>,
+    args: &Args,
+) -> IoResult<()> {
+    write!(out, "{first}")?;
+    for group in groups {
+        if args.delta_only {
+            // Only the derived columns are printed.
+        } else if args.value_columns().len() > 1 {
+            // A column for each aggregation of each group.
+            for column in args.value_columns() {
+                write!(out, ",{}", csv_field(&format!("{group}:{column}")))?;
+            }
+        } else {
+            write!(out, ",{}", csv_field(group))?;
+        }
+        for column in args.derived_columns() {
+            write!(out, ",{}", csv_field(&format!("{group}:{column}")))?;
+        }
+    }
+    writeln!(out)
+}
+
+// Print the first aggregation of the two inputs of --compare side by side for each bucket, followed
+// by the difference of the second from the first and their ratio, after a header row if `header`.
+// Empty buckets are filled in like those of any other series.
+fn write_comparison(
+    out: &mut impl Write,
+    mut groups: HashMap<String, HashMap<DateTime<Utc>, Aggregate>>,
+    bucketing: &Bucketing,
+    latest: Option<DateTime<Utc>>,
+    prefix: &str,
+    header: bool,
+    args: &Args,
+) -> Result<(), Error> {
+    // In the order the inputs were given, whether or not they had entries.
+    let groups: Vec<(String, HashMap<DateTime<Utc>, Aggregate>)> = group_names(&args.inputs)
+        .into_iter()
+        .map(|group| {
+            let buckets = groups.remove(&group).unwrap_or_default();
+            (group, buckets)
+        })
+        .collect();
+    let filled_buckets = series_buckets(&groups, bucketing, latest, args)?;
+    if header {
+        let first = args.inputs[0].name();
+        let second = match &args.compare_offset {
+            Some((_, offset)) => format!("{first}+{offset}"),
+            None => args.inputs[1].name(),
+        };
+        writeln!(
+            out,
+            "{}bucket,{},{},difference,ratio",
+            if prefix.is_empty() { "" } else { "granularity," },
+            csv_field(&first),
+            csv_field(&second),
+        )?;
+    }
+    let trailing: Vec<Trailing> = groups
+        .iter()
+        .map(|(_, buckets)| Trailing::for_series(buckets, &filled_buckets, bucketing, args))
+        .collect();
+    let empty = args.new_aggregate();
+    let format = |value: Option<f64>| value.map_or_else(String::new, |value| value.to_string());
+    for bucket in filled_buckets {
+        // The value of each input in the bucket, and how it is printed.
+        let [(first, first_field), (second, second_field)] = [0, 1].map(|index| {
+            let aggregate = groups[index].1.get(&bucket).unwrap_or(&empty);
+            match &args.fill_value {
+                Some(fill_value) if aggregate.count() == 0 => (fill_value.parse::<f64>().ok(), fill_value.clone()),
+                _ => {
+                    let value = trailing[index].values(aggregate, bucketing, bucket, args)[0];
+                    (value, format(value))
+                }
+            }
+        });
+        let difference = first.zip(second).map(|(first, second)| second - first);
+        let ratio = first
+            .zip(second)
+            .filter(|(first, _)| *first != 0.0)
+            .map(|(first, second)| second / first);
+        writeln!(
+            out,
+            "{prefix}{},{first_field},{second_field},{},{}",
+            args.label(bucket),
+            format(difference),
+            format(ratio),
+        )?;
+    }
+    Ok(())
+}
+
+// The value above which a bucket of the series is a spike: the mean of the first aggregation over
+// the buckets printed plus sigma standard deviations. None if no bucket has a value.
+#[allow(clippy::cast_precision_loss)]
+fn spike_threshold(
+    buckets: &HashMap<DateTime<Utc>, Aggregate>,
+    filled_buckets: &[DateTime<Utc>],
+    bucketing: &Bucketing,
+    sigma: f64,
+    args: &Args,
+) -> Option<f64> {
+    let empty = args.new_aggregate();
+    let values: Vec<f64> = filled_buckets
+        .iter()
+        .filter_map(|bucket| args.values(buckets.get(bucket).unwrap_or(&empty), bucketing, *bucket)[0])
+        .collect();
+    if values.is_empty() {
+        return None;
+    }
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let variance = values.iter().map(|value| (value - mean).powi(2)).sum::<f64>() / values.len() as f64;
+    Some(mean + sigma * variance.sqrt())
+}
+
+// The values of the empty buckets of a series which lie between buckets with entries, on the
+// straight line between the values of the nearest of them, for --fill interpolate. Counts and
+// distinct counts are left as they are.
+#[allow(clippy::cast_precision_loss)]
+fn interpolate(
+    buckets: &HashMap<DateTime<Utc>, Aggregate>,
+    filled_buckets: &[DateTime<Utc>],
+    bucketing: &Bucketing,
+    args: &Args,
+) -> HashMap<DateTime<Utc>, Vec<Option<f64>>> {
+    let empty = args.new_aggregate();
+    // The buckets may be in descending order, or sorted by count.
+    let mut ordered = filled_buckets.to_vec();
+    ordered.sort_unstable();
+    let mut rows: Vec<(DateTime<Utc>, bool, Vec<Option<f64>>)> = ordered
+        .into_iter()
+        .map(|bucket| {
+            let aggregate = buckets.get(&bucket).unwrap_or(&empty);
+            (
+                bucket,
+                aggregate.count() == 0,
+                args.values(aggregate, bucketing, bucket),
+            )
+        })
+        .collect();
+    let interpolated_columns = args
+        .aggregations
+        .iter()
+        .map(|aggregation| aggregation.needs_values())
+        .chain(args.expression.as_ref().map(|_| true));
+    for (column, interpolated) in interpolated_columns.enumerate() {
+        if !interpolated {
+            continue;
+        }
+        // The latest bucket with entries and a value so far.
+        let mut before: Option<(usize, f64)> = None;
+        for index in 0..rows.len() {
+            let (bucket, empty, ref values) = rows[index];
+            let Some(value) = values[column].filter(|_| !empty) else {
+                continue;
+            };
+            if let Some((before_index, before_value)) = before {
+                let before_bucket = rows[before_index].0;
+                let span = (bucket - before_bucket).num_milliseconds() as f64;
+                for row in &mut rows[before_index + 1..index] {
+                    if row.1 {
+                        let fraction = (row.0 - before_bucket).num_milliseconds() as f64 / span;
+                        row.2[column] = Some(before_value + (value - before_value) * fraction);
+                    }
+                }
+            }
+            before = Some((index, value));
+        }
+    }
+    rows.into_iter()
+        .filter(|(_, empty, _)| *empty)
+        .map(|(bucket, _, values)| (bucket, values))
+        .collect()
+}
+
+// A row of output, in bold red if it is highlighted.
+fn paint(row: String, highlighted: bool) -> String {
+    if highlighted {
+        format!("\x1b[1;31m{row}\x1b[0m")
+    } else {
+        row
+    }
+}
+
+// The buckets printed for a series in normal mode, in order: those of every group, within the
+// --last window, and with the empty buckets between them unless --no-fill was specified.
+fn series_buckets(
+    groups: &[(String, HashMap<DateTime<Utc>, Aggregate>)],
+    bucketing: &Bucketing,
+    latest: Option<DateTime<Utc>>,
+    args: &Args,
+) -> Result<Vec<DateTime<Utc>>, Error> {
+    // Sort the buckets of every group by time.
+    let mut ordered_buckets: Vec<DateTime<Utc>> =
+        groups.iter().flat_map(|(_, buckets)| buckets.keys().copied()).collect();
+    // Drop buckets before the --last window.
+    let window_start = window_start(bucketing, latest, args);
+    if let Some(window_start) = window_start {
+        ordered_buckets.retain(|bucket| *bucket >= window_start);
+    }
+    match args.order {
+        DateTimeOrder::Ascending => ordered_buckets.sort_unstable(),
+        DateTimeOrder::Descending => ordered_buckets.sort_unstable_by_key(|bucket| Reverse(*bucket)),
+    }
+    ordered_buckets.dedup();
+
+    // Unless --no-fill was specified, we need to emit 0s for buckets which don't exist.
+    let mut filled_buckets = Vec::with_capacity(ordered_buckets.len());
+    let mut prev_bucket = chrono::MAX_DATE.and_hms(0, 0, 0);
+    // Fill the whole --fill-from/--fill-to (or --since/--until) window, not just the span of the
+    // entries.
+    let fill_window = args.fill_empty_buckets && matches!(args.order, DateTimeOrder::Ascending);
+    let fill_start = args
+        .fill_from
+        .or(args.since)
+        .map(|start| bucketing.bucketize(&start))
+        .max(window_start);
+    if let (Some(fill_start), true) = (fill_start, fill_window) {
+        prev_bucket = fill_start;
+    }
+    for bucket in ordered_buckets {
+        if args.fill_empty_buckets {
+            check_fill(prev_bucket, bucket, bucketing, args.max_fill)?;
+            while prev_bucket < bucket {
+                filled_buckets.push(prev_bucket);
+                prev_bucket = bucketing.successor(&prev_bucket);
+            }
+        }
+        filled_buckets.push(bucket);
+        prev_bucket = bucketing.successor(&bucket);
+    }
+    if let (Some(fill_end), true) = (args.fill_to.or(args.until), fill_window) {
+        check_fill(prev_bucket, fill_end, bucketing, args.max_fill)?;
+        while prev_bucket < fill_end {
+            filled_buckets.push(prev_bucket);
+            prev_bucket = bucketing.successor(&prev_bucket);
+        }
+    }
+    Ok(filled_buckets)
+}
+
+// The first bucket of the --last window, if one was given. It is rounded back to a bucket boundary,
+// since entries are only known by bucket by the time they are printed.
+fn window_start(bucketing: &Bucketing, latest: Option<DateTime<Utc>>, args: &Args) -> Option<DateTime<Utc>> {
+    args.last
+        .zip(latest)
+        .map(|(last, latest)| bucketing.bucketize(&(latest - last)))
+}
+
+// 256-color palette entries which --heatmap-style grid shades cells with, from cold to hot.
+const HEATMAP_COLORS: [u8; 12] = [17, 19, 21, 27, 33, 39, 45, 226, 220, 214, 208, 196];
+
+// A cell of a --heatmap, if anything was counted into it: its first bucket, and the entries.
+type HeatmapCell = Option<(DateTime<Utc>, Aggregate)>;
+
+// A row of a --heatmap as printed: the local date/time it starts at, and the value of each cell
+// along with whether it is empty.
+type HeatmapRow = (NaiveDateTime, Vec<(Option<f64>, bool)>);
+
+// Add the empty rows of a --heatmap between those with entries. Whole rows are filled in, so they
+// are what `max_fill` limits.
+fn fill_heatmap_rows(
+    rows: &mut BTreeMap<NaiveDateTime, Vec<HeatmapCell>>,
+    columns: usize,
+    row_width: Duration,
+    max_fill: usize,
+) -> Result<(), Error> {
+    // Each row with entries, and a bucket in it to name it by if the gap after it is too long.
+    let counted: Vec<(NaiveDateTime, DateTime<Utc>)> = rows
+        .iter()
+        .filter_map(|(row, cells)| Some((*row, cells.iter().flatten().next()?.0)))
+        .collect();
+    for pair in counted.windows(2) {
+        let ((row, from), (next_row, until)) = (pair[0], pair[1]);
+        let empty_rows = (next_row - row).num_seconds() / row_width.num_seconds() - 1;
+        if usize::try_from(empty_rows).is_ok_and(|empty_rows| empty_rows > max_fill) {
+            return Err(Error::MaxFill { from, until, max_fill });
+        }
+        let mut row = row + row_width;
+        while row < next_row {
+            rows.entry(row).or_insert_with(|| vec![None; columns]);
+            row += row_width;
+        }
+    }
+    Ok(())
+}
+
+// Print the buckets of a series in normal mode as a --heatmap.
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_precision_loss,
+    clippy::cast_sign_loss
+)]
+fn write_heatmap(
+    out: &mut impl Write,
+    groups: HashMap<String, HashMap<DateTime<Utc>, Aggregate>>,
+    bucketing: &Bucketing,
+    latest: Option<DateTime<Utc>>,
+    heatmap: Heatmap,
+    args: &Args,
+) -> Result<(), Error> {
+    let window_start = window_start(bucketing, latest, args);
+    let (columns, row_width) = match heatmap {
+        Heatmap::Hours => (24, Duration::days(1)),
+        Heatmap::Minutes => (60, Duration::hours(1)),
+    };
+    // The cells of each row, by the local date/time the row starts at. A cell can get more than one
+    // bucket when clocks go back for daylight saving time.
+    let mut rows: BTreeMap<NaiveDateTime, Vec<HeatmapCell>> = BTreeMap::new();
+    for (bucket, aggregate) in groups.into_iter().flat_map(|(_, buckets)| buckets) {
+        if window_start.is_some_and(|start| bucket < start) {
+            continue;
+        }
+        let local = local_time(bucket, bucketing);
+        let (row, column) = match heatmap {
+            Heatmap::Hours => (local.date().and_hms(0, 0, 0), local.hour()),
+            Heatmap::Minutes => (local.date().and_hms(local.hour(), 0, 0), local.minute()),
+        };
+        let cell = &mut rows.entry(row).or_insert_with(|| vec![None; columns])[column as usize];
+        match cell {
+            Some((_, existing)) => existing.merge(&aggregate),
+            None => *cell = Some((bucket, aggregate)),
+        }
+    }
+    if args.fill_empty_buckets {
+        fill_heatmap_rows(&mut rows, columns, row_width, args.max_fill)?;
+    }
+    let values: Vec<HeatmapRow> = rows
+        .into_iter()
+        .map(|(row, cells)| {
+            let empty = args.new_aggregate();
+            let values = cells
+                .iter()
+                .map(|cell| match cell {
+                    Some((bucket, aggregate)) => {
+                        (args.values(aggregate, bucketing, *bucket)[0], aggregate.count() == 0)
+                    }
+                    None => (args.values(&empty, bucketing, DateTime::from_utc(row, Utc))[0], true),
+                })
+                .collect();
+            (row, values)
+        })
+        .collect();
+    let ordered: Box<dyn Iterator<Item = &HeatmapRow>> = match args.order {
+        DateTimeOrder::Ascending => Box::new(values.iter()),
+        DateTimeOrder::Descending => Box::new(values.iter().rev()),
+    };
+    let row_format = match heatmap {
+        Heatmap::Hours => "%Y-%m-%d",
+        Heatmap::Minutes => "%Y-%m-%d %H:00",
+    };
+
+    if !args.heatmap_grid {
+        write!(out, "{}", if columns == 24 { "date" } else { "hour" })?;
+        for column in 0..columns {
+            write!(out, ",{column:02}")?;
+        }
+        writeln!(out)?;
+        for (row, values) in ordered {
+            write!(out, "{}", row.format(row_format))?;
+            for (value, empty) in values {
+                match (&args.fill_value, empty) {
+                    (Some(fill_value), true) => write!(out, ",{fill_value}")?,
+                    _ => write!(out, ",{}", value.map_or_else(String::new, |value| value.to_string()))?,
+                }
+            }
+            writeln!(out)?;
+        }
+        return Ok(());
+    }
+
+    // Each cell is two characters wide, shaded by its value relative to the largest.
+    let max = values
+        .iter()
+        .flat_map(|(_, values)| values.iter().filter_map(|(value, _)| *value))
+        .fold(0.0, f64::max);
+    let label_width = if columns == 24 { 10 } else { 16 };
+    write!(out, "{:label_width$} ", "")?;
+    for column in 0..columns {
+        // Only every third column is numbered, so that the numbers don't run together.
+        if column % 3 == 0 {
+            write!(out, "{column:<6}")?;
+        }
+    }
+    writeln!(out)?;
+    for (row, values) in ordered {
+        write!(out, "{} ", row.format(row_format))?;
+        for (value, _) in values {
+            match value {
+                Some(value) if *value > 0.0 => {
+                    let shade = (value / max * (HEATMAP_COLORS.len() - 1) as f64).round() as usize;
+                    write!(out, "\x1b[48;5;{}m  \x1b[0m", HEATMAP_COLORS[shade])?;
+                }
+                _ => write!(out, "\u{b7} ")?,
+            }
+        }
+        writeln!(out)?;
+    }
+    writeln!(out, "{:label_width$} \u{b7} is 0, up to {max} at the hottest", "")?;
+    Ok(())
+}
+
+// Print the spans of empty buckets of a series in normal mode which last at least as long as
+// --gaps, rather than the buckets.
+fn write_gaps(
+    out: &mut impl Write,
+    mut groups: Vec<(String, HashMap<DateTime<Utc>, Aggregate>)>,
+    bucketing: &Bucketing,
+    latest: Option<DateTime<Utc>>,
+    min_gap: Duration,
+    prefix: &str,
+    args: &Args,
+) -> IoResult<()> {
+    let grouped = args.group_by.is_some() || args.per_file;
+    let window_start = window_start(bucketing, latest, args);
+    let start = args
+        .fill_from
+        .or(args.since)
+        .map(|start| bucketing.bucketize(&start))
+        .max(window_start);
+    let end = args.fill_to.or(args.until);
+
+    if groups.is_empty() && !grouped {
+        // Without any entries, the whole window is a gap.
+        groups.push((String::new(), HashMap::new()));
+    }
+    let mut gaps: Vec<(DateTime<Utc>, DateTime<Utc>, String)> = Vec::new();
+    for (group, buckets) in groups {
+        let mut buckets: Vec<DateTime<Utc>> = buckets
+            .into_iter()
+            .filter(|(bucket, aggregate)| aggregate.count() > 0 && window_start.is_none_or(|start| *bucket >= start))
+            .map(|(bucket, _)| bucket)
+            .collect();
+        buckets.sort_unstable();
+        // A gap runs from the end of each bucket with entries to the start of the next one.
+        let mut gap_start = start;
+        for bucket in buckets.into_iter().map(Some).chain(Some(end)) {
+            if let (Some(gap_start), Some(gap_end)) = (gap_start, bucket) {
+                if gap_end - gap_start >= min_gap {
+                    gaps.push((gap_start, gap_end, group.clone()));
+                }
+            }
+            gap_start = bucket.map(|bucket| bucketing.successor(&bucket));
+        }
+    }
+    match args.order {
+        DateTimeOrder::Ascending => gaps.sort_unstable(),
+        DateTimeOrder::Descending => gaps.sort_unstable_by(|left, right| right.cmp(left)),
+    }
+
+    for (gap_start, gap_end, group) in gaps {
+        write!(out, "{prefix}{},{},", args.label(gap_start), args.label(gap_end))?;
+        if grouped {
+            write!(out, "{},", csv_field(&group))?;
+        }
+        writeln!(out, "{}", (gap_end - gap_start).num_seconds())?;
+    }
+    Ok(())
+}
+
+// Print the buckets of a series in normal mode folded into a row for each part of a --by cycle.
+fn write_cycle(
+    out: &mut impl Write,
+    groups: Vec<(String, HashMap<DateTime<Utc>, Aggregate>)>,
+    bucketing: &Bucketing,
+    latest: Option<DateTime<Utc>>,
+    cycle: Cycle,
+    args: &Args,
+) -> IoResult<()> {
+    let labels = cycle.labels();
+    let window_start = window_start(bucketing, latest, args);
+    let groups: Vec<(String, Vec<Aggregate>)> = groups
+        .into_iter()
+        .map(|(group, buckets)| {
+            let mut rows = vec![args.new_aggregate(); labels.len()];
+            for (bucket, aggregate) in buckets {
+                if window_start.is_none_or(|start| bucket >= start) {
+                    rows[cycle.row(local_time(bucket, bucketing))].merge(&aggregate);
+                }
+            }
+            (group, rows)
+        })
+        .collect();
+    let fields = |aggregate: &Aggregate| {
+        if let (Some(fill_value), 0) = (&args.fill_value, aggregate.count()) {
+            return vec![fill_value.as_str(); args.value_columns().len()].join(",");
+        }
+        args.aggregations
+            .iter()
+            .map(|aggregation| aggregate.format(*aggregation))
+            .chain(args.expression.as_ref().map(|expression| {
+                expression
+                    .evaluate(aggregate)
+                    .map_or_else(String::new, |value| value.to_string())
+            }))
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+    let mut order: Vec<usize> = (0..labels.len()).collect();
+    if let DateTimeOrder::Descending = args.order {
+        order.reverse();
+    }
+
+    if args.wide {
+        write_wide_header(out, "bucket", groups.iter().map(|(group, _)| group.as_str()), args)?;
+        for row in order {
+            write!(out, "{}", labels[row])?;
+            for (_, rows) in &groups {
+                write!(out, ",{}", fields(&rows[row]))?;
+            }
+            writeln!(out)?;
+        }
+    } else {
+        let grouped = args.group_by.is_some() || args.per_file;
+        for row in order {
+            for (group, rows) in &groups {
+                if grouped {
+                    writeln!(out, "{},{},{}", labels[row], csv_field(group), fields(&rows[row]))?;
+                } else {
+                    writeln!(out, "{},{}", labels[row], fields(&rows[row]))?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// The local date/time at which a bucket starts, in the --timezone if one is given.
+fn local_time(bucket: DateTime<Utc>, bucketing: &Bucketing) -> NaiveDateTime {
+    match bucketing.timezone() {
+        Some(timezone) => bucket.with_timezone(&timezone).naive_local(),
+        None => bucket.naive_utc(),
+    }
+}
+
+#[cfg(test)]
+mod cycle_tests {
+    use super::{test_args, write_cycle, write_heatmap, Aggregate, Cycle};
+    use chrono::{DateTime, NaiveDate, TimeZone, Utc, Weekday};
+    use hashbrown::HashMap;
+
+    // A series of a single entry in each of these hours of 2019-03-11.
+    fn series(hours: &[u32]) -> HashMap<DateTime<Utc>, Aggregate> {
+        hours
+            .iter()
+            .map(|hour| {
+                let mut aggregate = Aggregate::new();
+                aggregate.add(None, None);
+                (Utc.ymd(2019, 3, 11).and_hms(*hour, 0, 0), aggregate)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn folds_days_of_the_week() {
+        let cases = vec![
+            (Weekday::Mon, 11, 0, "Mon"),
+            (Weekday::Mon, 17, 6, "Sun"),
+            (Weekday::Sun, 17, 0, "Sun"),
+            (Weekday::Sun, 16, 6, "Sat"),
+        ];
+        for (week_start, day, row, label) in cases {
+            let cycle = Cycle::DayOfWeek(week_start);
+            // 2019-03-11 is a Monday.
+            let local = NaiveDate::from_ymd(2019, 3, day).and_hms(13, 0, 0);
+            assert_eq!(cycle.row(local), row, "2019-03-{day} from {week_start:?}");
+            assert_eq!(cycle.labels()[row], label);
+        }
+    }
+
+    #[test]
+    fn folds_hours_of_the_day() {
+        let cases = vec![((0, 0), 0, "00"), ((9, 59), 9, "09"), ((23, 30), 23, "23")];
+        for ((hour, minute), row, label) in cases {
+            let local = NaiveDate::from_ymd(2019, 3, 11).and_hms(hour, minute, 0);
+            assert_eq!(Cycle::HourOfDay.row(local), row, "{hour}:{minute}");
+            assert_eq!(Cycle::HourOfDay.labels()[row], label);
+        }
+    }
+
+    #[test]
+    fn keeps_to_the_last_window() {
+        let latest = Some(Utc.ymd(2019, 3, 11).and_hms(3, 30, 0));
+
+        let args = test_args(&["tbuck", "%F %T", "--heatmap=hour", "--last=2h"]);
+        let mut out = Vec::new();
+        let groups = vec![(String::new(), series(&[0, 1, 2, 3]))].into_iter().collect();
+        write_heatmap(
+            &mut out,
+            groups,
+            &args.bucketings[0],
+            latest,
+            args.heatmap.unwrap(),
+            &args,
+        )
+        .unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .ends_with("\n2019-03-11,0,1,1,1,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0\n"));
+
+        let args = test_args(&["tbuck", "%F %T", "--by=hour-of-day", "--last=2h"]);
+        let mut out = Vec::new();
+        write_cycle(
+            &mut out,
+            vec![(String::new(), series(&[0, 1, 2, 3]))],
+            &args.bucketings[0],
+            latest,
+            args.by.unwrap(),
+            &args,
+        )
+        .unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .starts_with("00,0\n01,1\n02,1\n03,1\n04,0\n"));
+    }
+
+    #[test]
+    fn fills_empty_cells_with_the_fill_value() {
+        let args = test_args(&["tbuck", "%F %T", "--heatmap=hour", "--fill-value=NaN"]);
+        let mut out = Vec::new();
+        let groups = vec![(String::new(), series(&[1, 2]))].into_iter().collect();
+        write_heatmap(
+            &mut out,
+            groups,
+            &args.bucketings[0],
+            None,
+            args.heatmap.unwrap(),
+            &args,
+        )
+        .unwrap();
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .ends_with("\n2019-03-11,NaN,1,1,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN,NaN\n"));
+
+        let args = test_args(&[
+            "tbuck",
+            "%F %T",
+            "--by=hour-of-day",
+            "--agg=count,sum",
+            "--value=x=(\\d+)",
+            "--fill-value=",
+        ]);
+        let mut out = Vec::new();
+        write_cycle(
+            &mut out,
+            vec![(String::new(), series(&[1]))],
+            &args.bucketings[0],
+            None,
+            args.by.unwrap(),
+            &args,
+        )
+        .unwrap();
+        assert!(String::from_utf8(out).unwrap().starts_with("00,,\n01,1,0\n02,,\n"));
+    }
+}
+
+// Quote a CSV field if it contains anything which would otherwise be misread.
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+// The value of a CSV field which may be quoted, as written by csv_field.
+fn csv_unquote(field: &str) -> Cow<'_, str> {
+    match field.strip_prefix('"').and_then(|field| field.strip_suffix('"')) {
+        Some(quoted) => Cow::Owned(quoted.replace("\"\"", "\"")),
+        None => Cow::Borrowed(field),
+    }
+}
+
+// Lay out rows of CSV as a table drawn with box-drawing characters, with numbers right-aligned.
+// Without a header, the first row is the header, and rows repeating it are left out.
+fn write_table(out: &mut impl Write, csv: &[u8], header: Option<Vec<String>>) -> IoResult<()> {
+    let mut rows: Vec<Vec<String>> = Vec::new();
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(csv);
+    for record in reader.records() {
+        let record = record.map_err(IoError::other)?;
+        rows.push(record.iter().map(str::to_string).collect());
+    }
+    let header = match header {
+        Some(header) => header,
+        None if rows.is_empty() => Vec::new(),
+        None => {
+            let header = rows.remove(0);
+            rows.retain(|row| *row != header);
+            header
+        }
+    };
+    let columns = rows.iter().map(Vec::len).chain(Some(header.len())).max().unwrap_or(0);
+    let mut widths = vec![0; columns];
+    for row in rows.iter().chain(Some(&header)) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let rule = |left: &str, middle: &str, right: &str| {
+        let lines: Vec<String> = widths.iter().map(|width| "─".repeat(width + 2)).collect();
+        format!("{left}{}{right}", lines.join(middle))
+    };
+    let write_row = |out: &mut dyn Write, row: &[String]| {
+        write!(out, "│")?;
+        for (index, width) in widths.iter().enumerate() {
+            let cell = row.get(index).map_or("", String::as_str);
+            if cell.parse::<f64>().is_ok() {
+                write!(out, " {cell:>width$} │")?;
+            } else {
+                write!(out, " {cell:<width$} │")?;
+            }
+        }
+        writeln!(out)
+    };
+    writeln!(out, "{}", rule("┌", "┬", "┐"))?;
+    write_row(out, &header)?;
+    writeln!(out, "{}", rule("├", "┼", "┤"))?;
+    for row in &rows {
+        write_row(out, row)?;
+    }
+    writeln!(out, "{}", rule("└", "┴", "┘"))
+}
+
+#[cfg(test)]
+mod output_tests {
+    use super::{
+        csv_field, csv_unquote, run, test_args, test_run, write_normal_series, write_table, Buffered, Output, Trailing,
+    };
+    use chrono::{DateTime, TimeZone, Utc};
+    use hashbrown::HashMap;
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::rc::Rc;
+    use tbuck::Aggregate;
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        let cases = vec![
+            ("200", "200"),
+            ("", ""),
+            ("a,b", "\"a,b\""),
+            ("say \"hi\"", "\"say \"\"hi\"\"\""),
+            ("two\nlines", "\"two\nlines\""),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(csv_field(input), expected);
+            assert_eq!(csv_unquote(expected), input);
+        }
+    }
+
+    #[test]
+    fn divides_counts_and_sums_by_the_width() {
+        let cases = vec![
+            (vec![], "0.03333333333333333"),
+            (vec!["-g", "2m"], "0.016666666666666666"),
+            (vec!["--agg", "sum", "--value", "ms=(\\d+)"], "0.6666666666666666"),
+            // Other aggregations, like averages, don't grow with the width.
+            (vec!["--agg", "avg", "--value", "ms=(\\d+)"], "20"),
+            // Calendar buckets are divided by their own length, 29 days for February 2024.
+            (vec!["-g", "1mo"], "0.0000007982120051085569"),
+        ];
+        for (options, expected) in cases {
+            let args = test_args(&[&["tbuck", "%F %T", "--rate"], options.as_slice()].concat());
+            // Two lines taking 10 and 30 ms.
+            let mut aggregate = args.new_aggregate();
+            aggregate.add(Some(10.0), None);
+            aggregate.add(Some(30.0), None);
+            let bucket = Utc.ymd(2024, 2, 1).and_hms(0, 0, 0);
+            let fields = Trailing::default().fields(&aggregate, args.bucketing(), bucket, &args);
+            assert_eq!(fields, expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn adds_a_moving_average() {
+        // How long the lines of each of four buckets took, in ms.
+        let rows: Vec<&[f64]> = vec![&[10.0, 30.0], &[], &[20.0], &[60.0]];
+        let cases = vec![
+            (vec!["--moving-avg", "2"], vec!["2,2", "0,1", "1,0.5", "1,1"]),
+            // Rows without a value are left out of the average.
+            (
+                vec!["--moving-avg", "3", "--agg", "avg", "--value", "ms=(\\d+)"],
+                vec!["20,20", ",20", "20,20", "60,40"],
+            ),
+        ];
+        for (options, expected) in cases {
+            let args = test_args(&[&["tbuck", "%F %T"], options.as_slice()].concat());
+            let mut trailing = Trailing::default();
+            let fields: Vec<String> = (0..)
+                .zip(&rows)
+                .map(|(minute, values)| {
+                    let mut aggregate = args.new_aggregate();
+                    for value in *values {
+                        aggregate.add(Some(*value), None);
+                    }
+                    let bucket = Utc.ymd(2024, 5, 14).and_hms(10, minute, 0);
+                    trailing.fields(&aggregate, args.bucketing(), bucket, &args)
+                })
+                .collect();
+            assert_eq!(fields, expected, "{options:?}");
+        }
+    }
+
+    // The buckets of each group, with how many entries each has in the minutes from 10:00 on.
+    fn test_groups(counts: &[(&str, &[usize])]) -> Vec<(String, HashMap<DateTime<Utc>, Aggregate>)> {
+        let mut groups = Vec::new();
+        for (group, counts) in counts {
+            let mut buckets = HashMap::new();
+            for (minute, count) in (0..).zip(*counts) {
+                // Empty buckets are left to be filled in, as when counting.
+                if *count > 0 {
+                    let aggregate = buckets
+                        .entry(Utc.ymd(2024, 5, 14).and_hms(10, minute, 0))
+                        .or_insert_with(Aggregate::new);
+                    for _ in 0..*count {
+                        aggregate.add(None, None);
+                    }
+                }
+            }
+            groups.push((group.to_string(), buckets));
+        }
+        groups
+    }
+
+    #[test]
+    fn adds_each_buckets_share_of_the_total() {
+        let cases = vec![
+            (
+                vec![],
+                vec![("", &[2, 0, 1, 1][..])],
+                "2024-05-14 10:00:00 UTC,2,50\n\
+                 2024-05-14 10:01:00 UTC,0,0\n\
+                 2024-05-14 10:02:00 UTC,1,25\n\
+                 2024-05-14 10:03:00 UTC,1,25\n",
+            ),
+            // Each group adds up to 100.
+            (
+                vec!["--group-by", "(GET|POST)"],
+                vec![("GET", &[1, 0, 1, 1][..]), ("POST", &[1][..])],
+                "2024-05-14 10:00:00 UTC,GET,1,33.33333333333333\n\
+                 2024-05-14 10:00:00 UTC,POST,1,100\n\
+                 2024-05-14 10:01:00 UTC,GET,0,0\n\
+                 2024-05-14 10:01:00 UTC,POST,0,0\n\
+                 2024-05-14 10:02:00 UTC,GET,1,33.33333333333333\n\
+                 2024-05-14 10:02:00 UTC,POST,0,0\n\
+                 2024-05-14 10:03:00 UTC,GET,1,33.33333333333333\n\
+                 2024-05-14 10:03:00 UTC,POST,0,0\n",
+            ),
+        ];
+        for (options, counts, expected) in cases {
+            let args = test_args(&[&["tbuck", "%F %T", "--percent"], options.as_slice()].concat());
+            let mut out = Vec::new();
+            write_normal_series(&mut out, &test_groups(&counts), args.bucketing(), None, "", &args).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn reports_buckets_breaching_the_alert_thresholds() {
+        let all = "2024-05-14 10:00:00 UTC,2\n\
+                   2024-05-14 10:01:00 UTC,0\n\
+                   2024-05-14 10:02:00 UTC,1\n\
+                   2024-05-14 10:03:00 UTC,1\n";
+        let cases = vec![
+            (vec!["--alert-over", "5"], all, 0),
+            (vec!["--alert-over", "1"], all, 1),
+            (vec!["--alert-over", "1", "--alert-under", "1"], all, 2),
+            (
+                vec!["--alert-over", "1", "--alert-only"],
+                "2024-05-14 10:00:00 UTC,2\n",
+                1,
+            ),
+            (
+                vec!["--alert-under", "1", "--alert-only"],
+                "2024-05-14 10:01:00 UTC,0\n",
+                1,
+            ),
+            // Compared after --rate.
+            (
+                vec!["--alert-over", "0.02", "--rate", "--alert-only"],
+                "2024-05-14 10:00:00 UTC,0.03333333333333333\n",
+                1,
+            ),
+        ];
+        for (options, expected, expected_breaches) in cases {
+            let args = test_args(&[&["tbuck", "%F %T"], options.as_slice()].concat());
+            let groups = test_groups(&[("", &[2, 0, 1, 1])]);
+            let mut out = Vec::new();
+            let breaches = write_normal_series(&mut out, &groups, args.bucketing(), None, "", &args).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), expected, "{options:?}");
+            assert_eq!(breaches, expected_breaches, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn replaces_the_output_file_only_on_success() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("app.log");
+        let output = dir.path().join("out.csv");
+        std::fs::write(&input, "2024-05-14 10:02:00 a\n2024-05-14 10:00:00 b\n").unwrap();
+        std::fs::write(&output, "previous\n").unwrap();
+        let (input, output) = (input.to_str().unwrap(), output.to_str().unwrap());
+        let cases = vec![
+            // Fails part way, since stream mode needs the entries in order.
+            (vec!["--stream"], false, "previous\n"),
+            (
+                vec![],
+                true,
+                "2024-05-14 10:00:00 UTC,1\n2024-05-14 10:01:00 UTC,0\n2024-05-14 10:02:00 UTC,1\n",
+            ),
+        ];
+        for (options, succeeds, expected) in cases {
+            let args = test_args(&[&["tbuck", "%F %T", input, "--output-file", output], options.as_slice()].concat());
+            assert_eq!(run(&args).is_ok(), succeeds, "{options:?}");
+            assert_eq!(std::fs::read_to_string(output).unwrap(), expected, "{options:?}");
+            // No temporary file is left behind either way.
+            assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2, "{options:?}");
+        }
+    }
+
+    // Lines of GET and POST requests, with how long each took, in three of four minutes.
+    const LINES: &str = "2024-05-14 10:00:10 GET ms=10\n\
+                         2024-05-14 10:00:20 POST ms=30\n\
+                         2024-05-14 10:02:05 GET ms=20\n\
+                         2024-05-14 10:03:00 GET ms=60\n";
+
+    #[test]
+    fn fills_a_fixed_window() {
+        let cases = vec![
+            (
+                vec![
+                    "--fill-from",
+                    "2024-05-14T09:58:00Z",
+                    "--fill-to",
+                    "2024-05-14T10:03:00Z",
+                ],
+                "2024-05-14 09:58:00 UTC,0\n\
+                 2024-05-14 09:59:00 UTC,0\n\
+                 2024-05-14 10:00:00 UTC,2\n\
+                 2024-05-14 10:01:00 UTC,0\n\
+                 2024-05-14 10:02:00 UTC,0\n",
+            ),
+            // Buckets with entries outside the window are still printed.
+            (
+                vec!["--fill-from", "2024-05-14T10:01:00Z"],
+                "2024-05-14 10:00:00 UTC,2\n",
+            ),
+            (
+                vec!["--fill-from", "2024-05-14T09:59:00Z", "--group-by", "(GET|POST)"],
+                "2024-05-14 09:59:00 UTC,GET,0\n\
+                 2024-05-14 09:59:00 UTC,POST,0\n\
+                 2024-05-14 10:00:00 UTC,GET,1\n\
+                 2024-05-14 10:00:00 UTC,POST,1\n",
+            ),
+        ];
+        for (options, expected) in cases {
+            let options = [options.as_slice(), &["--until", "2024-05-14T10:01:00Z"]].concat();
+            let (output, result) = test_run(&options, LINES);
+            result.unwrap();
+            assert_eq!(output, expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn reports_spans_of_empty_buckets() {
+        let lines = "2024-05-14 10:00:10 GET\n\
+                     2024-05-14 10:01:00 GET\n\
+                     2024-05-14 10:04:30 GET\n\
+                     2024-05-14 10:05:00 POST\n\
+                     2024-05-14 10:07:00 GET\n";
+        let cases = vec![
+            (
+                vec!["--gaps", "1m"],
+                "2024-05-14 10:02:00 UTC,2024-05-14 10:04:00 UTC,120\n\
+                 2024-05-14 10:06:00 UTC,2024-05-14 10:07:00 UTC,60\n",
+            ),
+            (
+                vec!["--gaps", "2m"],
+                "2024-05-14 10:02:00 UTC,2024-05-14 10:04:00 UTC,120\n",
+            ),
+            (vec!["--gaps", "3m"], ""),
+            // The span after the last entry is only known up to --fill-to.
+            (
+                vec!["--gaps", "2m", "--fill-to", "2024-05-14T10:10:00Z"],
+                "2024-05-14 10:02:00 UTC,2024-05-14 10:04:00 UTC,120\n\
+                 2024-05-14 10:08:00 UTC,2024-05-14 10:10:00 UTC,120\n",
+            ),
+            (
+                vec!["--gaps", "2m", "--group-by", "(GET|POST)"],
+                "2024-05-14 10:02:00 UTC,2024-05-14 10:04:00 UTC,GET,120\n\
+                 2024-05-14 10:05:00 UTC,2024-05-14 10:07:00 UTC,GET,120\n",
+            ),
+        ];
+        for (options, expected) in cases {
+            let (output, result) = test_run(&options, lines);
+            result.unwrap();
+            assert_eq!(output, expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn counts_sessions() {
+        // a's and b's second lines are each more than 90s after their first, so start new sessions.
+        let lines = "2024-05-14 10:00:10 user=a\n\
+                     2024-05-14 10:00:20 user=b\n\
+                     2024-05-14 10:01:50 user=a\n\
+                     2024-05-14 10:02:05 user=b\n\
+                     2024-05-14 10:03:00 user=a\n\
+                     2024-05-14 10:03:30 nobody\n";
+        let cases = vec![
+            (
+                "--sessions",
+                "2024-05-14 10:00:00 UTC,2\n\
+                 2024-05-14 10:01:00 UTC,1\n\
+                 2024-05-14 10:02:00 UTC,1\n",
+            ),
+            (
+                "--sessions=active",
+                "2024-05-14 10:00:00 UTC,2\n\
+                 2024-05-14 10:01:00 UTC,1\n\
+                 2024-05-14 10:02:00 UTC,2\n\
+                 2024-05-14 10:03:00 UTC,1\n",
+            ),
+        ];
+        for (sessions, expected) in cases {
+            let options = [sessions, "--session-gap", "90s", "--session-key", "user=(\\w+)"];
+            let (output, result) = test_run(&options, lines);
+            result.unwrap();
+            assert_eq!(output, expected, "{sessions}");
+        }
+    }
+
+    #[test]
+    fn tables_are_aligned() {
+        let cases = vec![
+            (
+                "a,12\n\"b, é\",3\n".to_string(),
+                Some(vec!["bucket".to_string(), "count".to_string()]),
+                "┌────────┬───────┐\n\
+                 │ bucket │ count │\n\
+                 ├────────┼───────┤\n\
+                 │ a      │    12 │\n\
+                 │ b, é   │     3 │\n\
+                 └────────┴───────┘\n",
+            ),
+            (
+                "bucket,x\na,1\nbucket,x\nb,\n".to_string(),
+                None,
+                "┌────────┬───┐\n\
+                 │ bucket │ x │\n\
+                 ├────────┼───┤\n\
+                 │ a      │ 1 │\n\
+                 │ b      │   │\n\
+                 └────────┴───┘\n",
+            ),
+        ];
+        for (csv, header, expected) in cases {
+            let mut out = Vec::new();
+            write_table(&mut out, csv.as_bytes(), header).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), expected, "{csv}");
+        }
+    }
+
+    #[test]
+    fn writes_out_each_row_when_flushing() {
+        // Records what was written out, while the Buffered writer is still holding on to it.
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let cases = vec![(false, ""), (true, "a,1\n")];
+        for (by_line, expected) in cases {
+            let written = Rc::new(RefCell::new(Vec::new()));
+            let mut buffered = Buffered::new(Shared(written.clone()), by_line);
+            write!(buffered, "a,1\nb,").unwrap();
+            assert_eq!(
+                String::from_utf8(written.borrow().clone()).unwrap(),
+                expected,
+                "{by_line}"
+            );
+            buffered.into_inner().unwrap();
+            assert_eq!(
+                String::from_utf8(written.borrow().clone()).unwrap(),
+                "a,1\nb,",
+                "{by_line}"
+            );
+        }
+    }
+
+    #[test]
+    fn flushes_rows_of_stdout_when_wanted_right_away() {
+        let cases = vec![
+            (vec![], false),
+            (vec!["--flush"], true),
+            (vec!["--stream"], true),
+            (vec!["--sorted-input"], true),
+        ];
+        for (options, by_line) in cases {
+            let args = test_args(&[&["tbuck", "%F %T"], options.as_slice()].concat());
+            match Output::create(&args).unwrap() {
+                Output::Stdout(writer) => {
+                    let writer = writer.into_inner().unwrap();
+                    assert_eq!(matches!(writer, Buffered::Lines(_)), by_line, "{options:?}");
+                }
+                _ => panic!("{:?} should print to stdout", options),
+            }
+        }
+    }
+
+    #[test]
+    fn labels_buckets_in_rfc3339() {
+        let cases = vec![
+            (vec![], "2024-05-14 10:00:00 UTC,2\n2024-05-14 10:01:00 UTC,0\n"),
+            (vec!["--rfc3339"], "2024-05-14T10:00:00Z,2\n2024-05-14T10:01:00Z,0\n"),
+            (
+                vec!["--timezone", "Europe/Paris"],
+                "2024-05-14 12:00:00 CEST,2\n2024-05-14 12:01:00 CEST,0\n",
+            ),
+            (
+                vec!["--rfc3339", "--timezone", "Europe/Paris"],
+                "2024-05-14T12:00:00+02:00,2\n2024-05-14T12:01:00+02:00,0\n",
+            ),
+        ];
+        for (options, expected) in cases {
+            let options = [options.as_slice(), &["--until", "2024-05-14T10:02:00Z"]].concat();
+            let (output, result) = test_run(&options, LINES);
+            result.unwrap();
+            assert_eq!(output, expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn prints_a_column_per_aggregation() {
+        let cases = vec![
+            (
+                vec!["--agg", "count,sum,max"],
+                "2024-05-14 10:00:00 UTC,2,40,30\n\
+                 2024-05-14 10:01:00 UTC,0,0,\n\
+                 2024-05-14 10:02:00 UTC,1,20,20\n",
+            ),
+            (
+                vec!["--agg", "count,sum", "--group-by", "(GET|POST)", "--wide"],
+                "bucket,GET:count,GET:sum,POST:count,POST:sum\n\
+                 2024-05-14 10:00:00 UTC,1,10,1,30\n\
+                 2024-05-14 10:01:00 UTC,0,0,0,0\n\
+                 2024-05-14 10:02:00 UTC,1,20,0,0\n",
+            ),
+            (
+                vec!["--agg", "count,sum", "--output", "table"],
+                "┌─────────────────────────┬───────┬─────┐\n\
+                 │ bucket                  │ count │ sum │\n\
+                 ├─────────────────────────┼───────┼─────┤\n\
+                 │ 2024-05-14 10:00:00 UTC │     2 │  40 │\n\
+                 │ 2024-05-14 10:01:00 UTC │     0 │   0 │\n\
+                 │ 2024-05-14 10:02:00 UTC │     1 │  20 │\n\
+                 └─────────────────────────┴───────┴─────┘\n",
+            ),
+        ];
+        for (options, expected) in cases {
+            let options = [
+                options.as_slice(),
+                &["--value", "ms=(\\d+)", "--until", "2024-05-14T10:03:00Z"],
+            ]
+            .concat();
+            let (output, result) = test_run(&options, LINES);
+            result.unwrap();
+            assert_eq!(output, expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn adds_the_change_per_second() {
+        let cases = vec![
+            (
+                vec![],
+                "2024-05-14 10:00:00 UTC,2,\n\
+                 2024-05-14 10:01:00 UTC,0,-0.03333333333333333\n\
+                 2024-05-14 10:02:00 UTC,1,0.016666666666666666\n\
+                 2024-05-14 10:03:00 UTC,1,0\n",
+            ),
+            // Divided by the time since the previous row printed.
+            (
+                vec!["--no-fill"],
+                "2024-05-14 10:00:00 UTC,2,\n\
+                 2024-05-14 10:02:00 UTC,1,-0.008333333333333333\n\
+                 2024-05-14 10:03:00 UTC,1,0\n",
+            ),
+            (
+                vec!["--group-by", "(GET|POST)", "--until", "2024-05-14T10:02:00Z"],
+                "2024-05-14 10:00:00 UTC,GET,1,\n\
+                 2024-05-14 10:00:00 UTC,POST,1,\n\
+                 2024-05-14 10:01:00 UTC,GET,0,-0.016666666666666666\n\
+                 2024-05-14 10:01:00 UTC,POST,0,-0.016666666666666666\n",
+            ),
+        ];
+        for (options, expected) in cases {
+            let options = [options.as_slice(), &["--derivative"]].concat();
+            let (output, result) = test_run(&options, LINES);
+            result.unwrap();
+            assert_eq!(output, expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn adds_an_exponentially_weighted_moving_average() {
+        let cases = vec![
+            (
+                vec!["--ewma", "0.5"],
+                "2024-05-14 10:00:00 UTC,2,2\n\
+                 2024-05-14 10:01:00 UTC,0,1\n\
+                 2024-05-14 10:02:00 UTC,1,1\n\
+                 2024-05-14 10:03:00 UTC,1,1\n",
+            ),
+            // Empty buckets have no average, and leave the smoothed value as it was.
+            (
+                vec!["--ewma", "0.25", "--agg", "avg", "--value", "ms=(\\d+)"],
+                "2024-05-14 10:00:00 UTC,20,20\n\
+                 2024-05-14 10:01:00 UTC,,20\n\
+                 2024-05-14 10:02:00 UTC,20,20\n\
+                 2024-05-14 10:03:00 UTC,60,30\n",
+            ),
+        ];
+        for (options, expected) in cases {
+            let (output, result) = test_run(&options, LINES);
+            result.unwrap();
+            assert_eq!(output, expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn interpolates_empty_buckets() {
+        let lines = "2024-05-14 10:00:10 GET ms=10\n\
+                     2024-05-14 10:03:00 GET ms=40\n\
+                     2024-05-14 10:04:00 POST ms=5\n";
+        let cases = vec![
+            // Counts are left as they are.
+            (
+                vec!["--agg", "count,max"],
+                "2024-05-14 10:00:00 UTC,1,10\n\
+                 2024-05-14 10:01:00 UTC,0,20\n\
+                 2024-05-14 10:02:00 UTC,0,30\n\
+                 2024-05-14 10:03:00 UTC,1,40\n\
+                 2024-05-14 10:04:00 UTC,1,5\n",
+            ),
+            // Buckets outside the values of a series have nothing to interpolate between.
+            (
+                vec![
+                    "--agg",
+                    "avg",
+                    "--group-by",
+                    "(GET|POST)",
+                    "--since",
+                    "2024-05-14T10:02:00Z",
+                ],
+                "2024-05-14 10:02:00 UTC,GET,\n\
+                 2024-05-14 10:02:00 UTC,POST,\n\
+                 2024-05-14 10:03:00 UTC,GET,40\n\
+                 2024-05-14 10:03:00 UTC,POST,\n\
+                 2024-05-14 10:04:00 UTC,GET,\n\
+                 2024-05-14 10:04:00 UTC,POST,5\n",
+            ),
+            (
+                vec!["--agg", "avg", "--fill-to", "2024-05-14T10:06:00Z"],
+                "2024-05-14 10:00:00 UTC,10\n\
+                 2024-05-14 10:01:00 UTC,20\n\
+                 2024-05-14 10:02:00 UTC,30\n\
+                 2024-05-14 10:03:00 UTC,40\n\
+                 2024-05-14 10:04:00 UTC,5\n\
+                 2024-05-14 10:05:00 UTC,\n",
+            ),
+        ];
+        for (options, expected) in cases {
+            let options = [options.as_slice(), &["--value", "ms=(\\d+)", "--fill", "interpolate"]].concat();
+            let (output, result) = test_run(&options, lines);
+            result.unwrap();
+            assert_eq!(output, expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn names_the_columns() {
+        let cases = vec![
+            (vec![], vec!["bucket", "count"]),
+            (vec!["--rate"], vec!["bucket", "count/s"]),
+            (
+                vec!["--rate", "--agg", "count,avg,sum", "--value", "ms=(\\d+)"],
+                vec!["bucket", "count/s", "avg", "sum/s"],
+            ),
+            (
+                vec![
+                    "--agg",
+                    "count,sum",
+                    "--value",
+                    "ms=(\\d+)",
+                    "--group-by",
+                    "(GET|POST)",
+                    "--delta",
+                ],
+                vec!["bucket", "group", "count", "sum", "delta"],
+            ),
+            (
+                vec!["-g", "1m", "-g", "1h", "--delta-only"],
+                vec!["granularity", "bucket", "delta"],
+            ),
+        ];
+        for (options, expected) in cases {
+            let args = test_args(&[&["tbuck", "%F %T"], options.as_slice()].concat());
+            assert_eq!(args.table_header(), expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn computes_expressions_of_optional_fields() {
+        let lines = "2024-05-14 10:00:10 ms=10 bytes=100\n\
+                     2024-05-14 10:00:20 ms=30\n\
+                     2024-05-14 10:00:30 ms=20 bytes=500\n";
+        let cases = vec![
+            // A line without bytes counts, but not towards sum(bytes).
+            (r"ms=(\d+)(?: bytes=(?P<bytes>\d+))?", "2024-05-14 10:00:00 UTC,3,200\n"),
+            // Without the field being optional, the line isn't counted at all.
+            (r"ms=(\d+) bytes=(?P<bytes>\d+)", "2024-05-14 10:00:00 UTC,2,300\n"),
+        ];
+        for (value, expected) in cases {
+            let (output, result) = test_run(&["--value", value, "--expr", "sum(bytes)/count"], lines);
+            result.unwrap();
+            assert_eq!(output, expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn compares_two_windows_of_an_input() {
+        let window = [
+            "--compare",
+            "--compare-offset",
+            "2m",
+            "--since",
+            "2024-05-14T10:00:00Z",
+            "--until",
+            "2024-05-14T10:02:00Z",
+        ];
+        let cases = vec![
+            (
+                vec![],
+                "2024-05-14 10:00:00 UTC,2,1,-1,0.5\n\
+                 2024-05-14 10:01:00 UTC,0,1,1,\n",
+            ),
+            // The header is only printed once.
+            (
+                vec!["-g", "1m", "-g", "2m", "--fill-value", "-"],
+                "1m,2024-05-14 10:00:00 UTC,2,1,-1,0.5\n\
+                 1m,2024-05-14 10:01:00 UTC,-,1,,\n\
+                 2m,2024-05-14 10:00:00 UTC,2,2,0,1\n",
+            ),
+        ];
+        for (options, expected) in cases {
+            let (output, result) = test_run(&[&window[..], &options].concat(), LINES);
+            result.unwrap();
+            let (header, rows) = output.split_once('\n').unwrap();
+            assert!(header.ends_with("/app.log+2m,difference,ratio"), "{:?}", header);
+            assert_eq!(rows, expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn compares_two_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let (before, after, output) = (
+            dir.path().join("before.log"),
+            dir.path().join("after.log"),
+            dir.path().join("out.csv"),
+        );
+        std::fs::write(
+            &before,
+            "2024-05-14 10:00:10 GET avg=10\n2024-05-14 10:02:10 GET avg=30\n",
+        )
+        .unwrap();
+        std::fs::write(
+            &after,
+            "2024-05-14 10:00:20 GET avg=20\n2024-05-14 10:01:20 GET avg=40\n2024-05-14 10:02:20 GET avg=60\n",
+        )
+        .unwrap();
+        let (before, after, output) = (
+            before.to_str().unwrap(),
+            after.to_str().unwrap(),
+            output.to_str().unwrap(),
+        );
+        let cases = vec![
+            (vec![], "10,20,10,2\n,40,,\n30,60,30,2\n"),
+            // The empty bucket of the first input is interpolated.
+            (vec!["--fill", "interpolate"], "10,20,10,2\n20,40,20,2\n30,60,30,2\n"),
+        ];
+        for (options, expected) in cases {
+            let options = [
+                &[
+                    "tbuck",
+                    "%F %T",
+                    before,
+                    after,
+                    "--compare",
+                    "--value",
+                    r"avg=(\d+)",
+                    "--agg",
+                    "avg",
+                    "--output-file",
+                    output,
+                ],
+                &options[..],
+            ]
+            .concat();
+            run(&test_args(&options)).unwrap();
+            let written = std::fs::read_to_string(output).unwrap();
+            let mut lines = written.lines();
+            assert_eq!(
+                lines.next().unwrap(),
+                format!("bucket,{before},{after},difference,ratio")
+            );
+            let values: String = lines
+                .map(|line| line.split_once(',').unwrap().1.to_string() + "\n")
+                .collect();
+            assert_eq!(values, expected, "{options:?}");
+        }
+    }
+
+    #[test]
+    fn counts_continuation_lines_when_asked() {
+        let lines = "2024-05-14 10:00:10 ERROR failed\n\
+                     \x20 at parse\n\
+                     \x20 at main\n\
+                     2024-05-14 10:01:10 INFO done\n\
+                     no date/time\n";
+        let cases = vec![
+            (
+                vec!["--record-start", r"^\d{4}-"],
+                "2024-05-14 10:00:00 UTC,1\n2024-05-14 10:01:00 UTC,1\n",
+            ),
+            (
+                vec!["--record-start", r"^\d{4}-", "--attach-continuations"],
+                "2024-05-14 10:00:00 UTC,3\n2024-05-14 10:01:00 UTC,2\n",
+            ),
+            // Continuation lines are still left out without --attach-continuations.
+            (
+                vec!["--record-start", r"^\d{4}-", "--carry-forward"],
+                "2024-05-14 10:00:00 UTC,1\n2024-05-14 10:01:00 UTC,1\n",
+            ),
+            (
+                vec!["--carry-forward"],
+                "2024-05-14 10:00:00 UTC,3\n2024-05-14 10:01:00 UTC,2\n",
+            ),
+        ];
+        for (options, expected) in cases {
+            let (output, result) = test_run(&options, lines);
+            result.unwrap();
+            assert_eq!(output, expected, "{options:?}");
+        }
+    }
+}
+
+// Entries which were dropped instead of counted, so the loss can be reported at the end.
+#[derive(Debug, Default)]
+struct Discarded {
+    count: u64,
+    // Earliest and latest date/time of the dropped entries.
+    range: Option<(DateTime<Utc>, DateTime<Utc>)>,
+}
+
+impl Discarded {
+    fn record(&mut self, entry: DateTime<Utc>) {
+        self.count += 1;
+        self.range = Some(match self.range {
+            None => (entry, entry),
+            Some((earliest, latest)) => (earliest.min(entry), latest.max(entry)),
+        });
+    }
+
+    // Print a warning to stderr describing the dropped entries, if there were any.
+    fn report(&self, reason: &str) {
+        if let Some((earliest, latest)) = self.range {
+            eprintln!(
+                "Discarded {} entries dated from {earliest} to {latest} {reason}",
+                self.count
+            );
+        }
+    }
+}
+
+// Print a bucket, preceded by empty buckets back to the previously printed bucket unless
+// --no-fill was specified.
+fn write_filled_bucket(
+    out: &Output,
+    previous: Option<DateTime<Utc>>,
+    bucket: DateTime<Utc>,
+    aggregate: &Aggregate,
+    trailing: &mut Trailing,
+    args: &Args,
+) -> Result<(), Error> {
+    if let (Some(previous), true) = (previous, args.fill_empty_buckets) {
+        let empty = args.new_aggregate();
+        let mut next_bucket = args.bucketing().successor(&previous);
+        check_fill(next_bucket, bucket, args.bucketing(), args.max_fill)?;
+        while next_bucket < bucket {
+            trailing.write_row(
+                &mut out.lock_for(next_bucket)?,
+                "",
+                &empty,
+                args.bucketing(),
+                next_bucket,
+                args,
+            )?;
+            next_bucket = args.bucketing().successor(&next_bucket);
+        }
+    }
+    trailing.write_row(
+        &mut out.lock_for(bucket)?,
+        "",
+        aggregate,
+        args.bucketing(),
+        bucket,
+        args,
+    )?;
+    Ok(())
+}
+
+// Check that the empty buckets from the first one until the next bucket with entries, which are
+// about to be filled in, are within --max-fill. A date/time mis-parsed far from the rest fails
+// before anything is printed for the gap rather than flooding the output.
+fn check_fill(first: DateTime<Utc>, until: DateTime<Utc>, bucketing: &Bucketing, max_fill: usize) -> Result<(), Error> {
+    // Worked out from the width rather than by walking the gap, which would take as long as filling
+    // it in. Buckets which don't divide a day evenly, or which change length with the clocks, make
+    // this off by a few, which is close enough to tell a mis-parsed date/time.
+    let filled = if let Some(width) = bucketing.granularity().width() {
+        let (gap, width) = ((until - first).num_seconds(), width.num_seconds());
+        // A gap ending before it starts has no buckets.
+        usize::try_from((gap + width - 1).div_euclid(width)).unwrap_or(0)
+    } else {
+        // Months and quarters vary in length, so are walked, but no further than needed.
+        let mut bucket = first;
+        let mut filled = 0;
+        while bucket < until && filled <= max_fill {
+            filled += 1;
+            bucket = bucketing.successor(&bucket);
+        }
+        filled
+    };
+    if filled > max_fill {
+        return Err(Error::MaxFill {
+            from: first,
+            until,
+            max_fill,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod fill_tests {
+    use super::{check_fill, output_buckets, test_run, Error};
+    use chrono::{DateTime, TimeZone, Utc};
+    use std::collections::BTreeMap;
+    use tbuck::{Bucketing, Granularity};
+
+    #[test]
+    fn counts_the_buckets_of_a_gap() {
+        let at = |month, day, hour| Utc.ymd(2024, month, day).and_hms(hour, 0, 0);
+        let cases = vec![
+            ("1h", at(5, 14, 10), at(5, 14, 13), 3, true),
+            ("1h", at(5, 14, 10), at(5, 14, 13), 2, false),
+            ("1h", at(5, 14, 10), at(5, 14, 10), 0, true),
+            ("1h", at(5, 14, 13), at(5, 14, 10), 0, true),
+            // Months vary in length, so are walked.
+            ("1mo", at(1, 1, 0), at(5, 1, 0), 4, true),
+            ("1mo", at(1, 1, 0), at(5, 1, 0), 3, false),
+            (
+                "1s",
+                Utc.ymd(1970, 1, 1).and_hms(0, 0, 0),
+                at(5, 14, 10),
+                1_000_000,
+                false,
+            ),
+        ];
+        for (granularity, first, until, max_fill, within) in cases {
+            let bucketing = Bucketing::new(Granularity::parse(granularity).unwrap());
+            let result = check_fill(first, until, &bucketing, max_fill);
+            assert_eq!(result.is_ok(), within, "{granularity} {first} {until} {max_fill}");
+        }
+    }
+
+    #[test]
+    fn limits_the_buckets_filled_in_between_earlier_outputs() {
+        let at = |minute| Utc.ymd(2024, 5, 14).and_hms(10, minute, 0);
+        let series: BTreeMap<DateTime<Utc>, f64> = vec![(at(0), 1.0), (at(4), 2.0)].into_iter().collect();
+        let bucketing = Bucketing::new(Granularity::parse("1m").unwrap());
+        let buckets = output_buckets(std::iter::once(&series), &bucketing, true, 3).unwrap();
+        assert_eq!(buckets, (0..=4).map(at).collect::<Vec<_>>());
+        assert!(output_buckets(std::iter::once(&series), &bucketing, true, 2).is_err());
+        assert_eq!(
+            output_buckets(std::iter::once(&series), &bucketing, false, 0).unwrap(),
+            vec![at(0), at(4)]
+        );
+    }
+
+    #[test]
+    fn fails_rather_than_fill_in_too_many() {
+        let lines = "2024-05-14 10:00:10 a\n2024-05-17 10:03:00 a\n";
+        let cases = vec![
+            (vec!["-g", "1h", "--max-fill", "71"], true),
+            (vec!["-g", "1h", "--max-fill", "70"], false),
+            (vec!["-g", "1h", "--max-fill", "0", "--no-fill"], true),
+            // Whole rows of a heatmap are filled in.
+            (vec!["--heatmap", "hour", "--max-fill", "2"], true),
+            (vec!["--heatmap", "hour", "--max-fill", "1"], false),
+        ];
+        for (options, within) in cases {
+            let (output, result) = test_run(&options, lines);
+            match result {
+                Ok(()) => assert!(within, "{:?} should have failed", options),
+                Err(Error::MaxFill { .. }) => {
+                    assert!(!within, "{:?} failed", options);
+                    // Nothing is printed for the gap.
+                    assert!(!output.contains(",0\n"), "{:?}", output);
+                }
+                Err(err) => panic!("{:?} failed: {}", options, err),
+            }
+        }
+    }
+}
+
+// The rows printed so far for one series, which the columns derived from earlier rows, like
+// --moving-avg, are computed from.
+#[derive(Debug, Default, Clone)]
+struct Trailing {
+    // The first aggregation of the most recent rows, oldest first, as many as are needed.
+    recent: VecDeque<Option<f64>>,
+    // The exponentially weighted moving average of the rows so far, once one had a value.
+    smoothed: Option<f64>,
+    // The first aggregation of the previous row, if there was one and it had a value.
+    previous: Option<f64>,
+    // The bucket of the previous row, if there was one.
+    previous_bucket: Option<DateTime<Utc>>,
+    // The first aggregation summed over every row of the series, when known up front.
+    total: Option<f64>,
+    // Whether the previous row breached the alert thresholds.
+    breached: bool,
+    // Number of rows which breached the alert thresholds.
+    breaches: u64,
+    // The first aggregation of every row printed, only kept for --summary.
+    printed: Vec<f64>,
+    // Rows whose first aggregation is over this are spikes, with --spike-sigma.
+    spike_over: Option<f64>,
+    // Whether the previous row is highlighted, with --color.
+    highlighted: bool,
+    // The values of the empty buckets of the series, with --fill interpolate.
+    interpolated: HashMap<DateTime<Utc>, Vec<Option<f64>>>,
+}
+
+impl Trailing {
+    // The state for printing a whole series of buckets, whose total is known up front.
+    fn for_series(
+        buckets: &HashMap<DateTime<Utc>, Aggregate>,
+        filled_buckets: &[DateTime<Utc>],
+        bucketing: &Bucketing,
+        args: &Args,
+    ) -> Self {
+        let total = filled_buckets
+            .iter()
+            .filter_map(|bucket| {
+                let aggregate = buckets.get(bucket)?;
+                args.values(aggregate, bucketing, *bucket)[0]
+            })
+            .sum();
+        Self {
+            total: Some(total),
+            spike_over: args
+                .spike_sigma
+                .and_then(|sigma| spike_threshold(buckets, filled_buckets, bucketing, sigma, args)),
+            interpolated: if args.interpolate {
+                interpolate(buckets, filled_buckets, bucketing, args)
+            } else {
+                HashMap::new()
+            },
+            ..Self::default()
+        }
+    }
+
+    // The fields of the next row, separated by commas: the aggregations of its bucket followed by
+    // the derived columns. The row is then remembered for the rows after it.
+    fn fields(&mut self, aggregate: &Aggregate, bucketing: &Bucketing, bucket: DateTime<Utc>, args: &Args) -> String {
+        let values = self.values(aggregate, bucketing, bucket, args);
+        let fields = self.format(&values, aggregate.count() == 0, bucket, args);
+        if let Some(window) = args.moving_avg {
+            self.recent.push_back(values[0]);
+            // Together with the next row, these make up its window.
+            while self.recent.len() >= window {
+                self.recent.pop_front();
+            }
+        }
+        if let Some(alpha) = args.ewma {
+            self.smoothed = self.smooth(values[0], alpha);
+        }
+        self.previous = values[0];
+        self.previous_bucket = Some(bucket);
+        self.breached = values[0].is_some_and(|value| {
+            args.alert_over.is_some_and(|over| value > over) || args.alert_under.is_some_and(|under| value < under)
+        });
+        self.highlighted =
+            args.color && (self.breached || values[0].zip(self.spike_over).is_some_and(|(value, over)| value > over));
+        fields
+    }
+
+    // Count the previous row as printed, if it would be: with --alert-only, only rows which
+    // breached are. Rows which are left out for other reasons aren't admitted at all, so that they
+    // don't count as breaches.
+    fn admit(&mut self, args: &Args) -> bool {
+        if self.breached {
+            self.breaches += 1;
+        }
+        let admitted = !args.alert_only || self.breached;
+        if let (true, Some(_), Some(value)) = (admitted, args.summary, self.previous) {
+            self.printed.push(value);
+        }
+        admitted
+    }
+
+    // Write the --summary rows of the rows printed, labeled in place of the bucket, and followed by
+    // the group if the series is one of several.
+    #[allow(clippy::cast_precision_loss)]
+    fn write_summary(&self, out: &mut impl Write, prefix: &str, group: Option<&str>) -> IoResult<()> {
+        let mut values = self.printed.clone();
+        values.sort_unstable_by(f64::total_cmp);
+        // Folded from 0 rather than summed, which starts from -0.
+        let total = values.iter().fold(0.0, |total, value| total + value);
+        let middle = values.len() / 2;
+        let median = match values.len() {
+            0 => None,
+            len if len % 2 == 1 => Some(values[middle]),
+            _ => Some(f64::midpoint(values[middle - 1], values[middle])),
+        };
+        let mean = if values.is_empty() {
+            None
+        } else {
+            Some(total / values.len() as f64)
+        };
+        let group = group.map_or_else(String::new, |group| format!("{},", csv_field(group)));
+        for (name, value) in [
+            ("total", Some(total)),
+            ("min", values.first().copied()),
+            ("max", values.last().copied()),
+            ("mean", mean),
+            ("median", median),
+        ] {
+            let value = value.map_or_else(String::new, |value| value.to_string());
+            writeln!(out, "{prefix}{name},{group}{value}")?;
+        }
+        Ok(())
+    }
+
+    // Print the next row of a series which is printed one row per bucket.
+    fn write_row(
+        &mut self,
+        out: &mut impl Write,
+        prefix: &str,
+        aggregate: &Aggregate,
+        bucketing: &Bucketing,
+        bucket: DateTime<Utc>,
+        args: &Args,
+    ) -> IoResult<()> {
+        let fields = self.fields(aggregate, bucketing, bucket, args);
+        if self.admit(args) {
+            writeln!(
+                out,
+                "{}",
+                paint(format!("{prefix}{},{fields}", args.label(bucket)), self.highlighted)
+            )?;
+        }
+        Ok(())
+    }
+
+    // Like fields, but without remembering the row, for rows which will be printed again.
+    fn peek_fields(&self, aggregate: &Aggregate, bucketing: &Bucketing, bucket: DateTime<Utc>, args: &Args) -> String {
+        self.format(
+            &self.values(aggregate, bucketing, bucket, args),
+            aggregate.count() == 0,
+            bucket,
+            args,
+        )
+    }
+
+    // The values of a bucket, or those interpolated for it if it is empty.
+    fn values(
+        &self,
+        aggregate: &Aggregate,
+        bucketing: &Bucketing,
+        bucket: DateTime<Utc>,
+        args: &Args,
+    ) -> Vec<Option<f64>> {
+        match self.interpolated.get(&bucket) {
+            Some(values) if aggregate.count() == 0 => values.clone(),
+            _ => args.values(aggregate, bucketing, bucket),
+        }
+    }
+
+    // The exponentially weighted moving average including a row with this value.
+    fn smooth(&self, value: Option<f64>, alpha: f64) -> Option<f64> {
+        match (self.smoothed, value) {
+            (Some(smoothed), Some(value)) => Some(alpha * value + (1.0 - alpha) * smoothed),
+            (smoothed, None) => smoothed,
+            (None, value) => value,
+        }
+    }
+
+    #[allow(clippy::cast_precision_loss)]
+    fn format(&self, values: &[Option<f64>], empty: bool, bucket: DateTime<Utc>, args: &Args) -> String {
+        let format = |value: Option<f64>| value.map_or_else(String::new, |value| value.to_string());
+        let mut fields: Vec<String> = if args.delta_only {
+            Vec::new()
+        } else if let (Some(fill_value), true) = (&args.fill_value, empty) {
+            vec![fill_value.clone(); values.len()]
+        } else {
+            values.iter().copied().map(format).collect()
+        };
+        if args.moving_avg.is_some() {
+            // Rows without a value, like the average of an empty bucket, are left out.
+            let window: Vec<f64> = self.recent.iter().copied().chain(Some(values[0])).flatten().collect();
+            let average = if window.is_empty() {
+                None
+            } else {
+                Some(window.iter().sum::<f64>() / window.len() as f64)
+            };
+            fields.push(format(average));
+        }
+        if let Some(alpha) = args.ewma {
+            fields.push(format(self.smooth(values[0], alpha)));
+        }
+        if args.delta {
+            fields.push(format(
+                self.previous.zip(values[0]).map(|(previous, value)| value - previous),
+            ));
+        }
+        if args.derivative {
+            // Rows may be more than a bucket apart without filling, so the gap is measured.
+            let seconds = self
+                .previous_bucket
+                .map(|previous| (bucket - previous).num_milliseconds() as f64 / 1000.0)
+                .filter(|seconds| *seconds != 0.0);
+            fields.push(format(
+                self.previous
+                    .zip(values[0])
+                    .zip(seconds)
+                    .map(|((previous, value), seconds)| (value - previous) / seconds),
+            ));
+        }
+        if args.percent {
+            let total = self.total.filter(|total| *total != 0.0);
+            fields.push(format(total.zip(values[0]).map(|(total, value)| value / total * 100.0)));
+        }
+        fields.join(",")
+    }
+}
+
+#[cfg(test)]
+mod summary_tests {
+    use super::{count_args, test_args, Trailing};
+    use chrono::{TimeZone, Utc};
+    use clap::App;
+
+    #[test]
+    fn summarizes() {
+        let cases = vec![
+            (vec![], None, "total,0\nmin,\nmax,\nmean,\nmedian,\n"),
+            (
+                vec![3.0, 0.0, 5.0],
+                None,
+                "total,8\nmin,0\nmax,5\nmean,2.6666666666666665\nmedian,3\n",
+            ),
+            (
+                vec![4.0, 1.0, 2.0, 1.0],
+                Some("a,b"),
+                "total,\"a,b\",8\nmin,\"a,b\",1\nmax,\"a,b\",4\nmean,\"a,b\",2\nmedian,\"a,b\",1.5\n",
+            ),
+        ];
+        for (printed, group, expected) in cases {
+            let trailing = Trailing {
+                printed: printed.clone(),
+                ..Trailing::default()
+            };
+            let mut out = Vec::new();
+            trailing.write_summary(&mut out, "", group).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), expected, "{printed:?}");
+        }
+    }
+
+    #[test]
+    fn smooths_exponentially() {
+        let cases = vec![
+            (None, Some(4.0), 0.5, Some(4.0)),
+            (Some(4.0), Some(2.0), 0.5, Some(3.0)),
+            (Some(4.0), Some(0.0), 0.25, Some(3.0)),
+            (Some(4.0), None, 0.5, Some(4.0)),
+            (None, None, 0.5, None),
+        ];
+        for (smoothed, value, alpha, expected) in cases {
+            let trailing = Trailing {
+                smoothed,
+                ..Trailing::default()
+            };
+            assert_eq!(
+                trailing.smooth(value, alpha),
+                expected,
+                "{smoothed:?} {value:?} {alpha}"
+            );
+        }
+    }
+
+    #[test]
+    fn prints_only_the_changes() {
+        let cases = vec![
+            (vec!["--delta-only"], vec!["", "2", "-5"]),
+            (
+                vec!["--delta-only", "--derivative"],
+                vec![",", "2,0.03333333333333333", "-5,-0.08333333333333333"],
+            ),
+        ];
+        for (flags, expected) in cases {
+            let args = test_args(&[&["tbuck", "%F %T"], flags.as_slice()].concat());
+            let mut trailing = Trailing::default();
+            let rows: Vec<String> = [(0, 3.0), (1, 5.0), (2, 0.0)]
+                .iter()
+                .map(|(minute, value)| {
+                    let bucket = Utc.ymd(2024, 5, 14).and_hms(10, *minute, 0);
+                    let fields = trailing.format(&[Some(*value)], false, bucket, &args);
+                    trailing.previous = Some(*value);
+                    trailing.previous_bucket = Some(bucket);
+                    fields
+                })
+                .collect();
+            assert_eq!(rows, expected, "{flags:?}");
+        }
+    }
+
+    #[test]
+    fn changes_only_exclude_other_columns() {
+        for flag in &["--moving-avg=3", "--ewma=0.5", "--percent"] {
+            let matches =
+                count_args(App::new("tbuck"), "1m").get_matches_from_safe(["tbuck", "%F %T", "--delta-only", flag]);
+            assert!(matches.is_err(), "{}", flag);
+        }
+    }
+}
+
+// Write the --summary of each series, with its group if it is one of several, to the output or to
+// stderr.
+fn write_summaries(
+    out: &mut impl Write,
+    series: &[(Option<&str>, &Trailing)],
+    prefix: &str,
+    args: &Args,
+) -> IoResult<()> {
+    let write = |out: &mut dyn Write| {
+        for (group, trailing) in series {
+            trailing.write_summary(&mut &mut *out, prefix, *group)?;
+        }
+        Ok(())
+    };
+    match args.summary {
+        None => Ok(()),
+        Some(SummaryTarget::Output) => write(out),
+        Some(SummaryTarget::Stderr) => write(&mut std::io::stderr().lock()),
+    }
+}
+
+// The order that datetime entries are expected in stream mode OR the order that buckets
+// will be printed in normal mode.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum DateTimeOrder {
+    Ascending,
+    Descending,
+}
+
+impl DateTimeOrder {
+    // Name of the order for use in messages.
+    fn name(self) -> &'static str {
+        match self {
+            DateTimeOrder::Ascending => "ascending",
+            DateTimeOrder::Descending => "descending",
+        }
+    }
+}
+
+// Number of lines at the start of each input file which --order auto samples.
+const ORDER_SAMPLE_LINES: usize = 1000;
+
+// The order of the inputs: as given, or with --order auto, the order sampled from each of them.
+// Inputs are counted as one, so must all be in the same order, but those too short to tell go
+// along with the rest. If none can tell, the order is ascending.
+fn resolve_order(args: &Args) -> Result<DateTimeOrder, clap::Error> {
+    if !args.detect_order {
+        return Ok(args.order);
+    }
+    let mut sampled: Option<(DateTimeOrder, &Input)> = None;
+    for input in &args.inputs {
+        let order = sample_order(input, args).map_err(|err| {
+            clap::Error::with_description(
+                &format!("Failed to sample {} for --order auto: {err}", input.name()),
+                clap::ErrorKind::Io,
+            )
+        })?;
+        match (order, sampled) {
+            (Some(order), Some((first, first_input))) if order != first => {
+                return Err(clap::Error::with_description(
+                    &format!(
+                        "--order auto found {} to be {} but {} to be {}; count them separately or give --order asc or desc",
+                        first_input.name(),
+                        first.name(),
+                        input.name(),
+                        order.name()
+                    ),
+                    clap::ErrorKind::InvalidValue,
+                ));
+            }
+            (Some(order), None) => sampled = Some((order, input)),
+            _ => {}
+        }
+    }
+    Ok(sampled.map_or(DateTimeOrder::Ascending, |(order, _)| order))
+}
+
+// The order of an input, read the way it is counted: whichever way most of the date/times in its
+// first lines step from the one before them, or None if none of them do. --timezone only changes
+// how buckets are labeled, not how date/times are read, so it has no bearing here.
+fn sample_order(input: &Input, args: &Args) -> IoResult<Option<DateTimeOrder>> {
+    let matcher = match args.column {
+        Some(column) => Matcher::new(&args.datetime_format).at_column(column),
+        None => Matcher::new(&args.datetime_format),
+    };
+    // A CSV column given by name is found in the header of this input.
+    let time_field = args
+        .time_field
+        .as_ref()
+        .map(|time_field| time_field.resolve(std::slice::from_ref(input)))
+        .transpose()?;
+    let skew = args.skew(input);
+    // The number of steps forward in time minus the number of steps back.
+    let mut balance: i64 = 0;
+    let mut previous = None;
+    input.open_bare_read(false, None, |read| {
+        for line in BufReader::new(read).split(b'\n').take(ORDER_SAMPLE_LINES) {
+            let line = line?;
+            let line = String::from_utf8_lossy(&line);
+            let text = match &time_field {
+                None => Cow::Borrowed(line.as_ref()),
+                Some(time_field) => match time_field.extract(&line) {
+                    Some(text) => text,
+                    None => continue,
+                },
+            };
+            let Some(Ok(datetime)) = matcher.find_datetime(&text, args.match_index) else {
+                continue;
+            };
+            let Some(datetime) = args.leap_seconds.apply(datetime) else {
+                continue;
+            };
+            let datetime = datetime - skew;
+            match previous.map(|previous| datetime.cmp(&previous)) {
+                Some(Ordering::Greater) => balance += 1,
+                Some(Ordering::Less) => balance -= 1,
+                _ => {}
+            }
+            previous = Some(datetime);
+        }
+        Ok::<(), IoError>(())
+    })?;
+    Ok(match balance.cmp(&0) {
+        Ordering::Greater => Some(DateTimeOrder::Ascending),
+        Ordering::Less => Some(DateTimeOrder::Descending),
+        Ordering::Equal => None,
+    })
+}
+
+#[cfg(test)]
+mod order_tests {
+    use super::{resolve_order, sample_order, test_args, DateTimeOrder};
+
+    #[test]
+    fn samples_the_order_of_each_input() {
+        let cases = vec![
+            ("ascending", "2024-05-14 10:00:00 a\n2024-05-14 10:00:01 b\n2024-05-14 10:00:05 c\n", Some(DateTimeOrder::Ascending)),
+            ("descending", "2024-05-14 10:00:05 a\n2024-05-14 10:00:01 b\n2024-05-14 10:00:00 c\n", Some(DateTimeOrder::Descending)),
+            // Mostly ascending, with an entry written late.
+            ("mixed", "2024-05-14 10:00:00 a\n2024-05-14 10:00:02 b\n2024-05-14 10:00:01 c\n2024-05-14 10:00:03 d\n2024-05-14 10:00:04 e\n", Some(DateTimeOrder::Ascending)),
+            ("undecided", "2024-05-14 10:00:00 a\nno date/time\n2024-05-14 10:00:00 b\n", None),
+        ];
+        let dir = tempfile::tempdir().unwrap();
+        for (name, lines, expected) in cases {
+            let path = dir.path().join(name);
+            std::fs::write(&path, lines).unwrap();
+            let args = test_args(&["tbuck", "%F %T", path.to_str().unwrap(), "--order", "auto"]);
+            assert_eq!(sample_order(&args.inputs[0], &args).unwrap(), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn resolves_one_order_for_all_inputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, lines: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, lines).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        let ascending = write("ascending", "2024-05-14 10:00:00 a\n2024-05-14 10:00:01 b\n");
+        let descending = write("descending", "2024-05-14 10:00:01 a\n2024-05-14 10:00:00 b\n");
+        let short = write("short", "2024-05-14 10:00:00 a\n");
+        let cases = vec![
+            (vec![&descending, &short], Some(DateTimeOrder::Descending)),
+            (vec![&short], Some(DateTimeOrder::Ascending)),
+            (vec![&ascending, &descending], None),
+        ];
+        for (inputs, expected) in cases {
+            let inputs: Vec<&str> = inputs.iter().map(|input| input.as_str()).collect();
+            let args = test_args(&[&["tbuck", "%F %T", "--order", "auto"], &inputs[..]].concat());
+            assert_eq!(resolve_order(&args).ok(), expected, "{inputs:?}");
+        }
+    }
+}
+
+// Where the program can take its input from.
+#[derive(Debug, Clone)]
+enum Input {
+    Stdin,
+    File(PathBuf),
+    // The systemd journal, or the entries of one unit.
+    Journal(Option<String>),
+    // A Windows Event Log file.
+    Evtx(PathBuf),
+    // Syslog messages received over the network.
+    Listen(Listener),
+    // The messages of a Kafka topic.
+    Kafka(KafkaSource),
+}
+
+// Format of the timestamps at the start of journalctl's short-unix output, and of the lines
+// rendered for the records of event logs.
+const EPOCH_FORMAT: &str = "%s";
+
+impl Input {
+    // Name of the input for use in messages and reports.
+    fn name(&self) -> String {
+        match self {
+            Input::Stdin => "<stdin>".to_string(),
+            Input::File(path) | Input::Evtx(path) => path.display().to_string(),
+            Input::Journal(None) => "<journal>".to_string(),
+            Input::Journal(Some(unit)) => format!("<journal:{unit}>"),
+            Input::Listen(listener) => listener.to_string(),
+            Input::Kafka(source) => format!("<kafka:{}>", source.topic),
+        }
+    }
+
+    // Like open_bare_read, for only part of a file.
+    fn open_range<E: From<IoError>>(
+        &self,
+        range: Range<u64>,
+        mut f: impl FnMut(&mut dyn Read) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let Input::File(path) = self else {
+            unreachable!("only files are resumed");
+        };
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(range.start))?;
+        f(&mut file.take(range.end - range.start))
+    }
+
+    // Invoke a callback function that accepts a `&mut dyn Read` for dynamic dispatch based on the
+    // type of input. If `follow` is set, file inputs never report end of file and instead wait for
+    // more data to be appended. Stdin already blocks, so it is unaffected. Inputs which wait for
+    // more data end on SIGINT, as if they had ended.
+    fn open_bare_read<E: From<IoError>>(
+        &self,
+        follow: bool,
+        limit: Option<InputLimit>,
+        mut f: impl FnMut(&mut dyn Read) -> Result<(), E>,
+    ) -> Result<(), E> {
+        // Only as much of the input as the limit allows is read, if there is one.
+        let mut limited = |read: &mut dyn Read| match limit {
+            Some(limit) => f(&mut LimitedRead {
+                read,
+                limit,
+                reached: false,
+            }),
+            None => f(read),
+        };
+        match self {
+            Input::Stdin => {
+                let mut stdin = read_stdin();
+                limited(&mut stdin)
+            }
+            Input::File(path) if follow => {
+                let mut follower = FollowReader::open(path)?;
+                limited(&mut follower)
+            }
+            Input::File(path) => {
+                let mut file = File::open(path)?;
+                limited(&mut file)
+            }
+            Input::Journal(unit) => {
+                let mut command = process::Command::new("journalctl");
+                command.args(["--no-pager", "--quiet", "--output", "short-unix"]);
+                if let Some(unit) = unit {
+                    command.args(["--unit", unit]);
+                }
+                if follow {
+                    command.arg("--follow");
+                }
+                let mut child = command
+                    .stdout(Stdio::piped())
+                    .spawn()
+                    .map_err(|err| IoError::new(err.kind(), format!("Failed to run journalctl: {err}")))?;
+                let mut stdout = child.stdout.take().expect("stdout is piped");
+                let result = limited(&mut stdout);
+                // If reading stopped early, closing the pipe ends journalctl with SIGPIPE, which
+                // isn't a failure. Otherwise it has finished on its own.
+                drop(stdout);
+                let status = child.wait()?;
+                if result.is_ok() && status.code().is_some_and(|code| code != 0) {
+                    return Err(IoError::other(format!("journalctl failed: {status}")).into());
+                }
+                result
+            }
+            Input::Evtx(path) => {
+                let lines = render_evtx(&std::fs::read(path)?)?;
+                limited(&mut lines.as_slice())
+            }
+            Input::Listen(listener) => {
+                let mut messages = listener.listen()?;
+                limited(&mut messages)
+            }
+            Input::Kafka(source) => {
+                let mut messages = source.consume(follow)?;
+                limited(&mut messages)
+            }
+        }
+    }
+}
+
+// A network address that syslog messages are received on, either as UDP datagrams or as lines
+// sent over TCP connections.
+#[derive(Debug, Clone)]
+enum Listener {
+    Udp(String),
+    Tcp(String),
+}
+
+// Largest syslog message that is received whole over UDP, which is the most a datagram can hold.
+const MAX_DATAGRAM_SIZE: usize = 65_535;
+
+impl Listener {
+    // Parse a listening address like 'udp://0.0.0.0:5514' or 'tcp://[::]:5514'.
+    fn parse(url: &str) -> Result<Self, String> {
+        let listener = if let Some(address) = url.strip_prefix("udp://") {
+            Listener::Udp(address.to_string())
+        } else if let Some(address) = url.strip_prefix("tcp://") {
+            Listener::Tcp(address.to_string())
+        } else {
+            return Err("Must start with udp:// or tcp://".to_string());
+        };
+        match listener
+            .address()
+            .to_socket_addrs()
+            .map(|mut addresses| addresses.next())
+        {
+            Ok(Some(_)) => Ok(listener),
+            Ok(None) => Err(format!("No addresses found for {}", listener.address())),
+            Err(err) => Err(format!("Not a valid HOST:PORT address: {err}")),
+        }
+    }
+
+    fn address(&self) -> &str {
+        match self {
+            Listener::Udp(address) | Listener::Tcp(address) => address,
+        }
+    }
+
+    // Start receiving messages, which can then be read as lines that each start with the time the
+    // message arrived in seconds. Messages are stamped on arrival rather than parsed for their own
+    // timestamp, since traditional syslog timestamps have neither a year nor a timezone, and so
+    // that they are in order for stream mode however far the senders' clocks have drifted.
+    fn listen(&self) -> IoResult<MessageRead> {
+        let (sender, receiver) = mpsc::channel();
+        match self {
+            Listener::Udp(address) => {
+                let socket = UdpSocket::bind(address.as_str())?;
+                std::thread::spawn(move || {
+                    let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+                    // Stops if the socket fails or the reading side has gone away.
+                    while let Ok(len) = socket.recv(&mut buf) {
+                        if sender
+                            .send(Ok(stamp_message(Utc::now().timestamp(), &buf[..len])))
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                });
+            }
+            Listener::Tcp(address) => {
+                let listener = TcpListener::bind(address.as_str())?;
+                std::thread::spawn(move || {
+                    for stream in listener.incoming() {
+                        // A connection which failed to be accepted only affects that sender.
+                        let Ok(stream) = stream else {
+                            continue;
+                        };
+                        let sender = sender.clone();
+                        std::thread::spawn(move || {
+                            // Messages are framed by newlines, the usual framing for syslog over TCP.
+                            for message in BufReader::new(stream).split(b'\n') {
+                                let Ok(message) = message else {
+                                    break;
+                                };
+                                if sender
+                                    .send(Ok(stamp_message(Utc::now().timestamp(), &message)))
+                                    .is_err()
+                                {
+                                    break;
+                                }
+                            }
+                        });
+                    }
+                });
+            }
+        }
+        Ok(MessageRead::new(receiver, interrupted))
+    }
+}
+
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Udp(address) => write!(f, "udp://{address}"),
+            Listener::Tcp(address) => write!(f, "tcp://{address}"),
+        }
+    }
+}
+
+// Render a received message as a line starting with its time in seconds. Line endings within the
+// message are replaced so that it stays a single line, and bytes which aren't UTF-8 are replaced
+// too, since senders don't always encode messages as UTF-8.
+fn stamp_message(seconds: i64, message: &[u8]) -> Vec<u8> {
+    let text = String::from_utf8_lossy(message);
+    let text = text.trim_end_matches(['\r', '\n', '\0']).replace(['\r', '\n'], " ");
+    format!("{seconds} {text}\n").into_bytes()
+}
+
+// A Kafka topic to read the messages of, from a comma-separated list of brokers.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+struct KafkaSource {
+    brokers: String,
+    topic: String,
+    from_beginning: bool,
+}
+
+#[cfg(test)]
+mod kafka_tests {
+    use super::{test_args, Input};
+
+    #[test]
+    fn reads_from_the_beginning_unless_following() {
+        let cases = vec![
+            (vec![], true),
+            (vec!["--from-beginning"], true),
+            (vec!["--stream", "--follow"], false),
+            (vec!["--stream", "--follow", "--from-beginning"], true),
+        ];
+        for (options, from_beginning) in cases {
+            let args = test_args(
+                &[
+                    &["tbuck", "--kafka", "broker:9092", "--topic", "logs"],
+                    options.as_slice(),
+                ]
+                .concat(),
+            );
+            match &args.inputs[..] {
+                [Input::Kafka(source)] => assert_eq!(source.from_beginning, from_beginning, "{options:?}"),
+                inputs => panic!("{:?}", inputs),
+            }
+        }
+    }
+}
+
+// How long to wait for the brokers to describe the topic before giving up.
+#[cfg(feature = "kafka")]
+const KAFKA_METADATA_TIMEOUT: StdDuration = StdDuration::from_secs(30);
+
+impl KafkaSource {
+    // Start consuming every partition of the topic, either from the beginning or from the messages
+    // produced from now on. Partitions are assigned directly rather than joining a consumer group,
+    // so that counting neither commits offsets nor rebalances anyone else's consumers. Unless
+    // `follow` is set, reading ends once the end of every partition has been reached.
+    #[cfg(feature = "kafka")]
+    fn consume(&self, follow: bool) -> IoResult<KafkaRead> {
+        use rdkafka::config::ClientConfig;
+        use rdkafka::consumer::{BaseConsumer, Consumer};
+        use rdkafka::error::RDKafkaErrorCode;
+        use rdkafka::{Offset, TopicPartitionList};
+
+        let consumer: BaseConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &self.brokers)
+            .set("enable.auto.commit", "false")
+            .set("enable.partition.eof", "true")
+            .create()
+            .map_err(kafka_error)?;
+        let metadata = consumer
+            .fetch_metadata(Some(&self.topic), KAFKA_METADATA_TIMEOUT)
+            .map_err(kafka_error)?;
+        let partitions = match metadata.topics().first() {
+            Some(topic) if topic.error().is_none() => topic.partitions(),
+            Some(topic) => {
+                let code = RDKafkaErrorCode::from(topic.error().expect("checked by the previous arm"));
+                return Err(IoError::other(format!("Kafka topic {}: {code}", self.topic)));
+            }
+            None => return Err(IoError::other(format!("Kafka topic {} wasn't found", self.topic))),
+        };
+        let offset = if self.from_beginning {
+            Offset::Beginning
+        } else {
+            Offset::End
+        };
+        let mut assignment = TopicPartitionList::new();
+        for partition in partitions {
+            assignment
+                .add_partition_offset(&self.topic, partition.id(), offset)
+                .map_err(kafka_error)?;
+        }
+        consumer.assign(&assignment).map_err(kafka_error)?;
+        Ok(KafkaRead {
+            consumer,
+            unfinished: if follow {
+                None
+            } else {
+                Some(
+                    partitions
+                        .iter()
+                        .map(rdkafka::metadata::MetadataPartition::id)
+                        .collect(),
+                )
+            },
+            pending: Vec::new(),
+            offset: 0,
+        })
+    }
+
+    #[cfg(not(feature = "kafka"))]
+    #[allow(clippy::unused_self)]
+    fn consume(&self, _follow: bool) -> IoResult<std::io::Empty> {
+        Err(IoError::new(
+            ErrorKind::Unsupported,
+            "Kafka support wasn't built in, it needs the kafka feature: cargo install tbuck --features kafka",
+        ))
+    }
+}
+
+#[cfg(feature = "kafka")]
+#[allow(clippy::needless_pass_by_value)]
+fn kafka_error(err: rdkafka::error::KafkaError) -> IoError {
+    IoError::other(format!("Kafka error: {err}"))
+}
+
+// Reader over the messages of a Kafka topic, as lines which start with the time each message was
+// produced in seconds.
+#[cfg(feature = "kafka")]
+struct KafkaRead {
+    consumer: rdkafka::consumer::BaseConsumer,
+    // The partitions whose end hasn't been reached yet, unless the topic is being followed.
+    unfinished: Option<std::collections::HashSet<i32>>,
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+#[cfg(feature = "kafka")]
+impl Read for KafkaRead {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        use rdkafka::error::KafkaError;
+        use rdkafka::message::Message;
+
+        while self.offset == self.pending.len() {
+            if self
+                .unfinished
+                .as_ref()
+                .is_some_and(std::collections::HashSet::is_empty)
+            {
+                return Ok(0);
+            }
+            // Polled with a timeout rather than waiting for the next message, so that SIGINT
+            // ends reading as if the topic had.
+            match self.consumer.poll(INTERRUPT_POLL_INTERVAL) {
+                Some(Ok(message)) => {
+                    // Messages without a timestamp, from before Kafka 0.10, are counted as they arrive.
+                    let seconds = message
+                        .timestamp()
+                        .to_millis()
+                        .map_or_else(|| Utc::now().timestamp(), |millis| millis.div_euclid(1000));
+                    self.pending = stamp_message(seconds, message.payload().unwrap_or_default());
+                    self.offset = 0;
+                }
+                Some(Err(KafkaError::PartitionEOF(partition))) => {
+                    if let Some(unfinished) = &mut self.unfinished {
+                        unfinished.remove(&partition);
+                    }
+                }
+                Some(Err(err)) => return Err(kafka_error(err)),
+                None if interrupted() => return Ok(0),
+                None => {}
+            }
+        }
+        let len = (&self.pending[self.offset..]).read(buf)?;
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+// Reader over the messages received by a listener, or the data read from stdin, which waits
+// until the next message arrives. Reading ends once the sending threads have all stopped, or on
+// SIGINT, as if they had.
+struct MessageRead {
+    messages: mpsc::Receiver<IoResult<Vec<u8>>>,
+    // Whether to stop waiting, which is checked every INTERRUPT_POLL_INTERVAL.
+    stop: fn() -> bool,
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+impl MessageRead {
+    fn new(messages: mpsc::Receiver<IoResult<Vec<u8>>>, stop: fn() -> bool) -> Self {
+        MessageRead {
+            messages,
+            stop,
+            pending: Vec::new(),
+            offset: 0,
+        }
+    }
+}
+
+impl Read for MessageRead {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        while self.offset == self.pending.len() {
+            match self.messages.recv_timeout(INTERRUPT_POLL_INTERVAL) {
+                Ok(message) => {
+                    self.pending = message?;
+                    self.offset = 0;
+                }
+                Err(RecvTimeoutError::Timeout) if (self.stop)() => return Ok(0),
+                Err(RecvTimeoutError::Timeout) => {}
+                // The sending threads have all stopped.
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        let len = (&self.pending[self.offset..]).read(buf)?;
+        self.offset += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod message_read_tests {
+    use super::MessageRead;
+    use std::io::{Error as IoError, ErrorKind, Read};
+    use std::sync::mpsc;
+    use std::time::{Duration, Instant};
 
-    Args {
-        datetime_format,
-        match_index,
-        granularity,
-        inputs,
-        fill_empty_buckets,
-        mode,
-        order,
-        tolerant,
+    #[test]
+    fn reads_messages_until_senders_stop() {
+        let (sender, receiver) = mpsc::channel();
+        sender.send(Ok(b"1 a\n".to_vec())).unwrap();
+        sender.send(Ok(b"2 b\n".to_vec())).unwrap();
+        drop(sender);
+        let mut text = String::new();
+        MessageRead::new(receiver, || false).read_to_string(&mut text).unwrap();
+        assert_eq!(text, "1 a\n2 b\n");
+    }
+
+    #[test]
+    fn passes_on_errors() {
+        let (sender, receiver) = mpsc::channel();
+        sender.send(Err(IoError::other("broken"))).unwrap();
+        let err = MessageRead::new(receiver, || false).read(&mut [0; 16]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn stops_waiting_when_told() {
+        // The sender is still alive, so without stopping the read would wait forever.
+        let (sender, receiver) = mpsc::channel::<std::io::Result<Vec<u8>>>();
+        let started = Instant::now();
+        let read = MessageRead::new(receiver, || true).read(&mut [0; 16]).unwrap();
+        assert_eq!(read, 0);
+        assert!(started.elapsed() < Duration::from_secs(5));
+        drop(sender);
     }
 }
 
-// Parsed CLI args.
-#[derive(Debug)]
-struct Args {
-    datetime_format: DateTimeFormat,
-    match_index: usize,
-    granularity: Granularity,
-    inputs: Vec<Input>,
-    fill_empty_buckets: bool,
-    mode: Mode,
-    order: DateTimeOrder,
-    tolerant: bool,
+// Most chunks of stdin read ahead of what has been counted.
+const STDIN_READ_AHEAD: usize = 16;
+
+// Read stdin on a separate thread, so that SIGINT can end the wait for more of it. The thread
+// isn't joined, as it may be waiting for input which never comes, and stops once the reading side
+// has gone away.
+fn read_stdin() -> MessageRead {
+    let (sender, receiver) = mpsc::sync_channel(STDIN_READ_AHEAD);
+    std::thread::spawn(move || {
+        let stdin = std::io::stdin();
+        let mut lock = stdin.lock();
+        loop {
+            let mut chunk = vec![0; 64 * 1024];
+            let result = match lock.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(len) => {
+                    chunk.truncate(len);
+                    Ok(chunk)
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => Err(err),
+            };
+            let failed = result.is_err();
+            if sender.send(result).is_err() || failed {
+                break;
+            }
+        }
+    });
+    MessageRead::new(receiver, interrupted)
 }
 
-#[derive(Debug, Copy, Clone)]
-enum Mode {
-    Normal,
-    Stream,
+// Layout of Windows Event Log files: a file header block, followed by chunks which each have a
+// header and then event records. Each record has a header and then its event in binary XML.
+const EVTX_FILE_SIGNATURE: &[u8] = b"ElfFile\0";
+const EVTX_CHUNK_SIGNATURE: &[u8] = b"ElfChnk\0";
+const EVTX_RECORD_SIGNATURE: &[u8] = b"**\0\0";
+const EVTX_CHUNK_SIZE: usize = 0x10000;
+const EVTX_CHUNK_HEADER_SIZE: usize = 0x200;
+const EVTX_RECORD_HEADER_SIZE: usize = 24;
+// Seconds from 1601-01-01, where Windows FILETIMEs count from, to the Unix epoch.
+const FILETIME_EPOCH_OFFSET: u64 = 11_644_473_600;
+
+// Render every record of a Windows Event Log file as a line like '1704103260 record=42', with the
+// System/TimeCreated of its event in seconds, or as 'record=42' if that can't be read, so that the
+// record counts as a line without a date/time.
+fn render_evtx(data: &[u8]) -> IoResult<Vec<u8>> {
+    // Little-endian integers, of which the caller checks there are enough bytes.
+    let read_u32 = |bytes: &[u8], at: usize| {
+        let mut value = [0; 4];
+        value.copy_from_slice(&bytes[at..at + 4]);
+        u32::from_le_bytes(value)
+    };
+    let read_u64 = |bytes: &[u8], at: usize| {
+        let mut value = [0; 8];
+        value.copy_from_slice(&bytes[at..at + 8]);
+        u64::from_le_bytes(value)
+    };
+    if data.len() < 0x2a || !data.starts_with(EVTX_FILE_SIGNATURE) {
+        return Err(IoError::new(
+            ErrorKind::InvalidData,
+            "Not a Windows Event Log (.evtx) file",
+        ));
+    }
+    let mut lines = Vec::new();
+    // Chunks are read until the end of the file rather than as many as the header counts, since
+    // the header isn't updated if the log wasn't closed cleanly.
+    let header_size = usize::from(u16::from_le_bytes([data[0x28], data[0x29]]));
+    let mut chunks = data
+        .get(header_size..)
+        .unwrap_or_default()
+        .chunks_exact(EVTX_CHUNK_SIZE);
+    for chunk in &mut chunks {
+        if !chunk.starts_with(EVTX_CHUNK_SIGNATURE) {
+            continue;
+        }
+        // Where the TimeCreated of each template of the chunk is, by the template's offset, since
+        // most records of a chunk share a few templates.
+        let mut templates = HashMap::new();
+        // Records end where the chunk's free space starts.
+        let end = (read_u32(chunk, 0x30) as usize).min(EVTX_CHUNK_SIZE);
+        let mut position = EVTX_CHUNK_HEADER_SIZE;
+        while position + EVTX_RECORD_HEADER_SIZE <= end && chunk[position..].starts_with(EVTX_RECORD_SIGNATURE) {
+            let size = read_u32(chunk, position + 4) as usize;
+            let id = read_u64(chunk, position + 8);
+            if size < EVTX_RECORD_HEADER_SIZE {
+                break;
+            }
+            let created = BinXml::at(chunk, position + EVTX_RECORD_HEADER_SIZE).time_created(&mut templates);
+            match created.and_then(|created| (created / 10_000_000).checked_sub(FILETIME_EPOCH_OFFSET)) {
+                Some(seconds) => writeln!(lines, "{seconds} record={id}")?,
+                None => writeln!(lines, "record={id}")?,
+            }
+            position += size;
+        }
+    }
+    Ok(lines)
 }
 
-// Mode-based runner. Contains business logic for normal and streaming modes.
-enum Runner {
-    // Normal mode will put everything into buckets and print them all at the end.
-    Normal {
-        // Unordered buckets - will be ordered after all lines have been counted.
-        buckets: HashMap<DateTime<Utc>, u64>,
-    },
-    Stream {
-        // How many entries have been seen for the current bucket.
-        count: u64,
-        // Current bucket. None only at the runner's beginning, when no bucket
-        // has been encountered yet, and then Some from then on.
-        bucket: Option<DateTime<Utc>>,
-    },
+// Binary XML tokens of event records. The 0x40 bit of some tokens says more of the same follow,
+// such as more attributes, which doesn't matter for reading them.
+const BINXML_OPEN_START_ELEMENT: u8 = 0x01;
+const BINXML_CLOSE_START_ELEMENT: u8 = 0x02;
+const BINXML_CLOSE_EMPTY_ELEMENT: u8 = 0x03;
+const BINXML_END_ELEMENT: u8 = 0x04;
+const BINXML_VALUE: u8 = 0x05;
+const BINXML_ATTRIBUTE: u8 = 0x06;
+const BINXML_CDATA: u8 = 0x07;
+const BINXML_CHAR_REF: u8 = 0x08;
+const BINXML_ENTITY_REF: u8 = 0x09;
+const BINXML_PI_TARGET: u8 = 0x0a;
+const BINXML_PI_DATA: u8 = 0x0b;
+const BINXML_TEMPLATE_INSTANCE: u8 = 0x0c;
+const BINXML_NORMAL_SUBSTITUTION: u8 = 0x0d;
+const BINXML_OPTIONAL_SUBSTITUTION: u8 = 0x0e;
+const BINXML_FRAGMENT_HEADER: u8 = 0x0f;
+const BINXML_MORE_FLAG: u8 = 0x40;
+// Types of binary XML values which tbuck reads.
+const BINXML_STRING_TYPE: u8 = 0x01;
+const BINXML_FILETIME_TYPE: u8 = 0x11;
+
+// Reads the binary XML of the events of a chunk of a Windows Event Log file. Events are written
+// as an instance of a template, an XML fragment defined once in the chunk with substitutions
+// standing in for the values which differ between events, followed by the values of the event.
+// Names and templates are referred to by their offset in the chunk, and written out right there
+// the first time they are used. Reads return None past the end of the chunk.
+struct BinXml<'a> {
+    chunk: &'a [u8],
+    position: usize,
 }
 
-impl Runner {
-    fn from_mode(mode: Mode) -> Self {
-        match mode {
-            Mode::Normal => Runner::Normal {
-                buckets: HashMap::with_capacity(1024),
-            },
-            Mode::Stream => Runner::Stream { count: 0, bucket: None },
+impl<'a> BinXml<'a> {
+    fn at(chunk: &'a [u8], position: usize) -> Self {
+        BinXml { chunk, position }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.chunk.get(self.position..self.position + N)?;
+        self.position += N;
+        <[u8; N]>::try_from(bytes).ok()
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes().map(u8::from_le_bytes)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes().map(u16::from_le_bytes)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.position += len;
+        (self.position <= self.chunk.len()).then_some(())
+    }
+
+    // Skip a string of UTF-16 characters, after their count.
+    fn skip_string(&mut self) -> Option<()> {
+        let len = usize::from(self.u16()?);
+        self.skip(len * 2)
+    }
+
+    // Read the offset of a name, skipping the name if it is written right after it. A name is the
+    // offset of the next name, a hash, and a null-terminated string of UTF-16 characters.
+    fn name(&mut self) -> Option<String> {
+        let offset = self.u32()? as usize;
+        let mut name = BinXml::at(self.chunk, offset);
+        name.skip(6)?;
+        let len = usize::from(name.u16()?);
+        let units: Vec<u16> = (0..len).map(|_| name.u16()).collect::<Option<_>>()?;
+        if offset == self.position {
+            self.skip(10 + len * 2)?;
         }
+        String::from_utf16(&units).ok()
     }
 
-    fn handle_bucket_entry(&mut self, entry: DateTime<Utc>, args: &Args) -> IoResult<()> {
-        match self {
-            Runner::Normal { buckets } => {
-                *buckets.entry(entry).or_insert(0) += 1;
-                Ok(())
+    // The System/TimeCreated/@SystemTime of the event starting here, as a FILETIME, with where it
+    // is substituted into each template of the chunk looked up in `templates` once.
+    fn time_created(&mut self, templates: &mut HashMap<usize, Option<u16>>) -> Option<u64> {
+        if self.u8()? != BINXML_FRAGMENT_HEADER {
+            return None;
+        }
+        self.skip(3)?;
+        if self.u8()? != BINXML_TEMPLATE_INSTANCE {
+            return None;
+        }
+        // An unknown byte and the template's identifier.
+        self.skip(5)?;
+        let template = self.u32()? as usize;
+        if template == self.position {
+            // The offset of the next template and the template's GUID, then the size of the
+            // fragment after them.
+            self.skip(20)?;
+            let size = self.u32()? as usize;
+            self.skip(size)?;
+        }
+        let substitution = *templates
+            .entry(template)
+            .or_insert_with(|| BinXml::at(self.chunk, template + 24).time_created_substitution());
+        // The values, each described by its size and type, before them all.
+        let count = self.u32()? as usize;
+        let mut value = BinXml::at(self.chunk, self.position + count * 4);
+        for index in 0..count {
+            let size = usize::from(self.u16()?);
+            let value_type = self.u8()?;
+            self.skip(1)?;
+            if Some(index) == substitution.map(usize::from) {
+                return (value_type == BINXML_FILETIME_TYPE && size == 8).then(|| value.u64())?;
             }
-            Runner::Stream { count, bucket } => {
-                let current_bucket = match bucket {
-                    Some(b) => b,
-                    None => {
-                        // If this is the first bucket, just record the entry and return.
-                        *bucket = Some(entry);
-                        *count = 1;
-                        return Ok(());
-                    }
-                };
-                // What to do next depends on both what ordering the user configured and what the actual relation between the
-                // current bucket and new entry is.
-                match (args.order, entry.cmp(current_bucket)) {
-                    (_, Ordering::Equal) => {
-                        // Same bucket. Just increment the count.
-                        *count += 1;
+            value.skip(size)?;
+        }
+        None
+    }
+
+    // Walk the fragment of a template to the substitution for System/TimeCreated/@SystemTime, and
+    // return its index among the values of an instance.
+    fn time_created_substitution(&mut self) -> Option<u16> {
+        // The elements which are open, the one whose start tag is being read, and the attribute
+        // whose value is being read.
+        let mut open = Vec::new();
+        let mut element = None;
+        let mut attribute = None;
+        loop {
+            let token = self.u8()?;
+            match token & !BINXML_MORE_FLAG {
+                BINXML_OPEN_START_ELEMENT => {
+                    // A dependency identifier and the size of the element.
+                    self.skip(6)?;
+                    element = Some(self.name()?);
+                    attribute = None;
+                    if token & BINXML_MORE_FLAG != 0 {
+                        // The size of the attributes.
+                        self.skip(4)?;
                     }
-                    (DateTimeOrder::Ascending, Ordering::Less) | (DateTimeOrder::Descending, Ordering::Greater) => {
-                        // Non-monotonic according to configured ordering.
-                        if !args.tolerant {
-                            // TODO: better error propagation.
-                            panic!("Non monotonic entry found");
-                        }
+                }
+                BINXML_CLOSE_START_ELEMENT => {
+                    open.extend(element.take());
+                    attribute = None;
+                }
+                BINXML_CLOSE_EMPTY_ELEMENT => {
+                    element = None;
+                    attribute = None;
+                }
+                BINXML_END_ELEMENT => {
+                    open.pop();
+                }
+                BINXML_VALUE => {
+                    if self.u8()? != BINXML_STRING_TYPE {
+                        return None;
                     }
-                    (DateTimeOrder::Ascending, Ordering::Greater) | (DateTimeOrder::Descending, Ordering::Less) => {
-                        // Monotonic. Print bucket(s) and advance to the next. We may be printing multiple buckets at
-                        // once so lock stdout.
-                        let stdout = std::io::stdout();
-                        let mut stdout_lock = stdout.lock();
-                        writeln!(stdout_lock, "{},{}", current_bucket, count)?;
-                        if args.fill_empty_buckets {
-                            let mut next_bucket = args.granularity.successor(current_bucket);
-                            while next_bucket < entry {
-                                writeln!(stdout_lock, "{},0", next_bucket)?;
-                                next_bucket = args.granularity.successor(&next_bucket);
-                            }
-                        }
-                        *count = 1;
-                        *bucket = Some(entry)
+                    self.skip_string()?;
+                }
+                BINXML_ATTRIBUTE => attribute = Some(self.name()?),
+                BINXML_CDATA | BINXML_PI_DATA => self.skip_string()?,
+                BINXML_CHAR_REF => self.skip(2)?,
+                BINXML_ENTITY_REF | BINXML_PI_TARGET => {
+                    self.name()?;
+                }
+                BINXML_NORMAL_SUBSTITUTION | BINXML_OPTIONAL_SUBSTITUTION => {
+                    let index = self.u16()?;
+                    self.skip(1)?;
+                    if open == ["Event", "System"]
+                        && element.as_deref() == Some("TimeCreated")
+                        && attribute.as_deref() == Some("SystemTime")
+                    {
+                        return Some(index);
                     }
                 }
-                Ok(())
+                BINXML_FRAGMENT_HEADER => self.skip(3)?,
+                // The end of the fragment without a TimeCreated, or a token which can't be skipped.
+                _ => return None,
             }
         }
     }
+}
 
-    fn finish(self, args: &Args) -> IoResult<()> {
-        match self {
-            Runner::Normal { buckets } => {
-                // Sort buckets by time.
-                let mut ordered_buckets: Vec<(DateTime<Utc>, u64)> = buckets.into_iter().collect();
-                match args.order {
-                    DateTimeOrder::Ascending => ordered_buckets.sort_unstable_by(|l, r| l.0.cmp(&r.0)),
-                    DateTimeOrder::Descending => ordered_buckets.sort_unstable_by(|l, r| r.0.cmp(&l.0)),
-                };
+// The names of the inputs as groups of --per-file. An input named the same as an earlier one, such
+// as a file given twice, is told apart by its position among them, like 'app.log (2)', rather
+// than counted into the same group.
+fn group_names(inputs: &[Input]) -> Vec<String> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    inputs
+        .iter()
+        .map(|input| {
+            let name = input.name();
+            let occurrence = seen.entry(name.clone()).or_insert(0);
+            *occurrence += 1;
+            if *occurrence == 1 {
+                name
+            } else {
+                format!("{name} ({occurrence})")
+            }
+        })
+        .collect()
+}
 
-                // Write output to stdout.
-                let stdout = std::io::stdout();
-                let mut stdout_lock = stdout.lock();
-                let mut prev_bucket = chrono::MAX_DATE.and_hms(0, 0, 0);
-                for (bucket, count) in &ordered_buckets {
-                    // Unless --no-fill was specified, we need to emit 0s for buckets which don't exist.
-                    if args.fill_empty_buckets {
-                        while prev_bucket < *bucket {
-                            writeln!(stdout_lock, "{},0", prev_bucket)?;
-                            prev_bucket = args.granularity.successor(&prev_bucket);
-                        }
+#[cfg(test)]
+mod group_name_tests {
+    use super::{group_names, Input};
+    use std::path::PathBuf;
+
+    #[test]
+    fn repeated_names_are_told_apart() {
+        let inputs = vec![
+            Input::File(PathBuf::from("a.log")),
+            Input::File(PathBuf::from("b.log")),
+            Input::File(PathBuf::from("a.log")),
+            Input::Evtx(PathBuf::from("a.log")),
+            Input::Stdin,
+        ];
+        assert_eq!(
+            group_names(&inputs),
+            vec!["a.log", "b.log", "a.log (2)", "a.log (3)", "<stdin>"]
+        );
+    }
+}
+
+#[cfg(test)]
+mod evtx_tests {
+    use super::render_evtx;
+
+    // Write a name where it is used, right after its offset in the chunk.
+    #[allow(clippy::cast_possible_truncation)]
+    fn name(chunk: &mut Vec<u8>, name: &str) {
+        let offset = chunk.len() as u32 + 4;
+        chunk.extend(offset.to_le_bytes());
+        // The offset of the next name and the hash, which aren't read.
+        chunk.extend([0; 6]);
+        chunk.extend((name.len() as u16).to_le_bytes());
+        chunk.extend(name.encode_utf16().flat_map(u16::to_le_bytes));
+        chunk.extend([0; 2]);
+    }
+
+    // Write the fragment of a template like Windows', with a substitution for the TimeCreated and
+    // its value taking one more. Sizes which aren't read are left 0.
+    fn template(chunk: &mut Vec<u8>) {
+        chunk.extend([0x0f, 1, 1, 0]);
+        for element in &["Event", "System"] {
+            chunk.extend([0x01, 0xff, 0xff, 0, 0, 0, 0]);
+            name(chunk, element);
+            chunk.push(0x02);
+        }
+        // <Level>4</Level>, with a value written into the template.
+        chunk.extend([0x01, 0xff, 0xff, 0, 0, 0, 0]);
+        name(chunk, "Level");
+        chunk.extend([0x02, 0x05, 0x01, 1, 0]);
+        chunk.extend("4".encode_utf16().flat_map(u16::to_le_bytes));
+        chunk.push(0x04);
+        // <TimeCreated SystemTime="..."/>, substituted by the second value.
+        chunk.extend([0x41, 0xff, 0xff, 0, 0, 0, 0]);
+        name(chunk, "TimeCreated");
+        chunk.extend([0; 4]);
+        chunk.push(0x06);
+        name(chunk, "SystemTime");
+        chunk.extend([0x0e, 1, 0, 0x11, 0x03]);
+        chunk.extend([0x04, 0x04, 0x00]);
+    }
+
+    // Build an event log of one chunk per list of (record id, written FILETIME, created FILETIME),
+    // or with a created FILETIME of 0 an event which isn't an instance of a template. The first
+    // record of a chunk defines the template, which later ones refer to.
+    #[allow(clippy::cast_possible_truncation)]
+    fn evtx(chunks: &[&[(u64, u64, u64)]]) -> Vec<u8> {
+        let mut data = vec![0; 0x1000];
+        data[..8].copy_from_slice(b"ElfFile\0");
+        data[0x28..0x2a].copy_from_slice(&0x1000_u16.to_le_bytes());
+        for records in chunks {
+            let mut chunk = vec![0; 0x200];
+            chunk[..8].copy_from_slice(b"ElfChnk\0");
+            let mut defined = None;
+            for (id, written, created) in *records {
+                let start = chunk.len();
+                chunk.extend(b"**\0\0");
+                chunk.extend([0; 4]);
+                chunk.extend(id.to_le_bytes());
+                chunk.extend(written.to_le_bytes());
+                chunk.extend([0x0f, 1, 1, 0]);
+                if *created == 0 {
+                    chunk.extend([0x01, 0xff, 0xff]);
+                } else {
+                    chunk.extend([0x0c, 1, 1, 0, 0, 0]);
+                    let offset = defined.unwrap_or(chunk.len() + 4);
+                    chunk.extend((offset as u32).to_le_bytes());
+                    if defined.is_none() {
+                        defined = Some(offset);
+                        chunk.extend([0; 20]);
+                        let size_at = chunk.len();
+                        chunk.extend([0; 4]);
+                        template(&mut chunk);
+                        let size = (chunk.len() - size_at - 4) as u32;
+                        chunk[size_at..size_at + 4].copy_from_slice(&size.to_le_bytes());
                     }
-                    writeln!(stdout_lock, "{},{}", bucket, count)?;
-                    prev_bucket = args.granularity.successor(bucket);
-                }
-            }
-            Runner::Stream { count, bucket } => {
-                if let Some(bucket) = bucket {
-                    // Don't bother locking stdout for a single write.
-                    println!("{},{}", bucket, count);
+                    // The level, then the TimeCreated.
+                    chunk.extend(2_u32.to_le_bytes());
+                    chunk.extend([2, 0, 0x06, 0, 8, 0, 0x11, 0]);
+                    chunk.extend(4_u16.to_le_bytes());
+                    chunk.extend(created.to_le_bytes());
                 }
+                let size = (chunk.len() + 4 - start) as u32;
+                chunk.extend(size.to_le_bytes());
+                chunk[start + 4..start + 8].copy_from_slice(&size.to_le_bytes());
             }
-        };
-        Ok(())
+            let free = chunk.len() as u32;
+            chunk[0x30..0x34].copy_from_slice(&free.to_le_bytes());
+            chunk.resize(0x10000, 0);
+            data.extend(chunk);
+        }
+        data
     }
-}
 
-// The order that datetime entries are expected in stream mode OR the order that buckets
-// will be printed in normal mode.
-#[derive(Debug, Copy, Clone)]
-enum DateTimeOrder {
-    Ascending,
-    Descending,
+    #[test]
+    fn renders_records() {
+        // 2024-01-01 10:01:00 UTC, and half a second later.
+        let created = 133_485_768_600_000_000;
+        // Events are written a little after they happen, or much later when forwarded.
+        let written = created + 50_000_000;
+        let data = evtx(&[
+            &[
+                (1, written, created),
+                (2, written, created + 5_000_000),
+                (3, written, 0),
+            ],
+            &[],
+            &[(4, written + 36_000_000_000, created + 600_000_000)],
+        ]);
+        let lines = String::from_utf8(render_evtx(&data).unwrap()).unwrap();
+        assert_eq!(
+            lines,
+            "1704103260 record=1\n1704103260 record=2\nrecord=3\n1704103320 record=4\n"
+        );
+
+        assert!(render_evtx(b"not an event log").is_err());
+        // A chunk cut short, as by a log which is still being written, is ignored.
+        let mut data = evtx(&[&[(1, written, created)]]);
+        data.truncate(0x1000 + 0x8000);
+        assert_eq!(render_evtx(&data).unwrap(), b"");
+    }
 }
 
-// Where the program can take its input from.
-#[derive(Debug)]
-enum Input {
-    Stdin,
-    File(PathBuf),
+// How long to sleep between checks for new data when following a file.
+const FOLLOW_POLL_INTERVAL: StdDuration = StdDuration::from_millis(250);
+
+// Reader over a file that is still being written to. Reaching the end of the file doesn't end
+// the read; instead the reader sleeps until more data shows up.
+struct FollowReader<'a> {
+    path: &'a Path,
+    file: File,
+    // Identity of the open file, used to detect rotation.
+    id: Option<FileId>,
+    // Byte offset of the next read, used to detect truncation.
+    position: u64,
 }
 
-impl Input {
-    // Invoke a callback function that accepts a `&mut dyn Read` for dynamic dispatch based on the
-    // type of input. This is mostly useful because it allows us to lock stdin for the entire
-    // duration of the program.
-    fn open_bare_read(&self, mut f: impl FnMut(&mut dyn Read) -> IoResult<()>) -> IoResult<()> {
-        match self {
-            Input::Stdin => {
-                let stdin = std::io::stdin();
-                let mut lock = stdin.lock();
-                f(&mut lock)
-            }
-            Input::File(path) => {
-                let mut file = std::fs::File::open(path)?;
-                f(&mut file)
-            }
-        }
+impl<'a> FollowReader<'a> {
+    fn open(path: &'a Path) -> IoResult<Self> {
+        let file = File::open(path)?;
+        Ok(Self {
+            path,
+            id: FileId::of(&file.metadata()?),
+            file,
+            position: 0,
+        })
+    }
+
+    // Whether the file at the path is no longer the one being read, because it was truncated or
+    // replaced by another, such as by log rotation.
+    fn replaced(&self, metadata: &std::fs::Metadata) -> bool {
+        metadata.len() < self.position || FileId::of(metadata) != self.id
     }
 }
 
-// Will be used both for finding timestamps within a line and parsing the timestamp into a datetime.
-#[derive(Debug)]
-struct DateTimeFormat {
-    chrono_items: Vec<FormatItem>,
-}
-
-impl DateTimeFormat {
-    // Parse the chrono format specifiers in a string into a DateTimeFormat. Returns Some() if all
-    // the specifiers in the string are actually supported, or None if the user tried to use an
-    // unsupported chrono specifier.
-    fn new(format_string: &str) -> Option<Self> {
-        let mut items_supported = true;
-        let chrono_items: Vec<FormatItem> = StrftimeItems::new(format_string)
-            .inspect(|item| {
-                items_supported &= match item {
-                    Item::Numeric(numeric, pad) => numeric_format_to_regex_fragment(numeric, *pad).is_some(),
-                    Item::Fixed(fixed) => fixed_format_to_regex_fragment(fixed).is_some(),
-                    _ => true,
-                }
-            })
-            .map(FormatItem::from_chrono)
-            .collect();
-        if items_supported {
-            Some(Self { chrono_items })
-        } else {
-            None
-        }
+// Identifies a file regardless of its path or contents, by its device and inode. Not known on
+// other platforms than Unix.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct FileId {
+    device: u64,
+    inode: u64,
+}
+
+impl FileId {
+    #[cfg(unix)]
+    #[allow(clippy::unnecessary_wraps)]
+    fn of(metadata: &std::fs::Metadata) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        Some(Self {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+        })
     }
 
-    // Build the regex which can find occurrences of this format in a line.
-    fn regex(&self) -> Regex {
-        let mut expression = String::with_capacity(128);
-        for item in &self.chrono_items {
-            match item {
-                FormatItem::Literal(string) | FormatItem::Space(string) => {
-                    // Remember to escape special characters.
-                    expression.push_str(&regex::escape(string));
-                }
-                FormatItem::Numeric(numeric, pad) => {
-                    expression.push_str(
-                        numeric_format_to_regex_fragment(numeric, *pad)
-                            .expect("validator should have rejected unsupported items"),
-                    );
-                }
-                FormatItem::Fixed(fixed) => {
-                    expression.push_str(
-                        fixed_format_to_regex_fragment(fixed)
-                            .expect("validator should have rejected unsupported items"),
+    #[cfg(not(unix))]
+    fn of(_metadata: &std::fs::Metadata) -> Option<Self> {
+        None
+    }
+}
+
+impl Read for FollowReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        loop {
+            let read = self.file.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                self.position += read as u64;
+                return Ok(read);
+            }
+            // At the end of the file. If the file at our path is now shorter than what we've
+            // already read, or another file altogether, then it was truncated or rotated out from
+            // under us, so start over. A rotated file may well have grown past our position
+            // already. The path can briefly be missing during rotation, in which case just keep
+            // waiting.
+            match std::fs::metadata(self.path) {
+                Ok(metadata) if self.replaced(&metadata) => {
+                    eprintln!(
+                        "{}: file truncated or replaced, reading from the beginning",
+                        self.path.display()
                     );
+                    *self = FollowReader::open(self.path)?;
                 }
+                // Following ends on SIGINT, as if the file had.
+                _ if interrupted() => return Ok(0),
+                _ => std::thread::sleep(FOLLOW_POLL_INTERVAL),
             }
         }
-        // Given that the only parts to the regex are A) user input that has been escaped and B) strings
-        // that our code is responsible for, we expect the regex to be valid.
-        Regex::new(&expression).expect("Regex unexpectedly invalid")
-    }
-
-    // Try to parse text that was matched by the regex into a DateTime<Utc>. This method's current
-    // implementation calls Parsed::to_datetime_with_timezone, which has the major implication that
-    // full date/time information must be specified in the string. In a future revision, we may
-    // want to be fancier here by accepting formats that don't have various components (missing
-    // year/month, for example). It seems like if we don't have eg year but the granularity is only
-    // in seconds, then it should be perfectly possible to still form buckets. However, if we were
-    // to do that we'd need to consider things like how we print out buckets when they're not really
-    // 'full' DateTimes - just accept 0s for missing components?
-    fn try_parse(&self, text: &str) -> chrono::format::ParseResult<DateTime<Utc>> {
-        let mut parsed = Parsed::new();
-        chrono::format::parse(&mut parsed, text, self.chrono_items.iter().map(FormatItem::to_chrono))?;
-        parsed.to_datetime_with_timezone(&Utc {})
-    }
-
-    // Determines whether there is enough information in the user's format string to satisfy chrono's
-    // parser. This works by building up a dummy string that matches the user's specification
-    // (substituting dummy values like 0001 for the year, etc), then trying to parse it.
-    fn has_enough_info(&self) -> bool {
-        let mut default_values = String::with_capacity(128);
-        for item in &self.chrono_items {
-            match item {
-                FormatItem::Literal(string) | FormatItem::Space(string) => {
-                    default_values.push_str(string);
-                }
-                FormatItem::Numeric(numeric, pad) => {
-                    default_values.push_str(
-                        numeric_format_to_default_value(numeric, *pad)
-                            .expect("validator should have rejected unsupported items"),
-                    );
-                }
-                FormatItem::Fixed(fixed) => {
-                    default_values.push_str(
-                        fixed_format_to_default_value(fixed).expect("validator should have rejected unsupported items"),
-                    );
+    }
+}
+
+// What is left of the --max-lines and --max-bytes of an input as it is read.
+#[derive(Debug, Copy, Clone)]
+struct InputLimit {
+    lines: Option<u64>,
+    bytes: Option<u64>,
+}
+
+impl InputLimit {
+    // Count data read from the input against the limit. If the limit is reached, returns how much
+    // of the data is within it, which goes up to the end of the line the limit is reached in.
+    fn consume(&mut self, data: &[u8]) -> Option<usize> {
+        for newline in memchr::memchr_iter(b'\n', data) {
+            let line_end = newline + 1;
+            if let Some(lines) = &mut self.lines {
+                *lines -= 1;
+                if *lines == 0 {
+                    return Some(line_end);
                 }
             }
+            if self.bytes.is_some_and(|bytes| bytes <= line_end as u64) {
+                return Some(line_end);
+            }
+        }
+        if let Some(bytes) = &mut self.bytes {
+            *bytes = bytes.saturating_sub(data.len() as u64);
         }
-        self.try_parse(&default_values).is_ok()
+        None
+    }
+
+    // The part of a whole input which is within the limit.
+    fn truncate(mut self, data: &[u8]) -> &[u8] {
+        &data[..self.consume(data).unwrap_or(data.len())]
     }
 }
 
-// Convert a Numeric chrono specifier (like "%Y") into a regex fragment that will match values of
-// that kind. Currently ignores the padding info - is there a case where doing so is incorrect?
-fn numeric_format_to_regex_fragment(numeric: &Numeric, _pad: Pad) -> Option<&'static str> {
-    use Numeric::*;
-    Some(match numeric {
-        Year => "-?\\d+",
-        Month | Day | Hour | Hour12 | Minute | Second => "\\d{2}",
-        Timestamp => "\\d+",
-        _ => return None,
-    })
+// Reader which ends once an input limit is reached.
+struct LimitedRead<'a> {
+    read: &'a mut dyn Read,
+    limit: InputLimit,
+    reached: bool,
 }
 
-// Get a dummy value for a chrono Numeric specifier.
-fn numeric_format_to_default_value(numeric: &Numeric, _pad: Pad) -> Option<&'static str> {
-    use Numeric::*;
-    Some(match numeric {
-        Year => "0001",
-        Month | Day | Hour12 => "01",
-        Hour | Minute | Second => "00",
-        Timestamp => "000000000",
-        _ => return None,
-    })
+impl Read for LimitedRead<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.reached {
+            return Ok(0);
+        }
+        let len = self.read.read(buf)?;
+        match self.limit.consume(&buf[..len]) {
+            Some(within) => {
+                self.reached = true;
+                Ok(within)
+            }
+            None => Ok(len),
+        }
+    }
 }
 
-// Convert a Fixed chrono specifier (like "%b") into a regex fragment that will match values of
-// that kind.
-fn fixed_format_to_regex_fragment(fixed: &Fixed) -> Option<&'static str> {
-    use Fixed::*;
-    Some(match fixed {
-        ShortMonthName => "Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec",
-        LongMonthName => "Jan(uary)?|Feb(ruary)?|Mar(ch)?|Apr(il)?|May|June?|July?|Aug(ust)?|Sep(tember)?|Oct(ober)?|Nov(ember)?|Dec(ember)?",
-        LowerAmPm | UpperAmPm => "am|AM|pm|PM",
-        _ => return None
-    })
+// Parse a size in bytes, optionally with a binary suffix, like '4096', '64K', '10M' or '1G'.
+fn parse_size(text: &str) -> Option<u64> {
+    let (amount, multiplier) = match text.chars().last()? {
+        'k' | 'K' => (&text[..text.len() - 1], 1 << 10),
+        'm' | 'M' => (&text[..text.len() - 1], 1 << 20),
+        'g' | 'G' => (&text[..text.len() - 1], 1 << 30),
+        _ => (text, 1),
+    };
+    amount.parse::<u64>().ok()?.checked_mul(multiplier)
 }
 
-// Get a dummy value for a chrono Fixed specifier.
-fn fixed_format_to_default_value(fixed: &Fixed) -> Option<&'static str> {
-    use Fixed::*;
-    Some(match fixed {
-        ShortMonthName => "Jan",
-        LongMonthName => "January",
-        LowerAmPm => "am",
-        UpperAmPm => "AM",
-        _ => return None,
-    })
+#[cfg(test)]
+mod follow_tests {
+    use super::FollowReader;
+    use std::io::{Read, Write};
+
+    fn read_some(reader: &mut FollowReader<'_>) -> String {
+        let mut buf = [0; 64];
+        let read = reader.read(&mut buf).unwrap();
+        String::from_utf8(buf[..read].to_vec()).unwrap()
+    }
+
+    #[test]
+    fn reopens_truncated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, "first line\n").unwrap();
+        let mut reader = FollowReader::open(&path).unwrap();
+        assert_eq!(read_some(&mut reader), "first line\n");
+        std::fs::write(&path, "new\n").unwrap();
+        assert_eq!(read_some(&mut reader), "new\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn reopens_rotated_files_which_grew_past_the_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        std::fs::write(&path, "old\n").unwrap();
+        let mut reader = FollowReader::open(&path).unwrap();
+        assert_eq!(read_some(&mut reader), "old\n");
+        std::fs::rename(&path, dir.path().join("app.log.1")).unwrap();
+        let mut rotated = std::fs::File::create(&path).unwrap();
+        rotated.write_all(b"rotated in\n").unwrap();
+        assert_eq!(read_some(&mut reader), "rotated in\n");
+    }
 }
 
 #[cfg(test)]
-mod datetime_format_tests {
-    use super::DateTimeFormat;
-    use chrono::{Datelike, Timelike};
+mod limit_tests {
+    use super::{count_args, parse_size, InputLimit};
+    use clap::App;
 
     #[test]
-    fn formats_are_matched() {
+    fn parses_sizes() {
         let cases = vec![
-            ("%Y", vec!["2019", "1", "0100", "100", "-1"]),
-            (
-                "%m",
-                vec!["01", "02", "03", "04", "05", "06", "07", "08", "09", "10", "11", "12"],
-            ),
-            (
-                "%b",
-                vec![
-                    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
-                ],
-            ),
-            (
-                "%B",
-                vec![
-                    "January",
-                    "February",
-                    "March",
-                    "April",
-                    "May",
-                    "June",
-                    "July",
-                    "August",
-                    "September",
-                    "October",
-                    "November",
-                    "December",
-                ],
-            ),
-            ("%d", vec!["01", "02", "10", "22", "31"]),
-            ("%F", vec!["1991-08-10", "2019-03-14"]),
-            ("%H", vec!["00", "02", "10", "19", "23"]),
-            ("%I", vec!["01", "02", "05", "10", "12"]),
-            ("%M", vec!["00", "02", "10", "19", "30", "44", "59"]),
-            ("%S", vec!["00", "02", "10", "19", "30", "44", "59", "60"]),
-            ("%T", vec!["00:00:00", "10:20:30", "23:59:60"]),
-            ("%p", vec!["AM", "PM"]),
-            ("%P", vec!["am", "pm"]),
-            ("%s", vec!["994518299"]),
+            ("0", Some(0)),
+            ("4096", Some(4096)),
+            ("64K", Some(65536)),
+            ("10m", Some(10 << 20)),
+            ("1G", Some(1 << 30)),
         ];
-        for (strftime, expected_matches) in &cases {
-            let format = DateTimeFormat::new(strftime).unwrap();
-            let regex = format.regex();
-            for expected_match in expected_matches {
-                assert!(regex.is_match(expected_match));
-            }
+        for (input, expected) in cases {
+            assert_eq!(parse_size(input), expected, "{input}");
+        }
+        let bad_cases = vec!["", "K", "-1", "1.5M", "1T", "99999999999999G"];
+        for input in bad_cases {
+            assert!(parse_size(input).is_none());
         }
     }
 
     #[test]
-    fn has_enough_info() {
-        let cases = vec!["%Y-%m-%d %H:%M:%S", "%F %T", "%b %d, %Y %I:%M %p"];
-        for strftime in &cases {
-            let format = DateTimeFormat::new(strftime).unwrap();
-            assert!(format.has_enough_info());
+    fn rejects_reading_no_bytes() {
+        let cases = vec![("0", false), ("0K", false), ("1", true)];
+        for (size, accepted) in cases {
+            let matches =
+                count_args(App::new("tbuck"), "1m").get_matches_from_safe(["tbuck", "%F %T", "--max-bytes", size]);
+            assert_eq!(matches.is_ok(), accepted, "{size}");
         }
     }
 
     #[test]
-    fn parses() {
+    fn truncates_at_the_end_of_a_line() {
+        let data = b"one\ntwo\nthree\nfour";
         let cases = vec![
-            ("%Y-%m-%d %H:%M:%S", "1991-08-10 01:02:03", 1991, 8, 10, 1, 2, 3),
-            (
-                "%b %d, %Y %I:%M:%S%P",
-                "Mar 14, 2019 04:59:34pm",
-                2019,
-                3,
-                14,
-                16,
-                59,
-                34,
-            ),
-            ("%s", "1552609482", 2019, 3, 15, 00, 24, 42),
-        ];
-        for (strftime, text, y, mo, d, h, mi, s) in cases {
-            let format = DateTimeFormat::new(strftime).unwrap();
-            let datetime = format.try_parse(text).unwrap();
-            let date = datetime.date();
-            let time = datetime.time();
-            assert_eq!(y, date.year());
-            assert_eq!(mo, date.month());
-            assert_eq!(d, date.day());
-            assert_eq!(h, time.hour());
-            assert_eq!(mi, time.minute());
-            assert_eq!(s, time.second());
-        }
-    }
-}
-
-#[derive(Debug, Eq, PartialEq)]
-enum Granularity {
-    Second(NonZeroU32),
-    Minute(NonZeroU32),
-    Hour(NonZeroU32),
-}
-
-impl Granularity {
-    fn parse(text: &str) -> Option<Self> {
-        if let Some(index) = text.find('s') {
-            text.split_at(index)
-                .0
-                .parse::<u32>()
-                .ok()
-                .and_then(NonZeroU32::new)
-                .map(Granularity::Second)
-        } else if let Some(index) = text.find('m') {
-            text.split_at(index)
-                .0
-                .parse::<u32>()
-                .ok()
-                .and_then(NonZeroU32::new)
-                .map(Granularity::Minute)
-        } else if let Some(index) = text.find('h') {
-            text.split_at(index)
-                .0
-                .parse::<u32>()
-                .ok()
-                .and_then(NonZeroU32::new)
-                .map(Granularity::Hour)
-        } else {
-            None
+            (Some(2), None, "one\ntwo\n"),
+            (None, Some(1), "one\n"),
+            (None, Some(4), "one\n"),
+            (None, Some(5), "one\ntwo\n"),
+            (Some(3), Some(5), "one\ntwo\n"),
+            (Some(9), None, "one\ntwo\nthree\nfour"),
+            (None, Some(100), "one\ntwo\nthree\nfour"),
+        ];
+        for (lines, bytes, expected) in cases {
+            let limit = InputLimit { lines, bytes };
+            assert_eq!(
+                limit.truncate(data),
+                expected.as_bytes(),
+                "{lines:?} lines, {bytes:?} bytes"
+            );
         }
     }
 
-    fn bucketize(&self, datetime: &DateTime<Utc>) -> DateTime<Utc> {
-        match self {
-            Granularity::Second(s) => {
-                let s = s.get();
-                let time = datetime.time();
-                datetime
-                    .date()
-                    .and_hms(time.hour(), time.minute(), time.second() / s * s)
-            }
-            Granularity::Minute(m) => {
-                let m = m.get();
-                let time = datetime.time();
-                datetime.date().and_hms(time.hour(), time.minute() / m * m, 0)
-            }
-            Granularity::Hour(h) => {
-                let h = h.get();
-                let time = datetime.time();
-                datetime.date().and_hms(time.hour() / h * h, 0, 0)
-            }
-        }
+    #[test]
+    fn consumes_across_reads() {
+        let mut limit = InputLimit {
+            lines: None,
+            bytes: Some(6),
+        };
+        assert_eq!(limit.consume(b"one\nt"), None);
+        assert_eq!(limit.consume(b"wo\nthree\n"), Some(3));
     }
+}
 
-    fn successor(&self, datetime: &DateTime<Utc>) -> DateTime<Utc> {
-        match self {
-            Granularity::Second(s) => *datetime + Duration::seconds(i64::from(s.get())),
-            Granularity::Minute(m) => *datetime + Duration::minutes(i64::from(m.get())),
-            Granularity::Hour(h) => *datetime + Duration::hours(i64::from(h.get())),
-        }
+// Parse a duration like '30s', '5m', '2h' or '1d'.
+fn parse_duration(text: &str) -> Option<Duration> {
+    let unit = text.chars().last()?;
+    let amount = text[..text.len() - unit.len_utf8()]
+        .parse::<i64>()
+        .ok()
+        .filter(|amount| *amount >= 0)?;
+    match unit {
+        's' => Some(Duration::seconds(amount)),
+        'm' => Some(Duration::minutes(amount)),
+        'h' => Some(Duration::hours(amount)),
+        'd' => Some(Duration::days(amount)),
+        _ => None,
+    }
+}
+
+// Parse a --skew, a file and how far its clock was ahead, like 'hostA.log=+00:02:13', or behind
+// with '-'.
+fn parse_skew(text: &str) -> Option<(PathBuf, Duration)> {
+    let (path, offset) = text.rsplit_once('=').filter(|(path, _)| !path.is_empty())?;
+    let (sign, offset) = match offset.strip_prefix('-') {
+        Some(offset) => (-1, offset),
+        None => (1, offset.strip_prefix('+').unwrap_or(offset)),
+    };
+    let parts: Vec<i64> = offset
+        .split(':')
+        .map(|part| part.parse::<i64>().ok().filter(|_| part.len() == 2))
+        .collect::<Option<_>>()?;
+    let [hours, minutes, seconds] = parts[..] else {
+        return None;
+    };
+    if minutes >= 60 || seconds >= 60 {
+        return None;
     }
+    Some((
+        PathBuf::from(path),
+        Duration::seconds(sign * (hours * 3600 + minutes * 60 + seconds)),
+    ))
+}
+
+// Parse a --align offset, either a duration or minutes and optionally seconds past the hour.
+fn parse_alignment(text: &str) -> Option<Duration> {
+    let Some(past_hour) = text.strip_prefix(':') else {
+        return parse_duration(text);
+    };
+    let mut parts = past_hour.splitn(2, ':').map(|part| {
+        Some(part)
+            .filter(|part| part.len() == 2)
+            .and_then(|part| part.parse::<i64>().ok())
+            .filter(|value| (0..60).contains(value))
+    });
+    let minutes = parts.next()??;
+    let seconds = parts.next().unwrap_or(Some(0))?;
+    Some(Duration::minutes(minutes) + Duration::seconds(seconds))
 }
 
 #[cfg(test)]
-mod granularity_tests {
-    use super::Granularity;
-    use chrono::naive::NaiveDate;
-    use chrono::{DateTime, Timelike, Utc};
-    use std::num::NonZeroU32;
+mod duration_tests {
+    use super::{parse_alignment, parse_duration, parse_skew, test_args};
+    use chrono::Duration;
+    use std::path::PathBuf;
 
     #[test]
     fn parses() {
         let cases = vec![
-            ("1s", Granularity::Second(NonZeroU32::new(1).unwrap())),
-            ("5s", Granularity::Second(NonZeroU32::new(5).unwrap())),
-            ("1m", Granularity::Minute(NonZeroU32::new(1).unwrap())),
-            ("3m", Granularity::Minute(NonZeroU32::new(3).unwrap())),
-            ("1h", Granularity::Hour(NonZeroU32::new(1).unwrap())),
-            ("10h", Granularity::Hour(NonZeroU32::new(10).unwrap())),
+            ("0s", Duration::seconds(0)),
+            ("30s", Duration::seconds(30)),
+            ("5m", Duration::minutes(5)),
+            ("2h", Duration::hours(2)),
+            ("7d", Duration::days(7)),
         ];
         for (input, expected) in cases {
-            assert_eq!(Granularity::parse(input).unwrap(), expected);
+            assert_eq!(parse_duration(input).unwrap(), expected);
         }
     }
 
     #[test]
     fn bad_parses() {
-        let cases = vec!["1", "-1s", "m"];
+        let cases = vec!["", "1", "-1s", "s", "1x", "1.5h"];
         for input in cases {
-            assert!(Granularity::parse(input).is_none());
+            assert!(parse_duration(input).is_none());
         }
     }
 
     #[test]
-    fn bucketize() {
-        for granularity_seconds in 1..100 {
-            let granularity = Granularity::Second(NonZeroU32::new(granularity_seconds).unwrap());
-            for input_second in 0..60 {
-                let expected_bucket_second = input_second / granularity_seconds * granularity_seconds;
-                let input = DateTime::from_utc(NaiveDate::from_ymd(1991, 8, 10).and_hms(10, 30, input_second), Utc {});
-                let bucket = granularity.bucketize(&input);
-                assert!(bucket.time().second() % granularity_seconds == 0);
-                assert_eq!(expected_bucket_second, bucket.time().second());
-            }
+    fn parses_alignments() {
+        let cases = vec![
+            ("30s", Duration::seconds(30)),
+            ("2m", Duration::minutes(2)),
+            (":02", Duration::minutes(2)),
+            (":15", Duration::minutes(15)),
+            (":02:30", Duration::seconds(150)),
+            (":00", Duration::zero()),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_alignment(input).unwrap(), expected);
         }
-
-        for granularity_minutes in 1..100 {
-            let granularity = Granularity::Minute(NonZeroU32::new(granularity_minutes).unwrap());
-            for input_minute in 0..60 {
-                let expected_bucket_minute = input_minute / granularity_minutes * granularity_minutes;
-                let input = DateTime::from_utc(NaiveDate::from_ymd(1991, 8, 10).and_hms(10, input_minute, 15), Utc {});
-                let bucket = granularity.bucketize(&input);
-                assert!(bucket.time().minute() % granularity_minutes == 0);
-                assert_eq!(expected_bucket_minute, bucket.time().minute());
-                assert_eq!(0, bucket.time().second());
-            }
+        let bad_cases = vec![":", ":2", ":60", ":02:", ":02:60", ":02:30:00", "::", ":-1", "2"];
+        for input in bad_cases {
+            assert!(parse_alignment(input).is_none());
         }
+    }
 
-        for granularity_hours in 1..100 {
-            let granularity = Granularity::Hour(NonZeroU32::new(granularity_hours).unwrap());
-            for input_hour in 0..24 {
-                let expected_bucket_hour = input_hour / granularity_hours * granularity_hours;
-                let input = DateTime::from_utc(NaiveDate::from_ymd(1991, 8, 10).and_hms(input_hour, 43, 15), Utc {});
-                let bucket = granularity.bucketize(&input);
-                assert!(bucket.time().hour() % granularity_hours == 0);
-                assert_eq!(expected_bucket_hour, bucket.time().hour());
-                assert_eq!(0, bucket.time().second());
-                assert_eq!(0, bucket.time().minute());
-            }
+    #[test]
+    fn parses_skews() {
+        let cases = vec![
+            ("hostA.log=+00:02:13", "hostA.log", Duration::seconds(133)),
+            ("logs/b.log=-01:00:00", "logs/b.log", Duration::hours(-1)),
+            ("a=b.log=00:00:30", "a=b.log", Duration::seconds(30)),
+        ];
+        for (input, path, skew) in cases {
+            assert_eq!(parse_skew(input), Some((PathBuf::from(path), skew)));
+        }
+        let bad_cases = vec![
+            "",
+            "a.log",
+            "=+00:00:01",
+            "a.log=",
+            "a.log=+0:00:01",
+            "a.log=+00:60:00",
+            "a.log=2m",
+            "a.log=+-00:00:01",
+        ];
+        for input in bad_cases {
+            assert!(parse_skew(input).is_none(), "{}", input);
         }
     }
-}
 
-// Owned equivalent of chrono::format::Item.
-#[derive(Debug)]
-enum FormatItem {
-    Literal(String),
-    Space(String),
-    Numeric(Numeric, Pad),
-    Fixed(Fixed),
-}
-
-impl FormatItem {
-    // Convert from chrono's Item to ours. Allocates string slices into owned strings.
-    fn from_chrono(item: Item<'_>) -> Self {
-        use chrono::format::Item::*;
-        match item {
-            Literal(str_slice) => FormatItem::Literal(str_slice.to_string()),
-            OwnedLiteral(box_str) => FormatItem::Literal(box_str.to_string()),
-            Space(str_slice) => FormatItem::Space(str_slice.to_string()),
-            OwnedSpace(box_str) => FormatItem::Space(box_str.to_string()),
-            Numeric(numeric, pad) => FormatItem::Numeric(numeric, pad),
-            Fixed(fixed) => FormatItem::Fixed(fixed),
-            Error => unimplemented!(),
-        }
-    }
-
-    // Convert back to chrono's representation. Needed for parsing.
-    fn to_chrono(&self) -> Item {
-        match self {
-            FormatItem::Literal(string) => Item::Literal(string.as_str()),
-            FormatItem::Space(string) => Item::Space(string.as_str()),
-            FormatItem::Numeric(numeric, pad) => Item::Numeric(numeric.clone(), *pad),
-            FormatItem::Fixed(fixed) => Item::Fixed(fixed.clone()),
+    #[test]
+    fn skews_input_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("host.evtx");
+        std::fs::write(&path, "").unwrap();
+        let path = path.to_str().unwrap();
+        let cases = vec![
+            vec!["tbuck", "%F %T", path, "--skew", "host.evtx=+00:01:00"],
+            vec!["tbuck", "--evtx", path, "--skew", "host.evtx=+00:01:00"],
+        ];
+        for options in cases {
+            let args = test_args(&options);
+            assert_eq!(args.skew(&args.inputs[0]), Duration::minutes(1), "{options:?}");
         }
     }
 }